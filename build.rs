@@ -0,0 +1,5 @@
+fn main() {
+    // Use the protoc shipped as a crate, so that building doesn't need protobuf installed.
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    tonic_build::compile_protos("proto/authtown.proto").unwrap();
+}
@@ -0,0 +1,8 @@
+-- Cookies from before sessions were stored have no row here, and can't be told apart from ones
+-- whose session was logged out of, so everyone is logged out once by the upgrade applying this.
+CREATE TABLE sessions (
+    id UUID PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,5 @@
+CREATE TABLE oauth_client_logout_uris (
+    client_id TEXT NOT NULL REFERENCES oauth_clients (id) ON DELETE CASCADE,
+    uri TEXT NOT NULL,
+    PRIMARY KEY (client_id, uri)
+);
@@ -0,0 +1,8 @@
+CREATE TABLE terms_acceptances (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    version TEXT NOT NULL,
+    accepted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX terms_acceptances_user_id ON terms_acceptances (user_id);
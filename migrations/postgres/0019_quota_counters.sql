@@ -0,0 +1,8 @@
+CREATE TABLE quota_counters (
+    key TEXT NOT NULL,
+    window_start TIMESTAMPTZ NOT NULL,
+    count BIGINT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (key, window_start)
+);
+CREATE INDEX quota_counters_expires_at ON quota_counters (expires_at);
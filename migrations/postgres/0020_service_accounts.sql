@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN service BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE oauth_clients ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
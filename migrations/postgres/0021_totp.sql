@@ -0,0 +1,7 @@
+CREATE TABLE totp_secrets (
+    user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    secret TEXT,
+    pending_secret TEXT,
+    last_step BIGINT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
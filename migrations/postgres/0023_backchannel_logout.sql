@@ -0,0 +1,4 @@
+CREATE TABLE oauth_client_backchannel_uris (
+    client_id TEXT PRIMARY KEY REFERENCES oauth_clients (id) ON DELETE CASCADE,
+    uri TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Nothing to change in the schema, usernames are normalized in Rust once this is applied, see
+-- Store::migrate.
@@ -0,0 +1,13 @@
+CREATE TABLE jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    run_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    locked_until INTEGER,
+    last_error TEXT,
+    dead INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+);
+
+CREATE INDEX jobs_run_at ON jobs (run_at) WHERE NOT dead;
@@ -0,0 +1,6 @@
+-- SQLite only adds columns with constant defaults, so existing passwords get their clock started
+-- separately. New users have the time set on insert.
+ALTER TABLE users ADD COLUMN password_changed_at INTEGER NOT NULL DEFAULT 0;
+UPDATE users SET password_changed_at = strftime('%s', 'now');
+
+ALTER TABLE sessions ADD COLUMN restricted INTEGER NOT NULL DEFAULT 0;
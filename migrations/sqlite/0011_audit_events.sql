@@ -0,0 +1,12 @@
+CREATE TABLE audit_events (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    kind TEXT NOT NULL,
+    ip TEXT,
+    device TEXT,
+    country TEXT,
+    details TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX audit_events_user_id_created_at ON audit_events (user_id, created_at);
@@ -0,0 +1,9 @@
+CREATE TABLE data_exports (
+    id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    data TEXT,
+    created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    expires_at INTEGER NOT NULL
+);
+
+CREATE INDEX data_exports_user_id ON data_exports (user_id);
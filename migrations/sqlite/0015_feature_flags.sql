@@ -0,0 +1,5 @@
+CREATE TABLE feature_flags (
+    name TEXT PRIMARY KEY,
+    enabled INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+);
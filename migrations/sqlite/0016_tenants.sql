@@ -0,0 +1,20 @@
+-- The unique constraint on usernames can only be dropped by recreating the table, which the
+-- migrations run with foreign keys switched off for, so that dropping the old one doesn't cascade.
+CREATE TABLE users_new (
+    id INTEGER PRIMARY KEY,
+    tenant_id TEXT NOT NULL DEFAULT 'default',
+    username TEXT NOT NULL,
+    password_phc TEXT NOT NULL,
+    email TEXT,
+    password_changed_at INTEGER NOT NULL DEFAULT 0,
+    status TEXT NOT NULL DEFAULT 'active',
+    UNIQUE (tenant_id, username)
+);
+INSERT INTO users_new (id, username, password_phc, email, password_changed_at, status)
+    SELECT id, username, password_phc, email, password_changed_at, status FROM users;
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+CREATE UNIQUE INDEX users_email ON users (tenant_id, email);
+
+ALTER TABLE audit_events ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
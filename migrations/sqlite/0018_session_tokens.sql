@@ -0,0 +1,4 @@
+-- Sessions from before have no token ID, which their cookies match until they're first rotated.
+ALTER TABLE sessions ADD COLUMN token_id TEXT;
+ALTER TABLE sessions ADD COLUMN previous_token_id TEXT;
+ALTER TABLE sessions ADD COLUMN rotated_at INTEGER;
@@ -0,0 +1,8 @@
+CREATE TABLE quota_counters (
+    key TEXT NOT NULL,
+    window_start INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    PRIMARY KEY (key, window_start)
+);
+CREATE INDEX quota_counters_expires_at ON quota_counters (expires_at);
@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN service INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE oauth_clients ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
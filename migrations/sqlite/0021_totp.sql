@@ -0,0 +1,7 @@
+CREATE TABLE totp_secrets (
+    user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    secret TEXT,
+    pending_secret TEXT,
+    last_step INTEGER,
+    created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+);
@@ -0,0 +1 @@
+ALTER TABLE oauth_codes ADD COLUMN code_challenge TEXT;
@@ -0,0 +1,355 @@
+use crate::audit::{self, AuditEvent};
+use crate::error::Error;
+use crate::i18n;
+use crate::jobs::{self, Task};
+use crate::mail;
+use crate::store::Store;
+use crate::templates::Templates;
+use crate::util::{env_duration_ms, env_flag, env_usize, env_var_opt, format_time, http_client};
+use hyper::header::CONTENT_TYPE;
+use hyper::http::uri::Scheme;
+use hyper::{Body, Method, Request, Uri};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use slog::{error, warn, Logger};
+use std::backtrace::Backtrace;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::lazy::SyncLazy;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Something in the audit log that looks like an attack in progress, as sent to operators.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Alert {
+    /// One of `failure_burst`, `failures_across_accounts` and `impossible_travel`.
+    pub kind: String,
+    /// Address the failed logins came from, for failures across accounts.
+    pub ip: Option<String>,
+    /// User logged in to from two countries, for impossible travel.
+    pub user_id: Option<i32>,
+    /// JSON object with the counts or countries that raised the alert.
+    pub details: serde_json::Value,
+    /// Seconds since the Unix epoch.
+    pub detected_at: u64,
+}
+
+/// When logins look like an attack rather than people forgetting their passwords. Setting any of
+/// the thresholds to 0 turns its check off.
+pub struct AnomalyPolicy {
+    /// From `ANOMALY_WINDOW_MS`, how far back failed logins are counted, 10 minutes by default.
+    /// An alert isn't raised again for the same thing until a window after the last one.
+    pub window: Duration,
+    /// From `ANOMALY_FAILURE_BURST`, failed logins across the whole service in the window that
+    /// make for credential stuffing, 100 by default.
+    pub failure_burst: usize,
+    /// From `ANOMALY_ACCOUNTS_PER_IP`, accounts failed to be logged in to from one address in the
+    /// window, 10 by default. Usernames with no account count as an account each.
+    pub accounts_per_ip: usize,
+    /// From `ANOMALY_TRAVEL_WINDOW_MS`, how soon after a login of the user one from another
+    /// country can't be them, an hour by default. Countries from the header of
+    /// [`crate::risk::RiskPolicy::country_header`] are all that's known of where logins are from.
+    pub travel_window: Duration,
+    /// From `ANOMALY_ALERT_EMAIL`, the address alerts are mailed to.
+    pub alert_email: Option<String>,
+    /// From `ANOMALY_ALERT_WEBHOOK_URL`, an HTTPS URL alerts are POSTed to in JSON.
+    pub alert_webhook: Option<String>,
+}
+
+/// What the analyzer remembers of the audit log between rounds.
+#[derive(Default)]
+pub struct Detector {
+    /// Position in the log of the last event looked at, missing until the first round.
+    position: Option<i64>,
+    failures: VecDeque<Failure>,
+    /// Last successful login of each user with a country.
+    logins: HashMap<i32, (SystemTime, String)>,
+    /// When alerts were last raised, by kind and whatever they're about.
+    raised: HashMap<(&'static str, String), SystemTime>,
+}
+
+struct Failure {
+    at: SystemTime,
+    ip: Option<String>,
+    user_id: Option<i32>,
+}
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10 * 60);
+const DEFAULT_FAILURE_BURST: usize = 100;
+const DEFAULT_ACCOUNTS_PER_IP: usize = 10;
+const DEFAULT_TRAVEL_WINDOW: Duration = Duration::from_secs(60 * 60);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events read from the log at once, so that a round after a long outage doesn't hold them all.
+const BATCH_SIZE: usize = 1000;
+
+pub const FAILURE_BURST: &str = "failure_burst";
+pub const FAILURES_ACROSS_ACCOUNTS: &str = "failures_across_accounts";
+pub const IMPOSSIBLE_TRAVEL: &str = "impossible_travel";
+
+static METRIC_ALERT_COUNT: SyncLazy<IntCounterVec> = SyncLazy::new(|| {
+    register_int_counter_vec!(
+        "authtown_anomaly_alert_count",
+        "Number of login anomalies alerted about, by kind",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+impl AnomalyPolicy {
+    pub fn from_env() -> Result<AnomalyPolicy, Error> {
+        let alert_webhook = env_var_opt("ANOMALY_ALERT_WEBHOOK_URL")?;
+        if let Some(url) = &alert_webhook {
+            // The client only speaks HTTPS, which alerts about attacks should go over anyway.
+            let uri: Option<Uri> = url.parse().ok();
+            if uri.and_then(|uri| uri.scheme().cloned()) != Some(Scheme::HTTPS) {
+                return Err(Error::InvalidAlertWebhook(
+                    url.clone(),
+                    Backtrace::capture(),
+                ));
+            }
+        }
+        Ok(AnomalyPolicy {
+            window: env_duration_ms("ANOMALY_WINDOW_MS", DEFAULT_WINDOW)?,
+            failure_burst: env_usize("ANOMALY_FAILURE_BURST", DEFAULT_FAILURE_BURST)?,
+            accounts_per_ip: env_usize("ANOMALY_ACCOUNTS_PER_IP", DEFAULT_ACCOUNTS_PER_IP)?,
+            travel_window: env_duration_ms("ANOMALY_TRAVEL_WINDOW_MS", DEFAULT_TRAVEL_WINDOW)?,
+            alert_email: env_var_opt("ANOMALY_ALERT_EMAIL")?,
+            alert_webhook,
+        })
+    }
+}
+
+impl Detector {
+    /// Raises alerts about the events recorded since the last round. The first round only finds
+    /// where the log ends, as what happened before the analyzer started says little about attacks
+    /// going on now.
+    pub async fn analyze(
+        &mut self,
+        store: &Store,
+        templates: &Templates,
+        policy: &AnomalyPolicy,
+        log: &Logger,
+    ) -> Result<(), Error> {
+        let Some(mut position) = self.position else {
+            self.position = Some(store.audit.position().await?);
+            return Ok(());
+        };
+        loop {
+            let events = store.audit.after(position, BATCH_SIZE).await?;
+            for (event_position, event) in &events {
+                // Moved past first, so that an alert failing to be queued doesn't get the event
+                // counted twice in the next round.
+                position = *event_position;
+                self.position = Some(position);
+                for alert in self.observe(event, policy) {
+                    warn!(log, "Login anomaly detected"; "kind" => &alert.kind, "ip" => &alert.ip, "user_id" => alert.user_id);
+                    raise(&alert, store, templates, policy).await?;
+                }
+            }
+            if events.len() < BATCH_SIZE {
+                break;
+            }
+        }
+        self.forget(policy, SystemTime::now());
+        Ok(())
+    }
+
+    /// Takes the event into account, returning the alerts it raises.
+    pub fn observe(&mut self, event: &AuditEvent, policy: &AnomalyPolicy) -> Vec<Alert> {
+        let now = event.created_at;
+        let mut alerts = Vec::new();
+        if event.kind == audit::LOGIN_FAILED {
+            self.failures
+                .retain(|failure| failure.at + policy.window > now);
+            self.failures.push_back(Failure {
+                at: now,
+                ip: event.ip.clone(),
+                user_id: event.user.map(|user| user.id),
+            });
+            let failures = self.failures.len();
+            if policy.failure_burst > 0 && failures >= policy.failure_burst {
+                let details = serde_json::json!({
+                    "failures": failures,
+                    "window_s": policy.window.as_secs(),
+                });
+                alerts.extend(self.alert(FAILURE_BURST, None, None, details, now, policy));
+            }
+            if let (true, Some(ip)) = (policy.accounts_per_ip > 0, &event.ip) {
+                let from_ip: Vec<_> = self
+                    .failures
+                    .iter()
+                    .filter(|failure| failure.ip.as_ref() == Some(ip))
+                    .collect();
+                let users: HashSet<_> = from_ip
+                    .iter()
+                    .filter_map(|failure| failure.user_id)
+                    .collect();
+                let unknown = from_ip
+                    .iter()
+                    .filter(|failure| failure.user_id.is_none())
+                    .count();
+                let accounts = users.len() + unknown;
+                if accounts >= policy.accounts_per_ip {
+                    let details = serde_json::json!({
+                        "accounts": accounts,
+                        "window_s": policy.window.as_secs(),
+                    });
+                    let ip = Some(ip.clone());
+                    alerts.extend(self.alert(
+                        FAILURES_ACROSS_ACCOUNTS,
+                        ip,
+                        None,
+                        details,
+                        now,
+                        policy,
+                    ));
+                }
+            }
+        }
+        if let (audit::LOGIN_SUCCEEDED, Some(user), Some(country)) =
+            (event.kind.as_str(), event.user, &event.country)
+        {
+            let last = self.logins.insert(user.id, (now, country.clone()));
+            if let Some((at, last_country)) = last {
+                let soon = now.duration_since(at).unwrap_or_default() < policy.travel_window;
+                if policy.travel_window > Duration::ZERO && soon && last_country != *country {
+                    let details = serde_json::json!({
+                        "from": last_country,
+                        "to": country,
+                        "minutes": now.duration_since(at).unwrap_or_default().as_secs() / 60,
+                    });
+                    let user_id = Some(user.id);
+                    alerts.extend(self.alert(
+                        IMPOSSIBLE_TRAVEL,
+                        None,
+                        user_id,
+                        details,
+                        now,
+                        policy,
+                    ));
+                }
+            }
+        }
+        alerts
+    }
+
+    fn alert(
+        &mut self,
+        kind: &'static str,
+        ip: Option<String>,
+        user_id: Option<i32>,
+        details: serde_json::Value,
+        now: SystemTime,
+        policy: &AnomalyPolicy,
+    ) -> Option<Alert> {
+        let subject = match (&ip, user_id) {
+            (Some(ip), _) => ip.clone(),
+            (None, Some(user_id)) => user_id.to_string(),
+            (None, None) => String::new(),
+        };
+        if let Some(raised_at) = self.raised.get(&(kind, subject.clone())) {
+            if *raised_at + policy.window > now {
+                return None;
+            }
+        }
+        self.raised.insert((kind, subject), now);
+        Some(Alert {
+            kind: kind.to_owned(),
+            ip,
+            user_id,
+            details,
+            detected_at: now.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        })
+    }
+
+    /// Drops what's too old to raise alerts anymore, so that memory doesn't grow with every user
+    /// that ever logged in.
+    fn forget(&mut self, policy: &AnomalyPolicy, now: SystemTime) {
+        self.failures
+            .retain(|failure| failure.at + policy.window > now);
+        self.logins
+            .retain(|_, (at, _)| *at + policy.travel_window > now);
+        self.raised
+            .retain(|_, raised_at| *raised_at + policy.window > now);
+    }
+}
+
+/// Spawns the analyzer when `ANOMALY_DETECTION` is set, reading the audit log every
+/// `ANOMALY_POLL_INTERVAL_MS`. Each instance running it raises alerts of its own, so only one of
+/// a deployment should.
+pub fn spawn(store: Arc<Store>, templates: Arc<Templates>, log: &Logger) -> Result<(), Error> {
+    if !env_flag("ANOMALY_DETECTION")? {
+        return Ok(());
+    }
+    let policy = AnomalyPolicy::from_env()?;
+    let interval = env_duration_ms("ANOMALY_POLL_INTERVAL_MS", DEFAULT_POLL_INTERVAL)?;
+    let log = log.clone();
+    tokio::spawn(async move {
+        let mut detector = Detector::default();
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            // The supervisor already reports the database being down, the next round will do.
+            if !store.is_healthy() {
+                continue;
+            }
+            if let Err(e) = detector.analyze(&store, &templates, &policy, &log).await {
+                error!(log, "Anomaly detection failed"; e.log_message(), e.log_backtrace());
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Counts the alert, and queues it to be mailed and posted to the webhook, whichever of them
+/// are configured.
+pub async fn raise(
+    alert: &Alert,
+    store: &Store,
+    templates: &Templates,
+    policy: &AnomalyPolicy,
+) -> Result<(), Error> {
+    METRIC_ALERT_COUNT.with_label_values(&[&alert.kind]).inc();
+    if let Some(email) = &policy.alert_email {
+        let mut context = tera::Context::new();
+        context.insert("kind", &alert.kind);
+        context.insert("ip", &alert.ip);
+        context.insert("user_id", &alert.user_id);
+        context.insert("details", &alert.details);
+        let detected_at = UNIX_EPOCH + Duration::from_secs(alert.detected_at);
+        context.insert("time", &format_time(detected_at));
+        let mail = mail::render(templates, "anomaly", email, i18n::DEFAULT_LOCALE, &context)?;
+        jobs::enqueue(&*store.jobs, &Task::SendMail(mail)).await?;
+    }
+    if let Some(url) = &policy.alert_webhook {
+        let task = Task::SendAlert {
+            url: url.clone(),
+            alert: alert.clone(),
+        };
+        jobs::enqueue(&*store.jobs, &task).await?;
+    }
+    Ok(())
+}
+
+pub async fn post_webhook(url: &str, alert: &Alert) -> Result<(), Error> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(alert)?))
+        .unwrap();
+    let response = tokio::time::timeout(WEBHOOK_TIMEOUT, http_client().request(request))
+        .await
+        .map_err(|_| Error::AlertTimeout(Backtrace::capture()))??;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Err(Error::AlertRejected {
+        status,
+        body: String::from_utf8_lossy(&body).into_owned(),
+        backtrace: Backtrace::capture(),
+    })
+}
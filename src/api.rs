@@ -0,0 +1,124 @@
+use crate::error::Error;
+use crate::features::Features;
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use uuid::Uuid;
+
+pub mod v1;
+
+pub const PREFIX: &str = "/api";
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub user: UserResponse,
+}
+
+#[derive(Serialize)]
+pub struct UserResponse {
+    pub id: i32,
+}
+
+#[derive(Serialize)]
+pub struct ChallengeResponse {
+    pub challenge: &'static str,
+}
+
+/// Whether each feature is switched on, keyed by its name.
+#[derive(Serialize)]
+pub struct FeaturesResponse<'a> {
+    pub features: &'a Features,
+}
+
+/// A page of the user's logins, newest first.
+#[derive(Serialize)]
+pub struct ActivityResponse {
+    pub logins: Vec<LoginResponse>,
+    pub page: usize,
+    /// Page with the logins before these, if there are any.
+    pub next_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct LoginResponse {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub succeeded: bool,
+    /// What was used to log in or tried, like `password` or `email_code`.
+    pub factor: Option<String>,
+    pub ip: Option<String>,
+    pub country: Option<String>,
+    /// ID of the browser, the same for every login from it.
+    pub device: Option<String>,
+    /// Whether it was the browser asking.
+    pub this_device: bool,
+}
+
+/// The mode the service is in, one of `normal`, `read-only` and `maintenance`.
+#[derive(Serialize)]
+pub struct ModeResponse {
+    pub mode: &'static str,
+}
+
+/// Answer to a registration that waits for an admin to approve it, naming the status as `pending`.
+#[derive(Serialize)]
+pub struct PendingResponse {
+    pub user: UserResponse,
+    pub status: &'static str,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    error: ErrorObject<'a>,
+    request_id: String,
+}
+
+#[derive(Serialize)]
+struct ErrorObject<'a> {
+    code: &'a str,
+    message: String,
+}
+
+/// Whether the client wants JSON responses rather than HTML pages and redirects, either by asking
+/// for them explicitly or by using the API routes.
+pub fn wants_json(request: &Request<Body>) -> bool {
+    request.uri().path().starts_with(&format!("{}/", PREFIX))
+        || header_contains(request, ACCEPT, "application/json")
+}
+
+/// Parses the body as JSON or as a form, depending on what the client says it sent.
+pub fn parse_body<T: DeserializeOwned>(request: &Request<Body>, body: &[u8]) -> Result<T, Error> {
+    if header_contains(request, CONTENT_TYPE, "application/json") {
+        Ok(serde_json::from_slice(body)?)
+    } else {
+        Ok(serde_urlencoded::from_bytes(body)?)
+    }
+}
+
+pub fn response(status: StatusCode, value: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(value).unwrap().into())
+        .unwrap()
+}
+
+pub fn error_response(error: &Error, request_id: Uuid, locale: &str) -> Response<Body> {
+    // Internal errors get the generic message, so that their details only end up in the logs.
+    response(
+        error.status_code(),
+        &ErrorResponse {
+            error: ErrorObject {
+                code: error.code(),
+                message: error.localized_message(locale),
+            },
+            request_id: request_id.to_string(),
+        },
+    )
+}
+
+fn header_contains(request: &Request<Body>, name: hyper::header::HeaderName, value: &str) -> bool {
+    let header = request.headers().get(name).map(|header| header.to_str());
+    matches!(header, Some(Ok(header)) if header.contains(value))
+}
@@ -0,0 +1,90 @@
+//! Request and response bodies of the versioned API. Fields may be added, but nothing here may be
+//! renamed or removed without moving to a new version, as clients depend on the exact shapes.
+
+use crate::claims::Claims;
+use crate::session::Session;
+use crate::user::Profile;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const PREFIX: &str = "/api/v1";
+
+#[derive(Deserialize)]
+pub struct CredentialsRequest {
+    pub username: String,
+    pub password: String,
+    /// Only looked at when registering, where it's required if the server has terms set up.
+    #[serde(default)]
+    pub accept_terms: bool,
+}
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TermsRequest {
+    pub accept: bool,
+}
+
+/// Answer to a login that still needs a second factor, naming the one to complete it with, or
+/// needs the terms accepted, naming `terms`.
+#[derive(Serialize)]
+pub struct ChallengeResponse {
+    pub challenge: &'static str,
+}
+
+/// Answer to a registration that waits for an admin to approve it, which `status` will say more
+/// about if other reasons ever come up.
+#[derive(Serialize)]
+pub struct PendingResponse {
+    pub user: UserObject,
+    pub status: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub session: SessionObject,
+    pub user: UserObject,
+    /// Left out when the session has none, like for servers without any set up.
+    #[serde(skip_serializing_if = "Claims::is_empty")]
+    pub claims: Claims,
+}
+
+#[derive(Serialize)]
+pub struct SessionObject {
+    pub id: Uuid,
+}
+
+#[derive(Serialize)]
+pub struct UserObject {
+    pub id: i32,
+}
+
+#[derive(Serialize)]
+pub struct ProfileResponse {
+    pub id: i32,
+    pub username: String,
+}
+
+impl From<&Session> for SessionResponse {
+    fn from(session: &Session) -> SessionResponse {
+        SessionResponse {
+            session: SessionObject { id: session.id() },
+            user: UserObject {
+                id: session.user().id,
+            },
+            claims: session.claims().clone(),
+        }
+    }
+}
+
+impl From<Profile> for ProfileResponse {
+    fn from(profile: Profile) -> ProfileResponse {
+        ProfileResponse {
+            id: profile.id,
+            username: profile.username,
+        }
+    }
+}
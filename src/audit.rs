@@ -0,0 +1,112 @@
+use crate::client::ClientInfo;
+use crate::error::Error;
+use crate::events::Event;
+use crate::jobs::{self, Task};
+use crate::store::Store;
+use crate::user::User;
+use async_trait::async_trait;
+use std::time::SystemTime;
+
+/// Something that happened to an account, kept for the user and operators to look back at.
+#[derive(Clone)]
+pub struct AuditEvent {
+    pub user: Option<User>,
+    /// Tenant the request was for, which for events of a user is the one they belong to.
+    pub tenant: String,
+    pub kind: String,
+    pub ip: Option<String>,
+    pub device: Option<String>,
+    pub country: Option<String>,
+    /// JSON object with whatever else is worth knowing about the event.
+    pub details: serde_json::Value,
+    pub created_at: SystemTime,
+}
+
+#[async_trait]
+pub trait AuditStore: Send + Sync {
+    async fn insert(&self, event: &AuditEvent) -> Result<(), Error>;
+
+    /// Events of the user since the given time, newest first, at most `limit` of them.
+    async fn list(
+        &self,
+        user: User,
+        since: SystemTime,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, Error>;
+
+    /// Logins of the user, both successful and failed, newest first, skipping the first `offset`
+    /// of them and returning at most `limit`.
+    async fn logins(
+        &self,
+        user: User,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, Error>;
+
+    /// Where the log ends, for reading the events recorded from now on with
+    /// [`AuditStore::after`].
+    async fn position(&self) -> Result<i64, Error>;
+
+    /// Events of every user recorded after the position, oldest first, at most `limit` of them,
+    /// each with its own position.
+    async fn after(&self, position: i64, limit: usize) -> Result<Vec<(i64, AuditEvent)>, Error>;
+}
+
+pub const REGISTERED: &str = "registered";
+pub const LOGIN_SUCCEEDED: &str = "login_succeeded";
+pub const LOGIN_FAILED: &str = "login_failed";
+/// Only for users logging out themselves, admins stopping an impersonation are recorded as such.
+pub const LOGGED_OUT: &str = "logged_out";
+pub const IMPERSONATION_STARTED: &str = "impersonation_started";
+pub const IMPERSONATION_ENDED: &str = "impersonation_ended";
+/// Anything other than a GET done by an admin impersonating the user.
+pub const IMPERSONATED_REQUEST: &str = "impersonated_request";
+/// The password or mailed code was right, but the login looked risky enough to ask for more.
+pub const LOGIN_STEP_UP: &str = "login_step_up";
+pub const TERMS_ACCEPTED: &str = "terms_accepted";
+pub const DATA_EXPORTED: &str = "data_exported";
+/// An admin suspended, banned or reinstated the account, recorded for both of them.
+pub const ACCOUNT_STATUS_CHANGED: &str = "account_status_changed";
+/// An admin approved or rejected a registration waiting for approval, recorded for both of them.
+pub const REGISTRATION_REVIEWED: &str = "registration_reviewed";
+/// An admin switched a feature on or off, recorded for the admin.
+pub const FEATURE_TOGGLED: &str = "feature_toggled";
+/// An admin switched the service into another mode, recorded for the admin.
+pub const MODE_SWITCHED: &str = "mode_switched";
+/// Recorded for the service account, and for the admin when it was made on the dashboard rather
+/// than with the command.
+pub const SERVICE_ACCOUNT_CREATED: &str = "service_account_created";
+/// A service account got a token with the credentials of its client, which is how it acts.
+pub const SERVICE_TOKEN_ISSUED: &str = "service_token_issued";
+
+impl AuditEvent {
+    pub fn new(
+        kind: &str,
+        user: Option<User>,
+        client: &ClientInfo,
+        details: serde_json::Value,
+    ) -> AuditEvent {
+        AuditEvent {
+            user,
+            tenant: client.tenant.id.clone(),
+            kind: kind.to_owned(),
+            ip: client.ip.map(|ip| ip.to_string()),
+            device: client.device.clone(),
+            country: client.country.clone(),
+            details,
+            created_at: SystemTime::now(),
+        }
+    }
+}
+
+/// Stores the event, and with `publish` set, queues it to be published as well, see
+/// [`crate::events`]. Only events in the life of an account are published: registrations,
+/// logins, logouts and changes of the account status.
+pub async fn record(store: &Store, event: &AuditEvent, publish: bool) -> Result<(), Error> {
+    store.audit.insert(event).await?;
+    if publish {
+        let event = Event::new(event);
+        jobs::enqueue(&*store.jobs, &Task::PublishEvent { event }).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::jobs::{self, Task};
+use crate::store::Store;
+use crate::user::User;
+use crate::util::http_client;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use sha2::{Digest, Sha256};
+use std::backtrace::Backtrace;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// How long clients have to accept a logout token, which OpenID Connect Back-Channel Logout
+/// section 2.4 recommends keeping to a couple of minutes.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(2 * 60);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Queues a logout token for each client the user allowed that has a back-channel logout URI, so
+/// that they end their own sessions of the user too.
+pub async fn notify(store: &Store, user: User, issuer: &str) -> Result<(), Error> {
+    for consent in store.consents.list(user).await? {
+        let Some(url) = store
+            .clients
+            .backchannel_logout_uri(&consent.client_id)
+            .await?
+        else {
+            continue;
+        };
+        let task = Task::BackchannelLogout {
+            url,
+            issuer: issuer.to_owned(),
+            client_id: consent.client_id,
+            user,
+        };
+        jobs::enqueue(&*store.jobs, &task).await?;
+    }
+    Ok(())
+}
+
+/// POSTs the logout token to the client. It's signed here rather than when queued, so that retries
+/// don't send one that has expired meanwhile.
+pub async fn send(
+    url: &str,
+    issuer: &str,
+    client_id: &str,
+    user: User,
+    crypto: &Crypto,
+) -> Result<(), Error> {
+    let token = logout_token(issuer, client_id, user, crypto, SystemTime::now());
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(serde_urlencoded::to_string([(
+            "logout_token",
+            &token,
+        )])?))
+        .unwrap();
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, http_client().request(request))
+        .await
+        .map_err(|_| Error::BackchannelLogoutTimeout(Backtrace::capture()))??;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Err(Error::BackchannelLogoutRejected {
+        status,
+        body: String::from_utf8_lossy(&body).into_owned(),
+        backtrace: Backtrace::capture(),
+    })
+}
+
+/// Logout token of OpenID Connect Back-Channel Logout section 2.4, a JWT signed with
+/// [`Crypto::token_key`].
+pub fn logout_token(
+    issuer: &str,
+    client_id: &str,
+    user: User,
+    crypto: &Crypto,
+    now: SystemTime,
+) -> String {
+    let key = crypto.token_key();
+    let header = serde_json::json!({
+        "alg": "EdDSA",
+        "typ": "logout+jwt",
+        "kid": key_id(&key),
+    });
+    let issued_at = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let claims = serde_json::json!({
+        "iss": issuer,
+        "aud": client_id,
+        "iat": issued_at,
+        "exp": issued_at + TOKEN_LIFETIME.as_secs(),
+        "jti": Uuid::new_v4().to_string(),
+        "sub": user.id.to_string(),
+        "events": { LOGOUT_EVENT: {} },
+    });
+    let signing_input = format!(
+        "{}.{}",
+        base64url(header.to_string().as_bytes()),
+        base64url(claims.to_string().as_bytes())
+    );
+    let signature = key.sign(signing_input.as_bytes());
+    format!("{}.{}", signing_input, base64url(signature.as_ref()))
+}
+
+/// JSON Web Key Set of RFC 7517 with the public half of [`Crypto::token_key`], for clients to
+/// check logout tokens with.
+pub fn jwks(crypto: &Crypto) -> serde_json::Value {
+    let key = crypto.token_key();
+    serde_json::json!({
+        "keys": [{
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "use": "sig",
+            "alg": "EdDSA",
+            "kid": key_id(&key),
+            "x": base64url(key.public_key().as_ref()),
+        }],
+    })
+}
+
+/// Changes along with the secret, so that clients know to fetch the key set again.
+fn key_id(key: &Ed25519KeyPair) -> String {
+    hex::encode(&Sha256::digest(key.public_key().as_ref())[..8])
+}
+
+fn base64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
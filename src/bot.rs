@@ -0,0 +1,100 @@
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::util::{env_duration_ms, env_usize};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What's known about how the registration form was filled in, for telling people from scripts.
+pub struct Signals {
+    /// Whether the field hidden from people was filled in, which only scripts filling in every
+    /// field they find do.
+    pub honeypot_filled: bool,
+    /// How long the form was open for, or `None` when it came back without a valid form token.
+    pub time_to_submit: Option<Duration>,
+}
+
+/// Scores how likely a registration is to be automated, from 0 for surely a person. Boxed in the
+/// [`BotPolicy`], so that it can be swapped for one asking an outside service that knows more.
+pub trait BotScorer: Send + Sync {
+    fn score(&self, signals: &Signals) -> usize;
+}
+
+/// Scores the signals the form itself gives. A missing token alone stays under the default
+/// threshold, as it's also what clients posting the form without loading the page send.
+pub struct HeuristicScorer {
+    pub min_time_to_submit: Duration,
+}
+
+pub struct BotPolicy {
+    pub scorer: Box<dyn BotScorer>,
+    /// Registrations scoring this much or more are rejected.
+    pub reject_score: usize,
+}
+
+const DEFAULT_MIN_TIME_TO_SUBMIT: Duration = Duration::from_secs(2);
+const DEFAULT_REJECT_SCORE: usize = 50;
+
+const HONEYPOT_SCORE: usize = 100;
+const TOO_FAST_SCORE: usize = 60;
+const NO_FORM_TOKEN_SCORE: usize = 30;
+
+const FORM_TOKEN_SIGNATURE_DOMAIN: &str = "form.";
+
+const NONCE_LENGTH: usize = 8;
+
+impl BotPolicy {
+    pub fn from_env() -> Result<BotPolicy, Error> {
+        Ok(BotPolicy {
+            scorer: Box::new(HeuristicScorer {
+                min_time_to_submit: env_duration_ms(
+                    "BOT_MIN_TIME_TO_SUBMIT_MS",
+                    DEFAULT_MIN_TIME_TO_SUBMIT,
+                )?,
+            }),
+            reject_score: env_usize("BOT_REJECT_SCORE", DEFAULT_REJECT_SCORE)?,
+        })
+    }
+}
+
+impl BotScorer for HeuristicScorer {
+    fn score(&self, signals: &Signals) -> usize {
+        let mut score = 0;
+        if signals.honeypot_filled {
+            score += HONEYPOT_SCORE;
+        }
+        match signals.time_to_submit {
+            Some(time) if time < self.min_time_to_submit => score += TOO_FAST_SCORE,
+            Some(_) => {}
+            None => score += NO_FORM_TOKEN_SCORE,
+        }
+        score
+    }
+}
+
+/// Token put in the form when it's rendered, telling when that was in a way that can't be forged.
+/// The nonce makes every rendering of a page differ, even ones within the same millisecond.
+pub fn form_token(crypto: &Crypto) -> String {
+    let rendered_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let mut nonce = [0; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    let payload = format!("{}.{}", rendered_at, hex::encode(nonce));
+    let signature = crypto.sign(&signed_data(&payload));
+    format!("{}.{}", payload, hex::encode(&signature.hash))
+}
+
+pub fn time_to_submit(form_token: &str, crypto: &Crypto) -> Option<Duration> {
+    let (payload, signature) = form_token.rsplit_once('.')?;
+    crypto
+        .verify(&signed_data(payload), &hex::decode(signature).ok()?)
+        .ok()?;
+    let (rendered_at, _nonce) = payload.split_once('.')?;
+    let rendered_at = UNIX_EPOCH + Duration::from_millis(rendered_at.parse().ok()?);
+    SystemTime::now().duration_since(rendered_at).ok()
+}
+
+fn signed_data(payload: &str) -> Vec<u8> {
+    format!("{}{}", FORM_TOKEN_SIGNATURE_DOMAIN, payload).into_bytes()
+}
@@ -0,0 +1,95 @@
+use crate::error::Error;
+use crate::store::Store;
+use crate::user::User;
+use crate::util::env_usize;
+use async_trait::async_trait;
+use std::backtrace::Backtrace;
+
+/// Whatever the deployment wants apps to know about the user without asking, like roles or
+/// entitlements, keyed by name. They're put together once at login and sealed into the session, so
+/// changes only reach sessions created after them.
+pub type Claims = serde_json::Map<String, serde_json::Value>;
+
+#[async_trait]
+pub trait ClaimStore: Send + Sync {
+    /// Claims stored for the user, which the hooks start from.
+    async fn list(&self, user: User) -> Result<Claims, Error>;
+
+    async fn set(&self, user: User, name: &str, value: &serde_json::Value) -> Result<(), Error>;
+
+    /// Removes the claim, returning whether the user had it.
+    async fn remove(&self, user: User, name: &str) -> Result<bool, Error>;
+}
+
+/// Adds to or changes the claims of a user logging in, for deployments working them out from
+/// somewhere else than the claims table. Registered in the [`ClaimsPolicy`] by whoever builds the
+/// server.
+#[async_trait]
+pub trait ClaimsHook: Send + Sync {
+    async fn claims(
+        &self,
+        user: User,
+        tenant: &str,
+        store: &Store,
+        claims: &mut Claims,
+    ) -> Result<(), Error>;
+}
+
+pub struct ClaimsPolicy {
+    /// Run in order, each seeing what the ones before it did.
+    pub hooks: Vec<Box<dyn ClaimsHook>>,
+    /// Most the claims can take up as JSON, from `CLAIMS_MAX_BYTES`, as they make the session
+    /// cookie longer and browsers don't keep cookies past 4 KiB.
+    pub max_bytes: usize,
+}
+
+const DEFAULT_MAX_BYTES: usize = 1024;
+
+const MAX_NAME_LENGTH: usize = 64;
+
+impl ClaimsPolicy {
+    pub fn from_env() -> Result<ClaimsPolicy, Error> {
+        Ok(ClaimsPolicy {
+            hooks: Vec::new(),
+            max_bytes: env_usize("CLAIMS_MAX_BYTES", DEFAULT_MAX_BYTES)?,
+        })
+    }
+}
+
+/// Claims of the user for a new session, the stored ones as changed by the hooks.
+pub async fn collect(
+    user: User,
+    tenant: &str,
+    store: &Store,
+    policy: &ClaimsPolicy,
+) -> Result<Claims, Error> {
+    let mut claims = store.claims.list(user).await?;
+    for hook in &policy.hooks {
+        hook.claims(user, tenant, store, &mut claims).await?;
+    }
+    for name in claims.keys() {
+        validate_name(name)?;
+    }
+    let size = serde_json::to_vec(&claims)?.len();
+    if size > policy.max_bytes {
+        return Err(Error::ClaimsTooLarge(size, Backtrace::capture()));
+    }
+    Ok(claims)
+}
+
+/// Names are kept to what's safe in headers and template variables, so that apps can use them
+/// without escaping.
+pub fn validate_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == ':');
+    if !valid {
+        return Err(Error::InvalidClaimName(
+            name.to_owned(),
+            Backtrace::capture(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use crate::error::Error;
+use crate::store::Store;
+use crate::util::env_duration_ms;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use slog::{error, info, Logger};
+use std::lazy::SyncLazy;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How long expired rows stay in the store before being purged. Expired rows are ignored right
+/// away either way; the retention only decides how long they are kept around for inspection.
+pub struct Retention {
+    pub sessions: Duration,
+    /// Applies to OAuth tokens and codes, and to one-time codes sent for the second factor.
+    pub tokens: Duration,
+}
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_SESSION_RETENTION: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+const DEFAULT_TOKEN_RETENTION: Duration = Duration::from_secs(60 * 60 * 24);
+
+static METRIC_PURGED_COUNT: SyncLazy<IntCounterVec> = SyncLazy::new(|| {
+    register_int_counter_vec!(
+        "authtown_cleanup_purged_count",
+        "Number of expired rows purged from the store, by kind",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+impl Retention {
+    pub fn from_env() -> Result<Retention, Error> {
+        Ok(Retention {
+            sessions: env_duration_ms("CLEANUP_SESSION_RETENTION_MS", DEFAULT_SESSION_RETENTION)?,
+            tokens: env_duration_ms("CLEANUP_TOKEN_RETENTION_MS", DEFAULT_TOKEN_RETENTION)?,
+        })
+    }
+}
+
+/// Spawns a task purging expired rows every `CLEANUP_INTERVAL_MS`, starting right away.
+pub fn spawn(store: Arc<Store>, log: &Logger) -> Result<(), Error> {
+    let interval = env_duration_ms("CLEANUP_INTERVAL_MS", DEFAULT_INTERVAL)?;
+    let retention = Retention::from_env()?;
+    let log = log.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            // The supervisor already reports the database being down, the next round will do.
+            if !store.is_healthy() {
+                continue;
+            }
+            if let Err(e) = purge(&store, &retention, &log).await {
+                error!(log, "Cleanup failed"; e.log_message(), e.log_backtrace());
+            }
+        }
+    });
+    Ok(())
+}
+
+pub async fn purge(store: &Store, retention: &Retention, log: &Logger) -> Result<(), Error> {
+    let now = SystemTime::now();
+    let sessions = store
+        .sessions
+        .purge_expired(now - retention.sessions)
+        .await?;
+    METRIC_PURGED_COUNT
+        .with_label_values(&["session"])
+        .inc_by(sessions);
+    let tokens = store.tokens.purge_expired(now - retention.tokens).await?;
+    METRIC_PURGED_COUNT
+        .with_label_values(&["token"])
+        .inc_by(tokens);
+    let codes = store.otp.purge_expired(now - retention.tokens).await?;
+    METRIC_PURGED_COUNT
+        .with_label_values(&["otp_code"])
+        .inc_by(codes);
+    // Exports hold a copy of everything about the user, so they're never kept past expiring.
+    let exports = store.exports.purge_expired(now).await?;
+    METRIC_PURGED_COUNT
+        .with_label_values(&["export"])
+        .inc_by(exports);
+    // Counts of windows that are over don't limit anything anymore.
+    let quota_counters = store.quotas.purge_expired(now).await?;
+    METRIC_PURGED_COUNT
+        .with_label_values(&["quota_counter"])
+        .inc_by(quota_counters);
+    info!(log, "Expired rows purged"; "sessions" => sessions, "tokens" => tokens, "otp_codes" => codes, "exports" => exports, "quota_counters" => quota_counters);
+    Ok(())
+}
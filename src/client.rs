@@ -0,0 +1,82 @@
+use crate::risk::RiskPolicy;
+use crate::tenant::Tenant;
+use cookie::{Cookie, SameSite};
+use hyper::{Body, Request};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Who is on the other end of a request, as far as can be told, for judging how risky a login is
+/// and for the audit log.
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    /// Random ID kept in a long-lived cookie, which tells apart browsers the user logged in from
+    /// before. It isn't secret, so it only ever counts as a hint.
+    pub device: Option<String>,
+    /// Country code given by the proxy in front, see [`RiskPolicy::country_header`].
+    pub country: Option<String>,
+    pub locale: &'static str,
+    /// Put in the request extensions by the server once it's resolved, see
+    /// [`Tenants::resolve`](crate::tenant::Tenants::resolve).
+    pub tenant: Arc<Tenant>,
+}
+
+/// Address of the connection a request came in on, put in the request extensions by the server.
+#[derive(Clone, Copy)]
+pub struct RemoteAddr(pub IpAddr);
+
+/// Device ID made up for a request that came without one, so that a cookie with it can be set on
+/// the response.
+#[derive(Clone)]
+pub struct NewDevice(pub String);
+
+const DEVICE_COOKIE_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 365 * 2);
+
+impl ClientInfo {
+    pub fn from_request(
+        req: &Request<Body>,
+        cookies: &HashMap<&str, Cookie>,
+        policy: &RiskPolicy,
+        locale: &'static str,
+    ) -> ClientInfo {
+        let device = match cookies.get("device") {
+            Some(cookie) => Some(cookie.value().to_owned()),
+            None => req
+                .extensions()
+                .get::<NewDevice>()
+                .map(|device| device.0.clone()),
+        };
+        let country = policy
+            .country_header
+            .as_ref()
+            .and_then(|header| req.headers().get(header))
+            .and_then(|country| country.to_str().ok())
+            .map(str::to_uppercase);
+        ClientInfo {
+            ip: req.extensions().get::<RemoteAddr>().map(|addr| addr.0),
+            device,
+            country,
+            locale,
+            tenant: req.extensions().get().cloned().unwrap_or_default(),
+        }
+    }
+}
+
+impl NewDevice {
+    pub fn generate() -> NewDevice {
+        NewDevice(Uuid::new_v4().to_string())
+    }
+
+    pub fn cookie(&self) -> Cookie<'static> {
+        Cookie::build("device", self.0.clone())
+            .max_age(DEVICE_COOKIE_MAX_AGE.try_into().unwrap())
+            .path("/")
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .finish()
+    }
+}
@@ -0,0 +1,159 @@
+use crate::der::{expect, tlv};
+use crate::error::Error;
+use crate::user::User;
+use crate::util::env_var_opt;
+use rustls::Certificate;
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+
+/// Certificate the client presented when the connection was made, put in the request extensions
+/// by the server. It has been verified against `TLS_CLIENT_CA_CERT` by then.
+#[derive(Clone)]
+pub struct PeerCertificate(pub Certificate);
+
+/// A name a certificate is issued to, either the common name of its subject or one of its subject
+/// alternative names.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum CertName {
+    CommonName(String),
+    Dns(String),
+    Email(String),
+    Uri(String),
+}
+
+/// Which users the client certificates log in as, for internal tooling and admin scripts to get
+/// at the API without a password. Requests with a certificate of no user are left to whatever
+/// other way of logging in they have.
+pub struct ClientCertPolicy {
+    /// From `CLIENT_CERT_USERS`, like `cn:deploy=3,dns:backup.internal=4,email:ops@example.com=1`,
+    /// where the kinds are `cn`, `dns`, `email` and `uri`.
+    pub users: HashMap<CertName, User>,
+}
+
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+impl ClientCertPolicy {
+    pub fn from_env() -> Result<ClientCertPolicy, Error> {
+        let users = match env_var_opt("CLIENT_CERT_USERS")? {
+            Some(users) => users
+                .split(',')
+                .map(str::trim)
+                .filter(|user| !user.is_empty())
+                .map(|user| {
+                    let invalid =
+                        || Error::InvalidClientCertUser(user.to_owned(), Backtrace::capture());
+                    // URIs can have an equals sign of their own, but the IDs can't.
+                    let (name, id) = user.rsplit_once('=').ok_or_else(invalid)?;
+                    let name = CertName::parse(name.trim()).ok_or_else(invalid)?;
+                    let id = id.trim().parse().map_err(|_| invalid())?;
+                    Ok((name, User { id }))
+                })
+                .collect::<Result<_, Error>>()?,
+            None => HashMap::new(),
+        };
+        Ok(ClientCertPolicy { users })
+    }
+
+    /// The user of the first name of the certificate that's mapped to one, going from the common
+    /// name through the alternative names in the order they're in.
+    pub fn user(&self, certificate: &Certificate) -> Option<User> {
+        if self.users.is_empty() {
+            return None;
+        }
+        names(&certificate.0)?
+            .iter()
+            .find_map(|name| self.users.get(name).copied())
+    }
+}
+
+impl CertName {
+    fn parse(name: &str) -> Option<CertName> {
+        let (kind, value) = name.split_once(':')?;
+        if value.is_empty() {
+            return None;
+        }
+        match kind {
+            "cn" => Some(CertName::CommonName(value.to_owned())),
+            "dns" => Some(CertName::Dns(value.to_lowercase())),
+            "email" => Some(CertName::Email(value.to_owned())),
+            "uri" => Some(CertName::Uri(value.to_owned())),
+            _ => None,
+        }
+    }
+}
+
+/// The names of a DER-encoded X.509 certificate, or none if it's not one. Only what's needed to
+/// find the names is parsed, as the certificate has already been verified.
+pub fn names(der: &[u8]) -> Option<Vec<CertName>> {
+    let (certificate, _) = expect(0x30, der)?;
+    let (tbs, _) = expect(0x30, certificate)?;
+    let mut rest = tbs;
+    // The version is optional, and the serial number, signature algorithm, issuer and validity
+    // come before the subject.
+    if rest.first() == Some(&0xa0) {
+        rest = tlv(rest)?.2;
+    }
+    for _ in 0..4 {
+        rest = tlv(rest)?.2;
+    }
+    let (subject, rest) = expect(0x30, rest)?;
+    let mut names = Vec::new();
+    let mut rdns = subject;
+    while !rdns.is_empty() {
+        let (rdn, next) = expect(0x31, rdns)?;
+        rdns = next;
+        let mut attributes = rdn;
+        while !attributes.is_empty() {
+            let (attribute, next) = expect(0x30, attributes)?;
+            attributes = next;
+            let (oid, value) = expect(0x06, attribute)?;
+            if oid == OID_COMMON_NAME {
+                let (_, value, _) = tlv(value)?;
+                names.push(CertName::CommonName(
+                    String::from_utf8(value.to_vec()).ok()?,
+                ));
+            }
+        }
+    }
+    // The public key, and the unique IDs hardly anyone uses, come before the extensions.
+    let mut rest = tlv(rest)?.2;
+    while let Some((tag, contents, next)) = tlv(rest) {
+        rest = next;
+        if tag == 0xa3 {
+            names.extend(alt_names(contents)?);
+        }
+    }
+    Some(names)
+}
+
+fn alt_names(extensions: &[u8]) -> Option<Vec<CertName>> {
+    let (mut extensions, _) = expect(0x30, extensions)?;
+    let mut names = Vec::new();
+    while !extensions.is_empty() {
+        let (extension, next) = expect(0x30, extensions)?;
+        extensions = next;
+        let (oid, mut rest) = expect(0x06, extension)?;
+        if oid != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+        // Whether the extension is critical, which is only there when it is.
+        if rest.first() == Some(&0x01) {
+            rest = tlv(rest)?.2;
+        }
+        let (value, _) = expect(0x04, rest)?;
+        let (mut general_names, _) = expect(0x30, value)?;
+        while !general_names.is_empty() {
+            let (tag, name, next) = tlv(general_names)?;
+            general_names = next;
+            let name = || String::from_utf8(name.to_vec()).ok();
+            match tag {
+                0x81 => names.push(CertName::Email(name()?)),
+                0x82 => names.push(CertName::Dns(name()?.to_lowercase())),
+                0x86 => names.push(CertName::Uri(name()?)),
+                _ => (),
+            }
+        }
+    }
+    Some(names)
+}
@@ -1,37 +1,330 @@
 use crate::error::Error;
-use crate::util::env_var;
+use crate::util::{env_var, env_var_opt};
+use aes_gcm::Aes256Gcm;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::XChaCha20Poly1305;
+use hmac::crypto_mac::MacError;
 use hmac::{Hmac, Mac, NewMac};
+use prometheus::{register_histogram_vec, HistogramVec};
+use ring::signature::Ed25519KeyPair;
 use sha2::Sha256;
+use std::backtrace::Backtrace;
 use std::convert::TryInto;
+use std::lazy::SyncLazy;
+use std::time::Instant;
+
+#[cfg(feature = "pkcs11")]
+mod pkcs11;
+
+#[cfg(feature = "pkcs11")]
+pub use pkcs11::Pkcs11Signer;
 
 pub struct Crypto {
     secret: [u8; 64],
+    /// What [`Crypto::sign`] goes through, which is the secret unless it's replaced with
+    /// [`Crypto::with_signer`].
+    signer: Box<dyn Signer>,
+    /// What [`Crypto::seal`] uses, while [`Crypto::unseal`] opens values sealed with any of them.
+    algorithm: SealAlgorithm,
+    /// Secrets rotated out, which values sealed before the rotation are still opened with.
+    previous: Vec<[u8; 64]>,
 }
 
 pub struct Signature {
     pub hash: [u8; 32],
 }
 
+/// Computes the HMAC-SHA256 tags of [`Crypto::sign`]. Deployments that don't want the signing key
+/// in the memory of the process can keep it in an HSM instead, with `Pkcs11Signer` of the `pkcs11`
+/// feature or their own implementation given through [`Crypto::with_signer`]. Tags are needed for answering requests,
+/// so one that can't be made is a failure of the whole service, and implementations panic rather
+/// than return errors.
+pub trait Signer: Send + Sync {
+    /// What the latency metrics are labeled with, like `software` or `pkcs11`.
+    fn name(&self) -> &'static str;
+
+    fn sign(&self, data: &[u8]) -> [u8; 32];
+}
+
+/// Signs with the secret from `SECRET`, for deployments without an HSM.
+pub struct SoftwareSigner {
+    secret: [u8; 64],
+}
+
+static METRIC_SIGNING_LATENCY: SyncLazy<HistogramVec> = SyncLazy::new(|| {
+    register_histogram_vec!(
+        "authtown_signing_latency",
+        "Latency of signing and verifying in seconds",
+        &["operation", "signer"]
+    )
+    .unwrap()
+});
+
+/// Authenticated encryption algorithm for sealing cookies, from `SEAL_ALGORITHM`. Its identifier
+/// goes into every sealed value, so that switching to another one keeps the values sealed before
+/// working.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SealAlgorithm {
+    Aes256Gcm,
+    /// Has nonces long enough to be picked at random without worrying about repeats, and is fast
+    /// without hardware AES support.
+    XChaCha20Poly1305,
+}
+
+/// Bytes in the key ID, which tells which secret a value was sealed with.
+const KEY_ID_LENGTH: usize = 4;
+
+const SEAL_KEY_DOMAIN: &[u8] = b"seal.";
+
+const KEY_ID_DOMAIN: &[u8] = b"key-id.";
+
+const TOKEN_KEY_DOMAIN: &[u8] = b"token-key.";
+
+const SELF_CHECK_VALUE: &[u8] = b"self-check";
+
+impl SealAlgorithm {
+    pub fn parse(name: &str) -> Result<SealAlgorithm, Error> {
+        match name {
+            "aes-256-gcm" => Ok(SealAlgorithm::Aes256Gcm),
+            "xchacha20-poly1305" => Ok(SealAlgorithm::XChaCha20Poly1305),
+            _ => Err(Error::UnknownSealAlgorithm(
+                name.to_owned(),
+                Backtrace::capture(),
+            )),
+        }
+    }
+
+    /// Identifier in sealed values, which mustn't change once values are sealed with it.
+    fn id(self) -> u8 {
+        match self {
+            SealAlgorithm::Aes256Gcm => 1,
+            SealAlgorithm::XChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<SealAlgorithm> {
+        match id {
+            1 => Some(SealAlgorithm::Aes256Gcm),
+            2 => Some(SealAlgorithm::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    fn nonce_length(self) -> usize {
+        match self {
+            SealAlgorithm::Aes256Gcm => 12,
+            SealAlgorithm::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    fn encrypt(self, key: &[u8; 32], nonce: &[u8], payload: Payload) -> Result<Vec<u8>, Error> {
+        let result = match self {
+            SealAlgorithm::Aes256Gcm => Aes256Gcm::new(key.into()).encrypt(nonce.into(), payload),
+            SealAlgorithm::XChaCha20Poly1305 => {
+                XChaCha20Poly1305::new(key.into()).encrypt(nonce.into(), payload)
+            }
+        };
+        result.map_err(|_| Error::Unseal(Backtrace::capture()))
+    }
+
+    fn decrypt(self, key: &[u8; 32], nonce: &[u8], payload: Payload) -> Result<Vec<u8>, Error> {
+        let result = match self {
+            SealAlgorithm::Aes256Gcm => Aes256Gcm::new(key.into()).decrypt(nonce.into(), payload),
+            SealAlgorithm::XChaCha20Poly1305 => {
+                XChaCha20Poly1305::new(key.into()).decrypt(nonce.into(), payload)
+            }
+        };
+        result.map_err(|_| Error::Unseal(Backtrace::capture()))
+    }
+}
+
 impl Crypto {
+    pub fn new(secret: [u8; 64]) -> Crypto {
+        Crypto {
+            secret,
+            signer: Box::new(SoftwareSigner { secret }),
+            algorithm: SealAlgorithm::Aes256Gcm,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Reads `SECRET`, along with `PREVIOUS_SECRETS`, a comma-separated list of secrets it
+    /// replaced, for values sealed with them to keep working until they expire. With the `pkcs11`
+    /// feature, setting `PKCS11_MODULE` makes signing go through `Pkcs11Signer`.
     pub fn from_env() -> Result<Crypto, Error> {
-        let secret = hex::decode(env_var("SECRET")?)?.try_into().unwrap();
-        Ok(Crypto { secret })
+        let mut crypto = Crypto::new(parse_secret(&env_var("SECRET")?)?);
+        if let Some(algorithm) = env_var_opt("SEAL_ALGORITHM")? {
+            crypto.algorithm = SealAlgorithm::parse(&algorithm)?;
+        }
+        if let Some(previous) = env_var_opt("PREVIOUS_SECRETS")? {
+            crypto.previous = previous
+                .split(',')
+                .map(str::trim)
+                .filter(|secret| !secret.is_empty())
+                .map(parse_secret)
+                .collect::<Result<_, _>>()?;
+        }
+        #[cfg(feature = "pkcs11")]
+        if env_var_opt("PKCS11_MODULE")?.is_some() {
+            crypto = crypto.with_signer(Pkcs11Signer::from_env()?);
+        }
+        Ok(crypto)
+    }
+
+    /// Signs through the signer instead of with the secret, see [`Signer`]. The secret is still
+    /// what cookies are sealed with.
+    pub fn with_signer(self, signer: impl Signer + 'static) -> Crypto {
+        Crypto {
+            signer: Box::new(signer),
+            ..self
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_algorithm(self, algorithm: SealAlgorithm) -> Crypto {
+        Crypto { algorithm, ..self }
+    }
+
+    /// Seals a test value with each of the secrets and opens it again, which fails at startup
+    /// rather than on the first cookie if something's off with how they seal.
+    pub fn self_check(&self) -> Result<(), Error> {
+        for secret in std::iter::once(&self.secret).chain(&self.previous) {
+            let crypto = Crypto {
+                algorithm: self.algorithm,
+                ..Crypto::new(*secret)
+            };
+            match self.unseal(&crypto.seal(SELF_CHECK_VALUE)) {
+                Ok(value) if value == SELF_CHECK_VALUE => (),
+                _ => return Err(Error::CryptoSelfCheck(Backtrace::capture())),
+            }
+        }
+        Ok(())
     }
 
     pub fn sign(&self, data: &[u8]) -> Signature {
-        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
-        mac.update(data);
         Signature {
-            hash: mac.finalize().into_bytes().into(),
+            hash: self.timed_sign("sign", data),
         }
     }
 
     pub fn verify(&self, data: &[u8], signature: &[u8]) -> Result<Signature, Error> {
-        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
-        mac.update(data);
-        mac.verify(signature)?;
-        Ok(Signature {
-            hash: signature.try_into().unwrap(),
-        })
+        let expected = self.timed_sign("verify", data);
+        // Compared in constant time, so that how long it takes doesn't tell how much matched.
+        let difference = expected
+            .iter()
+            .zip(signature)
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        if signature.len() != expected.len() || difference != 0 {
+            return Err(Error::CryptoSignatureVerification(
+                MacError,
+                Backtrace::capture(),
+            ));
+        }
+        Ok(Signature { hash: expected })
+    }
+
+    fn timed_sign(&self, operation: &str, data: &[u8]) -> [u8; 32] {
+        let start = Instant::now();
+        let hash = self.signer.sign(data);
+        METRIC_SIGNING_LATENCY
+            .with_label_values(&[operation, self.signer.name()])
+            .observe(start.elapsed().as_secs_f64());
+        hash
+    }
+
+    /// Encrypts the data so that it can be neither read nor changed, into base64url safe to put in
+    /// cookies as is. The algorithm and key IDs come first and are authenticated along with the
+    /// data, then the random nonce, then the ciphertext.
+    pub fn seal(&self, data: &[u8]) -> String {
+        let algorithm = self.algorithm;
+        let mut header = vec![algorithm.id()];
+        header.extend_from_slice(&key_id(&self.secret));
+        let mut nonce = vec![0; algorithm.nonce_length()];
+        OsRng.fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: data,
+            aad: &header,
+        };
+        let ciphertext = algorithm
+            .encrypt(&seal_key(&self.secret), &nonce, payload)
+            .unwrap();
+        let mut sealed = header;
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        base64::encode_config(sealed, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Opens a value from [`Crypto::seal`], whichever algorithm and secret it was sealed with.
+    pub fn unseal(&self, sealed: &str) -> Result<Vec<u8>, Error> {
+        let unseal_failed = || Error::Unseal(Backtrace::capture());
+        let sealed =
+            base64::decode_config(sealed, base64::URL_SAFE_NO_PAD).map_err(|_| unseal_failed())?;
+        let header_length = 1 + KEY_ID_LENGTH;
+        let algorithm = sealed
+            .first()
+            .and_then(|&id| SealAlgorithm::from_id(id))
+            .ok_or_else(unseal_failed)?;
+        if sealed.len() < header_length + algorithm.nonce_length() {
+            return Err(unseal_failed());
+        }
+        let (header, rest) = sealed.split_at(header_length);
+        let (nonce, ciphertext) = rest.split_at(algorithm.nonce_length());
+        let secret = std::iter::once(&self.secret)
+            .chain(&self.previous)
+            .find(|secret| key_id(secret) == header[1..])
+            .ok_or_else(unseal_failed)?;
+        let payload = Payload {
+            msg: ciphertext,
+            aad: header,
+        };
+        algorithm.decrypt(&seal_key(secret), nonce, payload)
+    }
+
+    /// Key the tokens other services check without knowing the secret are signed with, which
+    /// they get the public half of from `/oauth/jwks`.
+    pub fn token_key(&self) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&hmac(&self.secret, TOKEN_KEY_DOMAIN)).unwrap()
     }
 }
+
+impl Signer for SoftwareSigner {
+    fn name(&self) -> &'static str {
+        "software"
+    }
+
+    fn sign(&self, data: &[u8]) -> [u8; 32] {
+        hmac(&self.secret, data)
+    }
+}
+
+/// A fresh secret in the form `SECRET` and `PREVIOUS_SECRETS` take.
+pub fn generate_secret() -> String {
+    let mut secret = [0; 64];
+    OsRng.fill_bytes(&mut secret);
+    hex::encode(secret)
+}
+
+fn parse_secret(hex: &str) -> Result<[u8; 64], Error> {
+    hex::decode(hex)?
+        .try_into()
+        .map_err(|_| Error::InvalidSecret(Backtrace::capture()))
+}
+
+/// Keys are derived from the secret rather than being the secret itself, so that encrypting with
+/// it never gets mixed up with signing.
+fn seal_key(secret: &[u8; 64]) -> [u8; 32] {
+    hmac(secret, SEAL_KEY_DOMAIN)
+}
+
+fn key_id(secret: &[u8; 64]) -> [u8; KEY_ID_LENGTH] {
+    hmac(secret, KEY_ID_DOMAIN)[..KEY_ID_LENGTH]
+        .try_into()
+        .unwrap()
+}
+
+fn hmac(secret: &[u8; 64], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
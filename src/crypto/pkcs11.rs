@@ -0,0 +1,67 @@
+use crate::crypto::Signer;
+use crate::error::Error;
+use crate::util::env_var;
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::types::AuthPin;
+use std::backtrace::Backtrace;
+use std::convert::TryInto;
+use std::sync::Mutex;
+
+/// Signs with an HMAC-SHA256 key kept in an HSM, through the PKCS#11 module of its vendor. The key
+/// never leaves the token, which computes every tag itself.
+pub struct Pkcs11Signer {
+    /// Sessions can't be used from several threads at once, and a tag takes little enough time
+    /// that taking turns is simpler than keeping a pool of them.
+    session: Mutex<Session>,
+    key: ObjectHandle,
+}
+
+impl Pkcs11Signer {
+    /// Loads the module from `PKCS11_MODULE`, logs in to the token labeled `PKCS11_TOKEN` with
+    /// `PKCS11_PIN`, and looks up the secret key labeled `PKCS11_KEY` on it.
+    pub fn from_env() -> Result<Pkcs11Signer, Error> {
+        let pkcs11 = Pkcs11::new(env_var("PKCS11_MODULE")?)?;
+        pkcs11.initialize(CInitializeArgs::OsThreads)?;
+        let token = env_var("PKCS11_TOKEN")?;
+        let slot = pkcs11
+            .get_slots_with_token()?
+            .into_iter()
+            .find(|&slot| matches!(pkcs11.get_token_info(slot), Ok(info) if info.label() == token));
+        let Some(slot) = slot else {
+            return Err(Error::Pkcs11TokenNotFound(token, Backtrace::capture()));
+        };
+        let session = pkcs11.open_ro_session(slot)?;
+        let pin = AuthPin::new(env_var("PKCS11_PIN")?);
+        session.login(UserType::User, Some(&pin))?;
+        let label = env_var("PKCS11_KEY")?;
+        let template = [
+            Attribute::Class(ObjectClass::SECRET_KEY),
+            Attribute::Label(label.clone().into_bytes()),
+        ];
+        let Some(&key) = session.find_objects(&template)?.first() else {
+            return Err(Error::Pkcs11KeyNotFound(label, Backtrace::capture()));
+        };
+        Ok(Pkcs11Signer {
+            session: Mutex::new(session),
+            key,
+        })
+    }
+}
+
+impl Signer for Pkcs11Signer {
+    fn name(&self) -> &'static str {
+        "pkcs11"
+    }
+
+    fn sign(&self, data: &[u8]) -> [u8; 32] {
+        let session = self.session.lock().unwrap();
+        session
+            .sign(&Mechanism::Sha256Hmac, self.key, data)
+            .expect("signing with the PKCS#11 token failed")
+            .try_into()
+            .expect("PKCS#11 token returned a tag that is not 32 bytes long")
+    }
+}
@@ -0,0 +1,281 @@
+use crate::error::Error;
+use crate::tls::{load_certificates, load_private_key};
+use crate::util::{env_duration_ms, env_usize, env_var_opt};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use slog::{error, info, Logger};
+use std::backtrace::Backtrace;
+use std::cmp;
+use std::collections::HashMap;
+use std::env::VarError;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_postgres::config::SslMode;
+use tokio_postgres::{Client, Config, Statement};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+pub struct Database {
+    config: Config,
+    tls: MakeRustlsConnect,
+    client: RwLock<Option<Arc<Client>>>,
+    timeout: Duration,
+    /// Connections left over from finished transactions, for the next ones to reuse.
+    idle: Mutex<Vec<Client>>,
+    pool_size: usize,
+    /// Statements prepared on the supervised connection, which go away along with it.
+    statements: Mutex<HashMap<&'static str, Statement>>,
+}
+
+tokio::task_local! {
+    /// Connection of the transaction the task is in, see [`Database::transaction`].
+    static TRANSACTION: Arc<Client>;
+}
+
+const BACKOFF_INITIAL: Duration = Duration::from_millis(100);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_POOL_SIZE: usize = 4;
+
+impl Database {
+    /// Whether TLS is used is decided by the `sslmode` parameter of the connection string, which
+    /// is `disable`, `prefer` or `require`. `DATABASE_CA_CERT` pins the server to a specific CA
+    /// instead of the public web roots, which `prefer` otherwise doesn't check the certificate
+    /// against, and `DATABASE_CLIENT_CERT` with `DATABASE_CLIENT_KEY` enable client certificate
+    /// authentication.
+    /// Queries that take longer than `DATABASE_TIMEOUT_MS` are abandoned, and
+    /// `DATABASE_POOL_SIZE` connections are kept around for transactions.
+    pub fn new(url: &str) -> Result<Database, Error> {
+        let config: Config = url.parse()?;
+        let tls = MakeRustlsConnect::new(tls_config_from_env(config.get_ssl_mode())?);
+        let timeout = env_duration_ms("DATABASE_TIMEOUT_MS", DEFAULT_TIMEOUT)?;
+        let pool_size = env_usize("DATABASE_POOL_SIZE", DEFAULT_POOL_SIZE)?;
+        Ok(Database {
+            config,
+            tls,
+            client: RwLock::new(None),
+            timeout,
+            idle: Mutex::new(Vec::new()),
+            pool_size,
+            statements: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Opens a connection separate from the supervised one, for one-off tasks like migrations.
+    pub async fn connect(&self, log: &Logger) -> Result<Client, Error> {
+        let (client, connection) = self.config.connect(self.tls.clone()).await?;
+        let log = log.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                let e = Error::from(e);
+                error!(log, "Database connection failed"; e.log_message());
+            }
+        });
+        Ok(client)
+    }
+
+    /// The connection to query through, which is the transaction's when called from within
+    /// [`Database::transaction`].
+    pub fn client(&self) -> Result<Arc<Client>, Error> {
+        if let Ok(client) = TRANSACTION.try_with(Arc::clone) {
+            return Ok(client);
+        }
+        match &*self.client.read().unwrap() {
+            Some(client) if !client.is_closed() => Ok(client.clone()),
+            _ => Err(Error::DatabaseUnavailable(Backtrace::capture())),
+        }
+    }
+
+    pub async fn timeout<T, E: Into<Error>>(
+        &self,
+        operation: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, Error> {
+        match tokio::time::timeout(self.timeout, operation).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(Error::DatabaseTimeout(Backtrace::capture())),
+        }
+    }
+
+    /// Prepares the query on the connection from [`Database::client`]. Statements for the
+    /// supervised connection are prepared once and reused, saving a round trip on the queries run
+    /// over and over, like looking up the session of every request.
+    pub async fn prepare(
+        &self,
+        client: &Arc<Client>,
+        query: &'static str,
+    ) -> Result<Statement, Error> {
+        if !self.is_supervised(client) {
+            return self.timeout(client.prepare(query)).await;
+        }
+        if let Some(statement) = self.statements.lock().unwrap().get(query) {
+            return Ok(statement.clone());
+        }
+        let statement = self.timeout(client.prepare(query)).await?;
+        let mut statements = self.statements.lock().unwrap();
+        // The connection could have been replaced while preparing, and then the statement is only
+        // good for this one query.
+        if self.is_supervised(client) {
+            statements.insert(query, statement.clone());
+        }
+        Ok(statement)
+    }
+
+    fn is_supervised(&self, client: &Arc<Client>) -> bool {
+        matches!(&*self.client.read().unwrap(), Some(supervised) if Arc::ptr_eq(supervised, client))
+    }
+
+    /// Runs the work in a transaction, committed if it succeeds and rolled back if it fails. The
+    /// supervised connection is shared by every request, so the transaction gets a connection of
+    /// its own, which every query made from within the work goes through. Transactions started
+    /// from within another one are part of it.
+    pub async fn transaction<T>(
+        &self,
+        log: &Logger,
+        work: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        if TRANSACTION.try_with(|_| ()).is_ok() {
+            return work.await;
+        }
+        let idle = self.idle.lock().unwrap().pop();
+        let client = match idle.filter(|client| !client.is_closed()) {
+            Some(client) => client,
+            None => self.timeout(self.connect(log)).await?,
+        };
+        let client = Arc::new(client);
+        self.timeout(client.batch_execute("BEGIN")).await?;
+        let result = TRANSACTION.scope(client.clone(), work).await;
+        let end = match result {
+            Ok(_) => "COMMIT",
+            Err(_) => "ROLLBACK",
+        };
+        let ended = self.timeout(client.batch_execute(end)).await;
+        // Connections that failed to end the transaction could still be in it, so they're closed.
+        if ended.is_ok() {
+            if let Ok(client) = Arc::try_unwrap(client) {
+                let mut idle = self.idle.lock().unwrap();
+                if idle.len() < self.pool_size {
+                    idle.push(client);
+                }
+            }
+        }
+        let value = result?;
+        ended?;
+        Ok(value)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.client().is_ok()
+    }
+
+    /// Keeps the database connection alive for the lifetime of the server, reconnecting with
+    /// exponential backoff whenever the connection fails or is closed by the other side.
+    pub async fn supervise(self: Arc<Self>, log: Logger) {
+        let mut backoff = BACKOFF_INITIAL;
+        loop {
+            match self.config.connect(self.tls.clone()).await {
+                Ok((client, connection)) => {
+                    info!(log, "Database connection established");
+                    *self.client.write().unwrap() = Some(Arc::new(client));
+                    backoff = BACKOFF_INITIAL;
+                    let result = connection.await;
+                    *self.client.write().unwrap() = None;
+                    self.statements.lock().unwrap().clear();
+                    match result {
+                        Ok(()) => error!(log, "Database connection closed"),
+                        Err(e) => {
+                            let e = Error::from(e);
+                            error!(log, "Database connection failed"; e.log_message());
+                        }
+                    }
+                }
+                Err(e) => {
+                    let e = Error::from(e);
+                    error!(log, "Database connection attempt failed"; e.log_message(), "retry_ms" => backoff.as_millis() as u64);
+                }
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = next_backoff(backoff);
+        }
+    }
+}
+
+/// How long to wait before the next attempt to reconnect, doubling up to [`BACKOFF_MAX`].
+pub fn next_backoff(backoff: Duration) -> Duration {
+    cmp::min(backoff * 2, BACKOFF_MAX)
+}
+
+fn tls_config_from_env(ssl_mode: SslMode) -> Result<ClientConfig, Error> {
+    let ca_path = env_var_opt("DATABASE_CA_CERT")?;
+    let mut roots = RootCertStore::empty();
+    if let Some(ca_path) = &ca_path {
+        let certificates: Vec<_> = load_certificates(ca_path)?
+            .into_iter()
+            .map(|certificate| certificate.0)
+            .collect();
+        if roots.add_parsable_certificates(&certificates).1 != 0 {
+            return Err(Error::TlsPem {
+                path: ca_path.clone(),
+                backtrace: Backtrace::capture(),
+            });
+        }
+    } else {
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+    }
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let client_cert = env_var_opt("DATABASE_CLIENT_CERT")?;
+    let client_key = env_var_opt("DATABASE_CLIENT_KEY")?;
+    let mut config = match (client_cert, client_key) {
+        (Some(cert_path), Some(key_path)) => builder
+            .with_single_cert(load_certificates(&cert_path)?, load_private_key(&key_path)?)?,
+        (None, None) => builder.with_no_client_auth(),
+        (Some(_), None) => return Err(missing_env_var("DATABASE_CLIENT_KEY")),
+        (None, Some(_)) => return Err(missing_env_var("DATABASE_CLIENT_CERT")),
+    };
+    if !verifies_certificate(ssl_mode, ca_path.is_some()) {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(UnverifiedCertificate));
+    }
+    Ok(config)
+}
+
+/// Whether the server's certificate is checked. As with libpq, preferring TLS only asks for
+/// encryption when the server offers it. Servers set up that way tend to have self-signed
+/// certificates, which worked before TLS was supported and shouldn't stop working now, so without
+/// a CA the certificate isn't checked.
+pub fn verifies_certificate(ssl_mode: SslMode, pinned_ca: bool) -> bool {
+    ssl_mode != SslMode::Prefer || pinned_ca
+}
+
+/// Takes the server's certificate as is, see [`tls_config_from_env`].
+struct UnverifiedCertificate;
+
+impl ServerCertVerifier for UnverifiedCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn missing_env_var(name: &'static str) -> Error {
+    Error::Environment {
+        name,
+        source: VarError::NotPresent,
+        backtrace: Backtrace::capture(),
+    }
+}
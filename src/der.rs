@@ -0,0 +1,40 @@
+//! Just enough of the Distinguished Encoding Rules of ASN.1 to pick values out of certificates and
+//! Kerberos messages, which have been checked by whoever signed or encrypted them.
+
+/// Splits off the first element, if it has the tag, returning its contents and what comes after.
+pub fn expect(tag: u8, input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (actual, contents, rest) = tlv(input)?;
+    (actual == tag).then_some((contents, rest))
+}
+
+/// Splits off the first element, returning its tag, its contents and what comes after.
+pub fn tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let length = if first < 0x80 {
+        first as usize
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 || input.len() < octets {
+            return None;
+        }
+        let (length, rest) = input.split_at(octets);
+        input = rest;
+        length
+            .iter()
+            .fold(0, |length, &octet| length << 8 | octet as usize)
+    };
+    if input.len() < length {
+        return None;
+    }
+    let (contents, rest) = input.split_at(length);
+    Some((tag, contents, rest))
+}
+
+/// Like [`expect`], for elements that can be left out, which leaves the input as it was.
+pub fn optional(tag: u8, input: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    match expect(tag, input) {
+        Some((contents, rest)) => (Some(contents), rest),
+        None => (None, input),
+    }
+}
@@ -0,0 +1,150 @@
+use crate::error::Error;
+use crate::util::env_var_opt;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use slog::{error, info, Logger};
+use std::backtrace::Backtrace;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Domains handing out throwaway email addresses, which registrations can't use. The list is read
+/// from `DISPOSABLE_DOMAINS_FILE`, one domain on each line, and falls back to a few well-known ones
+/// when that isn't set.
+pub struct DisposablePolicy {
+    domains: RwLock<HashSet<String>>,
+    path: Option<PathBuf>,
+    /// From `DISPOSABLE_DOMAINS_ACTION`, either `reject` or `approve`.
+    pub action: DisposableAction,
+}
+
+/// What happens to a registration with a throwaway address.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum DisposableAction {
+    Reject,
+    /// Let through, but left waiting for an admin like when `REGISTRATION_APPROVAL` is set.
+    Approve,
+}
+
+const DEFAULT_DOMAINS: &[&str] = &[
+    "10minutemail.com",
+    "dispostable.com",
+    "getnada.com",
+    "guerrillamail.com",
+    "mailinator.com",
+    "maildrop.cc",
+    "sharklasers.com",
+    "temp-mail.org",
+    "throwawaymail.com",
+    "trashmail.com",
+    "yopmail.com",
+];
+
+impl DisposablePolicy {
+    pub fn from_env() -> Result<DisposablePolicy, Error> {
+        let path = env_var_opt("DISPOSABLE_DOMAINS_FILE")?.map(PathBuf::from);
+        let domains = match &path {
+            Some(path) => read(path)?,
+            None => DEFAULT_DOMAINS
+                .iter()
+                .map(|&domain| domain.to_owned())
+                .collect(),
+        };
+        let action = match env_var_opt("DISPOSABLE_DOMAINS_ACTION")?.as_deref() {
+            None | Some("reject") => DisposableAction::Reject,
+            Some("approve") => DisposableAction::Approve,
+            Some(action) => {
+                return Err(Error::UnknownDisposableAction(
+                    action.to_owned(),
+                    Backtrace::capture(),
+                ))
+            }
+        };
+        Ok(DisposablePolicy {
+            domains: RwLock::new(domains),
+            path,
+            action,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn new(domains: &[&str], action: DisposableAction) -> DisposablePolicy {
+        DisposablePolicy {
+            domains: RwLock::new(domains.iter().map(|&domain| domain.to_owned()).collect()),
+            path: None,
+            action,
+        }
+    }
+
+    /// Whether the normalized address is at a listed domain or at any subdomain of one, as some
+    /// services hand out addresses at random subdomains.
+    pub fn is_disposable(&self, email: &str) -> bool {
+        let Some((_, mut domain)) = email.rsplit_once('@') else { return false; };
+        let domains = self.domains.read().unwrap();
+        loop {
+            if domains.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Reads the list again whenever the file changes, so that it can be updated without
+    /// restarting the server. If the file can't be read, the previous list is kept.
+    pub fn watch(self: Arc<Self>, log: Logger) -> Result<(), Error> {
+        let Some(path) = self.path.clone() else { return Ok(()); };
+        // Tools updating the list tend to replace the file rather than write to it, which a watch
+        // on the file itself wouldn't survive.
+        let directory = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher = notify::watcher(sender, Duration::from_millis(100))?;
+        watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+        std::thread::spawn(move || {
+            let _watcher = watcher;
+            for event in receiver {
+                let touched = match &event {
+                    DebouncedEvent::Create(changed)
+                    | DebouncedEvent::Write(changed)
+                    | DebouncedEvent::Chmod(changed)
+                    | DebouncedEvent::Remove(changed) => changed.file_name() == path.file_name(),
+                    DebouncedEvent::Rename(from, to) => {
+                        from.file_name() == path.file_name() || to.file_name() == path.file_name()
+                    }
+                    DebouncedEvent::Rescan => true,
+                    _ => false,
+                };
+                if !touched {
+                    continue;
+                }
+                match read(&path) {
+                    Ok(domains) => {
+                        let count = domains.len();
+                        *self.domains.write().unwrap() = domains;
+                        info!(log, "Disposable email domains reloaded"; "count" => count);
+                    }
+                    Err(e) => {
+                        error!(log, "Disposable email domains reload failed"; e.log_message());
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Lines starting with `#` are comments, so that lists published with them can be used as is.
+fn read(path: &Path) -> Result<HashSet<String>, Error> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect())
+}
@@ -1,25 +1,318 @@
+use crate::features::Feature;
+use crate::i18n;
+use crate::user::AccountStatus;
 use hmac::crypto_mac::MacError;
+use hyper::StatusCode;
 use slog::SingleKV;
 use std::backtrace::Backtrace;
 use std::error::Error as StdError;
 use thiserror::Error;
 
+/// Broad category of an error, deciding which status code it is reported with and whether it is the
+/// client's fault or ours.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    Conflict,
+    Unprocessable,
+    TooManyRequests,
+    Internal,
+    Unavailable,
+    GatewayTimeout,
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("database error")]
     Database(#[from] tokio_postgres::Error, Backtrace),
+    #[error("database operation timed out")]
+    DatabaseTimeout(Backtrace),
+    #[error("SQLite error")]
+    Sqlite(#[from] rusqlite::Error, Backtrace),
+    #[error("database unavailable")]
+    DatabaseUnavailable(Backtrace),
     #[error("network or http error")]
     Http(#[from] hyper::Error, Backtrace),
+    #[error("reading the request body timed out")]
+    BodyTimeout(Backtrace),
+    #[error("handling the request timed out")]
+    HandlerTimeout(Backtrace),
     #[error("environment variable {name} missing")]
     Environment {
         name: &'static str,
         source: std::env::VarError,
         backtrace: Backtrace,
     },
+    #[error("page not found")]
+    NotFound(Backtrace),
+    #[error("method not allowed")]
+    MethodNotAllowed(Vec<hyper::Method>, Backtrace),
+    #[error("route has no parameter {0}")]
+    NoSuchRouteParam(String, Backtrace),
+    #[error("not logged in")]
+    NotLoggedIn(Backtrace),
+    #[error("malformed session cookie")]
+    MalformedSession(Backtrace),
+    #[error("sealed value is malformed, was tampered with or was sealed with an unknown secret")]
+    Unseal(Backtrace),
+    #[error("user not found")]
+    UserNotFound(Backtrace),
+    #[error("wrong password")]
+    WrongPassword(Backtrace),
+    #[error("username already taken")]
+    UsernameTaken(Backtrace),
+    #[error("username must be between {min} and {max} characters long")]
+    InvalidUsernameLength {
+        min: usize,
+        max: usize,
+        backtrace: Backtrace,
+    },
+    #[error("username contains characters that aren't allowed")]
+    InvalidUsernameCharacters(Backtrace),
+    #[error("username is reserved")]
+    UsernameReserved(Backtrace),
+    #[error("email address already taken")]
+    EmailTaken(Backtrace),
+    #[error("email address is invalid")]
+    InvalidEmail(Backtrace),
+    #[error("identity already linked to a user")]
+    IdentityTaken(Backtrace),
+    #[error("can't remove the last login method")]
+    LastLoginMethod(Backtrace),
+    #[error("password expired and has to be changed first")]
+    PasswordExpired(Backtrace),
+    #[error("new password is the same as the old one")]
+    PasswordUnchanged(Backtrace),
+    #[error("password rejected: {0}")]
+    PasswordRejected(String, Backtrace),
+    #[error("only admins can do this")]
+    NotAdmin(Backtrace),
+    #[error("no user named {0}")]
+    UnknownUser(String, Backtrace),
+    #[error("not allowed while impersonating")]
+    Impersonating(Backtrace),
+    #[error("terms of service not accepted")]
+    TermsNotAccepted(Backtrace),
+    #[error("data export not found, expired or already downloaded")]
+    ExportNotFound(Backtrace),
+    #[error("account is {}", .0.as_str())]
+    AccountSuspended(AccountStatus, Backtrace),
+    #[error("unknown account status {0}")]
+    UnknownAccountStatus(String, Backtrace),
+    #[error("service accounts can't log in interactively")]
+    ServiceAccountLogin(Backtrace),
+    #[error("Kerberos token rejected: {0}")]
+    KerberosToken(&'static str, Backtrace),
+    #[error("no user has the Kerberos principal {0}")]
+    KerberosPrincipalUnknown(String, Backtrace),
+    #[error("registration is not waiting for approval")]
+    RegistrationNotPending(Backtrace),
+    #[error("email address is at a disposable domain")]
+    DisposableEmail(Backtrace),
+    #[error("unknown disposable email action {0}")]
+    UnknownDisposableAction(String, Backtrace),
+    #[error("feature {} is switched off", .0.as_str())]
+    FeatureDisabled(Feature, Backtrace),
+    #[error("unknown feature {0}")]
+    UnknownFeature(String, Backtrace),
+    #[error("too many registrations from the IP address or email domain")]
+    RegistrationQuotaExceeded(Backtrace),
+    #[error("registration quota {0} is not of the form <domain>=<limit>")]
+    InvalidRegistrationQuota(String, Backtrace),
+    #[error("unknown mode {0}")]
+    UnknownMode(String, Backtrace),
+    #[error("service is down for maintenance")]
+    Maintenance(Backtrace),
+    #[error("service is read-only")]
+    ReadOnly(Backtrace),
+    #[error("tenant {0} has an invalid or duplicate ID or host")]
+    InvalidTenant(String, Backtrace),
+    #[error("invalid session cookie configuration: {0}")]
+    InvalidCookiePolicy(String, Backtrace),
+    #[error("unknown seal algorithm {0}")]
+    UnknownSealAlgorithm(String, Backtrace),
+    #[error("secret is not 64 bytes long")]
+    InvalidSecret(Backtrace),
+    #[error("Sentry DSN is not of the form https://<key>@<host>/<project>")]
+    InvalidSentryDsn(Backtrace),
+    #[error("password hash is not a PHC string")]
+    InvalidPasswordHash(Backtrace),
+    #[error("record {record} can't be imported")]
+    InvalidImport {
+        record: usize,
+        source: Box<Error>,
+        backtrace: Backtrace,
+    },
+    #[error("invalid CSV: {0}")]
+    InvalidCsv(String, Backtrace),
+    #[error("logout from another origin without a valid logout token")]
+    InvalidLogoutToken(Backtrace),
+    #[error("consent form sent without a valid consent token")]
+    InvalidConsentToken(Backtrace),
+    #[error("unsubscribe link is invalid")]
+    InvalidUnsubscribeToken(Backtrace),
+    #[error("claim name {0} is empty, too long or has characters other than ASCII letters, digits, _, - and :")]
+    InvalidClaimName(String, Backtrace),
+    #[error("claims take up {0} bytes, more than CLAIMS_MAX_BYTES allows")]
+    ClaimsTooLarge(usize, Backtrace),
+    #[error("OAuth client authentication failed")]
+    InvalidClient(Backtrace),
+    #[error("OAuth client ID already taken")]
+    ClientIdTaken(Backtrace),
+    #[error("OAuth client {0} not found")]
+    ClientNotFound(String, Backtrace),
+    #[error("locale {0} is not available")]
+    UnknownLocale(String, Backtrace),
+    #[error("redirect URI is not registered for the client")]
+    UnregisteredRedirectUri(Backtrace),
+    #[error("scope {0} is not allowed for the client")]
+    ScopeNotAllowed(String, Backtrace),
+    #[error("{0} must not be empty")]
+    EmptyField(&'static str, Backtrace),
+    #[error("database schema version {current} is newer than the latest known version {latest}")]
+    SchemaTooNew {
+        current: i32,
+        latest: i32,
+        backtrace: Backtrace,
+    },
+    #[error("database migration left references to missing rows")]
+    ForeignKeyViolation(Backtrace),
+    #[error(
+        "database migration {version} is {} in the database but {} in this build, so the \
+         database was migrated by a different build",
+        .found.as_deref().unwrap_or("missing"),
+        .expected.unwrap_or("missing")
+    )]
+    SchemaMismatch {
+        version: i32,
+        found: Option<String>,
+        expected: Option<&'static str>,
+        backtrace: Backtrace,
+    },
+    #[error("sealing and opening a test value with the secret or one of PREVIOUS_SECRETS failed")]
+    CryptoSelfCheck(Backtrace),
+    #[cfg(feature = "pkcs11")]
+    #[error("PKCS#11 error")]
+    Pkcs11(#[from] cryptoki::error::Error, Backtrace),
+    #[cfg(feature = "pkcs11")]
+    #[error("no PKCS#11 token is labeled {0}")]
+    Pkcs11TokenNotFound(String, Backtrace),
+    #[cfg(feature = "pkcs11")]
+    #[error("no secret key on the PKCS#11 token is labeled {0}")]
+    Pkcs11KeyNotFound(String, Backtrace),
+    #[error("template {0} is missing from the templates directory")]
+    TemplateMissing(&'static str, Backtrace),
+    #[error("unknown command {0}")]
+    UnknownCommand(String, Backtrace),
+    #[error("usage: authtown {0}")]
+    Usage(&'static str, Backtrace),
+    #[error("TLS error")]
+    Tls(#[from] rustls::Error, Backtrace),
+    #[error("no valid PEM items found in {path}")]
+    TlsPem { path: String, backtrace: Backtrace },
+    #[error("client certificate user {0} is not of the form <kind>:<name>=<user ID>")]
+    InvalidClientCertUser(String, Backtrace),
+    #[error("keytab {0} is malformed or has no AES keys")]
+    InvalidKeytab(String, Backtrace),
     #[error("IO error")]
     Io(#[from] std::io::Error, Backtrace),
+    #[error("SMTP error")]
+    Smtp(#[from] lettre::transport::smtp::Error, Backtrace),
+    #[error("invalid email address")]
+    MailAddress(#[from] lettre::address::AddressError, Backtrace),
+    #[error("building the email failed")]
+    MailBuild(#[from] lettre::error::Error, Backtrace),
+    #[error("mail provider rejected the mail with status {status}: {body}")]
+    MailRejected {
+        status: StatusCode,
+        body: String,
+        backtrace: Backtrace,
+    },
+    #[error("sending mail timed out")]
+    MailTimeout(Backtrace),
+    #[error("job took too long")]
+    JobTimeout(Backtrace),
+    #[error("unknown mail provider {0}")]
+    UnknownMailProvider(String, Backtrace),
+    #[error("unknown SMTP TLS mode {0}")]
+    UnknownSmtpTls(String, Backtrace),
+    #[error("no mail provider configured")]
+    MailNotConfigured(Backtrace),
+    #[error("SMS provider rejected the message with status {status}: {body}")]
+    SmsRejected {
+        status: StatusCode,
+        body: String,
+        backtrace: Backtrace,
+    },
+    #[error("sending SMS timed out")]
+    SmsTimeout(Backtrace),
+    #[error("unknown SMS provider {0}")]
+    UnknownSmsProvider(String, Backtrace),
+    #[error("no SMS provider configured")]
+    SmsNotConfigured(Backtrace),
+    #[error("message broker rejected the event with status {status}: {body}")]
+    EventRejected {
+        status: StatusCode,
+        body: String,
+        backtrace: Backtrace,
+    },
+    #[error("publishing the event timed out")]
+    EventTimeout(Backtrace),
+    #[error("alert webhook {0} is not an HTTPS URL")]
+    InvalidAlertWebhook(String, Backtrace),
+    #[error("alert webhook rejected the alert with status {status}: {body}")]
+    AlertRejected {
+        status: StatusCode,
+        body: String,
+        backtrace: Backtrace,
+    },
+    #[error("posting the alert timed out")]
+    AlertTimeout(Backtrace),
+    #[error("back-channel logout URI {0} is not an HTTPS URL")]
+    InvalidBackchannelLogoutUri(String, Backtrace),
+    #[error("client rejected the logout token with status {status}: {body}")]
+    BackchannelLogoutRejected {
+        status: StatusCode,
+        body: String,
+        backtrace: Backtrace,
+    },
+    #[error("posting the logout token timed out")]
+    BackchannelLogoutTimeout(Backtrace),
+    #[error("unknown event publisher {0}")]
+    UnknownEventPublisher(String, Backtrace),
+    #[error("no event publisher configured")]
+    EventsNotConfigured(Backtrace),
+    #[error("NATS URL {0} is not of the form tls://<host>[:<port>] or nats://<host>[:<port>]")]
+    InvalidNatsUrl(String, Backtrace),
+    #[error("NATS_TOKEN is only sent to tls:// URLs")]
+    NatsTokenWithoutTls(Backtrace),
+    #[error("unexpected reply from the NATS server: {0}")]
+    NatsProtocol(String, Backtrace),
+    #[error("phone number is not in the E.164 format")]
+    InvalidPhoneNumber(Backtrace),
+    #[error("no phone number to verify")]
+    NoPhoneNumber(Backtrace),
+    #[error("wrong or expired one-time code")]
+    WrongCode(Backtrace),
+    #[error("too many one-time codes sent")]
+    TooManyCodes(Backtrace),
+    #[error("no authenticator app to verify")]
+    NoTotp(Backtrace),
+    #[error("too many wrong one-time codes entered")]
+    TooManyCodeAttempts(Backtrace),
+    #[error("login challenge missing or expired")]
+    NoLoginChallenge(Backtrace),
+    #[error("QR code error")]
+    QrCode(#[from] qrcode::types::QrError, Backtrace),
     #[error("HTML templating error")]
     HtmlTemplate(#[from] tera::Error, Backtrace),
+    #[error("file watching error")]
+    Notify(#[from] notify::Error, Backtrace),
     #[error("UTF-8 HTTP header decoding failed")]
     Utf8Decoding(#[from] hyper::header::ToStrError, Backtrace),
     #[error("cookie parse error")]
@@ -34,10 +327,271 @@ pub enum Error {
     UuidParse(#[from] uuid::Error, Backtrace),
     #[error("url encoding deserialization error")]
     UrlEncoding(#[from] serde_urlencoded::de::Error, Backtrace),
+    #[error("url encoding serialization error")]
+    UrlSerialization(#[from] serde_urlencoded::ser::Error, Backtrace),
+    #[error("address parse error")]
+    AddrParse(#[from] std::net::AddrParseError, Backtrace),
+    #[error("gRPC transport error")]
+    Grpc(#[from] tonic::transport::Error, Backtrace),
+    #[error("JSON deserialization error")]
+    Json(#[from] serde_json::Error, Backtrace),
+}
+
+impl ErrorKind {
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorKind::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::Unprocessable => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::GatewayTimeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    pub fn is_client_error(self) -> bool {
+        self.status_code().is_client_error()
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::BadRequest => "bad_request",
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::Forbidden => "forbidden",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::MethodNotAllowed => "method_not_allowed",
+            ErrorKind::RequestTimeout => "request_timeout",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::Unprocessable => "unprocessable",
+            ErrorKind::TooManyRequests => "too_many_requests",
+            ErrorKind::Internal => "internal",
+            ErrorKind::Unavailable => "unavailable",
+            ErrorKind::GatewayTimeout => "gateway_timeout",
+        }
+    }
 }
 
 impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Database(e, _) if e.is_closed() => ErrorKind::Unavailable,
+            Error::DatabaseUnavailable(_) => ErrorKind::Unavailable,
+            Error::DatabaseTimeout(_) => ErrorKind::Unavailable,
+            Error::BodyTimeout(_) => ErrorKind::RequestTimeout,
+            Error::HandlerTimeout(_) => ErrorKind::GatewayTimeout,
+            Error::NotFound(_) => ErrorKind::NotFound,
+            Error::MethodNotAllowed(_, _) => ErrorKind::MethodNotAllowed,
+            Error::NotLoggedIn(_) => ErrorKind::Unauthorized,
+            Error::UserNotFound(_) => ErrorKind::Unauthorized,
+            Error::WrongPassword(_) => ErrorKind::Unauthorized,
+            Error::CryptoSignatureVerification(_, _) => ErrorKind::Unauthorized,
+            Error::MalformedSession(_) => ErrorKind::Unauthorized,
+            Error::Unseal(_) => ErrorKind::Unauthorized,
+            Error::UsernameTaken(_) => ErrorKind::Conflict,
+            Error::InvalidUsernameLength { .. } => ErrorKind::Unprocessable,
+            Error::InvalidUsernameCharacters(_) => ErrorKind::Unprocessable,
+            Error::UsernameReserved(_) => ErrorKind::Unprocessable,
+            Error::EmailTaken(_) => ErrorKind::Conflict,
+            Error::InvalidEmail(_) => ErrorKind::Unprocessable,
+            Error::IdentityTaken(_) => ErrorKind::Conflict,
+            Error::LastLoginMethod(_) => ErrorKind::Conflict,
+            Error::PasswordExpired(_) => ErrorKind::Forbidden,
+            Error::PasswordUnchanged(_) => ErrorKind::Unprocessable,
+            Error::PasswordRejected(_, _) => ErrorKind::Unprocessable,
+            Error::NotAdmin(_) => ErrorKind::Forbidden,
+            Error::UnknownUser(_, _) => ErrorKind::Unprocessable,
+            Error::Impersonating(_) => ErrorKind::Forbidden,
+            Error::TermsNotAccepted(_) => ErrorKind::Unprocessable,
+            Error::ExportNotFound(_) => ErrorKind::NotFound,
+            Error::AccountSuspended(_, _) => ErrorKind::Forbidden,
+            Error::ServiceAccountLogin(_) => ErrorKind::Forbidden,
+            Error::KerberosToken(_, _) => ErrorKind::Unauthorized,
+            Error::KerberosPrincipalUnknown(_, _) => ErrorKind::Unauthorized,
+            Error::InvalidClient(_) => ErrorKind::Unauthorized,
+            Error::ClientIdTaken(_) => ErrorKind::Conflict,
+            Error::ClientNotFound(_, _) => ErrorKind::NotFound,
+            Error::ScopeNotAllowed(_, _) => ErrorKind::BadRequest,
+            Error::UnregisteredRedirectUri(_) => ErrorKind::BadRequest,
+            Error::UnknownLocale(_, _) => ErrorKind::BadRequest,
+            Error::UnknownAccountStatus(_, _) => ErrorKind::BadRequest,
+            Error::RegistrationNotPending(_) => ErrorKind::Conflict,
+            Error::DisposableEmail(_) => ErrorKind::Unprocessable,
+            Error::FeatureDisabled(_, _) => ErrorKind::Forbidden,
+            Error::UnknownFeature(_, _) => ErrorKind::BadRequest,
+            Error::RegistrationQuotaExceeded(_) => ErrorKind::TooManyRequests,
+            Error::UnknownMode(_, _) => ErrorKind::BadRequest,
+            Error::Maintenance(_) => ErrorKind::Unavailable,
+            Error::ReadOnly(_) => ErrorKind::Unavailable,
+            Error::InvalidLogoutToken(_) => ErrorKind::Forbidden,
+            Error::InvalidConsentToken(_) => ErrorKind::Forbidden,
+            Error::InvalidUnsubscribeToken(_) => ErrorKind::Forbidden,
+            Error::EmptyField(_, _) => ErrorKind::Unprocessable,
+            Error::MailAddress(_, _) => ErrorKind::Unprocessable,
+            Error::InvalidPhoneNumber(_) => ErrorKind::Unprocessable,
+            Error::NoPhoneNumber(_) => ErrorKind::BadRequest,
+            Error::WrongCode(_) => ErrorKind::Unauthorized,
+            Error::TooManyCodes(_) => ErrorKind::TooManyRequests,
+            Error::NoTotp(_) => ErrorKind::BadRequest,
+            Error::TooManyCodeAttempts(_) => ErrorKind::TooManyRequests,
+            Error::NoLoginChallenge(_) => ErrorKind::Unauthorized,
+            // These can only come from parsing what the client sent, be it the form body, the
+            // cookie header or the session cookie inside it.
+            Error::UrlEncoding(_, _)
+            | Error::Utf8Decoding(_, _)
+            | Error::Parse(_, _)
+            | Error::IntParse(_, _)
+            | Error::HexDecode(_, _)
+            | Error::UuidParse(_, _)
+            | Error::Json(_, _) => ErrorKind::BadRequest,
+            _ => ErrorKind::Internal,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.kind().status_code()
+    }
+
+    /// Identifier for API clients to tell errors apart by, which unlike the messages is meant to
+    /// stay the same between versions.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotLoggedIn(_) => "not_logged_in",
+            Error::UserNotFound(_) | Error::WrongPassword(_) => "invalid_credentials",
+            Error::CryptoSignatureVerification(_, _)
+            | Error::MalformedSession(_)
+            | Error::Unseal(_) => "invalid_session",
+            Error::UsernameTaken(_) => "username_taken",
+            Error::InvalidUsernameLength { .. } => "invalid_username_length",
+            Error::InvalidUsernameCharacters(_) => "invalid_username_characters",
+            Error::UsernameReserved(_) => "username_reserved",
+            Error::EmailTaken(_) => "email_taken",
+            Error::InvalidEmail(_) => "invalid_email",
+            Error::IdentityTaken(_) => "identity_taken",
+            Error::LastLoginMethod(_) => "last_login_method",
+            Error::PasswordExpired(_) => "password_expired",
+            Error::PasswordUnchanged(_) => "password_unchanged",
+            Error::PasswordRejected(_, _) => "password_rejected",
+            Error::NotAdmin(_) => "not_admin",
+            Error::UnknownUser(_, _) => "unknown_user",
+            Error::Impersonating(_) => "impersonating",
+            Error::TermsNotAccepted(_) => "terms_not_accepted",
+            Error::ExportNotFound(_) => "export_not_found",
+            Error::AccountSuspended(AccountStatus::Banned, _) => "account_banned",
+            Error::AccountSuspended(AccountStatus::Pending, _) => "account_pending",
+            Error::AccountSuspended(AccountStatus::Rejected, _) => "account_rejected",
+            Error::AccountSuspended(_, _) => "account_suspended",
+            Error::ServiceAccountLogin(_) => "service_account_login",
+            Error::KerberosToken(_, _) => "invalid_kerberos_token",
+            Error::KerberosPrincipalUnknown(_, _) => "unknown_kerberos_principal",
+            Error::RegistrationNotPending(_) => "registration_not_pending",
+            Error::DisposableEmail(_) => "disposable_email",
+            Error::FeatureDisabled(_, _) => "feature_disabled",
+            Error::RegistrationQuotaExceeded(_) => "registration_quota_exceeded",
+            Error::Maintenance(_) => "maintenance",
+            Error::ReadOnly(_) => "read_only",
+            Error::InvalidLogoutToken(_) => "invalid_logout_token",
+            Error::InvalidConsentToken(_) => "invalid_consent_token",
+            Error::InvalidUnsubscribeToken(_) => "invalid_unsubscribe_token",
+            Error::InvalidClient(_) => "invalid_client",
+            Error::ClientIdTaken(_) => "client_id_taken",
+            Error::ScopeNotAllowed(_, _) => "invalid_scope",
+            Error::EmptyField(_, _) => "empty_field",
+            Error::InvalidPhoneNumber(_) => "invalid_phone_number",
+            Error::WrongCode(_) => "invalid_code",
+            Error::TooManyCodes(_) => "too_many_codes",
+            Error::NoTotp(_) => "no_totp",
+            Error::TooManyCodeAttempts(_) => "too_many_code_attempts",
+            Error::NoLoginChallenge(_) => "no_login_challenge",
+            _ => self.kind().code(),
+        }
+    }
+
+    /// Sentence explaining the error to the person filling in a form, as opposed to the log message
+    /// meant for whoever runs the server.
+    pub fn user_message(&self) -> String {
+        self.localized_message(i18n::DEFAULT_LOCALE)
+    }
+
+    pub fn localized_message(&self, locale: &str) -> String {
+        let key = match self {
+            Error::UserNotFound(_) | Error::WrongPassword(_) => "error-invalid-credentials",
+            Error::UsernameTaken(_) => "error-username-taken",
+            Error::InvalidUsernameLength { min, max, .. } => {
+                let (min, max) = (min.to_string(), max.to_string());
+                return i18n::translate(
+                    locale,
+                    "error-invalid-username-length",
+                    &[("min", &min), ("max", &max)],
+                );
+            }
+            Error::InvalidUsernameCharacters(_) => "error-invalid-username-characters",
+            Error::UsernameReserved(_) => "error-username-reserved",
+            Error::EmailTaken(_) => "error-email-taken",
+            Error::InvalidEmail(_) => "error-invalid-email",
+            Error::IdentityTaken(_) => "error-identity-taken",
+            Error::LastLoginMethod(_) => "error-last-login-method",
+            Error::PasswordExpired(_) => "error-password-expired",
+            Error::PasswordUnchanged(_) => "error-password-unchanged",
+            Error::NotAdmin(_) => "error-not-admin",
+            Error::Impersonating(_) => "error-impersonating",
+            Error::TermsNotAccepted(_) => "error-terms-not-accepted",
+            Error::ExportNotFound(_) => "error-export-not-found",
+            Error::AccountSuspended(AccountStatus::Banned, _) => "error-account-banned",
+            Error::AccountSuspended(AccountStatus::Pending, _) => "error-account-pending",
+            Error::AccountSuspended(AccountStatus::Rejected, _) => "error-account-rejected",
+            Error::AccountSuspended(_, _) => "error-account-suspended",
+            Error::ServiceAccountLogin(_) => "error-service-account-login",
+            Error::KerberosToken(_, _) => "error-kerberos-token",
+            Error::KerberosPrincipalUnknown(_, _) => "error-kerberos-principal-unknown",
+            Error::RegistrationNotPending(_) => "error-registration-not-pending",
+            Error::DisposableEmail(_) => "error-disposable-email",
+            Error::FeatureDisabled(_, _) => "error-feature-disabled",
+            Error::RegistrationQuotaExceeded(_) => "error-registration-quota-exceeded",
+            Error::Maintenance(_) => "error-maintenance",
+            Error::ReadOnly(_) => "error-read-only",
+            Error::InvalidLogoutToken(_) => "error-invalid-logout-token",
+            Error::InvalidConsentToken(_) => "error-invalid-consent-token",
+            Error::InvalidUnsubscribeToken(_) => "error-invalid-unsubscribe-token",
+            Error::PasswordRejected(reason, _) => {
+                return i18n::translate(locale, "error-password-rejected", &[("reason", reason)]);
+            }
+            Error::UnknownUser(username, _) => {
+                return i18n::translate(locale, "error-unknown-user", &[("username", username)]);
+            }
+            Error::EmptyField(field, _) => {
+                let field = i18n::translate(locale, &format!("field-{}", field), &[]);
+                return i18n::translate(locale, "error-empty-field", &[("field", &field)]);
+            }
+            Error::NotLoggedIn(_) => "error-not-logged-in",
+            Error::CryptoSignatureVerification(_, _)
+            | Error::MalformedSession(_)
+            | Error::Unseal(_) => "error-invalid-session",
+            Error::NotFound(_) => "error-not-found",
+            Error::MethodNotAllowed(_, _) => "error-method-not-allowed",
+            Error::InvalidPhoneNumber(_) => "error-invalid-phone-number",
+            Error::WrongCode(_) => "error-wrong-code",
+            Error::TooManyCodes(_) => "error-too-many-codes",
+            Error::NoTotp(_) => "error-no-totp",
+            Error::TooManyCodeAttempts(_) => "error-too-many-code-attempts",
+            Error::NoLoginChallenge(_) => "error-no-login-challenge",
+            _ if self.kind() == ErrorKind::BadRequest => "error-bad-request",
+            _ => "error-internal",
+        };
+        i18n::translate(locale, key, &[])
+    }
+
     pub fn log_message(&self) -> SingleKV<String> {
+        SingleKV::from(("message", self.full_message()))
+    }
+
+    /// The message along with the messages of every error that caused it.
+    pub fn full_message(&self) -> String {
         let mut error: &dyn StdError = &self;
         let mut buf = error.to_string().replace('\n', " ");
         while let Some(source) = error.source() {
@@ -47,10 +601,14 @@ impl Error {
             }
             error = source;
         }
-        SingleKV::from(("message", buf))
+        buf
     }
 
     pub fn log_backtrace(&self) -> SingleKV<Option<String>> {
-        SingleKV::from(("backtrace", self.backtrace().map(Backtrace::to_string)))
+        SingleKV::from(("backtrace", self.backtrace_string()))
+    }
+
+    pub fn backtrace_string(&self) -> Option<String> {
+        self.backtrace().map(Backtrace::to_string)
     }
 }
@@ -0,0 +1,128 @@
+use crate::audit::AuditEvent;
+use crate::error::Error;
+use crate::util::{env_duration_ms, env_var_opt};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+use std::backtrace::Backtrace;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+use uuid::Uuid;
+
+mod kafka;
+mod nats;
+
+pub use kafka::KafkaPublisher;
+pub use nats::NatsPublisher;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something that happened in the life of an account, as published for analytics and SIEM
+/// pipelines to consume. Events of the audit log are published when they're recorded by
+/// [`crate::audit::record`] with publishing on, through the job queue, so that each of them is
+/// published at least once.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Event {
+    /// Unique to the event, for consumers to drop the duplicates a retried job can make.
+    pub id: Uuid,
+    /// One of the kinds of [`crate::audit`] events.
+    pub kind: String,
+    pub tenant: String,
+    pub user_id: Option<i32>,
+    pub ip: Option<String>,
+    pub device: Option<String>,
+    pub country: Option<String>,
+    pub details: serde_json::Value,
+    /// Seconds since the Unix epoch.
+    pub occurred_at: u64,
+}
+
+/// Something that publishes events, like a message broker.
+#[async_trait]
+pub trait EventPublisher: Send + Sync {
+    async fn publish(&self, event: &Event) -> Result<(), Error>;
+}
+
+pub struct EventSink {
+    publisher: Box<dyn EventPublisher>,
+    timeout: Duration,
+}
+
+/// Logs events instead of publishing them, and keeps them for tests to look at.
+pub struct DryRunPublisher {
+    log: Logger,
+    published: Mutex<Vec<Event>>,
+}
+
+impl Event {
+    pub fn new(event: &AuditEvent) -> Event {
+        Event {
+            id: Uuid::new_v4(),
+            kind: event.kind.clone(),
+            tenant: event.tenant.clone(),
+            user_id: event.user.map(|user| user.id),
+            ip: event.ip.clone(),
+            device: event.device.clone(),
+            country: event.country.clone(),
+            details: event.details.clone(),
+            occurred_at: event
+                .created_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+}
+
+impl EventSink {
+    /// Picks the publisher from `EVENTS_PUBLISHER`, which is one of `kafka`, `nats` and `dry-run`.
+    pub fn from_env(log: &Logger) -> Result<Option<EventSink>, Error> {
+        let Some(name) = env_var_opt("EVENTS_PUBLISHER")? else {
+            return Ok(None);
+        };
+        let publisher: Box<dyn EventPublisher> = match name.as_str() {
+            "kafka" => Box::new(KafkaPublisher::from_env()?),
+            "nats" => Box::new(NatsPublisher::from_env()?),
+            "dry-run" => Box::new(DryRunPublisher::new(log.clone())),
+            _ => return Err(Error::UnknownEventPublisher(name, Backtrace::capture())),
+        };
+        Ok(Some(EventSink {
+            publisher,
+            timeout: env_duration_ms("EVENTS_TIMEOUT_MS", DEFAULT_TIMEOUT)?,
+        }))
+    }
+
+    /// Whether events are published at all, for deciding whether to queue them.
+    pub fn is_enabled() -> Result<bool, Error> {
+        Ok(env_var_opt("EVENTS_PUBLISHER")?.is_some())
+    }
+
+    pub async fn publish(&self, event: &Event) -> Result<(), Error> {
+        tokio::time::timeout(self.timeout, self.publisher.publish(event))
+            .await
+            .unwrap_or_else(|_| Err(Error::EventTimeout(Backtrace::capture())))
+    }
+}
+
+impl DryRunPublisher {
+    pub fn new(log: Logger) -> DryRunPublisher {
+        DryRunPublisher {
+            log,
+            published: Mutex::new(Vec::new()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn published(&self) -> Vec<Event> {
+        self.published.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl EventPublisher for DryRunPublisher {
+    async fn publish(&self, event: &Event) -> Result<(), Error> {
+        info!(self.log, "Event not published in dry-run mode"; "id" => event.id.to_string(), "kind" => &event.kind);
+        self.published.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use crate::error::Error;
+use crate::events::{Event, EventPublisher};
+use crate::util::{env_var, env_var_opt, http_client};
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use serde::Deserialize;
+use std::backtrace::Backtrace;
+
+const DEFAULT_TOPIC: &str = "authtown.events";
+
+/// Publishes to Kafka through the REST Proxy, as speaking the Kafka protocol itself would need a
+/// client library. Events are keyed by the user, so that each user's events stay in order on the
+/// partition they all go to.
+pub struct KafkaPublisher {
+    client: Client<HttpsConnector<HttpConnector>>,
+    url: String,
+    /// Credentials for proxies behind basic authentication, as `<username>:<password>`.
+    credentials: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProduceResponse {
+    offsets: Vec<Offset>,
+}
+
+#[derive(Deserialize)]
+struct Offset {
+    error_code: Option<i32>,
+    error: Option<String>,
+}
+
+impl KafkaPublisher {
+    /// Reads `KAFKA_REST_URL`, the address of the proxy, `KAFKA_TOPIC`, and `KAFKA_REST_USERNAME`
+    /// and `KAFKA_REST_PASSWORD` if the proxy needs them.
+    pub fn from_env() -> Result<KafkaPublisher, Error> {
+        let base = env_var("KAFKA_REST_URL")?;
+        let topic = env_var_opt("KAFKA_TOPIC")?.unwrap_or_else(|| DEFAULT_TOPIC.to_owned());
+        let credentials = match env_var_opt("KAFKA_REST_USERNAME")? {
+            Some(username) => Some(format!("{}:{}", username, env_var("KAFKA_REST_PASSWORD")?)),
+            None => None,
+        };
+        Ok(KafkaPublisher {
+            client: http_client(),
+            url: format!("{}/topics/{}", base.trim_end_matches('/'), topic),
+            credentials,
+        })
+    }
+}
+
+#[async_trait]
+impl EventPublisher for KafkaPublisher {
+    async fn publish(&self, event: &Event) -> Result<(), Error> {
+        let key = match event.user_id {
+            Some(user_id) => format!("{}/{}", event.tenant, user_id),
+            None => event.id.to_string(),
+        };
+        let body = serde_json::json!({ "records": [{ "key": key, "value": event }] });
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.url)
+            .header(CONTENT_TYPE, "application/vnd.kafka.json.v2+json")
+            .header(ACCEPT, "application/vnd.kafka.v2+json");
+        if let Some(credentials) = &self.credentials {
+            let credentials = base64::encode(credentials);
+            request = request.header(AUTHORIZATION, format!("Basic {}", credentials));
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let rejected = |body: String| Error::EventRejected {
+            status,
+            body,
+            backtrace: Backtrace::capture(),
+        };
+        if !status.is_success() {
+            return Err(rejected(String::from_utf8_lossy(&body).into_owned()));
+        }
+        // The proxy answers with a success even when the broker failed to take the record, which
+        // only the offsets tell.
+        let response: ProduceResponse = serde_json::from_slice(&body)?;
+        for offset in response.offsets {
+            if offset.error_code.is_some() {
+                return Err(rejected(offset.error.unwrap_or_default()));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use crate::error::Error;
+use crate::events::{Event, EventPublisher};
+use crate::tls::load_certificates;
+use crate::util::{env_var, env_var_opt};
+use async_trait::async_trait;
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use std::backtrace::Backtrace;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+const DEFAULT_PORT: u16 = 4222;
+const DEFAULT_SUBJECT: &str = "authtown.events";
+
+/// Publishes to NATS, on a subject of the kind of the event under `NATS_SUBJECT`, like
+/// `authtown.events.login_succeeded`. Each event gets a connection of its own, which is closed once
+/// the server answers the ping sent after it, as that's when it's known to have been taken.
+pub struct NatsPublisher {
+    address: String,
+    /// Set for `tls://` URLs, along with the name the certificate of the server is checked against.
+    tls: Option<(TlsConnector, ServerName)>,
+    subject: String,
+    token: Option<String>,
+}
+
+impl NatsPublisher {
+    /// Reads `NATS_URL`, like `tls://nats.example.com:4222`, `NATS_SUBJECT` and `NATS_TOKEN` if the
+    /// server needs one. The certificate of the server is checked against `NATS_CA_CERT`, or the
+    /// usual CAs if that's not set. Plain `nats://` URLs are for servers on a trusted network, and
+    /// aren't allowed along with a token, which would be sent in the clear.
+    pub fn from_env() -> Result<NatsPublisher, Error> {
+        let url = env_var("NATS_URL")?;
+        let invalid = || Error::InvalidNatsUrl(url.clone(), Backtrace::capture());
+        let (tls, host) = match url.split_once("://") {
+            Some(("nats", host)) => (false, host),
+            Some(("tls", host)) => (true, host),
+            _ => return Err(invalid()),
+        };
+        let host = host.trim_end_matches('/');
+        if host.is_empty() || host.contains('/') || host.contains('@') {
+            return Err(invalid());
+        }
+        let (name, address) = match host.rsplit_once(':') {
+            Some((name, _)) => (name, host.to_owned()),
+            None => (host, format!("{}:{}", host, DEFAULT_PORT)),
+        };
+        let token = env_var_opt("NATS_TOKEN")?;
+        if token.is_some() && !tls {
+            return Err(Error::NatsTokenWithoutTls(Backtrace::capture()));
+        }
+        let tls = match tls {
+            true => {
+                let name = ServerName::try_from(name).map_err(|_| invalid())?;
+                Some((TlsConnector::from(Arc::new(tls_config_from_env()?)), name))
+            }
+            false => None,
+        };
+        Ok(NatsPublisher {
+            address,
+            tls,
+            subject: env_var_opt("NATS_SUBJECT")?.unwrap_or_else(|| DEFAULT_SUBJECT.to_owned()),
+            token,
+        })
+    }
+
+    /// Publishes over a connection the server has already sent its `INFO` on.
+    async fn publish_on(
+        &self,
+        stream: impl AsyncRead + AsyncWrite,
+        event: &Event,
+    ) -> Result<(), Error> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        let connect = serde_json::json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "authtown",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "auth_token": self.token,
+        });
+        let payload = serde_json::to_string(event)?;
+        let message = format!(
+            "CONNECT {}\r\nPUB {}.{} {}\r\n{}\r\nPING\r\n",
+            connect,
+            self.subject,
+            event.kind,
+            payload.len(),
+            payload
+        );
+        writer.write_all(message.as_bytes()).await?;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                return Err(Error::NatsProtocol(
+                    "connection closed".to_owned(),
+                    Backtrace::capture(),
+                ));
+            }
+            match line.trim_end() {
+                "PONG" => return Ok(()),
+                "PING" => writer.write_all(b"PONG\r\n").await?,
+                // Servers in a cluster tell about changes to it at any time.
+                reply if reply == "+OK" || reply.starts_with("INFO ") => (),
+                // Anything else is an error, whether the server says so with -ERR or not.
+                reply => return Err(Error::NatsProtocol(reply.to_owned(), Backtrace::capture())),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EventPublisher for NatsPublisher {
+    async fn publish(&self, event: &Event) -> Result<(), Error> {
+        // The server speaks first, before the connection is upgraded to TLS.
+        let mut stream = BufReader::new(TcpStream::connect(&self.address).await?);
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        if !line.starts_with("INFO ") {
+            return Err(Error::NatsProtocol(line, Backtrace::capture()));
+        }
+        let stream = stream.into_inner();
+        match &self.tls {
+            Some((connector, name)) => {
+                let stream = connector.connect(name.clone(), stream).await?;
+                self.publish_on(stream, event).await
+            }
+            None => self.publish_on(stream, event).await,
+        }
+    }
+}
+
+fn tls_config_from_env() -> Result<ClientConfig, Error> {
+    let mut roots = RootCertStore::empty();
+    match env_var_opt("NATS_CA_CERT")? {
+        Some(ca_path) => {
+            let certificates: Vec<_> = load_certificates(&ca_path)?
+                .into_iter()
+                .map(|certificate| certificate.0)
+                .collect();
+            if roots.add_parsable_certificates(&certificates).1 != 0 {
+                return Err(Error::TlsPem {
+                    path: ca_path,
+                    backtrace: Backtrace::capture(),
+                });
+            }
+        }
+        None => {
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }));
+        }
+    }
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
@@ -0,0 +1,147 @@
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::jobs::{self, Task};
+use crate::store::Store;
+use crate::user::User;
+use async_trait::async_trait;
+use std::backtrace::Backtrace;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Copy of everything stored about a user, which they can ask for and download once. It's put
+/// together by the job queue, as users with a long history can have a lot of it.
+pub struct Export {
+    pub id: Uuid,
+    /// Whether the job has put the data together yet.
+    pub ready: bool,
+}
+
+#[async_trait]
+pub trait ExportStore: Send + Sync {
+    /// Starts an export of the user's data, left empty until [`assemble`] fills it in.
+    async fn insert(&self, user: User, id: Uuid, expires_at: SystemTime) -> Result<(), Error>;
+
+    async fn complete(&self, id: Uuid, data: &str) -> Result<(), Error>;
+
+    /// Newest unexpired export of the user, ready or not.
+    async fn latest(&self, user: User) -> Result<Option<Export>, Error>;
+
+    /// Deletes a ready unexpired export, returning whose it was and the data, so that the link to
+    /// it only works once.
+    async fn take(&self, id: Uuid) -> Result<Option<(User, String)>, Error>;
+
+    /// Deletes exports that expired before the given time, returning how many there were.
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error>;
+}
+
+/// How long an export can be downloaded for after it was asked for.
+pub const EXPIRATION_TIME: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// The log is newest first, so on the off chance there's more than this, the oldest are left out.
+const MAX_AUDIT_EVENTS: usize = 100_000;
+
+const LINK_SIGNATURE_DOMAIN: &str = "export.";
+
+/// Queues an export of the user's data, unless one is already being put together.
+pub async fn request(store: &Store, user: User) -> Result<(), Error> {
+    if let Some(export) = store.exports.latest(user).await? {
+        if !export.ready {
+            return Ok(());
+        }
+    }
+    let id = Uuid::new_v4();
+    let expires_at = SystemTime::now() + EXPIRATION_TIME;
+    store.exports.insert(user, id, expires_at).await?;
+    jobs::enqueue(&*store.jobs, &Task::ExportData { user, export: id }).await
+}
+
+/// Puts together the export, as the job queued by [`request`].
+pub async fn assemble(store: &Store, user: User, id: Uuid) -> Result<(), Error> {
+    let data = collect(store, user).await?;
+    store
+        .exports
+        .complete(id, &serde_json::to_string_pretty(&data)?)
+        .await
+}
+
+async fn collect(store: &Store, user: User) -> Result<serde_json::Value, Error> {
+    let profile = store.users.profile(user).await?;
+    let identities = store.users.identities(user).await?;
+    let sessions = store.sessions.list(user).await?;
+    let phone = store.otp.phone(user).await?;
+    let totp = store.otp.totp(user).await?;
+    let applications = store.consents.list(user).await?;
+    let terms = store.users.accepted_terms(user).await?;
+    let events = store.audit.list(user, UNIX_EPOCH, MAX_AUDIT_EVENTS).await?;
+    Ok(serde_json::json!({
+        "exported_at": unix_time(SystemTime::now()),
+        "profile": {
+            "id": profile.id,
+            "username": profile.username,
+            "email": profile.email,
+            "has_password": profile.has_password,
+            "password_changed_at": unix_time(profile.password_changed_at),
+        },
+        "identities": identities.iter().map(|identity| serde_json::json!({
+            "provider": identity.provider,
+            "subject": identity.subject,
+        })).collect::<Vec<_>>(),
+        "phone": phone.map(|phone| serde_json::json!({
+            "number": phone.number,
+            "verified": phone.verified,
+        })),
+        // The secret stays out, as anyone who gets hold of the export could make codes with it.
+        "authenticator_app": totp.map(|totp| serde_json::json!({
+            "enrolled": totp.secret.is_some(),
+            "pending": totp.pending.is_some(),
+        })),
+        "accepted_terms_version": terms,
+        "sessions": sessions.iter().map(|session| serde_json::json!({
+            "id": session.id,
+            "created_at": unix_time(session.created_at),
+            "expires_at": unix_time(session.expires_at),
+        })).collect::<Vec<_>>(),
+        "applications": applications.iter().map(|consent| serde_json::json!({
+            "client_id": consent.client_id,
+            "scope": consent.scope,
+        })).collect::<Vec<_>>(),
+        "audit_events": events.iter().map(|event| serde_json::json!({
+            "kind": event.kind,
+            "ip": event.ip,
+            "device": event.device,
+            "country": event.country,
+            "details": event.details,
+            "created_at": unix_time(event.created_at),
+        })).collect::<Vec<_>>(),
+    }))
+}
+
+/// Link downloading the export, signed so that export IDs turning up elsewhere, like in the logs,
+/// can't be used to download them.
+pub fn download_link(id: Uuid, crypto: &Crypto) -> String {
+    let signature = crypto.sign(&signed_data(&id.to_string()));
+    format!(
+        "/settings/export/download?token={}.{}",
+        id,
+        hex::encode(&signature.hash)
+    )
+}
+
+/// Export ID from the token of a [`download_link`]. Tampered tokens fail like used ones do.
+pub fn verify_token(token: &str, crypto: &Crypto) -> Result<Uuid, Error> {
+    let not_found = || Error::ExportNotFound(Backtrace::capture());
+    let (id, signature) = token.split_once('.').ok_or_else(not_found)?;
+    let signature = hex::decode(signature).map_err(|_| not_found())?;
+    crypto
+        .verify(&signed_data(id), &signature)
+        .map_err(|_| not_found())?;
+    id.parse().map_err(|_| not_found())
+}
+
+fn signed_data(id: &str) -> Vec<u8> {
+    format!("{}{}", LINK_SIGNATURE_DOMAIN, id).into_bytes()
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
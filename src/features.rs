@@ -0,0 +1,121 @@
+use crate::error::Error;
+use crate::util::env_var_opt;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+
+/// Part of logging in or signing up that can be switched off while the server is running, say
+/// during an incident or while a flow is being fixed.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Feature {
+    Registration,
+    PasswordLogin,
+    /// Logging in with a code mailed in place of the password.
+    EmailLogin,
+    /// Applications logging users in through the OAuth endpoints.
+    OAuth,
+    /// Asking users with a phone number for a texted code at login. Switching it off lets them in
+    /// with the first factor alone, for when the SMS gateway is down.
+    SecondFactor,
+}
+
+#[async_trait]
+pub trait FeatureStore: Send + Sync {
+    /// Features an admin switched on or off, which take precedence over the configured defaults.
+    async fn list(&self) -> Result<Vec<(String, bool)>, Error>;
+
+    async fn set(&self, name: &str, enabled: bool) -> Result<(), Error>;
+}
+
+/// Which features are on by default, from `FEATURES_DISABLED`, a comma-separated list of the
+/// features to start out switched off.
+pub struct FeaturePolicy {
+    pub disabled: Vec<Feature>,
+}
+
+/// Whether each feature is on, as of when it was looked up. Serialized for the templates as an
+/// object from feature names to booleans.
+#[derive(Serialize)]
+#[serde(transparent)]
+pub struct Features {
+    enabled: BTreeMap<&'static str, bool>,
+}
+
+impl Feature {
+    pub const ALL: &'static [Feature] = &[
+        Feature::Registration,
+        Feature::PasswordLogin,
+        Feature::EmailLogin,
+        Feature::OAuth,
+        Feature::SecondFactor,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Feature::Registration => "registration",
+            Feature::PasswordLogin => "password_login",
+            Feature::EmailLogin => "email_login",
+            Feature::OAuth => "oauth",
+            Feature::SecondFactor => "second_factor",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Feature> {
+        Feature::ALL
+            .iter()
+            .copied()
+            .find(|feature| feature.as_str() == name)
+    }
+}
+
+impl FeaturePolicy {
+    pub fn from_env() -> Result<FeaturePolicy, Error> {
+        let disabled = match env_var_opt("FEATURES_DISABLED")? {
+            Some(names) => names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    Feature::parse(name)
+                        .ok_or_else(|| Error::UnknownFeature(name.to_owned(), Backtrace::capture()))
+                })
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        Ok(FeaturePolicy { disabled })
+    }
+}
+
+impl Features {
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.enabled[feature.as_str()]
+    }
+}
+
+/// Looks up whether each feature is on. Switches stored for features that no longer exist are
+/// ignored, so that removing one doesn't break servers that still have it stored.
+pub async fn load(store: &dyn FeatureStore, policy: &FeaturePolicy) -> Result<Features, Error> {
+    let mut enabled: BTreeMap<_, _> = Feature::ALL
+        .iter()
+        .map(|&feature| (feature.as_str(), !policy.disabled.contains(&feature)))
+        .collect();
+    for (name, value) in store.list().await? {
+        if let Some(feature) = Feature::parse(&name) {
+            enabled.insert(feature.as_str(), value);
+        }
+    }
+    Ok(Features { enabled })
+}
+
+/// Fails when the feature is switched off.
+pub async fn require(
+    store: &dyn FeatureStore,
+    policy: &FeaturePolicy,
+    feature: Feature,
+) -> Result<(), Error> {
+    if !load(store, policy).await?.is_enabled(feature) {
+        return Err(Error::FeatureDisabled(feature, Backtrace::capture()));
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use crate::crypto::Crypto;
+use crate::error::Error;
+use cookie::{Cookie, SameSite};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::Duration;
+
+/// Message carried over a redirect to be shown once on the next page, like a failed login being
+/// reported next to the login form.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Flash {
+    /// Form the message is about, or none for messages about the whole page.
+    pub form: Option<String>,
+    pub message: String,
+    /// Username to fill the form with again, so that only the password has to be retyped.
+    pub username: Option<String>,
+}
+
+// Long enough to survive the redirect, short enough not to show up again days later.
+const EXPIRATION_TIME: Duration = Duration::from_secs(60);
+
+// Signed payloads are prefixed so that a signed value from another cookie can't pass as a flash.
+const SIGNATURE_DOMAIN: &str = "flash.";
+
+impl Flash {
+    pub fn form(form: &str, message: String, username: &str) -> Flash {
+        Flash {
+            form: Some(form.to_owned()),
+            message,
+            username: Some(username.to_owned()),
+        }
+    }
+
+    /// Message about a form that has nothing worth filling in again.
+    pub fn error(form: &str, message: String) -> Flash {
+        Flash {
+            form: Some(form.to_owned()),
+            message,
+            username: None,
+        }
+    }
+
+    pub fn notice(message: &str) -> Flash {
+        Flash {
+            form: None,
+            message: message.to_owned(),
+            username: None,
+        }
+    }
+
+    /// Flashes are only cosmetic, so ones that are malformed or badly signed are ignored rather
+    /// than failing the whole request.
+    pub fn from_cookies(cookies: &HashMap<&str, Cookie>, crypto: &Crypto) -> Option<Flash> {
+        let cookie = cookies.get("flash")?;
+        let (payload, signature) = cookie.value().rsplit_once('.')?;
+        crypto
+            .verify(&signed_data(payload), &hex::decode(signature).ok()?)
+            .ok()?;
+        serde_urlencoded::from_str(payload).ok()
+    }
+
+    pub fn cookie(&self, crypto: &Crypto) -> Result<Cookie<'static>, Error> {
+        let payload = serde_urlencoded::to_string(self)?;
+        let signature = crypto.sign(&signed_data(&payload));
+        Ok(cookie_raw(
+            format!("{}.{}", payload, hex::encode(&signature.hash)),
+            EXPIRATION_TIME,
+        ))
+    }
+
+    pub fn cookie_clear() -> Cookie<'static> {
+        cookie_raw(String::new(), Duration::ZERO)
+    }
+}
+
+fn signed_data(payload: &str) -> Vec<u8> {
+    format!("{}{}", SIGNATURE_DOMAIN, payload).into_bytes()
+}
+
+fn cookie_raw(value: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build("flash", value)
+        .max_age(max_age.try_into().unwrap())
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .finish()
+}
@@ -0,0 +1,277 @@
+use crate::audit::AuditEvent;
+use crate::error::Error;
+use crate::oauth::TokenInfo;
+use crate::session::SessionInfo;
+use crate::store::Store;
+use crate::user::User;
+use async_graphql::parser::parse_query;
+use async_graphql::parser::types::OperationType;
+use async_graphql::{Context, EmptySubscription, ErrorExtensions, Json, Object, ID};
+use slog::{error, Logger};
+use std::backtrace::Backtrace;
+use std::lazy::SyncLazy;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub type Schema = async_graphql::Schema<Query, Mutation, EmptySubscription>;
+
+/// Most audit events returned at once, which is as far back as the query can look.
+const MAX_AUDIT_EVENTS: i32 = 100;
+
+/// The schema holds no state, so it's built once; the store, logger and [`Auth`] are attached to
+/// every request instead.
+pub static SCHEMA: SyncLazy<Schema> =
+    SyncLazy::new(|| Schema::new(Query, Mutation, EmptySubscription));
+
+/// Session the request was made with, absent for anonymous requests.
+pub struct Auth {
+    pub user: User,
+    pub session: Uuid,
+}
+
+pub struct Query;
+
+pub struct Mutation;
+
+struct Viewer {
+    user: User,
+    session: Uuid,
+}
+
+struct ViewerSession {
+    info: SessionInfo,
+    current: bool,
+}
+
+struct ViewerAuditEvent(AuditEvent);
+
+struct ViewerToken(TokenInfo);
+
+#[Object]
+impl Query {
+    /// The logged in user, or null when not logged in.
+    async fn me(&self, ctx: &Context<'_>) -> Option<Viewer> {
+        ctx.data_opt::<Auth>().map(Viewer::new)
+    }
+}
+
+#[Object]
+impl Mutation {
+    async fn update_username(
+        &self,
+        ctx: &Context<'_>,
+        username: String,
+    ) -> async_graphql::Result<Viewer> {
+        let auth = auth(ctx)?;
+        if username.is_empty() {
+            return Err(report(
+                ctx,
+                Error::EmptyField("username", Backtrace::capture()),
+            ));
+        }
+        store(ctx)
+            .users
+            .rename(auth.user, &username)
+            .await
+            .map_err(|e| report(ctx, e))?;
+        Ok(Viewer::new(auth))
+    }
+
+    /// Logs one of the user's sessions out, returning whether it was still active.
+    async fn revoke_session(&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<bool> {
+        let auth = auth(ctx)?;
+        let id: Uuid = id.parse().map_err(|e| report(ctx, Error::from(e)))?;
+        store(ctx)
+            .sessions
+            .revoke(auth.user, id)
+            .await
+            .map_err(|e| report(ctx, e))
+    }
+
+    /// Revokes one of the tokens clients got on behalf of the user, returning whether it was still
+    /// valid.
+    async fn revoke_token(&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<bool> {
+        let auth = auth(ctx)?;
+        store(ctx)
+            .tokens
+            .revoke_by_id(auth.user, &id)
+            .await
+            .map_err(|e| report(ctx, e))
+    }
+}
+
+#[Object]
+impl Viewer {
+    async fn id(&self) -> i32 {
+        self.user.id
+    }
+
+    async fn username(&self, ctx: &Context<'_>) -> async_graphql::Result<String> {
+        let profile = store(ctx)
+            .users
+            .profile(self.user)
+            .await
+            .map_err(|e| report(ctx, e))?;
+        Ok(profile.username)
+    }
+
+    async fn sessions(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<ViewerSession>> {
+        let sessions = store(ctx)
+            .sessions
+            .list(self.user)
+            .await
+            .map_err(|e| report(ctx, e))?;
+        Ok(sessions
+            .into_iter()
+            .map(|info| ViewerSession {
+                current: info.id == self.session,
+                info,
+            })
+            .collect())
+    }
+
+    /// Events of the account, newest first.
+    async fn audit_events(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 20)] limit: i32,
+    ) -> async_graphql::Result<Vec<ViewerAuditEvent>> {
+        let limit = limit.clamp(0, MAX_AUDIT_EVENTS) as usize;
+        let events = store(ctx)
+            .audit
+            .list(self.user, UNIX_EPOCH, limit)
+            .await
+            .map_err(|e| report(ctx, e))?;
+        Ok(events.into_iter().map(ViewerAuditEvent).collect())
+    }
+
+    /// Tokens clients got on behalf of the user that are still valid.
+    async fn tokens(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<ViewerToken>> {
+        let tokens = store(ctx)
+            .tokens
+            .list(self.user)
+            .await
+            .map_err(|e| report(ctx, e))?;
+        Ok(tokens.into_iter().map(ViewerToken).collect())
+    }
+}
+
+#[Object]
+impl ViewerSession {
+    async fn id(&self) -> ID {
+        ID(self.info.id.to_string())
+    }
+
+    /// Unix time in seconds.
+    async fn created_at(&self) -> i64 {
+        unix_time(self.info.created_at)
+    }
+
+    /// Unix time in seconds.
+    async fn expires_at(&self) -> i64 {
+        unix_time(self.info.expires_at)
+    }
+
+    /// Whether this is the session the request was made with.
+    async fn current(&self) -> bool {
+        self.current
+    }
+}
+
+#[Object]
+impl ViewerAuditEvent {
+    async fn kind(&self) -> &str {
+        &self.0.kind
+    }
+
+    async fn ip(&self) -> Option<&str> {
+        self.0.ip.as_deref()
+    }
+
+    async fn device(&self) -> Option<&str> {
+        self.0.device.as_deref()
+    }
+
+    async fn country(&self) -> Option<&str> {
+        self.0.country.as_deref()
+    }
+
+    /// Whatever else is worth knowing about the event, which depends on the kind.
+    async fn details(&self) -> Json<&serde_json::Value> {
+        Json(&self.0.details)
+    }
+
+    /// Unix time in seconds.
+    async fn created_at(&self) -> i64 {
+        unix_time(self.0.created_at)
+    }
+}
+
+#[Object]
+impl ViewerToken {
+    async fn id(&self) -> ID {
+        ID(self.0.id.clone())
+    }
+
+    async fn client_id(&self) -> &str {
+        &self.0.client_id
+    }
+
+    /// Space-separated scopes, in the form OAuth uses.
+    async fn scope(&self) -> &str {
+        &self.0.scope
+    }
+
+    /// Unix time in seconds.
+    async fn expires_at(&self) -> i64 {
+        unix_time(self.0.expires_at)
+    }
+}
+
+impl Viewer {
+    fn new(auth: &Auth) -> Viewer {
+        Viewer {
+            user: auth.user,
+            session: auth.session,
+        }
+    }
+}
+
+/// Whether the request runs a mutation, which read-only mode turns away. Requests that don't parse
+/// are left for the schema to reject.
+pub fn is_mutation(request: &async_graphql::Request) -> bool {
+    let Ok(document) = parse_query(&request.query) else { return false; };
+    let name = request.operation_name.as_deref();
+    document
+        .operations
+        .iter()
+        .any(|(operation_name, operation)| {
+            operation.node.ty == OperationType::Mutation
+                && (name.is_none() || operation_name.map(|name| name.as_str()) == name)
+        })
+}
+
+fn auth<'a>(ctx: &Context<'a>) -> async_graphql::Result<&'a Auth> {
+    ctx.data_opt::<Auth>()
+        .ok_or_else(|| report(ctx, Error::NotLoggedIn(Backtrace::capture())))
+}
+
+fn store<'a>(ctx: &Context<'a>) -> &'a Store {
+    ctx.data_unchecked::<Arc<Store>>()
+}
+
+/// Turns the error into a GraphQL one with the same code and message the JSON API would use, and
+/// logs it if it's our fault, since GraphQL responses don't go through the usual error handling.
+fn report(ctx: &Context<'_>, e: Error) -> async_graphql::Error {
+    if !e.kind().is_client_error() {
+        let log = ctx.data_unchecked::<Logger>();
+        error!(log, "GraphQL resolver failed"; e.log_message(), e.log_backtrace());
+    }
+    async_graphql::Error::new(e.user_message())
+        .extend_with(|_, extensions| extensions.set("code", e.code()))
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
@@ -0,0 +1,137 @@
+use crate::crypto::Crypto;
+use crate::error::{Error, ErrorKind};
+use crate::oauth::unix_time;
+use crate::session::Session;
+use crate::store::Store;
+use crate::user::User;
+use slog::{error, info, Logger};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("authtown.v1");
+}
+
+use proto::auth_server::{Auth, AuthServer};
+
+/// Authentication service for other backends, meant to listen on an internal address only, as it
+/// answers questions about any user to anyone who can connect.
+pub struct AuthService {
+    store: Arc<Store>,
+    crypto: Arc<Crypto>,
+    log: Logger,
+}
+
+pub async fn serve(
+    address: SocketAddr,
+    store: Arc<Store>,
+    crypto: Arc<Crypto>,
+    log: Logger,
+) -> Result<(), Error> {
+    info!(log, "gRPC listening on {}", address);
+    let service = AuthService::new(store, crypto, log);
+    tonic::transport::Server::builder()
+        .add_service(AuthServer::new(service))
+        .serve(address)
+        .await?;
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl Auth for AuthService {
+    async fn verify_session(
+        &self,
+        request: Request<proto::VerifySessionRequest>,
+    ) -> Result<Response<proto::VerifySessionResponse>, Status> {
+        let session = Session::from_cookie_value(&request.get_ref().cookie, &self.crypto)
+            .map_err(|e| self.status(e))?;
+        if !self
+            .store
+            .sessions
+            .is_active(&session)
+            .await
+            .map_err(|e| self.status(e))?
+        {
+            return Err(Status::unauthenticated("session is not active"));
+        }
+        Ok(Response::new(proto::VerifySessionResponse {
+            user_id: session.user().id,
+            session_id: session.id().to_string(),
+        }))
+    }
+
+    async fn verify_token(
+        &self,
+        request: Request<proto::VerifyTokenRequest>,
+    ) -> Result<Response<proto::VerifyTokenResponse>, Status> {
+        let token = self
+            .store
+            .tokens
+            .get(&request.get_ref().token)
+            .await
+            .map_err(|e| self.status(e))?;
+        let Some(token) = token else {
+            return Ok(Response::new(proto::VerifyTokenResponse::default()));
+        };
+        Ok(Response::new(proto::VerifyTokenResponse {
+            user_id: token.user.map(|user| user.id),
+            active: true,
+            scope: token.scope,
+            client_id: token.client_id,
+            expires_at: unix_time(token.expires_at),
+        }))
+    }
+
+    async fn get_user(
+        &self,
+        request: Request<proto::GetUserRequest>,
+    ) -> Result<Response<proto::User>, Status> {
+        let user = User {
+            id: request.get_ref().user_id,
+        };
+        let profile = self
+            .store
+            .users
+            .profile(user)
+            .await
+            .map_err(|e| self.status(e))?;
+        Ok(Response::new(proto::User {
+            id: profile.id,
+            username: profile.username,
+        }))
+    }
+}
+
+impl AuthService {
+    pub fn new(store: Arc<Store>, crypto: Arc<Crypto>, log: Logger) -> AuthService {
+        AuthService { store, crypto, log }
+    }
+
+    fn status(&self, e: Error) -> Status {
+        if !e.kind().is_client_error() {
+            error!(self.log, "gRPC request failed"; e.log_message(), e.log_backtrace());
+        }
+        let message = e.user_message();
+        match e {
+            // Outside of logging in, a missing user is just a missing resource.
+            Error::UserNotFound(_) => Status::not_found("user not found"),
+            _ => match e.kind() {
+                ErrorKind::BadRequest | ErrorKind::Unprocessable => {
+                    Status::invalid_argument(message)
+                }
+                ErrorKind::Unauthorized => Status::unauthenticated(message),
+                ErrorKind::Forbidden => Status::permission_denied(message),
+                ErrorKind::NotFound => Status::not_found(message),
+                ErrorKind::MethodNotAllowed => Status::unimplemented(message),
+                ErrorKind::Conflict => Status::already_exists(message),
+                ErrorKind::TooManyRequests => Status::resource_exhausted(message),
+                ErrorKind::RequestTimeout | ErrorKind::GatewayTimeout => {
+                    Status::deadline_exceeded(message)
+                }
+                ErrorKind::Unavailable => Status::unavailable(message),
+                ErrorKind::Internal => Status::internal(message),
+            },
+        }
+    }
+}
@@ -0,0 +1,767 @@
+//! Handlers of the routes in the route tables, split by what they're for, along with what the
+//! pages and the API have in common: the steps of registering, logging in and changing
+//! credentials, which both take users through.
+
+pub mod admin;
+pub mod api;
+pub mod auth;
+pub mod oauth;
+pub mod settings;
+
+use crate::audit::{self, AuditEvent};
+use crate::backchannel;
+use crate::claims;
+use crate::client::ClientInfo;
+use crate::crypto::Crypto;
+use crate::disposable::DisposableAction;
+use crate::error::{Error, ErrorKind};
+use crate::features::{self, Feature, Features};
+use crate::flash::Flash;
+use crate::mode::Mode;
+use crate::notifications::{self, Category};
+use crate::otp::{self, Challenge, Channel, Purpose};
+use crate::plugins;
+use crate::risk;
+use crate::routes;
+use crate::session::Session;
+use crate::store::Store;
+use crate::templates::Templates;
+use crate::tenant::Tenant;
+use crate::terms::PendingTerms;
+use crate::totp;
+use crate::user::{self, AccountStatus, User};
+use crate::util::{format_time, is_local_path};
+use crate::{check_status, Config};
+use hyper::header::{LOCATION, SET_COOKIE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use slog::{info, Logger};
+use std::backtrace::Backtrace;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Deserialize)]
+struct AuthRegisterRequest {
+    username: String,
+    password: String,
+    /// Optional, but needed for logging in with mailed codes.
+    #[serde(default)]
+    email: Option<String>,
+    next: Option<String>,
+    /// Hidden from people by the form, see [`bot::Signals`].
+    #[serde(default)]
+    website: String,
+    #[serde(default)]
+    form_token: String,
+    /// Required once `TERMS_VERSION` is set, see [`TermsPolicy::accept`].
+    #[serde(default)]
+    accept_terms: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthLoginRequest {
+    username: String,
+    password: String,
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TermsRequest {
+    #[serde(default)]
+    accept: bool,
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeRequest {
+    code: String,
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmailRequest {
+    email: String,
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmailCodeRequest {
+    email: String,
+    code: String,
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangePasswordRequest {
+    #[serde(default)]
+    current_password: String,
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ActivityQuery {
+    page: Option<usize>,
+}
+
+/// What [`router`] worked out about a request for a page, for the handler of its route.
+pub struct Page<'a> {
+    pub session: Option<Session>,
+    pub client: &'a ClientInfo,
+    pub locale: &'static str,
+    /// Everything the templates of every page get, see [`Ctx`].
+    pub context: tera::Context,
+    /// Whether the context has a flash in it, which the page clears once it's been shown.
+    pub had_flash: bool,
+    pub features: &'a Features,
+    pub store: &'a Arc<Store>,
+    pub templates: &'a Arc<Templates>,
+    pub crypto: &'a Arc<Crypto>,
+    pub config: &'a Arc<Config>,
+    pub log: &'a Logger,
+}
+
+/// Counterpart of [`Page`] for the routes of [`api_router`] and [`api_v1_router`].
+pub struct Api<'a> {
+    pub session: Option<Session>,
+    pub client: &'a ClientInfo,
+    pub locale: &'static str,
+    pub store: &'a Arc<Store>,
+    pub templates: &'a Arc<Templates>,
+    pub crypto: &'a Arc<Crypto>,
+    pub config: &'a Arc<Config>,
+    pub log: &'a Logger,
+}
+
+/// Counterpart of [`Page`] for the endpoints of other services and of OAuth clients, which don't
+/// go by the session the way pages do.
+pub struct Endpoint<'a> {
+    pub client: &'a ClientInfo,
+    pub locale: &'static str,
+    pub store: &'a Arc<Store>,
+    pub templates: &'a Arc<Templates>,
+    pub crypto: &'a Arc<Crypto>,
+    pub config: &'a Arc<Config>,
+    pub log: &'a Logger,
+}
+
+pub type PageHandler = for<'a> fn(Request<Body>, Page<'a>) -> routes::HandlerFuture<'a>;
+
+pub type ApiHandler = for<'a> fn(Request<Body>, Api<'a>) -> routes::HandlerFuture<'a>;
+
+pub type EndpointHandler = for<'a> fn(Request<Body>, Endpoint<'a>) -> routes::HandlerFuture<'a>;
+
+/// Outcome of checking the password or a mailed code, which for users with a second factor, and
+/// for logins that look risky, is only the first step. Users who haven't accepted the current
+/// terms have to do that last.
+enum Login {
+    Session(Session),
+    Challenge(Challenge),
+    Terms(PendingTerms),
+}
+
+/// Outcome of registering, which only logs the user in right away when registrations don't need
+/// approval.
+enum Registration {
+    Session(Session),
+    Pending(User),
+}
+
+/// What the user got past the first step of the login with.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum FirstFactor {
+    Password,
+    EmailCode,
+    Kerberos,
+}
+
+/// Logins on each page of the login activity.
+const ACTIVITY_PAGE_SIZE: usize = 20;
+
+impl FirstFactor {
+    fn as_str(self) -> &'static str {
+        match self {
+            FirstFactor::Password => "password",
+            FirstFactor::EmailCode => "email_code",
+            FirstFactor::Kerberos => "kerberos",
+        }
+    }
+}
+
+/// Creates the account, recording the terms as accepted if there are any. When registrations need
+/// approval, or the address is a throwaway one that isn't rejected outright, the account is left
+/// pending instead of being logged in, unless it's an admin's.
+#[allow(clippy::too_many_arguments)]
+async fn register(
+    username: &str,
+    password: &str,
+    email: Option<&str>,
+    accept_terms: bool,
+    client: &ClientInfo,
+    store: &Store,
+    crypto: &Crypto,
+    config: &Config,
+    log: &Logger,
+) -> Result<Registration, Error> {
+    if config.mode.get() == Mode::ReadOnly {
+        return Err(Error::ReadOnly(Backtrace::capture()));
+    }
+    features::require(&*store.features, &config.features, Feature::Registration).await?;
+    let terms = client.tenant.terms(&config.terms).accept(accept_terms)?;
+    if username.is_empty() {
+        return Err(Error::EmptyField("username", Backtrace::capture()));
+    }
+    if password.is_empty() {
+        return Err(Error::EmptyField("password", Backtrace::capture()));
+    }
+    plugins::validate_password(&config.plugins, None, password).await?;
+    // Forms send the field even when it's left empty.
+    let email = match email.map(str::trim).filter(|email| !email.is_empty()) {
+        Some(email) => Some(user::normalize_email(email)?),
+        None => None,
+    };
+    let disposable = matches!(&email, Some(email) if config.disposable.is_disposable(email));
+    if disposable && config.disposable.action == DisposableAction::Reject {
+        return Err(Error::DisposableEmail(Backtrace::capture()));
+    }
+    let quotas = &config.quotas;
+    quotas
+        .check(&*store.quotas, client.ip, email.as_deref())
+        .await?;
+    // A failure anywhere after the account is stored takes it back, so that registering again
+    // doesn't run into the username being taken.
+    let work = async {
+        let user = store
+            .users
+            .insert(&client.tenant.id, username, password, email.as_deref())
+            .await?;
+        if let Some(version) = terms {
+            store.users.accept_terms(user, version).await?;
+        }
+        quotas
+            .record(&*store.quotas, client.ip, email.as_deref())
+            .await?;
+        // Admins are let in right away, as otherwise nobody could approve the first registrations.
+        let approval = client.tenant.approval(config.approval);
+        let pending = (approval || disposable) && !config.admins.contains(&user);
+        if pending {
+            store.users.set_status(user, AccountStatus::Pending).await?;
+        }
+        let details = serde_json::json!({ "pending": pending, "disposable_email": disposable });
+        let event = AuditEvent::new(audit::REGISTERED, Some(user), client, details);
+        audit::record(store, &event, config.publish_events).await?;
+        for plugin in &config.plugins {
+            plugin.on_register(user, &client.tenant.id, store).await?;
+        }
+        if pending {
+            return Ok(Registration::Pending(user));
+        }
+        let claims = claims::collect(user, &client.tenant.id, store, &config.claims).await?;
+        let session = Session::create(user, &client.tenant.id, claims, crypto);
+        store.sessions.insert(&session, false).await?;
+        Ok(Registration::Session(session))
+    };
+    store.transaction(log, work).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn log_in(
+    username: &str,
+    password: &str,
+    client: &ClientInfo,
+    store: &Store,
+    templates: &Templates,
+    crypto: &Crypto,
+    config: &Config,
+    log: &Logger,
+) -> Result<Login, Error> {
+    features::require(&*store.features, &config.features, Feature::PasswordLogin).await?;
+    let tenant = &client.tenant.id;
+    let user = match store.users.get_and_verify(tenant, username, password).await {
+        Ok(user) => user,
+        Err(e @ (Error::UserNotFound(_) | Error::WrongPassword(_))) => {
+            // Looked up for missing users too, so that the time taken doesn't tell them apart.
+            let user = user::find_by_login(&*store.users, tenant, username).await?;
+            let details = serde_json::json!({ "factor": FirstFactor::Password.as_str() });
+            let event = AuditEvent::new(audit::LOGIN_FAILED, user, client, details);
+            audit::record(store, &event, config.publish_events).await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    let factor = FirstFactor::Password;
+    start_login(user, factor, client, store, templates, crypto, config, log).await
+}
+
+/// Mails a login code to the address if it belongs to anyone. Whether it does isn't told apart in
+/// the response, so that the form can't be used to find out who has an account.
+async fn send_login_code(
+    email: &str,
+    client: &ClientInfo,
+    store: &Store,
+    templates: &Templates,
+    config: &Config,
+    log: &Logger,
+) -> Result<(), Error> {
+    features::require(&*store.features, &config.features, Feature::EmailLogin).await?;
+    let email = user::normalize_email(email)?;
+    let Some(user) = store.users.find_by_email(&client.tenant.id, &email).await? else {
+        info!(log, "Login code not mailed, no user has the address");
+        return Ok(());
+    };
+    let locale = client.locale;
+    match otp::mail_code(store, templates, user, Purpose::LoginEmail, &email, locale).await {
+        Ok(()) => info!(log, "Login code mailed"; user),
+        // Only existing users can run into the limit, so it's kept quiet like a missing user is.
+        Err(Error::TooManyCodes(_)) => {
+            info!(log, "Login code not mailed, too many sent lately"; user)
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+/// Checks a mailed login code, which stands in for the password, so the second factor is still
+/// asked for afterwards.
+#[allow(clippy::too_many_arguments)]
+async fn log_in_with_code(
+    email: &str,
+    code: &str,
+    client: &ClientInfo,
+    store: &Store,
+    templates: &Templates,
+    crypto: &Crypto,
+    config: &Config,
+    log: &Logger,
+) -> Result<Login, Error> {
+    features::require(&*store.features, &config.features, Feature::EmailLogin).await?;
+    let email = user::normalize_email(email)?;
+    let Some(user) = store.users.find_by_email(&client.tenant.id, &email).await? else {
+        return Err(Error::WrongCode(Backtrace::capture()));
+    };
+    if !store
+        .otp
+        .check_code(user, Purpose::LoginEmail, code.trim())
+        .await?
+    {
+        let details = serde_json::json!({ "factor": FirstFactor::EmailCode.as_str() });
+        let event = AuditEvent::new(audit::LOGIN_FAILED, Some(user), client, details);
+        audit::record(store, &event, config.publish_events).await?;
+        return Err(Error::WrongCode(Backtrace::capture()));
+    }
+    let factor = FirstFactor::EmailCode;
+    start_login(user, factor, client, store, templates, crypto, config, log).await
+}
+
+/// Logs in a user who got past the first step, or sends them a code leaving the rest to
+/// [`complete_challenge`]. Users with an authenticator app enrolled are always asked for a code
+/// from it, those with a phone number always get a text message, and others get a mail when the
+/// login looks risky by the [`RiskPolicy`].
+#[allow(clippy::too_many_arguments)]
+async fn start_login(
+    user: User,
+    factor: FirstFactor,
+    client: &ClientInfo,
+    store: &Store,
+    templates: &Templates,
+    crypto: &Crypto,
+    config: &Config,
+    log: &Logger,
+) -> Result<Login, Error> {
+    if let Err(e) = check_status(user, store).await {
+        let details = serde_json::json!({ "factor": factor.as_str(), "reason": "suspended" });
+        let event = AuditEvent::new(audit::LOGIN_FAILED, Some(user), client, details);
+        audit::record(store, &event, config.publish_events).await?;
+        return Err(e);
+    }
+    let mut totp = store.otp.totp(user).await?.and_then(|totp| totp.secret);
+    let mut phone = store.otp.phone(user).await?.filter(|phone| phone.verified);
+    if totp.is_some() || phone.is_some() {
+        let features = features::load(&*store.features, &config.features).await?;
+        if !features.is_enabled(Feature::SecondFactor) {
+            info!(log, "Second factor skipped, it is switched off"; user);
+            totp = None;
+            phone = None;
+        }
+    }
+    // Asking the app costs nothing, unlike texting a code.
+    if totp.is_some() {
+        return Ok(Login::Challenge(Challenge::new(user, Channel::Totp)));
+    }
+    if let Some(phone) = phone {
+        let number = &phone.number;
+        match otp::send_code(store, crypto, user, Purpose::Login, number, client.locale).await {
+            Ok(()) => info!(log, "Login code sent"; user),
+            // A code sent a moment ago may still be on its way, so the login can go on with it.
+            Err(Error::TooManyCodes(_)) => {
+                info!(log, "Login code not sent, too many sent lately"; user)
+            }
+            Err(e) => return Err(e),
+        }
+        return Ok(Login::Challenge(Challenge::new(user, Channel::Sms)));
+    }
+    let assessment = risk::assess(&*store.audit, user, client, &config.risk).await?;
+    // A mailed code already proves the address, so there's no point in mailing another one.
+    if assessment.requires_step_up(&config.risk) && factor == FirstFactor::Password {
+        match store.users.profile(user).await?.email {
+            Some(email) => {
+                let locale = client.locale;
+                match otp::mail_code(store, templates, user, Purpose::Login, &email, locale).await {
+                    Ok(()) => {
+                        info!(log, "Risky login, confirmation code mailed"; user, "score" => assessment.score)
+                    }
+                    Err(Error::TooManyCodes(_)) => {
+                        info!(log, "Risky login, confirmation code not mailed, too many sent lately"; user)
+                    }
+                    Err(e) => return Err(e),
+                }
+                let details = serde_json::json!({
+                    "factor": factor.as_str(),
+                    "channel": Channel::Email.as_str(),
+                    "risk": assessment,
+                });
+                let event = AuditEvent::new(audit::LOGIN_STEP_UP, Some(user), client, details);
+                store.audit.insert(&event).await?;
+                return Ok(Login::Challenge(Challenge::new(user, Channel::Email)));
+            }
+            None => {
+                info!(log, "Risky login let through, there is no way to confirm it"; user, "score" => assessment.score)
+            }
+        }
+    }
+    let new_device = assessment.factors.contains(&"new_device");
+    let details = serde_json::json!({ "factor": factor.as_str(), "risk": assessment });
+    let event = AuditEvent::new(audit::LOGIN_SUCCEEDED, Some(user), client, details);
+    audit::record(store, &event, config.publish_events).await?;
+    if new_device {
+        let mut context = tera::Context::new();
+        context.insert("time", &format_time(SystemTime::now()));
+        context.insert("ip", &client.ip.map(|ip| ip.to_string()));
+        context.insert("country", &client.country);
+        let policy = &config.notifications;
+        let locale = client.locale;
+        let category = Category::NewDevice;
+        if notifications::notify(
+            user,
+            category,
+            "new-device",
+            locale,
+            &context,
+            store,
+            templates,
+            crypto,
+            policy,
+        )
+        .await?
+        {
+            info!(log, "New device login mailed"; user);
+        }
+    }
+    finish_login(user, &client.tenant, store, crypto, config).await
+}
+
+async fn complete_challenge(
+    challenge: &Challenge,
+    code: &str,
+    client: &ClientInfo,
+    store: &Store,
+    crypto: &Crypto,
+    config: &Config,
+) -> Result<Login, Error> {
+    let user = challenge.user;
+    let details = serde_json::json!({ "factor": challenge.channel.as_str() });
+    let checked = match challenge.channel {
+        Channel::Totp => match totp::check(user, code, store, crypto).await {
+            // The app may have been removed in the meantime, which leaves nothing to check against.
+            Err(Error::NoTotp(_)) => Err(Error::NoLoginChallenge(Backtrace::capture())),
+            checked => checked,
+        },
+        Channel::Sms | Channel::Email => {
+            match store
+                .otp
+                .check_code(user, Purpose::Login, code.trim())
+                .await?
+            {
+                true => Ok(()),
+                false => Err(Error::WrongCode(Backtrace::capture())),
+            }
+        }
+    };
+    if let Err(e) = checked {
+        let event = AuditEvent::new(audit::LOGIN_FAILED, Some(user), client, details);
+        audit::record(store, &event, config.publish_events).await?;
+        return Err(e);
+    }
+    let event = AuditEvent::new(audit::LOGIN_SUCCEEDED, Some(user), client, details);
+    audit::record(store, &event, config.publish_events).await?;
+    finish_login(user, &client.tenant, store, crypto, config).await
+}
+
+/// The page of the user's logins, counted from 1, marking the ones from the device asking.
+async fn login_activity(
+    user: User,
+    page: Option<usize>,
+    client: &ClientInfo,
+    store: &Store,
+) -> Result<crate::api::ActivityResponse, Error> {
+    let page = page.unwrap_or(1).max(1);
+    // Pages too far back to have anything on them would make offsets the databases can't take.
+    let offset = (page - 1)
+        .saturating_mul(ACTIVITY_PAGE_SIZE)
+        .min(i64::MAX as usize);
+    let mut events = store
+        .audit
+        .logins(user, offset, ACTIVITY_PAGE_SIZE + 1)
+        .await?;
+    let more = events.len() > ACTIVITY_PAGE_SIZE;
+    events.truncate(ACTIVITY_PAGE_SIZE);
+    let logins = events
+        .into_iter()
+        .map(|event| crate::api::LoginResponse {
+            time: event
+                .created_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            succeeded: event.kind == audit::LOGIN_SUCCEEDED,
+            factor: event.details["factor"].as_str().map(str::to_owned),
+            this_device: event.device.is_some() && event.device == client.device,
+            ip: event.ip,
+            country: event.country,
+            device: event.device,
+        })
+        .collect();
+    Ok(crate::api::ActivityResponse {
+        logins,
+        page,
+        next_page: more.then(|| page + 1),
+    })
+}
+
+/// Creates the session of a user who got past every factor, unless there are terms they haven't
+/// accepted the current version of, which [`accept_terms`] waits for first.
+async fn finish_login(
+    user: User,
+    tenant: &Tenant,
+    store: &Store,
+    crypto: &Crypto,
+    config: &Config,
+) -> Result<Login, Error> {
+    if let Some(version) = &tenant.terms(&config.terms).version {
+        if store.users.accepted_terms(user).await?.as_ref() != Some(version) {
+            return Ok(Login::Terms(PendingTerms::new(user)));
+        }
+    }
+    Ok(Login::Session(
+        create_session(user, tenant, store, crypto, config).await?,
+    ))
+}
+
+/// Records the current terms as accepted by the user of the pending login, and lets them in.
+async fn accept_terms(
+    pending: &PendingTerms,
+    accepted: bool,
+    client: &ClientInfo,
+    store: &Store,
+    crypto: &Crypto,
+    config: &Config,
+) -> Result<Session, Error> {
+    let user = pending.user;
+    // The terms may have been taken down during the login, which leaves nothing to accept.
+    if let Some(version) = client.tenant.terms(&config.terms).accept(accepted)? {
+        store.users.accept_terms(user, version).await?;
+        let details = serde_json::json!({ "version": version });
+        let event = AuditEvent::new(audit::TERMS_ACCEPTED, Some(user), client, details);
+        store.audit.insert(&event).await?;
+    }
+    create_session(user, &client.tenant, store, crypto, config).await
+}
+
+/// Checks that the session is of an admin acting as themselves, as impersonating someone doesn't
+/// give their rights to impersonate others in turn.
+fn require_admin(session: &Session, config: &Config) -> Result<User, Error> {
+    let user = *session.user();
+    if session.impersonator().is_some() || !config.admins.contains(&user) {
+        return Err(Error::NotAdmin(Backtrace::capture()));
+    }
+    Ok(user)
+}
+
+/// Ends the session, recording it in the audit logs like [`impersonate`] does when it was an
+/// impersonation.
+async fn log_out(
+    session: &Session,
+    client: &ClientInfo,
+    store: &Store,
+    config: &Config,
+) -> Result<(), Error> {
+    store.sessions.delete(session).await?;
+    let Some(impersonator) = session.impersonator() else {
+        let details = serde_json::json!({ "session": session.id() });
+        let event = AuditEvent::new(audit::LOGGED_OUT, Some(*session.user()), client, details);
+        audit::record(store, &event, config.publish_events).await?;
+        // Clients only hear about users logging out themselves. Impersonations ending leave the
+        // user's own sessions at the clients alone.
+        return match &config.notifications.public_url {
+            Some(issuer) => backchannel::notify(store, *session.user(), issuer).await,
+            None => Ok(()),
+        };
+    };
+    let kind = audit::IMPERSONATION_ENDED;
+    let user = *session.user();
+    let details = serde_json::json!({ "impersonator": impersonator.id, "session": session.id() });
+    store
+        .audit
+        .insert(&AuditEvent::new(kind, Some(user), client, details))
+        .await?;
+    let details = serde_json::json!({ "user": user.id, "session": session.id() });
+    store
+        .audit
+        .insert(&AuditEvent::new(kind, Some(impersonator), client, details))
+        .await
+}
+
+/// Creates the session of a user who completed the login, restricted to changing the password when
+/// it's older than `PASSWORD_MAX_AGE_MS` allows.
+async fn create_session(
+    user: User,
+    tenant: &Tenant,
+    store: &Store,
+    crypto: &Crypto,
+    config: &Config,
+) -> Result<Session, Error> {
+    // Checked again, as the account may have been suspended while the login was underway.
+    check_status(user, store).await?;
+    let profile = store.users.profile(user).await?;
+    // Whatever way of logging in got this far, like an identity linked by an operator, service
+    // accounts only act through their OAuth clients.
+    if profile.service {
+        return Err(Error::ServiceAccountLogin(Backtrace::capture()));
+    }
+    // The signed cookies carrying a login between its steps don't name the tenant, so one taken
+    // from another tenant's pages mustn't get the user in here.
+    if profile.tenant != tenant.id {
+        return Err(Error::UserNotFound(Backtrace::capture()));
+    }
+    let restricted = match config.password_max_age {
+        // Users without a password log in some other way, so there's nothing to change.
+        Some(max_age) => {
+            profile.has_password && profile.password_changed_at + max_age <= SystemTime::now()
+        }
+        None => false,
+    };
+    let claims = claims::collect(user, &tenant.id, store, &config.claims).await?;
+    let session = Session::create(user, &tenant.id, claims, crypto);
+    store.sessions.insert(&session, restricted).await?;
+    for plugin in &config.plugins {
+        plugin.on_login(&session, store).await?;
+    }
+    Ok(session)
+}
+
+/// Replaces the password after checking the current one, which users who have none can skip. The
+/// session is let out of the restriction of an expired password, while sessions elsewhere stay in
+/// it. The user is mailed a security alert about it, in case it wasn't them.
+#[allow(clippy::too_many_arguments)]
+async fn change_password(
+    session: &Session,
+    current_password: &str,
+    password: &str,
+    locale: &str,
+    store: &Store,
+    templates: &Templates,
+    crypto: &Crypto,
+    config: &Config,
+) -> Result<(), Error> {
+    let user = *session.user();
+    let profile = store.users.profile(user).await?;
+    if profile.has_password {
+        let verified = store
+            .users
+            .get_and_verify(&profile.tenant, &profile.username, current_password)
+            .await?;
+        if verified != user {
+            return Err(Error::WrongPassword(Backtrace::capture()));
+        }
+    }
+    if password.is_empty() {
+        return Err(Error::EmptyField("password", Backtrace::capture()));
+    }
+    if password == current_password {
+        return Err(Error::PasswordUnchanged(Backtrace::capture()));
+    }
+    plugins::validate_password(&config.plugins, Some(user), password).await?;
+    store.users.set_password(user, password).await?;
+    store.sessions.unrestrict(session).await?;
+    let category = Category::Security;
+    let context = tera::Context::new();
+    let policy = &config.notifications;
+    notifications::notify(
+        user,
+        category,
+        "password-changed",
+        locale,
+        &context,
+        store,
+        templates,
+        crypto,
+        policy,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Like [`form_error`], for forms that live on other pages than the main one.
+fn flash_error(
+    flash: Flash,
+    location: &str,
+    error: Error,
+    crypto: &Crypto,
+    log: &Logger,
+) -> Result<Response<Body>, Error> {
+    // Trying again won't help until the quota's window is over, which its page explains.
+    if let Error::RegistrationQuotaExceeded(_) = &error {
+        return Err(error);
+    }
+    // Whichever step of the login found out, there's nothing to retry, so it gets its own page.
+    if let Error::AccountSuspended(status, _) = &error {
+        info!(log, "Login of a suspended account rejected"; "status" => status.as_str());
+        let query = serde_urlencoded::to_string([("status", status.as_str())])?;
+        return Ok(Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION, format!("/auth/suspended?{}", query))
+            .header(SET_COOKIE, Challenge::cookie_clear().to_string())
+            .header(SET_COOKIE, PendingTerms::cookie_clear().to_string())
+            .body(Body::empty())
+            .unwrap());
+    }
+    if !matches!(
+        error.kind(),
+        ErrorKind::Unauthorized
+            | ErrorKind::Conflict
+            | ErrorKind::Unprocessable
+            | ErrorKind::TooManyRequests
+    ) {
+        return Err(error);
+    }
+    info!(log, "Form rejected"; "form" => flash.form.as_deref(), error.log_message());
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, location)
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Login page that returns to the given page once logged in.
+fn login_location(next: &str) -> Result<String, Error> {
+    Ok(format!(
+        "/?{}",
+        serde_urlencoded::to_string([("next", next)])?
+    ))
+}
+
+/// Where to go after logging in, falling back to the main page for anything that would leave the
+/// site, so that the parameter can't be used for open redirects.
+fn next_location(next: Option<&str>) -> &str {
+    next.filter(|next| is_local_path(next)).unwrap_or("/")
+}
@@ -0,0 +1,615 @@
+//! Pages for admins, and the endpoints for checking on the service.
+
+use crate::audit::{self, AuditEvent};
+use crate::claims;
+use crate::client::ClientInfo;
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::flash::Flash;
+use crate::handlers::{flash_error, log_out, login_location, require_admin, Page};
+use crate::i18n;
+use crate::jobs;
+use crate::mail;
+use crate::routes;
+use crate::session::Session;
+use crate::store::Store;
+use crate::templates::Templates;
+use crate::user::{self, AccountStatus, User};
+use crate::{create_service_account, get_cookies, see_other, Config};
+use hyper::header::{CACHE_CONTROL, LOCATION, SET_COOKIE};
+use hyper::{Body, Request, Response, StatusCode};
+use prometheus::Encoder;
+use serde::{Deserialize, Serialize};
+use slog::info;
+use std::backtrace::Backtrace;
+
+#[derive(Debug, Deserialize)]
+struct ImpersonateRequest {
+    username: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountStatusRequest {
+    username: String,
+    status: String,
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReviewRequest {
+    user: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServiceAccountRequest {
+    username: String,
+    /// Space-separated, like OAuth scopes on the wire.
+    scopes: String,
+}
+
+#[derive(Serialize)]
+struct CtxRegistration {
+    id: i32,
+    username: String,
+    email: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CtxServiceAccount {
+    id: i32,
+    username: String,
+    status: &'static str,
+    clients: Vec<CtxServiceClient>,
+}
+
+#[derive(Serialize)]
+struct CtxServiceClient {
+    id: String,
+    scopes: String,
+}
+
+pub async fn impersonate_page(
+    _req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        had_flash,
+        templates,
+        config,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/admin/impersonate")?));
+    };
+    require_admin(session, config)?;
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("impersonate.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn impersonate_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let admin = require_admin(session, config)?;
+    let body: ImpersonateRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let impersonation =
+        match impersonate(admin, &body.username, client, store, crypto, config).await {
+            Ok(impersonation) => impersonation,
+            Err(e) => {
+                let flash = Flash::error("impersonate", e.localized_message(locale));
+                return flash_error(flash, "/admin/impersonate", e, crypto, log);
+            }
+        };
+    info!(log, "Impersonation started"; impersonation.user(), &impersonation);
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/")
+        .header(
+            SET_COOKIE,
+            impersonation.cookie_login(&config.cookies).to_string(),
+        )
+        .header(
+            SET_COOKIE,
+            session.cookie_impersonator(&config.cookies).to_string(),
+        )
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn status_page(_req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        had_flash,
+        templates,
+        config,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/admin/status")?));
+    };
+    require_admin(session, config)?;
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("status.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn status_form(mut req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let admin = require_admin(session, config)?;
+    let body: AccountStatusRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let Some(status) = AccountStatus::parse(&body.status) else {
+        return Err(Error::UnknownAccountStatus(
+            body.status,
+            Backtrace::capture(),
+        ));
+    };
+    let (username, reason) = (&body.username, &body.reason);
+    let user =
+        match set_account_status(admin, username, status, reason, client, store, config).await {
+            Ok(user) => user,
+            Err(e) => {
+                let flash = Flash::error("status", e.localized_message(locale));
+                return flash_error(flash, "/admin/status", e, crypto, log);
+            }
+        };
+    info!(log, "Account status changed"; user, "admin" => admin.id, "status" => status.as_str());
+    let message = i18n::translate(
+        locale,
+        &format!("notice-account-{}", status.as_str()),
+        &[("username", &user::normalize_username(body.username.trim()))],
+    );
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/admin/status")
+        .header(
+            SET_COOKIE,
+            Flash::notice(&message).cookie(crypto)?.to_string(),
+        )
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn registrations_page(
+    _req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        context,
+        had_flash,
+        store,
+        templates,
+        config,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/admin/registrations")?));
+    };
+    require_admin(session, config)?;
+    let mut registrations = Vec::new();
+    for (user, username) in store
+        .users
+        .with_status(&client.tenant.id, AccountStatus::Pending)
+        .await?
+    {
+        let email = store.users.profile(user).await?.email;
+        registrations.push(CtxRegistration {
+            id: user.id,
+            username,
+            email,
+        });
+    }
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    let mut context = context;
+    context.insert("registrations", &registrations);
+    Ok(response
+        .body(templates.render("registrations.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn review_form(mut req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        locale,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let admin = require_admin(session, config)?;
+    let approved = req.uri().path() == "/admin/registrations/approve";
+    let body: ReviewRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let user = User { id: body.user };
+    if let Err(e) = review_registration(admin, user, approved, client, store, templates).await {
+        let flash = Flash::error("registrations", e.localized_message(locale));
+        return flash_error(flash, "/admin/registrations", e, crypto, log);
+    }
+    info!(log, "Registration reviewed"; user, "admin" => admin.id, "approved" => approved);
+    let message = if approved {
+        "notice-registration-approved"
+    } else {
+        "notice-registration-rejected"
+    };
+    let message = i18n::translate(locale, message, &[]);
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/admin/registrations")
+        .header(
+            SET_COOKIE,
+            Flash::notice(&message).cookie(crypto)?.to_string(),
+        )
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn service_accounts_page(
+    _req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        context,
+        had_flash,
+        store,
+        templates,
+        config,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/admin/service-accounts")?));
+    };
+    require_admin(session, config)?;
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    let mut context = context;
+    let accounts = list_service_accounts(&client.tenant.id, store).await?;
+    context.insert("service_accounts", &accounts);
+    Ok(response
+        .body(templates.render("service-accounts.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn service_account_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        locale,
+        context,
+        had_flash,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let admin = require_admin(session, config)?;
+    let body: ServiceAccountRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let scopes: Vec<String> = body.scopes.split_whitespace().map(str::to_owned).collect();
+    let tenant = &client.tenant.id;
+    let (user, client_id, secret) =
+        match create_service_account(tenant, &body.username, &scopes, store, log).await {
+            Ok(created) => created,
+            Err(e) => {
+                let flash = Flash::error("service-account", e.localized_message(locale));
+                return flash_error(flash, "/admin/service-accounts", e, crypto, log);
+            }
+        };
+    let kind = audit::SERVICE_ACCOUNT_CREATED;
+    let details = serde_json::json!({ "admin": admin.id, "client_id": &client_id });
+    let event = AuditEvent::new(kind, Some(user), client, details);
+    store.audit.insert(&event).await?;
+    let details = serde_json::json!({ "user": user.id, "client_id": &client_id });
+    let event = AuditEvent::new(kind, Some(admin), client, details);
+    store.audit.insert(&event).await?;
+    info!(log, "Service account created"; user, "admin" => admin.id, "client_id" => &client_id, "scopes" => scopes.join(" "));
+    // The secret is only ever shown here, so the page is rendered right away rather than
+    // carrying it over a redirect in a cookie.
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CACHE_CONTROL, "no-store");
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    let mut context = context;
+    let accounts = list_service_accounts(tenant, store).await?;
+    context.insert("service_accounts", &accounts);
+    context.insert("client_id", &client_id);
+    context.insert("client_secret", &secret);
+    Ok(response
+        .body(templates.render("service-accounts.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn stop_impersonating_form(
+    req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let cookies = get_cookies(&req)?;
+    let Some(session) = session.filter(|session| session.impersonator().is_some()) else {
+        return Ok(see_other("/"));
+    };
+    log_out(&session, client, store, config).await?;
+    info!(log, "Impersonation stopped"; session.user(), &session);
+    // The admin's own session was kept aside, and is only given back if it's still theirs
+    // to use.
+    let admin_session = match Session::from_impersonator_cookies(
+        &cookies,
+        crypto,
+        &client.tenant.id,
+        &config.cookies,
+    ) {
+        Ok(Some(admin_session))
+            if Some(*admin_session.user()) == session.impersonator()
+                && store.sessions.is_active(&admin_session).await? =>
+        {
+            Some(admin_session)
+        }
+        _ => None,
+    };
+    let session_cookie = match &admin_session {
+        Some(admin_session) => admin_session.cookie_login(&config.cookies),
+        None => Session::cookie_logout(&config.cookies),
+    };
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/admin/impersonate")
+        .header(SET_COOKIE, session_cookie.to_string())
+        .header(
+            SET_COOKIE,
+            Session::cookie_impersonator_clear(&config.cookies).to_string(),
+        )
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn readyz(_req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page { store, .. } = page;
+    let status = if store.is_healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn metrics(_req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page { log, .. } = page;
+    info!(log, "Scrapping metrics");
+    let encoder = prometheus::TextEncoder::new();
+    let families = prometheus::gather();
+    let mut buffer = Vec::new();
+    encoder.encode(&families, &mut buffer).unwrap();
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(buffer.into())
+        .unwrap())
+}
+
+/// Changes whether the user may log in, recording the reason in the audit logs of both the user
+/// and the admin. Suspending or banning logs the user out everywhere and revokes the tokens of
+/// their applications right away, rather than waiting for them to expire.
+async fn set_account_status(
+    admin: User,
+    username: &str,
+    status: AccountStatus,
+    reason: &str,
+    client: &ClientInfo,
+    store: &Store,
+    config: &Config,
+) -> Result<User, Error> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(Error::EmptyField("reason", Backtrace::capture()));
+    }
+    let username = user::normalize_username(username.trim());
+    let Some(user) = store
+        .users
+        .find_by_username(&client.tenant.id, &username)
+        .await?
+    else {
+        return Err(Error::UnknownUser(username, Backtrace::capture()));
+    };
+    store.users.set_status(user, status).await?;
+    let mut sessions = 0;
+    if status != AccountStatus::Active {
+        sessions = store.sessions.revoke_all(user).await?;
+        for consent in store.consents.list(user).await? {
+            store.tokens.revoke_all(&consent.client_id, user).await?;
+        }
+        // Service accounts have no consents, only clients of their own.
+        for owned in store.clients.of_user(user).await? {
+            store.tokens.revoke_all(&owned.id, user).await?;
+        }
+    }
+    let kind = audit::ACCOUNT_STATUS_CHANGED;
+    let details = serde_json::json!({
+        "admin": admin.id,
+        "status": status.as_str(),
+        "reason": reason,
+        "sessions_revoked": sessions,
+    });
+    let event = AuditEvent::new(kind, Some(user), client, details);
+    audit::record(store, &event, config.publish_events).await?;
+    let details =
+        serde_json::json!({ "user": user.id, "status": status.as_str(), "reason": reason });
+    store
+        .audit
+        .insert(&AuditEvent::new(kind, Some(admin), client, details))
+        .await?;
+    Ok(user)
+}
+
+/// Service accounts of the tenant with their clients, for the admin dashboard.
+async fn list_service_accounts(
+    tenant: &str,
+    store: &Store,
+) -> Result<Vec<CtxServiceAccount>, Error> {
+    let mut accounts = Vec::new();
+    for (user, username) in store.users.service_accounts(tenant).await? {
+        let clients = store.clients.of_user(user).await?;
+        accounts.push(CtxServiceAccount {
+            id: user.id,
+            username,
+            status: store.users.status(user).await?.as_str(),
+            clients: clients
+                .into_iter()
+                .map(|client| CtxServiceClient {
+                    id: client.id,
+                    scopes: client.scopes.join(" "),
+                })
+                .collect(),
+        });
+    }
+    Ok(accounts)
+}
+
+/// Approves or rejects a pending registration, recording it in the audit logs of both the user and
+/// the admin, and mailing the user about it if they gave an address. Registrations that were
+/// already decided on are left alone, so that two admins going through the queue at once don't
+/// mail anyone twice.
+async fn review_registration(
+    admin: User,
+    user: User,
+    approved: bool,
+    client: &ClientInfo,
+    store: &Store,
+    templates: &Templates,
+) -> Result<(), Error> {
+    // Admins only go through the queue of their own tenant, so users of others count as not
+    // waiting.
+    let profile = store.users.profile(user).await?;
+    if profile.tenant != client.tenant.id
+        || store.users.status(user).await? != AccountStatus::Pending
+    {
+        return Err(Error::RegistrationNotPending(Backtrace::capture()));
+    }
+    let status = if approved {
+        AccountStatus::Active
+    } else {
+        AccountStatus::Rejected
+    };
+    store.users.set_status(user, status).await?;
+    let kind = audit::REGISTRATION_REVIEWED;
+    let details = serde_json::json!({ "admin": admin.id, "approved": approved });
+    store
+        .audit
+        .insert(&AuditEvent::new(kind, Some(user), client, details))
+        .await?;
+    let details = serde_json::json!({ "user": user.id, "approved": approved });
+    store
+        .audit
+        .insert(&AuditEvent::new(kind, Some(admin), client, details))
+        .await?;
+    if let Some(email) = profile.email {
+        let name = if approved {
+            "registration-approved"
+        } else {
+            "registration-rejected"
+        };
+        // The language the user registered in isn't kept, so the mail is in the default one.
+        let context = tera::Context::new();
+        let mail = mail::render(templates, name, &email, i18n::DEFAULT_LOCALE, &context)?;
+        jobs::enqueue(&*store.jobs, &jobs::Task::SendMail(mail)).await?;
+    }
+    Ok(())
+}
+
+/// Starts a session of the user with the username for the admin, recording it in the audit logs
+/// of both.
+async fn impersonate(
+    admin: User,
+    username: &str,
+    client: &ClientInfo,
+    store: &Store,
+    crypto: &Crypto,
+    config: &Config,
+) -> Result<Session, Error> {
+    let username = user::normalize_username(username.trim());
+    let Some(user) = store
+        .users
+        .find_by_username(&client.tenant.id, &username)
+        .await?
+    else {
+        return Err(Error::UnknownUser(username, Backtrace::capture()));
+    };
+    if store.users.profile(user).await?.service {
+        return Err(Error::ServiceAccountLogin(Backtrace::capture()));
+    }
+    // The admin sees what the user would, claims included.
+    let claims = claims::collect(user, &client.tenant.id, store, &config.claims).await?;
+    let session = Session::impersonate(user, admin, &client.tenant.id, claims, crypto);
+    store.sessions.insert(&session, false).await?;
+    let kind = audit::IMPERSONATION_STARTED;
+    let details = serde_json::json!({ "impersonator": admin.id, "session": session.id() });
+    store
+        .audit
+        .insert(&AuditEvent::new(kind, Some(user), client, details))
+        .await?;
+    let details = serde_json::json!({ "user": user.id, "session": session.id() });
+    store
+        .audit
+        .insert(&AuditEvent::new(kind, Some(admin), client, details))
+        .await?;
+    Ok(session)
+}
@@ -0,0 +1,694 @@
+//! The JSON API, both the one answering the same requests as the pages and the versioned one.
+
+use crate::api;
+use crate::audit::{self, AuditEvent};
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::features::{self, Feature};
+use crate::get_cookies;
+use crate::graphql;
+use crate::handlers::{
+    accept_terms, change_password, complete_challenge, log_in, log_in_with_code, log_out,
+    login_activity, register, require_admin, send_login_code, ActivityQuery, Api, AuthLoginRequest,
+    AuthRegisterRequest, ChangePasswordRequest, CodeRequest, EmailCodeRequest, EmailRequest, Login,
+    Registration, TermsRequest,
+};
+use crate::mode::Mode;
+use crate::otp::Challenge;
+use crate::routes;
+use crate::session::{CookiePolicy, Session};
+use crate::terms::PendingTerms;
+use crate::user::AccountStatus;
+use hyper::header::{HeaderMap, SET_COOKIE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use slog::{info, Logger};
+use std::backtrace::Backtrace;
+
+#[derive(Debug, Deserialize)]
+struct FeatureRequest {
+    name: String,
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModeRequest {
+    mode: String,
+}
+
+pub async fn api_register(mut req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        client,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = api;
+    let body: AuthRegisterRequest = routes::body(&mut req, config.timeouts.body).await?;
+    info!(log, "Registering a new account"; "username" => &body.username);
+    let email = body.email.as_deref();
+    let registration = register(
+        &body.username,
+        &body.password,
+        email,
+        body.accept_terms,
+        client,
+        store,
+        crypto,
+        config,
+        log,
+    )
+    .await?;
+    let session = match registration {
+        Registration::Session(session) => session,
+        Registration::Pending(user) => {
+            info!(log, "Registration awaits approval"; user);
+            let pending_response = api::PendingResponse {
+                user: api::UserResponse { id: user.id },
+                status: AccountStatus::Pending.as_str(),
+            };
+            return Ok(api::response(StatusCode::ACCEPTED, &pending_response));
+        }
+    };
+    info!(log, "Logged in after registration"; &session);
+    let mut response = api::response(StatusCode::CREATED, &session_response(&session));
+    response.headers_mut().insert(
+        SET_COOKIE,
+        session
+            .cookie_login(&config.cookies)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    Ok(response)
+}
+
+pub async fn api_log_in(mut req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        client,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = api;
+    let body: AuthLoginRequest = routes::body(&mut req, config.timeouts.body).await?;
+    info!(log, "Logging in"; "username" => &body.username);
+    let login = log_in(
+        &body.username,
+        &body.password,
+        client,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+    )
+    .await?;
+    Ok(api_login_response(login, crypto, &config.cookies, log))
+}
+
+pub async fn api_send_login_code(
+    mut req: Request<Body>,
+    api: Api<'_>,
+) -> Result<Response<Body>, Error> {
+    let Api {
+        client,
+        store,
+        templates,
+        config,
+        log,
+        ..
+    } = api;
+    let body: EmailRequest = routes::body(&mut req, config.timeouts.body).await?;
+    send_login_code(&body.email, client, store, templates, config, log).await?;
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn api_log_in_with_code(
+    mut req: Request<Body>,
+    api: Api<'_>,
+) -> Result<Response<Body>, Error> {
+    let Api {
+        client,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = api;
+    let body: EmailCodeRequest = routes::body(&mut req, config.timeouts.body).await?;
+    let login = log_in_with_code(
+        &body.email,
+        &body.code,
+        client,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+    )
+    .await?;
+    Ok(api_login_response(login, crypto, &config.cookies, log))
+}
+
+pub async fn api_complete_challenge(
+    mut req: Request<Body>,
+    api: Api<'_>,
+) -> Result<Response<Body>, Error> {
+    let Api {
+        client,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = api;
+    let challenge = Challenge::from_cookies(&get_cookies(&req)?, crypto)?;
+    let body: CodeRequest = routes::body(&mut req, config.timeouts.body).await?;
+    let session =
+        match complete_challenge(&challenge, &body.code, client, store, crypto, config).await? {
+            Login::Session(session) => session,
+            login => return Ok(api_login_response(login, crypto, &config.cookies, log)),
+        };
+    info!(log, "Logged in with a text message code"; session.user(), &session);
+    let mut response = api::response(StatusCode::OK, &session_response(&session));
+    let headers = response.headers_mut();
+    headers.append(
+        SET_COOKIE,
+        session
+            .cookie_login(&config.cookies)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    headers.append(
+        SET_COOKIE,
+        Challenge::cookie_clear().to_string().parse().unwrap(),
+    );
+    Ok(response)
+}
+
+pub async fn api_accept_terms(
+    mut req: Request<Body>,
+    api: Api<'_>,
+) -> Result<Response<Body>, Error> {
+    let Api {
+        client,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = api;
+    let pending = PendingTerms::from_cookies(&get_cookies(&req)?, crypto)?;
+    let body: TermsRequest = routes::body(&mut req, config.timeouts.body).await?;
+    let session = accept_terms(&pending, body.accept, client, store, crypto, config).await?;
+    info!(log, "Logged in after accepting the terms"; session.user(), &session);
+    let mut response = api::response(StatusCode::OK, &session_response(&session));
+    let headers = response.headers_mut();
+    headers.append(
+        SET_COOKIE,
+        session
+            .cookie_login(&config.cookies)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    headers.append(
+        SET_COOKIE,
+        PendingTerms::cookie_clear().to_string().parse().unwrap(),
+    );
+    Ok(response)
+}
+
+pub async fn api_change_password(
+    mut req: Request<Body>,
+    api: Api<'_>,
+) -> Result<Response<Body>, Error> {
+    let Api {
+        session,
+        locale,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = api;
+    let session = routes::session(&session)?;
+    let body: ChangePasswordRequest = routes::body(&mut req, config.timeouts.body).await?;
+    change_password(
+        session,
+        &body.current_password,
+        &body.password,
+        locale,
+        store,
+        templates,
+        crypto,
+        config,
+    )
+    .await?;
+    info!(log, "Password changed"; session.user());
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn api_log_out(_req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        session,
+        client,
+        store,
+        config,
+        log,
+        ..
+    } = api;
+    info!(log, "Logging out");
+    if let Some(session) = &session {
+        log_out(session, client, store, config).await?;
+    }
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(
+            SET_COOKIE,
+            Session::cookie_logout(&config.cookies).to_string(),
+        )
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn api_graphql(mut req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        session,
+        store,
+        config,
+        log,
+        ..
+    } = api;
+    let request: async_graphql::Request = routes::json(&mut req, config.timeouts.body).await?;
+    // Queries and mutations share the route, so the table can't tell which ones make changes.
+    if config.mode.get() == Mode::ReadOnly && graphql::is_mutation(&request) {
+        return Err(Error::ReadOnly(Backtrace::capture()));
+    }
+    let mut request = request.data(store.clone()).data(log.clone());
+    if let Some(session) = &session {
+        request = request.data(graphql::Auth {
+            user: *session.user(),
+            session: session.id(),
+        });
+    }
+    let response = graphql::SCHEMA.execute(request).await;
+    Ok(api::response(StatusCode::OK, &response))
+}
+
+pub async fn api_session(_req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api { session, .. } = api;
+    match &session {
+        Some(session) => Ok(api::response(StatusCode::OK, &session_response(session))),
+        None => Err(Error::NotLoggedIn(Backtrace::capture())),
+    }
+}
+
+pub async fn api_activity(req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        session,
+        client,
+        store,
+        ..
+    } = api;
+    let session = routes::session(&session)?;
+    let query: ActivityQuery = serde_urlencoded::from_str(req.uri().query().unwrap_or_default())?;
+    let activity = login_activity(*session.user(), query.page, client, store).await?;
+    Ok(api::response(StatusCode::OK, &activity))
+}
+
+pub async fn api_features(_req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        session,
+        store,
+        config,
+        ..
+    } = api;
+    let session = routes::session(&session)?;
+    require_admin(session, config)?;
+    let features = features::load(&*store.features, &config.features).await?;
+    let features_response = api::FeaturesResponse {
+        features: &features,
+    };
+    Ok(api::response(StatusCode::OK, &features_response))
+}
+
+pub async fn api_set_feature(
+    mut req: Request<Body>,
+    api: Api<'_>,
+) -> Result<Response<Body>, Error> {
+    let Api {
+        session,
+        client,
+        store,
+        config,
+        log,
+        ..
+    } = api;
+    let session = routes::session(&session)?;
+    let admin = require_admin(session, config)?;
+    let body: FeatureRequest = routes::body(&mut req, config.timeouts.body).await?;
+    let Some(feature) = Feature::parse(&body.name) else {
+        return Err(Error::UnknownFeature(body.name, Backtrace::capture()));
+    };
+    store.features.set(feature.as_str(), body.enabled).await?;
+    let details = serde_json::json!({ "feature": feature.as_str(), "enabled": body.enabled });
+    let event = AuditEvent::new(audit::FEATURE_TOGGLED, Some(admin), client, details);
+    store.audit.insert(&event).await?;
+    info!(log, "Feature toggled"; admin, "feature" => feature.as_str(), "enabled" => body.enabled);
+    let features = features::load(&*store.features, &config.features).await?;
+    let features_response = api::FeaturesResponse {
+        features: &features,
+    };
+    Ok(api::response(StatusCode::OK, &features_response))
+}
+
+pub async fn api_mode(_req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        session, config, ..
+    } = api;
+    let session = routes::session(&session)?;
+    require_admin(session, config)?;
+    let mode = config.mode.get().as_str();
+    Ok(api::response(StatusCode::OK, &api::ModeResponse { mode }))
+}
+
+pub async fn api_set_mode(mut req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        session,
+        client,
+        store,
+        config,
+        log,
+        ..
+    } = api;
+    let session = routes::session(&session)?;
+    let admin = require_admin(session, config)?;
+    let body: ModeRequest = routes::body(&mut req, config.timeouts.body).await?;
+    let Some(mode) = Mode::parse(&body.mode) else {
+        return Err(Error::UnknownMode(body.mode, Backtrace::capture()));
+    };
+    config.mode.set(mode);
+    let details = serde_json::json!({ "mode": mode.as_str() });
+    let event = AuditEvent::new(audit::MODE_SWITCHED, Some(admin), client, details);
+    store.audit.insert(&event).await?;
+    info!(log, "Mode switched"; admin, "mode" => mode.as_str());
+    let mode = mode.as_str();
+    Ok(api::response(StatusCode::OK, &api::ModeResponse { mode }))
+}
+
+pub async fn v1_register(mut req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        client,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = api;
+    let body: api::v1::CredentialsRequest = routes::json(&mut req, config.timeouts.body).await?;
+    info!(log, "Registering a new account"; "username" => &body.username);
+    let registration = register(
+        &body.username,
+        &body.password,
+        None,
+        body.accept_terms,
+        client,
+        store,
+        crypto,
+        config,
+        log,
+    )
+    .await?;
+    let session = match registration {
+        Registration::Session(session) => session,
+        Registration::Pending(user) => {
+            info!(log, "Registration awaits approval"; user);
+            let pending_response = api::v1::PendingResponse {
+                user: api::v1::UserObject { id: user.id },
+                status: AccountStatus::Pending.as_str(),
+            };
+            return Ok(api::response(StatusCode::ACCEPTED, &pending_response));
+        }
+    };
+    info!(log, "Logged in after registration"; &session);
+    let mut response = api::response(
+        StatusCode::CREATED,
+        &api::v1::SessionResponse::from(&session),
+    );
+    response.headers_mut().insert(
+        SET_COOKIE,
+        session
+            .cookie_login(&config.cookies)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    Ok(response)
+}
+
+pub async fn v1_log_in(mut req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        client,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = api;
+    let body: api::v1::CredentialsRequest = routes::json(&mut req, config.timeouts.body).await?;
+    info!(log, "Logging in"; "username" => &body.username);
+    let login = log_in(
+        &body.username,
+        &body.password,
+        client,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+    )
+    .await?;
+    Ok(v1_login_response(login, crypto, &config.cookies, log))
+}
+
+pub async fn v1_complete_challenge(
+    mut req: Request<Body>,
+    api: Api<'_>,
+) -> Result<Response<Body>, Error> {
+    let Api {
+        client,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = api;
+    let challenge = Challenge::from_cookies(&get_cookies(&req)?, crypto)?;
+    let body: api::v1::CodeRequest = routes::json(&mut req, config.timeouts.body).await?;
+    let session =
+        match complete_challenge(&challenge, &body.code, client, store, crypto, config).await? {
+            Login::Session(session) => session,
+            login => return Ok(v1_login_response(login, crypto, &config.cookies, log)),
+        };
+    info!(log, "Logged in with a text message code"; session.user(), &session);
+    let mut response = api::response(StatusCode::OK, &api::v1::SessionResponse::from(&session));
+    let headers = response.headers_mut();
+    headers.append(
+        SET_COOKIE,
+        session
+            .cookie_login(&config.cookies)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    headers.append(
+        SET_COOKIE,
+        Challenge::cookie_clear().to_string().parse().unwrap(),
+    );
+    Ok(response)
+}
+
+pub async fn v1_accept_terms(
+    mut req: Request<Body>,
+    api: Api<'_>,
+) -> Result<Response<Body>, Error> {
+    let Api {
+        client,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = api;
+    let pending = PendingTerms::from_cookies(&get_cookies(&req)?, crypto)?;
+    let body: api::v1::TermsRequest = routes::json(&mut req, config.timeouts.body).await?;
+    let session = accept_terms(&pending, body.accept, client, store, crypto, config).await?;
+    info!(log, "Logged in after accepting the terms"; session.user(), &session);
+    let mut response = api::response(StatusCode::OK, &api::v1::SessionResponse::from(&session));
+    let headers = response.headers_mut();
+    headers.append(
+        SET_COOKIE,
+        session
+            .cookie_login(&config.cookies)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    headers.append(
+        SET_COOKIE,
+        PendingTerms::cookie_clear().to_string().parse().unwrap(),
+    );
+    Ok(response)
+}
+
+pub async fn v1_session(_req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api { session, .. } = api;
+    let session = routes::session(&session)?;
+    Ok(api::response(
+        StatusCode::OK,
+        &api::v1::SessionResponse::from(session),
+    ))
+}
+
+pub async fn v1_log_out(_req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api {
+        session,
+        client,
+        store,
+        config,
+        log,
+        ..
+    } = api;
+    info!(log, "Logging out");
+    let session = routes::session(&session)?;
+    log_out(session, client, store, config).await?;
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(
+            SET_COOKIE,
+            Session::cookie_logout(&config.cookies).to_string(),
+        )
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn v1_profile(_req: Request<Body>, api: Api<'_>) -> Result<Response<Body>, Error> {
+    let Api { session, store, .. } = api;
+    let session = routes::session(&session)?;
+    let profile = store.users.profile(*session.user()).await?;
+    Ok(api::response(
+        StatusCode::OK,
+        &api::v1::ProfileResponse::from(profile),
+    ))
+}
+
+/// Counterpart of [`login_redirect`] for the JSON API, which answers with the session or with the
+/// kind of step still needed, being the second factor or `terms`.
+fn api_login_response(
+    login: Login,
+    crypto: &Crypto,
+    cookies: &CookiePolicy,
+    log: &Logger,
+) -> Response<Body> {
+    match login {
+        Login::Session(session) => {
+            info!(log, "Logged in"; session.user(), &session);
+            let mut response = api::response(StatusCode::OK, &session_response(&session));
+            response.headers_mut().insert(
+                SET_COOKIE,
+                session.cookie_login(cookies).to_string().parse().unwrap(),
+            );
+            response
+        }
+        Login::Challenge(challenge) => {
+            let challenge_response = api::ChallengeResponse {
+                challenge: challenge.channel.as_str(),
+            };
+            let mut response = api::response(StatusCode::ACCEPTED, &challenge_response);
+            response.headers_mut().insert(
+                SET_COOKIE,
+                challenge.cookie(crypto).to_string().parse().unwrap(),
+            );
+            response
+        }
+        Login::Terms(pending) => {
+            let challenge_response = api::ChallengeResponse { challenge: "terms" };
+            let mut response = api::response(StatusCode::ACCEPTED, &challenge_response);
+            terms_cookies(response.headers_mut(), &pending, crypto);
+            response
+        }
+    }
+}
+
+/// Cookies carrying a login on to the terms, in place of the challenge it may have come from.
+fn terms_cookies(headers: &mut HeaderMap, pending: &PendingTerms, crypto: &Crypto) {
+    headers.append(
+        SET_COOKIE,
+        pending.cookie(crypto).to_string().parse().unwrap(),
+    );
+    headers.append(
+        SET_COOKIE,
+        Challenge::cookie_clear().to_string().parse().unwrap(),
+    );
+}
+
+/// Counterpart of [`api_login_response`] for the versioned API.
+fn v1_login_response(
+    login: Login,
+    crypto: &Crypto,
+    cookies: &CookiePolicy,
+    log: &Logger,
+) -> Response<Body> {
+    match login {
+        Login::Session(session) => {
+            info!(log, "Logged in"; session.user(), &session);
+            let mut response =
+                api::response(StatusCode::OK, &api::v1::SessionResponse::from(&session));
+            response.headers_mut().insert(
+                SET_COOKIE,
+                session.cookie_login(cookies).to_string().parse().unwrap(),
+            );
+            response
+        }
+        Login::Challenge(challenge) => {
+            let challenge_response = api::v1::ChallengeResponse {
+                challenge: challenge.channel.as_str(),
+            };
+            let mut response = api::response(StatusCode::ACCEPTED, &challenge_response);
+            response.headers_mut().insert(
+                SET_COOKIE,
+                challenge.cookie(crypto).to_string().parse().unwrap(),
+            );
+            response
+        }
+        Login::Terms(pending) => {
+            let challenge_response = api::v1::ChallengeResponse { challenge: "terms" };
+            let mut response = api::response(StatusCode::ACCEPTED, &challenge_response);
+            terms_cookies(response.headers_mut(), &pending, crypto);
+            response
+        }
+    }
+}
+
+fn session_response(session: &Session) -> api::SessionResponse {
+    api::SessionResponse {
+        user: api::UserResponse {
+            id: session.user().id,
+        },
+    }
+}
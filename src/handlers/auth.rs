@@ -0,0 +1,876 @@
+//! Pages for registering, logging in and out, and the endpoints telling other services who's
+//! logged in.
+
+use crate::bot;
+use crate::client::ClientInfo;
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::flash::Flash;
+use crate::handlers::{
+    accept_terms, complete_challenge, flash_error, log_in, log_in_with_code, log_out,
+    next_location, register, send_login_code, start_login, AuthLoginRequest, AuthRegisterRequest,
+    CodeRequest, EmailCodeRequest, EmailRequest, Endpoint, FirstFactor, Login, Page, Registration,
+    TermsRequest,
+};
+use crate::i18n;
+use crate::kerberos;
+use crate::otp::{self, Challenge, Channel, Purpose};
+use crate::routes;
+use crate::session::{CookiePolicy, Session};
+use crate::sso;
+use crate::store::Store;
+use crate::templates::Templates;
+use crate::terms::PendingTerms;
+use crate::user::{self, AccountStatus};
+use crate::util::is_local_path;
+use crate::{get_cookies, see_other, Config, PageQuery};
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION,
+    CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION, ORIGIN, SET_COOKIE, VARY,
+    WWW_AUTHENTICATE,
+};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use slog::{info, Logger};
+use std::backtrace::Backtrace;
+use std::time::SystemTime;
+
+#[derive(Debug, Deserialize)]
+struct EmailQuery {
+    email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NextRequest {
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SuspendedQuery {
+    status: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LogoutRequest {
+    /// Needed when logging out from an app at another origin, see [`sso::logout_token`].
+    logout_token: Option<String>,
+    /// Where to go afterwards, which besides a local path can be a page of an app at `SSO_DOMAIN`.
+    next: Option<String>,
+}
+
+/// Stands for the form token in cached renders of the index.
+const FORM_TOKEN_PLACEHOLDER: &str = "form-token-placeholder";
+
+pub async fn index(req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        context,
+        had_flash,
+        templates,
+        crypto,
+        config,
+        ..
+    } = page;
+    let mut response = Response::builder().status(StatusCode::OK);
+    // The flash has been shown now, so it shouldn't appear again after a refresh.
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    let mut context = context;
+    let terms = client.tenant.terms(&config.terms);
+    context.insert("terms_version", &terms.version);
+    context.insert("terms_url", &terms.url);
+    context.insert("negotiate", &config.kerberos.is_some());
+    if session.is_some() || had_flash {
+        context.insert("form_token", &bot::form_token(crypto));
+        return Ok(response
+            .body(templates.render("index.html", &context)?.into())
+            .unwrap());
+    }
+    // Anonymous visitors all see the same page, save for the form token, which is put in
+    // after it comes out of the cache so that it still tells when the page was loaded.
+    context.insert("form_token", FORM_TOKEN_PLACEHOLDER);
+    let rendered = templates.render_cached("index.html", &context)?;
+    // Logging in or getting a flash changes the page without changing the URL, so browsers
+    // have to check whether it's still the same every time.
+    let response = response
+        .header(CACHE_CONTROL, "private, no-cache")
+        .header(VARY, "Cookie, Accept-Language")
+        .header(ETAG, &rendered.etag);
+    if if_none_match(&req, &rendered.etag) {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap());
+    }
+    let html = rendered
+        .html
+        .replace(FORM_TOKEN_PLACEHOLDER, &bot::form_token(crypto));
+    Ok(response.body(html.into()).unwrap())
+}
+
+pub async fn register_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        client,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let body: AuthRegisterRequest = routes::form(&mut req, config.timeouts.body).await?;
+    info!(log, "Registering a new account"; "username" => &body.username);
+    let signals = bot::Signals {
+        honeypot_filled: !body.website.is_empty(),
+        time_to_submit: bot::time_to_submit(&body.form_token, crypto),
+    };
+    let score = config.bot.scorer.score(&signals);
+    if score >= config.bot.reject_score {
+        // Going on as if it worked doesn't tell the script what to change to get through.
+        info!(log, "Registration rejected as automated"; "score" => score);
+        return Ok(see_other(next_location(body.next.as_deref())));
+    }
+    let (username, password) = (&body.username, &body.password);
+    let (email, accept_terms) = (body.email.as_deref(), body.accept_terms);
+    let registration = register(
+        username,
+        password,
+        email,
+        accept_terms,
+        client,
+        store,
+        crypto,
+        config,
+        log,
+    )
+    .await;
+    match registration {
+        Ok(Registration::Session(session)) => {
+            info!(log, "Logged in after registration"; &session);
+            Ok(Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, next_location(body.next.as_deref()))
+                .header(
+                    SET_COOKIE,
+                    session.cookie_login(&config.cookies).to_string(),
+                )
+                .body(Body::empty())
+                .unwrap())
+        }
+        Ok(Registration::Pending(user)) => {
+            info!(log, "Registration awaits approval"; user);
+            let query = serde_urlencoded::to_string([("status", "pending")])?;
+            Ok(see_other(&format!("/auth/suspended?{}", query)))
+        }
+        Err(e) => form_error(
+            "register",
+            &body.username,
+            body.next.as_deref(),
+            e,
+            crypto,
+            locale,
+            log,
+        ),
+    }
+}
+
+pub async fn login_form(mut req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        client,
+        locale,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let body: AuthLoginRequest = routes::form(&mut req, config.timeouts.body).await?;
+    info!(log, "Logging in"; "username" => &body.username);
+    match log_in(
+        &body.username,
+        &body.password,
+        client,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+    )
+    .await
+    {
+        Ok(login) => login_redirect(login, body.next.as_deref(), crypto, &config.cookies, log),
+        Err(e) => form_error(
+            "login",
+            &body.username,
+            body.next.as_deref(),
+            e,
+            crypto,
+            locale,
+            log,
+        ),
+    }
+}
+
+pub async fn negotiate(req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        client,
+        locale,
+        context,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    if config.kerberos.is_none() {
+        return Err(Error::NotFound(Backtrace::capture()));
+    }
+    let query: PageQuery = serde_urlencoded::from_str(req.uri().query().unwrap_or_default())?;
+    let next = query.next.as_deref();
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Negotiate "));
+    let Some(token) = token else {
+        // Browsers of domain members answer the challenge with a ticket right away, and
+        // the others show the login form that comes with it.
+        let mut context = context;
+        let terms = client.tenant.terms(&config.terms);
+        context.insert("terms_version", &terms.version);
+        context.insert("terms_url", &terms.url);
+        context.insert("negotiate", &true);
+        context.insert("form_token", &bot::form_token(crypto));
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, "Negotiate")
+            .header(CACHE_CONTROL, "no-store")
+            .body(templates.render("index.html", &context)?.into())
+            .unwrap());
+    };
+    match log_in_with_kerberos(token, client, store, templates, crypto, config, log).await {
+        Ok(login) => login_redirect(login, next, crypto, &config.cookies, log),
+        Err(e) => form_error("login", "", next, e, crypto, locale, log),
+    }
+}
+
+pub async fn email_page(req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        context,
+        had_flash,
+        templates,
+        ..
+    } = page;
+    let query: EmailQuery = serde_urlencoded::from_str(req.uri().query().unwrap_or_default())?;
+    let mut context = context;
+    context.insert("email", &query.email);
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("email.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn email_form(mut req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        client,
+        locale,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let body: EmailRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let next = body.next.as_deref();
+    if let Err(e) = send_login_code(&body.email, client, store, templates, config, log).await {
+        let flash = Flash::error("email", e.localized_message(locale));
+        return flash_error(flash, &email_location(None, next)?, e, crypto, log);
+    }
+    let flash = Flash::notice(&i18n::translate(locale, "notice-login-code-sent", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, email_location(Some(&body.email), next)?)
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn email_code_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        client,
+        locale,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let body: EmailCodeRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let next = body.next.as_deref();
+    match log_in_with_code(
+        &body.email,
+        &body.code,
+        client,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+    )
+    .await
+    {
+        Ok(login) => login_redirect(login, next, crypto, &config.cookies, log),
+        Err(e) => flash_error(
+            Flash::error("code", e.localized_message(locale)),
+            &email_location(Some(&body.email), next)?,
+            e,
+            crypto,
+            log,
+        ),
+    }
+}
+
+pub async fn challenge_page(req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        context,
+        had_flash,
+        templates,
+        crypto,
+        ..
+    } = page;
+    let cookies = get_cookies(&req)?;
+    let Ok(challenge) = Challenge::from_cookies(&cookies, crypto) else {
+        return Ok(see_other("/"));
+    };
+    let mut context = context;
+    context.insert("channel", challenge.channel.as_str());
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("sms.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn challenge_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        client,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let cookies = get_cookies(&req)?;
+    // Checked before reading the body, which needs the request the cookies borrow from.
+    let challenge = Challenge::from_cookies(&cookies, crypto);
+    let body: CodeRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let next = body.next.as_deref();
+    let challenge = match challenge {
+        Ok(challenge) => challenge,
+        Err(e) => return form_error("login", "", next, e, crypto, locale, log),
+    };
+    match complete_challenge(&challenge, &body.code, client, store, crypto, config).await {
+        Ok(Login::Session(session)) => {
+            info!(log, "Logged in with a text message code"; session.user(), &session);
+            Ok(Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, next_location(next))
+                .header(
+                    SET_COOKIE,
+                    session.cookie_login(&config.cookies).to_string(),
+                )
+                .header(SET_COOKIE, Challenge::cookie_clear().to_string())
+                .body(Body::empty())
+                .unwrap())
+        }
+        Ok(login) => login_redirect(login, next, crypto, &config.cookies, log),
+        Err(e) => flash_error(
+            Flash::error("sms", e.localized_message(locale)),
+            &sms_location(next)?,
+            e,
+            crypto,
+            log,
+        ),
+    }
+}
+
+pub async fn resend_form(mut req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        locale,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let cookies = get_cookies(&req)?;
+    let challenge = Challenge::from_cookies(&cookies, crypto);
+    let body: NextRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let next = body.next.as_deref();
+    let challenge = match challenge {
+        Ok(challenge) => challenge,
+        Err(e) => return form_error("login", "", next, e, crypto, locale, log),
+    };
+    let location = sms_location(next)?;
+    if let Err(e) = resend_code(&challenge, store, crypto, templates, locale).await {
+        let flash = Flash::error("sms", e.localized_message(locale));
+        return flash_error(flash, &location, e, crypto, log);
+    }
+    info!(log, "Login code sent again"; challenge.user);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-code-sent", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, location)
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn terms_page(req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        client,
+        context,
+        had_flash,
+        templates,
+        crypto,
+        config,
+        ..
+    } = page;
+    let cookies = get_cookies(&req)?;
+    if PendingTerms::from_cookies(&cookies, crypto).is_err() {
+        return Ok(see_other("/"));
+    }
+    let mut context = context;
+    let terms = client.tenant.terms(&config.terms);
+    context.insert("terms_version", &terms.version);
+    context.insert("terms_url", &terms.url);
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("terms.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn terms_form(mut req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        client,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let cookies = get_cookies(&req)?;
+    let pending = PendingTerms::from_cookies(&cookies, crypto);
+    let body: TermsRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let next = body.next.as_deref();
+    let pending = match pending {
+        Ok(pending) => pending,
+        Err(e) => return form_error("login", "", next, e, crypto, locale, log),
+    };
+    match accept_terms(&pending, body.accept, client, store, crypto, config).await {
+        Ok(session) => {
+            info!(log, "Logged in after accepting the terms"; session.user(), &session);
+            Ok(Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, next_location(next))
+                .header(
+                    SET_COOKIE,
+                    session.cookie_login(&config.cookies).to_string(),
+                )
+                .header(SET_COOKIE, PendingTerms::cookie_clear().to_string())
+                .body(Body::empty())
+                .unwrap())
+        }
+        Err(e) => flash_error(
+            Flash::error("terms", e.localized_message(locale)),
+            &terms_location(next)?,
+            e,
+            crypto,
+            log,
+        ),
+    }
+}
+
+pub async fn suspended_page(req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        context, templates, ..
+    } = page;
+    let query: SuspendedQuery = serde_urlencoded::from_str(req.uri().query().unwrap_or_default())?;
+    let status = query.status.as_deref().and_then(AccountStatus::parse);
+    let mut context = context;
+    let status = status.unwrap_or(AccountStatus::Suspended);
+    context.insert("status", status.as_str());
+    Ok(Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(templates.render("suspended.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn logout_form(mut req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    info!(log, "Logging out");
+    let cross_origin = sso::is_cross_origin(&req);
+    let body: LogoutRequest = routes::form(&mut req, config.timeouts.body).await?;
+    if let Some(session) = &session {
+        // Pages elsewhere could otherwise log anyone visiting them out.
+        let token = body.logout_token.as_deref().unwrap_or_default();
+        if cross_origin && !sso::verify_logout_token(token, session, crypto) {
+            return Err(Error::InvalidLogoutToken(Backtrace::capture()));
+        }
+        log_out(session, client, store, config).await?;
+    }
+    let mut response = Response::builder().status(StatusCode::SEE_OTHER).header(
+        SET_COOKIE,
+        Session::cookie_logout(&config.cookies).to_string(),
+    );
+    // The notice is only shown here, so there's no point in leaving it for later when going
+    // back to the app.
+    response = match body.next.as_deref().filter(|next| config.sso.allows(next)) {
+        Some(next) => response.header(LOCATION, next),
+        None => {
+            let flash = Flash::notice(&i18n::translate(locale, "notice-logged-out", &[]));
+            response
+                .header(LOCATION, next_location(body.next.as_deref()))
+                .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        }
+    };
+    Ok(response.body(Body::empty()).unwrap())
+}
+
+/// Logs in the user of the principal the browser has a Kerberos ticket of, which is the user with
+/// the principal linked as an identity, or else the user of the same username if the realm is
+/// mapped by usernames.
+async fn log_in_with_kerberos(
+    token: &str,
+    client: &ClientInfo,
+    store: &Store,
+    templates: &Templates,
+    crypto: &Crypto,
+    config: &Config,
+    log: &Logger,
+) -> Result<Login, Error> {
+    let Some(kerberos) = &config.kerberos else {
+        return Err(Error::NotFound(Backtrace::capture()));
+    };
+    let token = base64::decode(token.trim())
+        .map_err(|_| Error::KerberosToken("not base64", Backtrace::capture()))?;
+    let principal = kerberos.accept(&token, SystemTime::now())?;
+    let subject = principal.to_string();
+    let user = match store
+        .users
+        .find_by_identity(kerberos::PROVIDER, &subject)
+        .await?
+    {
+        Some(user) => Some(user),
+        None => match kerberos.username(&principal) {
+            Some(username) => {
+                let username = user::normalize_username(username);
+                store
+                    .users
+                    .find_by_username(&client.tenant.id, &username)
+                    .await?
+            }
+            None => None,
+        },
+    };
+    // Identities are linked across tenants, so the user found may be of another one.
+    let user = match user {
+        Some(user) if store.users.profile(user).await?.tenant == client.tenant.id => user,
+        _ => {
+            return Err(Error::KerberosPrincipalUnknown(
+                subject,
+                Backtrace::capture(),
+            ))
+        }
+    };
+    info!(log, "Kerberos ticket accepted"; user, "principal" => &subject);
+    let factor = FirstFactor::Kerberos;
+    start_login(user, factor, client, store, templates, crypto, config, log).await
+}
+
+/// Sends the code a challenge waits for again, the same way it was sent the first time.
+async fn resend_code(
+    challenge: &Challenge,
+    store: &Store,
+    crypto: &Crypto,
+    templates: &Templates,
+    locale: &str,
+) -> Result<(), Error> {
+    let user = challenge.user;
+    match challenge.channel {
+        Channel::Sms => {
+            let phone = store.otp.phone(user).await?;
+            let Some(phone) = phone.filter(|phone| phone.verified) else {
+                return Err(Error::NoPhoneNumber(Backtrace::capture()));
+            };
+            otp::send_code(store, crypto, user, Purpose::Login, &phone.number, locale).await
+        }
+        Channel::Email => {
+            // The address may have been removed in the meantime, which leaves nothing to confirm.
+            let Some(email) = store.users.profile(user).await?.email else {
+                return Err(Error::NoLoginChallenge(Backtrace::capture()));
+            };
+            otp::mail_code(store, templates, user, Purpose::Login, &email, locale).await
+        }
+        // The app makes its own codes, there's nothing to send.
+        Channel::Totp => Err(Error::NoLoginChallenge(Backtrace::capture())),
+    }
+}
+
+/// Redirect finishing the first step of a login, to the next page or to the second factor.
+fn login_redirect(
+    login: Login,
+    next: Option<&str>,
+    crypto: &Crypto,
+    cookies: &CookiePolicy,
+    log: &Logger,
+) -> Result<Response<Body>, Error> {
+    Ok(match login {
+        Login::Session(session) => {
+            info!(log, "Logged in"; session.user(), &session);
+            Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, next_location(next))
+                .header(SET_COOKIE, session.cookie_login(cookies).to_string())
+                .body(Body::empty())
+                .unwrap()
+        }
+        Login::Challenge(challenge) => Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION, sms_location(next)?)
+            .header(SET_COOKIE, challenge.cookie(crypto).to_string())
+            .body(Body::empty())
+            .unwrap(),
+        Login::Terms(pending) => Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION, terms_location(next)?)
+            .header(SET_COOKIE, pending.cookie(crypto).to_string())
+            .header(SET_COOKIE, Challenge::cookie_clear().to_string())
+            .body(Body::empty())
+            .unwrap(),
+    })
+}
+
+/// Answers auth subrequests from reverse proxies like nginx's `auth_request` or Traefik's
+/// ForwardAuth, which let the original request through on 200 and pass the headers on to the app.
+/// Invalid cookies count as not being logged in here, so that they still lead to the login page.
+pub async fn forward_auth(
+    req: Request<Body>,
+    endpoint: Endpoint<'_>,
+) -> Result<Response<Body>, Error> {
+    let Endpoint {
+        client,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = endpoint;
+    let cookies = get_cookies(&req)?;
+    let session = match Session::from_cookies(&cookies, crypto, &client.tenant.id, &config.cookies)
+    {
+        Ok(Some(session)) if store.sessions.is_active(&session).await? => Some(session),
+        _ => None,
+    };
+    let Some(session) = session else {
+        info!(log, "Forward auth denied");
+        // nginx calls it X-Original-URI by convention, Traefik sends X-Forwarded-Uri.
+        let original = ["X-Original-URI", "X-Forwarded-Uri"]
+            .iter()
+            .find_map(|name| req.headers().get(*name))
+            .and_then(|header| header.to_str().ok())
+            .filter(|uri| is_local_path(uri));
+        let location = match original {
+            Some(next) => format!("/?{}", serde_urlencoded::to_string([("next", next)])?),
+            None => "/".to_owned(),
+        };
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(LOCATION, location)
+            .body(Body::empty())
+            .unwrap());
+    };
+    let profile = store.users.profile(*session.user()).await?;
+    info!(log, "Forward auth allowed"; &session, session.user());
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("X-Auth-User-Id", profile.id)
+        .header("X-Auth-Tenant-Id", &profile.tenant);
+    // Apps may want to hold back from some things while an admin is looking around as the user.
+    if let Some(impersonator) = session.impersonator() {
+        response = response.header("X-Auth-Impersonator-Id", impersonator.id);
+    }
+    // Usernames aren't restricted to what's allowed in headers, so some can't be passed on.
+    if let Ok(username) = HeaderValue::from_str(&profile.username) {
+        response = response.header("X-Auth-Username", username);
+    }
+    // The same goes for claims, which are passed on as JSON when there are any.
+    if !session.claims().is_empty() {
+        let claims = serde_json::to_string(session.claims())?;
+        if let Ok(claims) = HeaderValue::from_str(&claims) {
+            response = response.header("X-Auth-Claims", claims);
+        }
+    }
+    Ok(response.body(Body::empty()).unwrap())
+}
+
+/// Tells apps at `SSO_DOMAIN` who's logged in, answering their cross-origin requests made with
+/// credentials. Like [`forward_auth`], invalid cookies count as not being logged in.
+pub async fn whoami(req: Request<Body>, endpoint: Endpoint<'_>) -> Result<Response<Body>, Error> {
+    let Endpoint {
+        client,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = endpoint;
+    let cookies = get_cookies(&req)?;
+    let session = match Session::from_cookies(&cookies, crypto, &client.tenant.id, &config.cookies)
+    {
+        Ok(Some(session)) if store.sessions.is_active(&session).await? => Some(session),
+        _ => None,
+    };
+    let mut response = Response::builder()
+        .header(CACHE_CONTROL, "no-store")
+        .header(VARY, "Origin");
+    let origin = req.headers().get(ORIGIN).filter(|origin| {
+        origin
+            .to_str()
+            .map_or(false, |origin| config.sso.allows(origin))
+    });
+    if let Some(origin) = origin {
+        response = response
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+            .header(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+    }
+    let Some(session) = session else {
+        info!(log, "Whoami without a session");
+        return Ok(response
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::empty())
+            .unwrap());
+    };
+    let profile = store.users.profile(*session.user()).await?;
+    info!(log, "Whoami answered"; &session, session.user());
+    let body = sso::WhoamiResponse {
+        user: sso::WhoamiUser {
+            id: profile.id,
+            username: profile.username,
+        },
+        tenant: profile.tenant,
+        impersonator: session.impersonator().map(|impersonator| impersonator.id),
+        claims: session.claims().clone(),
+        logout_token: sso::logout_token(&session, crypto),
+    };
+    Ok(response
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&body)?.into())
+        .unwrap())
+}
+
+/// Sends the browser back to the form with the error flashed next to it, if it's one that can be
+/// fixed by filling in the form differently. Any other error is left for the error page.
+fn form_error(
+    form: &str,
+    username: &str,
+    next: Option<&str>,
+    error: Error,
+    crypto: &Crypto,
+    locale: &str,
+    log: &Logger,
+) -> Result<Response<Body>, Error> {
+    let flash = Flash::form(form, error.localized_message(locale), username);
+    // The form page needs the next page too, or it would be lost after the first failed attempt.
+    let location = match next.filter(|next| is_local_path(next)) {
+        Some(next) => format!("/?{}", serde_urlencoded::to_string([("next", next)])?),
+        None => "/".to_owned(),
+    };
+    flash_error(flash, &location, error, crypto, log)
+}
+
+/// Page asking for the mailed login code, or for the address to mail it to when there's none yet.
+fn email_location(email: Option<&str>, next: Option<&str>) -> Result<String, Error> {
+    let mut query = Vec::new();
+    if let Some(email) = email {
+        query.push(("email", email));
+    }
+    if let Some(next) = next.filter(|next| is_local_path(next)) {
+        query.push(("next", next));
+    }
+    if query.is_empty() {
+        return Ok("/auth/email".to_owned());
+    }
+    Ok(format!(
+        "/auth/email?{}",
+        serde_urlencoded::to_string(query)?
+    ))
+}
+
+/// Page asking for the text message code, passing along where to go once logged in.
+fn sms_location(next: Option<&str>) -> Result<String, Error> {
+    step_location("/auth/sms", next)
+}
+
+/// Page asking to accept the terms, passing along where to go once logged in.
+fn terms_location(next: Option<&str>) -> Result<String, Error> {
+    step_location("/auth/terms", next)
+}
+
+fn step_location(path: &str, next: Option<&str>) -> Result<String, Error> {
+    Ok(match next.filter(|next| is_local_path(next)) {
+        Some(next) => format!(
+            "{}?{}",
+            path,
+            serde_urlencoded::to_string([("next", next)])?
+        ),
+        None => path.to_owned(),
+    })
+}
+
+/// Whether the browser already has the version of the page with the entity tag.
+fn if_none_match(req: &Request<Body>, etag: &str) -> bool {
+    req.headers()
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
@@ -0,0 +1,539 @@
+//! OAuth and OpenID Connect endpoints, and the authorization page where users consent to clients.
+
+use crate::audit::{self, AuditEvent};
+use crate::backchannel;
+use crate::claims::{self, Claims};
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::features::{self, Feature};
+use crate::flash::Flash;
+use crate::handlers::{log_out, login_location, Endpoint, Page};
+use crate::i18n;
+use crate::oauth;
+use crate::routes;
+use crate::session::Session;
+use crate::sso;
+use crate::store::Store;
+use crate::templates::Templates;
+use crate::tenant::TenantPrefix;
+use crate::{check_status, get_cookies, see_other, Config, CtxTenant};
+use hyper::header::{LOCATION, SET_COOKIE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use slog::{info, Logger};
+use std::backtrace::Backtrace;
+
+pub async fn authorize_page(req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        features,
+        store,
+        templates,
+        crypto,
+        log,
+        ..
+    } = page;
+    if !features.is_enabled(Feature::OAuth) {
+        return Err(Error::FeatureDisabled(Feature::OAuth, Backtrace::capture()));
+    }
+    let request: oauth::AuthorizeRequest =
+        serde_urlencoded::from_str(req.uri().query().unwrap_or_default())?;
+    authorize(
+        request,
+        None,
+        session.as_ref(),
+        store,
+        templates,
+        crypto,
+        context,
+        log,
+    )
+    .await
+}
+
+pub async fn consent_form(mut req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        features,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    if !features.is_enabled(Feature::OAuth) {
+        return Err(Error::FeatureDisabled(Feature::OAuth, Backtrace::capture()));
+    }
+    let body: oauth::ConsentRequest = routes::form(&mut req, config.timeouts.body).await?;
+    // Pages elsewhere could otherwise have the browser allow their client in the user's
+    // name. Without a session, the user is only sent to log in.
+    if let Some(session) = &session {
+        let client_id = body.request.client_id.as_deref().unwrap_or_default();
+        let token = body.consent_token.as_deref().unwrap_or_default();
+        if !oauth::verify_consent_token(token, session, client_id, crypto) {
+            return Err(Error::InvalidConsentToken(Backtrace::capture()));
+        }
+    }
+    let decision = Some(body.decision.as_str());
+    authorize(
+        body.request,
+        decision,
+        session.as_ref(),
+        store,
+        templates,
+        crypto,
+        context,
+        log,
+    )
+    .await
+}
+
+pub async fn jwks(_req: Request<Body>, endpoint: Endpoint<'_>) -> Result<Response<Body>, Error> {
+    let Endpoint { crypto, .. } = endpoint;
+    Ok(oauth::response(StatusCode::OK, &backchannel::jwks(crypto)))
+}
+
+pub async fn introspect(
+    mut req: Request<Body>,
+    endpoint: Endpoint<'_>,
+) -> Result<Response<Body>, Error> {
+    let Endpoint {
+        store, config, log, ..
+    } = endpoint;
+    let body: oauth::IntrospectRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let Some(client) = authenticate_client(&req, body.credentials, store, log).await? else {
+        return Ok(invalid_client());
+    };
+    let response = match store.tokens.get(&body.token).await? {
+        Some(token) => {
+            let claims = token_claims(&token, store, config).await?;
+            oauth::IntrospectResponse::active(token, claims)
+        }
+        None => oauth::IntrospectResponse::inactive(),
+    };
+    info!(log, "Token introspected"; "client_id" => &client.id, "active" => response.active);
+    Ok(oauth::response(StatusCode::OK, &response))
+}
+
+pub async fn token(
+    mut req: Request<Body>,
+    endpoint: Endpoint<'_>,
+) -> Result<Response<Body>, Error> {
+    let Endpoint {
+        client: client_info,
+        store,
+        config,
+        log,
+        ..
+    } = endpoint;
+    // Introspection and revocation keep working, so that applications can still check and
+    // get rid of the tokens they were given before.
+    if !features::load(&*store.features, &config.features)
+        .await?
+        .is_enabled(Feature::OAuth)
+    {
+        return Ok(oauth::error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "temporarily_unavailable",
+            "Logging in through applications is switched off.",
+        ));
+    }
+    let body: oauth::TokenRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let client = match oauth::public_client_id(&req, &body.credentials) {
+        Some(id) => store.clients.get(&id).await?.filter(|client| client.public),
+        None => authenticate_client(&req, body.credentials, store, log).await?,
+    };
+    let Some(client) = client else {
+        return Ok(invalid_client());
+    };
+    let (user, scope) = match body.grant_type.as_deref() {
+        Some("authorization_code") => {
+            let code = match body.code.as_deref() {
+                Some(code) => store.tokens.take_code(code).await?,
+                None => None,
+            };
+            // The redirect URI is compared too, so that a code leaked from a different
+            // redirect can't be redeemed, per RFC 6749 section 4.1.3.
+            let redirect_uri = body.redirect_uri.as_ref();
+            let verifier = body.code_verifier.as_deref();
+            let Some(code) = code.filter(|code| {
+                code.client_id == client.id
+                    && Some(&code.redirect_uri) == redirect_uri
+                    && match (&code.code_challenge, verifier) {
+                        (Some(challenge), Some(verifier)) => {
+                            oauth::verify_code_challenge(verifier, challenge)
+                        }
+                        (Some(_), None) => false,
+                        (None, _) => !client.public,
+                    }
+            }) else {
+                info!(log, "Authorization code rejected"; "client_id" => &client.id);
+                return Ok(oauth::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_grant",
+                    "The authorization code is invalid or has expired.",
+                ));
+            };
+            (Some(code.user), code.scope)
+        }
+        Some("client_credentials") if client.public => {
+            info!(log, "Client credentials grant of a public client rejected"; "client_id" => &client.id);
+            return Ok(oauth::error_response(
+                StatusCode::BAD_REQUEST,
+                "unauthorized_client",
+                "Clients without a secret can only use the authorization_code grant.",
+            ));
+        }
+        Some("client_credentials") => {
+            if let Some(user) = client.user {
+                if let Err(e) = check_status(user, store).await {
+                    info!(log, "Token request of a suspended service account rejected"; user, "client_id" => &client.id, e.log_message());
+                    return Ok(oauth::error_response(
+                        StatusCode::BAD_REQUEST,
+                        "unauthorized_client",
+                        "The service account of the client is suspended.",
+                    ));
+                }
+            }
+            let requested: Vec<&str> = body
+                .scope
+                .as_deref()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect();
+            match oauth::grant_scope(&client, &requested) {
+                Ok(scope) => (client.user, scope),
+                Err(e) => {
+                    info!(log, "Token request rejected"; "client_id" => &client.id, e.log_message());
+                    return Ok(oauth::error_response(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_scope",
+                        &e.to_string(),
+                    ));
+                }
+            }
+        }
+        Some(grant_type) => {
+            info!(log, "Unsupported grant type"; "client_id" => &client.id, "grant_type" => grant_type);
+            return Ok(oauth::error_response(
+                StatusCode::BAD_REQUEST,
+                "unsupported_grant_type",
+                "Only the authorization_code and client_credentials grants are supported.",
+            ));
+        }
+        None => {
+            return Ok(oauth::error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                "The grant_type parameter is missing.",
+            ))
+        }
+    };
+    let lifetime = oauth::ACCESS_TOKEN_EXPIRATION_TIME;
+    let access_token =
+        oauth::issue_token(&*store.tokens, &client, user, scope.clone(), lifetime).await?;
+    info!(log, "Token issued"; "client_id" => &client.id, "scope" => &scope);
+    if let (Some(user), Some("client_credentials")) = (user, body.grant_type.as_deref()) {
+        let tenant = store.users.profile(user).await?.tenant;
+        let details = serde_json::json!({ "client_id": &client.id, "scope": &scope });
+        let event = AuditEvent::new(
+            audit::SERVICE_TOKEN_ISSUED,
+            Some(user),
+            client_info,
+            details,
+        );
+        store.audit.insert(&AuditEvent { tenant, ..event }).await?;
+    }
+    Ok(oauth::response(
+        StatusCode::OK,
+        &oauth::TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: lifetime.as_secs(),
+            scope,
+        },
+    ))
+}
+
+pub async fn revoke_token(
+    mut req: Request<Body>,
+    endpoint: Endpoint<'_>,
+) -> Result<Response<Body>, Error> {
+    let Endpoint {
+        store, config, log, ..
+    } = endpoint;
+    let body: oauth::RevokeRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let Some(client) = authenticate_client(&req, body.credentials, store, log).await? else {
+        return Ok(invalid_client());
+    };
+    // Unknown tokens and tokens of other clients get the same answer, so that the endpoint
+    // can't be used to find out which tokens exist.
+    if store.tokens.revoke(&body.token, &client.id).await? {
+        info!(log, "Token revoked"; "client_id" => &client.id);
+    } else {
+        info!(log, "Token to revoke not found"; "client_id" => &client.id);
+    }
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Authorization endpoint of the authorization code grant. Users who already allowed the client
+/// everything it asks for are sent straight back to it, others are asked on the consent page first.
+#[allow(clippy::too_many_arguments)]
+async fn authorize(
+    request: oauth::AuthorizeRequest,
+    decision: Option<&str>,
+    session: Option<&Session>,
+    store: &Store,
+    templates: &Templates,
+    crypto: &Crypto,
+    mut context: tera::Context,
+    log: &Logger,
+) -> Result<Response<Body>, Error> {
+    // Until the client and the redirect URI are known to be right, errors are shown to the user
+    // instead of being sent to the redirect URI, per RFC 6749 section 4.1.2.1.
+    let client_id = request.client_id.as_deref().unwrap_or_default();
+    let Some(client) = store.clients.get(client_id).await? else {
+        return Err(Error::ClientNotFound(
+            client_id.to_owned(),
+            Backtrace::capture(),
+        ));
+    };
+    let redirect_uri = request.redirect_uri.as_deref().unwrap_or_default();
+    if !store
+        .clients
+        .has_redirect_uri(&client.id, redirect_uri)
+        .await?
+    {
+        info!(log, "Redirect URI rejected"; "client_id" => &client.id, "uri" => redirect_uri);
+        return Err(Error::UnregisteredRedirectUri(Backtrace::capture()));
+    }
+    let state = request.state.as_deref();
+    let redirect_error = |error: &str| -> Result<Response<Body>, Error> {
+        let location = oauth::redirect_location(redirect_uri, &[("error", error)], state)?;
+        Ok(see_other(&location))
+    };
+    if request.response_type.as_deref() != Some("code") {
+        return redirect_error("unsupported_response_type");
+    }
+    // Public clients can't prove the code was issued to them with a secret, so they have to with
+    // PKCE instead.
+    let method = request.code_challenge_method.as_deref();
+    match &request.code_challenge {
+        Some(_) if method == Some(oauth::CODE_CHALLENGE_METHOD) => (),
+        None if !client.public => (),
+        _ => {
+            info!(log, "PKCE challenge rejected"; "client_id" => &client.id, "method" => method);
+            return redirect_error("invalid_request");
+        }
+    }
+    let requested: Vec<&str> = request
+        .scope
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect();
+    let Ok(scope) = oauth::grant_scope(&client, &requested) else {
+        return redirect_error("invalid_scope");
+    };
+    let Some(session) = session else {
+        let next = format!(
+            "/oauth/authorize?{}",
+            serde_urlencoded::to_string(&request)?
+        );
+        return Ok(see_other(&login_location(&next)?));
+    };
+    let user = *session.user();
+    let consented = store.consents.get(user, &client.id).await?;
+    match decision {
+        Some("allow") => {
+            let granted = oauth::merge_scopes(consented.as_deref().unwrap_or_default(), &scope);
+            store.consents.grant(user, &client.id, &granted).await?;
+            info!(log, "Consent granted"; user, "client_id" => &client.id, "scope" => &granted);
+        }
+        Some(_) => {
+            info!(log, "Consent denied"; user, "client_id" => &client.id);
+            return redirect_error("access_denied");
+        }
+        None if matches!(&consented, Some(consented) if oauth::scope_covers(consented, &scope)) => {
+        }
+        None => {
+            let scopes: Vec<&str> = scope.split_whitespace().collect();
+            context.insert("client_id", &client.id);
+            context.insert("scopes", &scopes);
+            context.insert("request", &request);
+            context.insert(
+                "consent_token",
+                &oauth::consent_token(session, &client.id, crypto),
+            );
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .body(templates.render("consent.html", &context)?.into())
+                .unwrap());
+        }
+    }
+    let challenge = request.code_challenge.clone();
+    let code = oauth::issue_code(
+        &*store.tokens,
+        &client,
+        user,
+        scope,
+        redirect_uri,
+        challenge,
+    )
+    .await?;
+    info!(log, "Authorization code issued"; user, "client_id" => &client.id);
+    Ok(see_other(&oauth::redirect_location(
+        redirect_uri,
+        &[("code", &code)],
+        state,
+    )?))
+}
+
+/// RP-initiated logout from OpenID Connect, where clients send the browser to log the user out
+/// everywhere. Unlike the other OAuth endpoints, this one acts on the session cookie.
+pub async fn oauth_logout(
+    mut req: Request<Body>,
+    endpoint: Endpoint<'_>,
+) -> Result<Response<Body>, Error> {
+    let Endpoint {
+        client,
+        locale,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+    } = endpoint;
+    let cookies = get_cookies(&req)?;
+    // A forged cookie shouldn't stop anyone from logging out.
+    let session = Session::from_cookies(&cookies, crypto, &client.tenant.id, &config.cookies)
+        .ok()
+        .flatten();
+    let request: oauth::LogoutRequest = match *req.method() {
+        Method::GET => serde_urlencoded::from_str(req.uri().query().unwrap_or_default())?,
+        Method::POST => routes::form(&mut req, config.timeouts.body).await?,
+        _ => return Err(Error::NotFound(Backtrace::capture())),
+    };
+    // Redirects are only allowed to URIs registered in advance, or the endpoint would be an open
+    // redirect. A URI without a client can't be checked, so it's rejected the same way.
+    let redirect = match &request.post_logout_redirect_uri {
+        Some(uri) => {
+            let client_id = request.client_id.as_deref().unwrap_or_default();
+            if !store
+                .clients
+                .has_logout_redirect_uri(client_id, uri)
+                .await?
+            {
+                info!(log, "Post-logout redirect URI rejected"; "client_id" => client_id, "uri" => uri);
+                return Err(Error::UnregisteredRedirectUri(Backtrace::capture()));
+            }
+            Some(oauth::redirect_location(
+                uri,
+                &[],
+                request.state.as_deref(),
+            )?)
+        }
+        None => None,
+    };
+    if let Some(session) = &session {
+        // Without asking first, any page could log its visitors out by linking here. The form has
+        // a token tied to the session, so only posts from it go through.
+        let token = request.logout_token.as_deref().unwrap_or_default();
+        if req.method() != Method::POST || !sso::verify_logout_token(token, session, crypto) {
+            let base = req
+                .extensions()
+                .get::<TenantPrefix>()
+                .map_or("", |prefix| prefix.0.as_str());
+            let mut context = tera::Context::new();
+            context.insert("lang", locale);
+            context.insert(
+                "tenant",
+                &CtxTenant {
+                    id: &client.tenant.id,
+                    name: &client.tenant.name,
+                    branding: &client.tenant.branding,
+                    base,
+                },
+            );
+            context.insert("request", &request);
+            context.insert("logout_token", &sso::logout_token(session, crypto));
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .body(templates.render("logout.html", &context)?.into())
+                .unwrap());
+        }
+    }
+    info!(log, "Logging out"; "client_id" => request.client_id.as_deref());
+    if let Some(session) = &session {
+        log_out(session, client, store, config).await?;
+    }
+    let mut response = Response::builder().status(StatusCode::SEE_OTHER).header(
+        SET_COOKIE,
+        Session::cookie_logout(&config.cookies).to_string(),
+    );
+    response = match redirect {
+        Some(location) => response.header(LOCATION, location),
+        None => {
+            let flash = Flash::notice(&i18n::translate(locale, "notice-logged-out", &[]));
+            response
+                .header(LOCATION, "/")
+                .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        }
+    };
+    Ok(response.body(Body::empty()).unwrap())
+}
+
+/// Authenticates the client making an OAuth request, with `None` meaning the credentials were
+/// missing or wrong.
+async fn authenticate_client(
+    req: &Request<Body>,
+    credentials: oauth::ClientCredentials,
+    store: &Store,
+    log: &Logger,
+) -> Result<Option<oauth::Client>, Error> {
+    let Some((id, secret)) = oauth::client_credentials(req, credentials) else {
+        info!(log, "OAuth client credentials missing");
+        return Ok(None);
+    };
+    match store.clients.authenticate(&id, &secret).await {
+        Ok(client) => Ok(Some(client)),
+        Err(Error::InvalidClient(_)) => {
+            info!(log, "OAuth client authentication failed"; "client_id" => &id);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn invalid_client() -> Response<Body> {
+    oauth::error_response(
+        StatusCode::UNAUTHORIZED,
+        "invalid_client",
+        "Client authentication failed.",
+    )
+}
+
+/// Tokens don't carry claims of their own, so they're put together afresh for the user the token
+/// was issued to. Tokens issued to clients themselves have none.
+async fn token_claims(
+    token: &oauth::AccessToken,
+    store: &Store,
+    config: &Config,
+) -> Result<Option<Claims>, Error> {
+    let Some(user) = token.user else { return Ok(None) };
+    let tenant = match store.users.profile(user).await {
+        Ok(profile) => profile.tenant,
+        // Deleting the user takes their tokens with them, but the token may have been looked up
+        // just before.
+        Err(Error::UserNotFound(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(Some(
+        claims::collect(user, &tenant, store, &config.claims).await?,
+    ))
+}
@@ -0,0 +1,1062 @@
+//! Pages where users manage their own account: login methods, second factors, notifications,
+//! connected applications and their data.
+
+use crate::audit::{self, AuditEvent};
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::export;
+use crate::flash::Flash;
+use crate::handlers::{
+    change_password, flash_error, login_activity, login_location, next_location, ActivityQuery,
+    ChangePasswordRequest, CodeRequest, EmailRequest, Page,
+};
+use crate::i18n;
+use crate::notifications::{self, Category};
+use crate::otp::{self, Purpose};
+use crate::routes;
+use crate::sms;
+use crate::store::Store;
+use crate::totp;
+use crate::user::{self, User};
+use crate::util::format_time;
+use crate::{see_other, Config};
+use hyper::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, LOCATION, SET_COOKIE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use slog::info;
+use std::backtrace::Backtrace;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[derive(Debug, Deserialize)]
+struct ExportDownloadQuery {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LanguageRequest {
+    lang: String,
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PhoneRequest {
+    number: String,
+}
+
+/// Password or code from the authenticator app in use, either of which confirms changes to the app.
+#[derive(Debug, Deserialize)]
+struct TotpChangeRequest {
+    #[serde(default)]
+    password: String,
+    #[serde(default)]
+    code: String,
+}
+
+/// Categories are left out of the form when their boxes aren't ticked, which is taken as off.
+#[derive(Debug, Deserialize)]
+struct NotificationsRequest {
+    #[serde(default)]
+    new_device: bool,
+    #[serde(default)]
+    security: bool,
+    #[serde(default)]
+    product: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct UnsubscribeRequest {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UnlinkIdentityRequest {
+    id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct RevokeApplicationRequest {
+    client_id: String,
+}
+
+/// A login as shown on the activity page, with the time written out.
+#[derive(Serialize)]
+struct CtxLogin {
+    time: String,
+    succeeded: bool,
+    ip: Option<String>,
+    country: Option<String>,
+    device: Option<String>,
+    this_device: bool,
+}
+
+#[derive(Serialize)]
+struct CtxNotification {
+    category: &'static str,
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct CtxApplication {
+    client_id: String,
+    scopes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CtxMethods {
+    password: bool,
+    email: Option<String>,
+    identities: Vec<CtxIdentity>,
+}
+
+#[derive(Serialize)]
+struct CtxIdentity {
+    id: i32,
+    provider: String,
+    subject: String,
+}
+
+#[derive(Serialize)]
+struct CtxPhone {
+    number: String,
+    verified: bool,
+}
+
+#[derive(Serialize)]
+struct CtxTotp {
+    enrolled: bool,
+    pending: bool,
+}
+
+pub async fn phone_page(_req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        had_flash,
+        store,
+        templates,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/settings/sms")?));
+    };
+    let phone = store.otp.phone(*session.user()).await?;
+    let mut context = context;
+    context.insert(
+        "phone",
+        &phone.map(|phone| CtxPhone {
+            number: phone.number,
+            verified: phone.verified,
+        }),
+    );
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("phone.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn phone_form(mut req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let body: PhoneRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let user = *session.user();
+    if let Err(e) = enroll_phone(user, body.number.trim(), store, crypto, locale).await {
+        let flash = Flash::error("phone", e.localized_message(locale));
+        return flash_error(flash, "/settings/sms", e, crypto, log);
+    }
+    info!(log, "Phone number enrollment started"; user);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-code-sent", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/sms")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn phone_verify_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let body: CodeRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let user = *session.user();
+    if let Err(e) = verify_phone(user, &body.code, store).await {
+        let flash = Flash::error("verify", e.localized_message(locale));
+        return flash_error(flash, "/settings/sms", e, crypto, log);
+    }
+    info!(log, "Phone number verified"; user);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-phone-verified", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/sms")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn phone_remove_form(
+    _req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let user = *session.user();
+    store.otp.remove_phone(user).await?;
+    info!(log, "Phone number removed"; user);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-phone-removed", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/sms")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn totp_page(_req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        had_flash,
+        store,
+        templates,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/settings/2fa")?));
+    };
+    let totp = store.otp.totp(*session.user()).await?;
+    let mut context = context;
+    context.insert(
+        "totp",
+        &totp.map(|totp| CtxTotp {
+            enrolled: totp.secret.is_some(),
+            pending: totp.pending.is_some(),
+        }),
+    );
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("totp.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn totp_qr(_req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        store,
+        crypto,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let issuer = &client.tenant.name;
+    let svg = totp::qr_code(*session.user(), issuer, store, crypto).await?;
+    // The image carries the secret, which no cache should keep.
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "image/svg+xml")
+        .header(CACHE_CONTROL, "no-store")
+        .body(svg.into())
+        .unwrap())
+}
+
+pub async fn totp_form(mut req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let body: TotpChangeRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let user = *session.user();
+    if let Err(e) = enroll_totp(user, &body, store, crypto).await {
+        let flash = Flash::error("totp", e.localized_message(locale));
+        return flash_error(flash, "/settings/2fa", e, crypto, log);
+    }
+    info!(log, "Authenticator app enrollment started"; user);
+    Ok(see_other("/settings/2fa"))
+}
+
+pub async fn totp_verify_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let body: CodeRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let user = *session.user();
+    if let Err(e) = totp::verify(user, &body.code, store, crypto).await {
+        let flash = Flash::error("verify", e.localized_message(locale));
+        return flash_error(flash, "/settings/2fa", e, crypto, log);
+    }
+    info!(log, "Authenticator app verified"; user);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-totp-verified", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/2fa")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn totp_remove_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let body: TotpChangeRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let user = *session.user();
+    if let Err(e) = remove_totp(user, &body, store, crypto).await {
+        let flash = Flash::error("remove", e.localized_message(locale));
+        return flash_error(flash, "/settings/2fa", e, crypto, log);
+    }
+    info!(log, "Authenticator app removed"; user);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-totp-removed", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/2fa")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn methods_page(_req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        had_flash,
+        store,
+        templates,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/settings/methods")?));
+    };
+    let user = *session.user();
+    let profile = store.users.profile(user).await?;
+    let identities = store.users.identities(user).await?;
+    let mut context = context;
+    context.insert(
+        "methods",
+        &CtxMethods {
+            password: profile.has_password,
+            email: profile.email,
+            identities: identities
+                .into_iter()
+                .map(|identity| CtxIdentity {
+                    id: identity.id,
+                    provider: identity.provider,
+                    subject: identity.subject,
+                })
+                .collect(),
+        },
+    );
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("methods.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn email_method_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let body: EmailRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let user = *session.user();
+    if let Err(e) = set_email(user, &body.email, store, config).await {
+        let flash = Flash::error("email", e.localized_message(locale));
+        return flash_error(flash, "/settings/methods", e, crypto, log);
+    }
+    info!(log, "Email address changed"; user);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-email-saved", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/methods")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn email_method_remove_form(
+    _req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let user = *session.user();
+    if let Err(e) = remove_email(user, store).await {
+        let flash = Flash::error("methods", e.localized_message(locale));
+        return flash_error(flash, "/settings/methods", e, crypto, log);
+    }
+    info!(log, "Email address removed"; user);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-email-removed", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/methods")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn password_method_remove_form(
+    _req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let user = *session.user();
+    if let Err(e) = remove_password(user, store).await {
+        let flash = Flash::error("methods", e.localized_message(locale));
+        return flash_error(flash, "/settings/methods", e, crypto, log);
+    }
+    info!(log, "Password removed"; user);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-password-removed", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/methods")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn password_page(_req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        had_flash,
+        store,
+        templates,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/settings/password")?));
+    };
+    let profile = store.users.profile(*session.user()).await?;
+    let mut context = context;
+    context.insert("has_password", &profile.has_password);
+    context.insert("expired", &store.sessions.is_restricted(session).await?);
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("password.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn password_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let body: ChangePasswordRequest = routes::form(&mut req, config.timeouts.body).await?;
+    if let Err(e) = change_password(
+        session,
+        &body.current_password,
+        &body.password,
+        locale,
+        store,
+        templates,
+        crypto,
+        config,
+    )
+    .await
+    {
+        let flash = Flash::error("password", e.localized_message(locale));
+        return flash_error(flash, "/settings/password", e, crypto, log);
+    }
+    info!(log, "Password changed"; session.user());
+    let flash = Flash::notice(&i18n::translate(locale, "notice-password-changed", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn unlink_identity_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let body: UnlinkIdentityRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let user = *session.user();
+    if let Err(e) = unlink_identity(user, body.id, store).await {
+        let flash = Flash::error("methods", e.localized_message(locale));
+        return flash_error(flash, "/settings/methods", e, crypto, log);
+    }
+    info!(log, "Identity unlinked"; user, "identity" => body.id);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-identity-unlinked", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/methods")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn applications_page(
+    _req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        had_flash,
+        store,
+        templates,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/settings/applications")?));
+    };
+    let applications: Vec<CtxApplication> = store
+        .consents
+        .list(*session.user())
+        .await?
+        .into_iter()
+        .map(|consent| CtxApplication {
+            client_id: consent.client_id,
+            scopes: consent
+                .scope
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect(),
+        })
+        .collect();
+    let mut context = context;
+    context.insert("applications", &applications);
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("applications.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn revoke_application_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let body: RevokeApplicationRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let user = *session.user();
+    // Tokens are revoked even without a consent, in case only the consent went missing.
+    let revoked = store.consents.revoke(user, &body.client_id).await?;
+    store.tokens.revoke_all(&body.client_id, user).await?;
+    info!(log, "Application access revoked"; user, "client_id" => &body.client_id, "had_consent" => revoked);
+    let message = i18n::translate(
+        locale,
+        "notice-access-revoked",
+        &[("client", &body.client_id)],
+    );
+    let flash = Flash::notice(&message);
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/applications")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn activity_page(req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        client,
+        context,
+        store,
+        templates,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/settings/activity")?));
+    };
+    let query: ActivityQuery = serde_urlencoded::from_str(req.uri().query().unwrap_or_default())?;
+    let activity = login_activity(*session.user(), query.page, client, store).await?;
+    let previous_page = (activity.page > 1).then(|| activity.page - 1);
+    let logins: Vec<CtxLogin> = activity
+        .logins
+        .into_iter()
+        .map(|login| CtxLogin {
+            time: format_time(UNIX_EPOCH + Duration::from_secs(login.time)),
+            succeeded: login.succeeded,
+            ip: login.ip,
+            country: login.country,
+            // The start of the ID is enough to tell the devices apart at a glance.
+            device: login.device.map(|device| device.chars().take(8).collect()),
+            this_device: login.this_device,
+        })
+        .collect();
+    let mut context = context;
+    context.insert("logins", &logins);
+    context.insert("previous_page", &previous_page);
+    context.insert("next_page", &activity.next_page);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(templates.render("activity.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn notifications_page(
+    _req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        had_flash,
+        store,
+        templates,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/settings/notifications")?));
+    };
+    let notifications: Vec<CtxNotification> =
+        notifications::preferences(&*store.notifications, *session.user())
+            .await?
+            .into_iter()
+            .map(|(category, enabled)| CtxNotification {
+                category: category.as_str(),
+                enabled,
+            })
+            .collect();
+    let mut context = context;
+    context.insert("notifications", &notifications);
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("notifications.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn notifications_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let body: NotificationsRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let user = *session.user();
+    for (category, enabled) in [
+        (Category::NewDevice, body.new_device),
+        (Category::Security, body.security),
+        (Category::Product, body.product),
+    ] {
+        store.notifications.set(user, category, enabled).await?;
+    }
+    info!(log, "Notification preferences changed"; user);
+    let message = i18n::translate(locale, "notice-notifications-saved", &[]);
+    let flash = Flash::notice(&message);
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/notifications")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Opened from links in mail, by whoever has the mailbox rather than a session, so nothing
+/// changes until the form is sent. That also keeps link scanners from unsubscribing people.
+pub async fn unsubscribe_page(req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        context,
+        templates,
+        crypto,
+        ..
+    } = page;
+    let query: UnsubscribeRequest =
+        serde_urlencoded::from_str(req.uri().query().unwrap_or_default())?;
+    let (_, category) = notifications::open_unsubscribe_token(&query.token, crypto)?;
+    let mut context = context;
+    context.insert("category", category.as_str());
+    context.insert("token", &query.token);
+    context.insert("unsubscribed", &false);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CACHE_CONTROL, "no-store")
+        .body(templates.render("unsubscribe.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn unsubscribe_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page {
+        context,
+        store,
+        templates,
+        crypto,
+        config,
+        log,
+        ..
+    } = page;
+    let body: UnsubscribeRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let (user, category) = notifications::open_unsubscribe_token(&body.token, crypto)?;
+    store.notifications.set(user, category, false).await?;
+    info!(log, "Unsubscribed from notifications"; user, "category" => category.as_str());
+    let mut context = context;
+    context.insert("category", category.as_str());
+    context.insert("unsubscribed", &true);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CACHE_CONTROL, "no-store")
+        .body(templates.render("unsubscribe.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn export_page(_req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        context,
+        had_flash,
+        store,
+        templates,
+        crypto,
+        ..
+    } = page;
+    let Some(session) = &session else {
+        return Ok(see_other(&login_location("/settings/export")?));
+    };
+    let export = store.exports.latest(*session.user()).await?;
+    let mut context = context;
+    let pending = matches!(&export, Some(export) if !export.ready);
+    context.insert("export_pending", &pending);
+    context.insert(
+        "export_link",
+        &export
+            .filter(|export| export.ready)
+            .map(|export| export::download_link(export.id, crypto)),
+    );
+    let mut response = Response::builder().status(StatusCode::OK);
+    if had_flash {
+        response = response.header(SET_COOKIE, Flash::cookie_clear().to_string());
+    }
+    Ok(response
+        .body(templates.render("export.html", &context)?.into())
+        .unwrap())
+}
+
+pub async fn export_form(_req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        session,
+        locale,
+        store,
+        crypto,
+        log,
+        ..
+    } = page;
+    let session = routes::session(&session)?;
+    let user = *session.user();
+    export::request(store, user).await?;
+    info!(log, "Data export requested"; user);
+    let flash = Flash::notice(&i18n::translate(locale, "notice-export-requested", &[]));
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/settings/export")
+        .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn export_download(req: Request<Body>, page: Page<'_>) -> Result<Response<Body>, Error> {
+    let Page {
+        client,
+        store,
+        crypto,
+        log,
+        ..
+    } = page;
+    let query: ExportDownloadQuery =
+        serde_urlencoded::from_str(req.uri().query().unwrap_or_default())?;
+    let id = export::verify_token(&query.token, crypto)?;
+    let Some((user, data)) = store.exports.take(id).await? else {
+        return Err(Error::ExportNotFound(Backtrace::capture()));
+    };
+    let details = serde_json::json!({ "export": id });
+    let event = AuditEvent::new(audit::DATA_EXPORTED, Some(user), client, details);
+    store.audit.insert(&event).await?;
+    info!(log, "Data export downloaded"; user);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .header(
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"authtown-export.json\"",
+        )
+        // The link only works once, so a cached copy would be the only one left.
+        .header(CACHE_CONTROL, "no-store")
+        .body(data.into())
+        .unwrap())
+}
+
+pub async fn language_form(
+    mut req: Request<Body>,
+    page: Page<'_>,
+) -> Result<Response<Body>, Error> {
+    let Page { config, log, .. } = page;
+    let body: LanguageRequest = routes::form(&mut req, config.timeouts.body).await?;
+    let Some(locale) = i18n::find(&body.lang) else {
+        return Err(Error::UnknownLocale(body.lang, Backtrace::capture()));
+    };
+    info!(log, "Language changed"; "locale" => locale);
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, next_location(body.next.as_deref()))
+        .header(SET_COOKIE, i18n::cookie(locale).to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Starts setting up a new authenticator app, which replaces the one in use once confirmed.
+async fn enroll_totp(
+    user: User,
+    body: &TotpChangeRequest,
+    store: &Store,
+    crypto: &Crypto,
+) -> Result<(), Error> {
+    confirm_totp_change(user, body, store, crypto).await?;
+    totp::enroll(user, store, crypto).await
+}
+
+async fn remove_totp(
+    user: User,
+    body: &TotpChangeRequest,
+    store: &Store,
+    crypto: &Crypto,
+) -> Result<(), Error> {
+    confirm_totp_change(user, body, store, crypto).await?;
+    store.otp.remove_totp(user).await
+}
+
+/// Checks that whoever changes the authenticator app in use is the user who set it up, with their
+/// password or a code from the app, as a session left open somewhere isn't enough to turn the
+/// second factor off. Users who are only setting one up have nothing to confirm.
+async fn confirm_totp_change(
+    user: User,
+    body: &TotpChangeRequest,
+    store: &Store,
+    crypto: &Crypto,
+) -> Result<(), Error> {
+    if store
+        .otp
+        .totp(user)
+        .await?
+        .and_then(|totp| totp.secret)
+        .is_none()
+    {
+        return Ok(());
+    }
+    if !body.code.trim().is_empty() {
+        return totp::check(user, &body.code, store, crypto).await;
+    }
+    let profile = store.users.profile(user).await?;
+    if !profile.has_password {
+        return Err(Error::WrongCode(Backtrace::capture()));
+    }
+    let verified = store
+        .users
+        .get_and_verify(&profile.tenant, &profile.username, &body.password)
+        .await?;
+    if verified != user {
+        return Err(Error::WrongPassword(Backtrace::capture()));
+    }
+    Ok(())
+}
+
+/// Sets the phone number and texts a code to it, which has to be entered back before the number
+/// is used for logging in.
+async fn enroll_phone(
+    user: User,
+    number: &str,
+    store: &Store,
+    crypto: &Crypto,
+    locale: &str,
+) -> Result<(), Error> {
+    if !sms::is_valid_number(number) {
+        return Err(Error::InvalidPhoneNumber(Backtrace::capture()));
+    }
+    store.otp.set_phone(user, number).await?;
+    otp::send_code(store, crypto, user, Purpose::EnrollPhone, number, locale).await
+}
+
+async fn verify_phone(user: User, code: &str, store: &Store) -> Result<(), Error> {
+    let Some(phone) = store.otp.phone(user).await? else {
+        return Err(Error::NoPhoneNumber(Backtrace::capture()));
+    };
+    if !store
+        .otp
+        .check_code(user, Purpose::EnrollPhone, code.trim())
+        .await?
+    {
+        return Err(Error::WrongCode(Backtrace::capture()));
+    }
+    store.otp.verify_phone(user, &phone.number).await?;
+    Ok(())
+}
+
+/// Sets the address, which can't be a throwaway one even when registering with one only needs
+/// approval, as there's nobody to approve the change.
+async fn set_email(user: User, email: &str, store: &Store, config: &Config) -> Result<(), Error> {
+    let email = user::normalize_email(email)?;
+    if config.disposable.is_disposable(&email) {
+        return Err(Error::DisposableEmail(Backtrace::capture()));
+    }
+    store.users.set_email(user, Some(&email)).await
+}
+
+async fn remove_email(user: User, store: &Store) -> Result<(), Error> {
+    let profile = store.users.profile(user).await?;
+    if profile.email.is_some() {
+        ensure_other_login_method(user, store).await?;
+        store.users.set_email(user, None).await?;
+    }
+    Ok(())
+}
+
+async fn remove_password(user: User, store: &Store) -> Result<(), Error> {
+    if store.users.profile(user).await?.has_password {
+        ensure_other_login_method(user, store).await?;
+        store.users.remove_password(user).await?;
+    }
+    Ok(())
+}
+
+async fn unlink_identity(user: User, id: i32, store: &Store) -> Result<(), Error> {
+    ensure_other_login_method(user, store).await?;
+    if !store.users.unlink_identity(user, id).await? {
+        return Err(Error::NotFound(Backtrace::capture()));
+    }
+    Ok(())
+}
+
+/// Refuses to remove a login method when it's the only one left, so that nobody can lock
+/// themselves out. Text message codes don't count, as they're only ever a second factor.
+async fn ensure_other_login_method(user: User, store: &Store) -> Result<(), Error> {
+    let profile = store.users.profile(user).await?;
+    let identities = store.users.identities(user).await?;
+    let methods =
+        usize::from(profile.has_password) + usize::from(profile.email.is_some()) + identities.len();
+    if methods < 2 {
+        return Err(Error::LastLoginMethod(Backtrace::capture()));
+    }
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use cookie::{Cookie, SameSite};
+use hyper::header::ACCEPT_LANGUAGE;
+use hyper::{Body, Request};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::lazy::SyncLazy;
+use std::time::Duration;
+
+/// Locale used when nothing the client asks for is available, and the one every message is
+/// guaranteed to exist in.
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Message catalogs, as flat maps from message keys to text with `{name}` placeholders. Adding a
+/// language only takes a new file here, as templates and errors refer to messages by their keys.
+const SOURCES: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.json")),
+    ("pl", include_str!("../locales/pl.json")),
+];
+
+static CATALOGS: SyncLazy<HashMap<&'static str, HashMap<String, String>>> = SyncLazy::new(|| {
+    SOURCES
+        .iter()
+        .map(|(locale, source)| (*locale, serde_json::from_str(source).unwrap()))
+        .collect()
+});
+
+// Unlike the flash, the preference is meant to be remembered.
+const COOKIE_EXPIRATION_TIME: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+#[derive(Serialize)]
+pub struct LocaleInfo {
+    pub code: &'static str,
+    pub name: String,
+}
+
+/// Looks the message up in the catalog of the locale, falling back to the default locale for
+/// messages that weren't translated yet, and to the key itself for ones that don't exist at all.
+pub fn translate(locale: &str, key: &str, args: &[(&str, &str)]) -> String {
+    let message = [locale, DEFAULT_LOCALE]
+        .iter()
+        .find_map(|locale| CATALOGS.get(locale)?.get(key))
+        .map_or(key, String::as_str);
+    let mut message = message.to_owned();
+    for (name, value) in args {
+        message = message.replace(&format!("{{{}}}", name), value);
+    }
+    message
+}
+
+/// Variant of [`translate`] for Tera templates, called as `t(key="...", lang=lang)`. Any other
+/// arguments fill in the placeholders of the same name.
+pub fn tera_translate(args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    let key = match args.get("key") {
+        Some(tera::Value::String(key)) => key,
+        _ => return Err("t() needs a string key argument".into()),
+    };
+    let locale = match args.get("lang") {
+        Some(tera::Value::String(locale)) => locale.as_str(),
+        _ => DEFAULT_LOCALE,
+    };
+    let values: Vec<(&str, String)> = args
+        .iter()
+        .filter(|(name, _)| !matches!(name.as_str(), "key" | "lang"))
+        .map(|(name, value)| match value {
+            tera::Value::String(value) => (name.as_str(), value.clone()),
+            value => (name.as_str(), value.to_string()),
+        })
+        .collect();
+    let values: Vec<(&str, &str)> = values
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect();
+    Ok(tera::Value::String(translate(locale, key, &values)))
+}
+
+/// Locale to answer the request in, which is the one the user picked if they did, and otherwise
+/// the best match for the languages the browser asks for.
+pub fn negotiate(cookies: &HashMap<&str, Cookie>, request: &Request<Body>) -> &'static str {
+    if let Some(locale) = cookies.get("lang").and_then(|cookie| find(cookie.value())) {
+        return locale;
+    }
+    let Some(Ok(header)) = request.headers().get(ACCEPT_LANGUAGE).map(|header| header.to_str()) else {
+        return DEFAULT_LOCALE;
+    };
+    let mut ranges: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.), |q| q.parse().ok())?;
+            Some((tag, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.)
+        .collect();
+    // Stable, so that ranges of equal quality keep the order the browser sent them in.
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    ranges
+        .iter()
+        .find_map(|(tag, _)| find(tag).or_else(|| find(tag.split('-').next()?)))
+        .unwrap_or(DEFAULT_LOCALE)
+}
+
+/// The locale with the given code, if there's a catalog for it.
+pub fn find(code: &str) -> Option<&'static str> {
+    SOURCES
+        .iter()
+        .map(|(locale, _)| *locale)
+        .find(|locale| locale.eq_ignore_ascii_case(code))
+}
+
+/// Every available locale with its name in its own language, for the language picker.
+pub fn locales() -> Vec<LocaleInfo> {
+    SOURCES
+        .iter()
+        .map(|(code, _)| LocaleInfo {
+            code,
+            name: translate(code, "language-name", &[]),
+        })
+        .collect()
+}
+
+pub fn cookie(locale: &'static str) -> Cookie<'static> {
+    Cookie::build("lang", locale)
+        .max_age(COOKIE_EXPIRATION_TIME.try_into().unwrap())
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .finish()
+}
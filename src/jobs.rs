@@ -0,0 +1,272 @@
+use crate::anomaly::{self, Alert};
+use crate::backchannel;
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::events::{Event, EventSink};
+use crate::export;
+use crate::mail::{Mail, Mailer};
+use crate::sms::{Sms, SmsSender};
+use crate::store::Store;
+use crate::user::User;
+use crate::util::{env_duration_ms, env_var_opt};
+use async_trait::async_trait;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use slog::{error, info, warn, Logger};
+use std::backtrace::Backtrace;
+use std::lazy::SyncLazy;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Work to be done outside of the request that asked for it, so that slow or flaky things like
+/// mail servers don't hold up the response.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Task {
+    SendMail(Mail),
+    /// The text carries a one-time code, so it's queued sealed with [`Crypto::seal`] and dropped
+    /// once the code expires.
+    SendSms {
+        to: String,
+        text: String,
+        /// Unix time in seconds.
+        expires_at: u64,
+    },
+    /// See [`export::assemble`].
+    ExportData {
+        user: User,
+        export: Uuid,
+    },
+    /// The event is kept in a field of its own, as it has a kind of its own too.
+    PublishEvent {
+        event: Event,
+    },
+    /// See [`anomaly::post_webhook`].
+    SendAlert {
+        url: String,
+        alert: Alert,
+    },
+    /// See [`backchannel::send`].
+    BackchannelLogout {
+        url: String,
+        issuer: String,
+        client_id: String,
+        user: User,
+    },
+}
+
+/// Whatever the workers deliver things with, each missing when not configured.
+struct Senders {
+    /// Signs what's sent to other services, which unlike the others is always there.
+    crypto: Arc<Crypto>,
+    mailer: Option<Arc<Mailer>>,
+    sms: Option<Arc<SmsSender>>,
+    events: Option<Arc<EventSink>>,
+}
+
+/// Task as stored in the queue, with the payload still serialized.
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    pub payload: String,
+    /// Number of times the job was claimed, including the current one.
+    pub attempts: i32,
+}
+
+#[async_trait]
+pub trait JobStore: Send + Sync {
+    async fn enqueue(&self, kind: &str, payload: &str) -> Result<(), Error>;
+
+    /// Claims the job that has been due the longest, hiding it from other workers until the lease
+    /// runs out. If the worker dies meanwhile, the job is picked up again after that.
+    async fn claim(&self, lease: Duration) -> Result<Option<Job>, Error>;
+
+    /// Removes the job after it succeeded.
+    async fn complete(&self, id: i64) -> Result<(), Error>;
+
+    async fn retry(&self, id: i64, error: &str, run_at: SystemTime) -> Result<(), Error>;
+
+    /// Moves the job to the dead letters, where it stays until requeued by hand.
+    async fn bury(&self, id: i64, error: &str) -> Result<(), Error>;
+
+    /// Makes every dead job due again with a fresh count of attempts, returning how many there were.
+    async fn requeue_dead(&self) -> Result<u64, Error>;
+}
+
+const DEFAULT_WORKERS: usize = 2;
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a job may run before it's considered lost and given to another worker.
+const LEASE: Duration = Duration::from_secs(5 * 60);
+
+const MAX_ATTEMPTS: i32 = 8;
+
+// Doubles with every attempt, so the last retry comes about 20 minutes after the first failure.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(10);
+
+static METRIC_JOB_COUNT: SyncLazy<IntCounterVec> = SyncLazy::new(|| {
+    register_int_counter_vec!(
+        "authtown_job_count",
+        "Number of background jobs run, by outcome",
+        &["kind", "outcome"]
+    )
+    .unwrap()
+});
+
+pub async fn enqueue(jobs: &dyn JobStore, task: &Task) -> Result<(), Error> {
+    let payload = serde_json::to_string(task)?;
+    jobs.enqueue(task.kind(), &payload).await
+}
+
+/// Spawns the workers, as many as `JOB_WORKERS` says. Each polls the queue every
+/// `JOB_POLL_INTERVAL_MS` while it's empty, and goes on to the next job right away otherwise.
+pub fn spawn_workers(
+    store: Arc<Store>,
+    crypto: Arc<Crypto>,
+    mailer: Option<Arc<Mailer>>,
+    sms: Option<Arc<SmsSender>>,
+    events: Option<Arc<EventSink>>,
+    log: &Logger,
+) -> Result<(), Error> {
+    let workers = match env_var_opt("JOB_WORKERS")? {
+        Some(workers) => workers.parse()?,
+        None => DEFAULT_WORKERS,
+    };
+    let poll_interval = env_duration_ms("JOB_POLL_INTERVAL_MS", DEFAULT_POLL_INTERVAL)?;
+    let senders = Arc::new(Senders {
+        crypto,
+        mailer,
+        sms,
+        events,
+    });
+    for worker in 0..workers {
+        let log = log.new(slog::o!("worker" => worker));
+        tokio::spawn(work(store.clone(), senders.clone(), poll_interval, log));
+    }
+    Ok(())
+}
+
+async fn work(store: Arc<Store>, senders: Arc<Senders>, poll_interval: Duration, log: Logger) {
+    loop {
+        // The supervisor already reports the database being down, no need to repeat that here.
+        if !store.is_healthy() {
+            tokio::time::sleep(poll_interval).await;
+            continue;
+        }
+        match store.jobs.claim(LEASE).await {
+            Ok(Some(job)) => {
+                if let Err(e) = run(&store, &senders, job, &log).await {
+                    error!(log, "Job bookkeeping failed"; e.log_message(), e.log_backtrace());
+                    tokio::time::sleep(poll_interval).await;
+                }
+            }
+            Ok(None) => tokio::time::sleep(poll_interval).await,
+            Err(e) => {
+                error!(log, "Claiming a job failed"; e.log_message(), e.log_backtrace());
+                tokio::time::sleep(poll_interval).await;
+            }
+        }
+    }
+}
+
+async fn run(store: &Store, senders: &Senders, job: Job, log: &Logger) -> Result<(), Error> {
+    let log = log.new(slog::o!("job_id" => job.id, "kind" => job.kind.clone()));
+    let task: Task = match serde_json::from_str(&job.payload) {
+        Ok(task) => task,
+        // Retrying won't make it parse, most likely it was queued by a newer version.
+        Err(e) => {
+            let e = Error::from(e);
+            error!(log, "Job payload is malformed"; e.log_message());
+            METRIC_JOB_COUNT
+                .with_label_values(&[&job.kind, "dead"])
+                .inc();
+            return store.jobs.bury(job.id, &e.full_message()).await;
+        }
+    };
+    let result = tokio::time::timeout(LEASE, perform(task, store, senders))
+        .await
+        .unwrap_or_else(|_| Err(Error::JobTimeout(Backtrace::capture())));
+    match result {
+        Ok(()) => {
+            info!(log, "Job completed"; "attempts" => job.attempts);
+            METRIC_JOB_COUNT
+                .with_label_values(&[&job.kind, "completed"])
+                .inc();
+            store.jobs.complete(job.id).await
+        }
+        Err(e) if job.attempts >= MAX_ATTEMPTS => {
+            error!(log, "Job failed for the last time"; "attempts" => job.attempts, e.log_message(), e.log_backtrace());
+            METRIC_JOB_COUNT
+                .with_label_values(&[&job.kind, "dead"])
+                .inc();
+            store.jobs.bury(job.id, &e.full_message()).await
+        }
+        Err(e) => {
+            let delay = RETRY_BASE_DELAY * 2u32.pow(job.attempts as u32 - 1);
+            warn!(log, "Job failed, will retry"; "attempts" => job.attempts, "delay_s" => delay.as_secs(), e.log_message());
+            METRIC_JOB_COUNT
+                .with_label_values(&[&job.kind, "retried"])
+                .inc();
+            let run_at = SystemTime::now() + delay;
+            store.jobs.retry(job.id, &e.full_message(), run_at).await
+        }
+    }
+}
+
+async fn perform(task: Task, store: &Store, senders: &Senders) -> Result<(), Error> {
+    match task {
+        Task::SendMail(mail) => {
+            let Some(mailer) = &senders.mailer else {
+                return Err(Error::MailNotConfigured(Backtrace::capture()));
+            };
+            mailer.send(&mail).await
+        }
+        Task::SendSms {
+            to,
+            text,
+            expires_at,
+        } => {
+            // The code is of no use anymore, so there's nothing left to retry.
+            if unix_time(SystemTime::now()) >= expires_at {
+                return Ok(());
+            }
+            let Some(sender) = &senders.sms else {
+                return Err(Error::SmsNotConfigured(Backtrace::capture()));
+            };
+            let text = String::from_utf8_lossy(&senders.crypto.unseal(&text)?).into_owned();
+            sender.send(&Sms { to, text }).await
+        }
+        Task::ExportData { user, export } => export::assemble(store, user, export).await,
+        Task::PublishEvent { event } => {
+            let Some(sink) = &senders.events else {
+                return Err(Error::EventsNotConfigured(Backtrace::capture()));
+            };
+            sink.publish(&event).await
+        }
+        Task::SendAlert { url, alert } => anomaly::post_webhook(&url, &alert).await,
+        Task::BackchannelLogout {
+            url,
+            issuer,
+            client_id,
+            user,
+        } => backchannel::send(&url, &issuer, &client_id, user, &senders.crypto).await,
+    }
+}
+
+impl Task {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Task::SendMail(_) => "send_mail",
+            Task::SendSms { .. } => "send_sms",
+            Task::ExportData { .. } => "export_data",
+            Task::PublishEvent { .. } => "publish_event",
+            Task::SendAlert { .. } => "send_alert",
+            Task::BackchannelLogout { .. } => "backchannel_logout",
+        }
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
@@ -0,0 +1,588 @@
+use crate::der::{expect, optional};
+use crate::error::Error;
+use crate::util::{constant_time_eq, env_duration_ms, env_var_opt, take};
+use aes_gcm::aes::cipher::generic_array::GenericArray;
+use aes_gcm::aes::{Aes128, Aes256, BlockDecrypt, BlockEncrypt, NewBlockCipher};
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Provider of the identities linking users to their principals, like `alice@EXAMPLE.COM`.
+pub const PROVIDER: &str = "kerberos";
+
+const DEFAULT_MAX_SKEW: Duration = Duration::from_secs(5 * 60);
+
+const OID_SPNEGO: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x02];
+const OID_KRB5: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x12, 0x01, 0x02, 0x02];
+/// The Kerberos mechanism as Windows lists it first, which is the other OID with a byte mangled.
+const OID_KRB5_MICROSOFT: &[u8] = &[0x2a, 0x86, 0x48, 0x82, 0xf7, 0x12, 0x01, 0x02, 0x02];
+
+/// aes128-cts-hmac-sha1-96 and aes256-cts-hmac-sha1-96 of RFC 3962, which is what domain
+/// controllers have used since the older ones were deprecated.
+const ENCTYPE_AES128: i32 = 17;
+const ENCTYPE_AES256: i32 = 18;
+
+const KEY_USAGE_TICKET: u32 = 2;
+const KEY_USAGE_AUTHENTICATOR: u32 = 11;
+
+/// Single sign-on for intranets, where browsers of domain members send a Kerberos ticket for the
+/// service with `Authorization: Negotiate`, as SPNEGO says. Tickets are checked against the keys
+/// of the service principal like `HTTP/auth.example.com@EXAMPLE.COM`, with no need to talk to the
+/// domain controllers. Mutual authentication isn't offered, which browsers don't ask for.
+pub struct KerberosPolicy {
+    /// From the keytab at `KERBEROS_KEYTAB`, as exported for the service principal.
+    keys: Vec<ServiceKey>,
+    /// From `KERBEROS_USERNAME_REALMS`, realms whose principals are taken to be the users of the
+    /// same username when they have no identity linked, like `EXAMPLE.COM,CORP.EXAMPLE.COM`.
+    username_realms: Vec<String>,
+    /// From `KERBEROS_MAX_SKEW_MS`, how far off the clocks of clients can be, five minutes by
+    /// default like in Kerberos itself.
+    max_skew: Duration,
+    /// Authenticators seen lately, so that a token taken off the wire can't be used again. They're
+    /// only kept in memory, so a token seen by one instance would still be taken by another, see
+    /// [`KerberosPolicy::from_env`].
+    replays: Mutex<HashMap<(String, u64, u32), SystemTime>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Principal {
+    pub name: Vec<String>,
+    pub realm: String,
+}
+
+struct ServiceKey {
+    principal: Principal,
+    version: u32,
+    enctype: i32,
+    key: Vec<u8>,
+}
+
+/// The parts of the ticket the service decrypts that matter for logging the client in.
+struct TicketPart<'a> {
+    key_type: i32,
+    key: &'a [u8],
+    client: Principal,
+    start: SystemTime,
+    end: SystemTime,
+}
+
+enum Aes {
+    Aes128(Box<Aes128>),
+    Aes256(Box<Aes256>),
+}
+
+impl KerberosPolicy {
+    /// Reads the keytab at `KERBEROS_KEYTAB`. Only one instance of a deployment should have it
+    /// set, or a load balancer should keep each client on one instance, as the authenticators
+    /// seen are only remembered by the instance that saw them.
+    pub fn from_env() -> Result<Option<KerberosPolicy>, Error> {
+        let Some(path) = env_var_opt("KERBEROS_KEYTAB")? else { return Ok(None); };
+        let username_realms = match env_var_opt("KERBEROS_USERNAME_REALMS")? {
+            Some(realms) => realms
+                .split(',')
+                .map(str::trim)
+                .filter(|realm| !realm.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            None => Vec::new(),
+        };
+        let max_skew = env_duration_ms("KERBEROS_MAX_SKEW_MS", DEFAULT_MAX_SKEW)?;
+        let keytab = std::fs::read(&path)?;
+        match KerberosPolicy::new(&keytab, username_realms, max_skew) {
+            Some(policy) => Ok(Some(policy)),
+            None => Err(Error::InvalidKeytab(path, Backtrace::capture())),
+        }
+    }
+
+    /// Fails when the keytab can't be parsed or has no keys of the supported encryption types.
+    pub fn new(
+        keytab: &[u8],
+        username_realms: Vec<String>,
+        max_skew: Duration,
+    ) -> Option<KerberosPolicy> {
+        let keys: Vec<_> = parse_keytab(keytab)?
+            .into_iter()
+            .filter(|key| key_length(key.enctype) == Some(key.key.len()))
+            .collect();
+        if keys.is_empty() {
+            return None;
+        }
+        Some(KerberosPolicy {
+            keys,
+            username_realms,
+            max_skew,
+            replays: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The username the principal stands for in its realm, if it's one of `username_realms` and
+    /// the principal has no instance, as in `alice@EXAMPLE.COM` rather than `alice/admin@...`.
+    pub fn username<'a>(&self, principal: &'a Principal) -> Option<&'a str> {
+        match principal.name.as_slice() {
+            [name] if self.username_realms.contains(&principal.realm) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Checks the token of an `Authorization: Negotiate` header, either SPNEGO or a bare Kerberos
+    /// one, returning the principal of the client it was made by.
+    pub fn accept(&self, token: &[u8], now: SystemTime) -> Result<Principal, Error> {
+        let rejected = |reason| Error::KerberosToken(reason, Backtrace::capture());
+        let ap_req = unwrap_token(token).ok_or_else(|| rejected("not a Kerberos token"))?;
+        let malformed = || rejected("malformed");
+        let (ap_req, _) = expect(0x6e, ap_req).ok_or_else(malformed)?;
+        let (fields, _) = expect(0x30, ap_req).ok_or_else(malformed)?;
+        let (_, fields) = expect(0xa0, fields).ok_or_else(malformed)?;
+        let (message_type, fields) = expect(0xa1, fields).ok_or_else(malformed)?;
+        if integer(message_type) != Some(14) {
+            return Err(malformed());
+        }
+        let (_, fields) = expect(0xa2, fields).ok_or_else(malformed)?;
+        let (ticket, fields) = expect(0xa3, fields).ok_or_else(malformed)?;
+        let (authenticator, _) = expect(0xa4, fields).ok_or_else(malformed)?;
+
+        let (service, enctype, version, cipher) = ticket_header(ticket).ok_or_else(malformed)?;
+        let key = self
+            .keys
+            .iter()
+            .filter(|key| key.principal == service && key.enctype == enctype)
+            .filter(|key| version.unwrap_or(key.version) == key.version)
+            .max_by_key(|key| key.version)
+            .ok_or_else(|| rejected("no key for the service"))?;
+        let ticket = decrypt(enctype, &key.key, KEY_USAGE_TICKET, cipher)
+            .ok_or_else(|| rejected("ticket not encrypted with the service key"))?;
+        let ticket = ticket_part(&ticket).ok_or_else(malformed)?;
+        if now + self.max_skew < ticket.start || ticket.end + self.max_skew < now {
+            return Err(rejected("ticket expired"));
+        }
+
+        let (enctype, _, cipher) = encrypted_data(authenticator).ok_or_else(malformed)?;
+        if enctype != ticket.key_type {
+            return Err(malformed());
+        }
+        let authenticator = decrypt(enctype, ticket.key, KEY_USAGE_AUTHENTICATOR, cipher)
+            .ok_or_else(|| rejected("authenticator not encrypted with the session key"))?;
+        let (client, time, microseconds) =
+            authenticator_part(&authenticator).ok_or_else(malformed)?;
+        if client != ticket.client {
+            return Err(rejected("authenticator of another client"));
+        }
+        let skew = match now.duration_since(time) {
+            Ok(skew) => skew,
+            Err(e) => e.duration(),
+        };
+        if skew > self.max_skew {
+            return Err(rejected("clock skew too great"));
+        }
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut replays = self.replays.lock().unwrap();
+        replays.retain(|_, expires| *expires > now);
+        let seen = (client.to_string(), seconds, microseconds);
+        if replays.contains_key(&seen) {
+            return Err(rejected("replayed"));
+        }
+        replays.insert(seen, time + self.max_skew);
+        Ok(client)
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.name.join("/"), self.realm)
+    }
+}
+
+impl Aes {
+    fn new(key: &[u8]) -> Aes {
+        match key.len() {
+            16 => Aes::Aes128(Box::new(Aes128::new(GenericArray::from_slice(key)))),
+            _ => Aes::Aes256(Box::new(Aes256::new(GenericArray::from_slice(key)))),
+        }
+    }
+
+    fn encrypt(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Aes::Aes128(aes) => aes.encrypt_block(block),
+            Aes::Aes256(aes) => aes.encrypt_block(block),
+        }
+    }
+
+    fn decrypt(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Aes::Aes128(aes) => aes.decrypt_block(block),
+            Aes::Aes256(aes) => aes.decrypt_block(block),
+        }
+    }
+}
+
+/// The Kerberos message inside the GSS-API framing, which for SPNEGO is inside the initial token
+/// of the first mechanism the client proposes. Clients that want something other than Kerberos
+/// first, like NTLM, aren't taken, as they have no ticket to check.
+fn unwrap_token(token: &[u8]) -> Option<&[u8]> {
+    let (framed, _) = expect(0x60, token)?;
+    let (mechanism, inner) = expect(0x06, framed)?;
+    if mechanism != OID_SPNEGO {
+        return unwrap_krb5(token);
+    }
+    let (init, _) = expect(0xa0, inner)?;
+    let (fields, _) = expect(0x30, init)?;
+    let (mechanisms, fields) = expect(0xa0, fields)?;
+    let (mechanisms, _) = expect(0x30, mechanisms)?;
+    let (first, _) = expect(0x06, mechanisms)?;
+    if first != OID_KRB5 && first != OID_KRB5_MICROSOFT {
+        return None;
+    }
+    let (_, fields) = optional(0xa1, fields);
+    let (mechanism_token, _) = expect(0xa2, fields)?;
+    let (mechanism_token, _) = expect(0x04, mechanism_token)?;
+    unwrap_krb5(mechanism_token)
+}
+
+fn unwrap_krb5(token: &[u8]) -> Option<&[u8]> {
+    let (framed, _) = expect(0x60, token)?;
+    let (mechanism, inner) = expect(0x06, framed)?;
+    if mechanism != OID_KRB5 && mechanism != OID_KRB5_MICROSOFT {
+        return None;
+    }
+    // The token ID of an AP-REQ.
+    inner.strip_prefix(&[0x01, 0x00])
+}
+
+/// The service the ticket is for and its encrypted part, with the encryption type and the version
+/// of the key if it's given.
+fn ticket_header(ticket: &[u8]) -> Option<(Principal, i32, Option<u32>, &[u8])> {
+    let (ticket, _) = expect(0x61, ticket)?;
+    let (fields, _) = expect(0x30, ticket)?;
+    let (_, fields) = expect(0xa0, fields)?;
+    let (realm, fields) = expect(0xa1, fields)?;
+    let (name, fields) = expect(0xa2, fields)?;
+    let (encrypted, _) = expect(0xa3, fields)?;
+    let service = Principal {
+        name: principal_name(name)?,
+        realm: string(realm)?,
+    };
+    let (enctype, version, cipher) = encrypted_data(encrypted)?;
+    Some((service, enctype, version, cipher))
+}
+
+fn ticket_part(ticket: &[u8]) -> Option<TicketPart> {
+    let (ticket, _) = expect(0x63, ticket)?;
+    let (fields, _) = expect(0x30, ticket)?;
+    let (flags, fields) = expect(0xa0, fields)?;
+    let (flags, _) = expect(0x03, flags)?;
+    // The invalid flag, of postdated tickets that haven't been validated with the KDC yet.
+    match flags.get(1) {
+        Some(flags) if flags & 0x01 == 0 => (),
+        _ => return None,
+    }
+    let (key, fields) = expect(0xa1, fields)?;
+    let (key, _) = expect(0x30, key)?;
+    let (key_type, key) = expect(0xa0, key)?;
+    let (key, _) = expect(0xa1, key)?;
+    let (key, _) = expect(0x04, key)?;
+    let (realm, fields) = expect(0xa2, fields)?;
+    let (name, fields) = expect(0xa3, fields)?;
+    let (_, fields) = expect(0xa4, fields)?;
+    let (auth_time, fields) = expect(0xa5, fields)?;
+    let (start_time, fields) = optional(0xa6, fields);
+    let (end_time, _) = expect(0xa7, fields)?;
+    Some(TicketPart {
+        key_type: integer(key_type)?.try_into().ok()?,
+        key,
+        client: Principal {
+            name: principal_name(name)?,
+            realm: string(realm)?,
+        },
+        start: time(start_time.unwrap_or(auth_time))?,
+        end: time(end_time)?,
+    })
+}
+
+/// The client the authenticator was made by and when, down to the microsecond.
+fn authenticator_part(authenticator: &[u8]) -> Option<(Principal, SystemTime, u32)> {
+    let (authenticator, _) = expect(0x62, authenticator)?;
+    let (fields, _) = expect(0x30, authenticator)?;
+    let (_, fields) = expect(0xa0, fields)?;
+    let (realm, fields) = expect(0xa1, fields)?;
+    let (name, fields) = expect(0xa2, fields)?;
+    let (_, fields) = optional(0xa3, fields);
+    let (microseconds, fields) = expect(0xa4, fields)?;
+    let (client_time, _) = expect(0xa5, fields)?;
+    let client = Principal {
+        name: principal_name(name)?,
+        realm: string(realm)?,
+    };
+    let microseconds = integer(microseconds)?.try_into().ok()?;
+    Some((client, time(client_time)?, microseconds))
+}
+
+fn encrypted_data(encrypted: &[u8]) -> Option<(i32, Option<u32>, &[u8])> {
+    let (fields, _) = expect(0x30, encrypted)?;
+    let (enctype, fields) = expect(0xa0, fields)?;
+    let (version, fields) = optional(0xa1, fields);
+    let (cipher, _) = expect(0xa2, fields)?;
+    let (cipher, _) = expect(0x04, cipher)?;
+    let version = match version {
+        Some(version) => Some(integer(version)?.try_into().ok()?),
+        None => None,
+    };
+    Some((integer(enctype)?.try_into().ok()?, version, cipher))
+}
+
+fn principal_name(name: &[u8]) -> Option<Vec<String>> {
+    let (fields, _) = expect(0x30, name)?;
+    let (_, fields) = expect(0xa0, fields)?;
+    let (components, _) = expect(0xa1, fields)?;
+    let (mut components, _) = expect(0x30, components)?;
+    let mut name = Vec::new();
+    while !components.is_empty() {
+        let (component, rest) = expect(0x1b, components)?;
+        name.push(String::from_utf8(component.to_vec()).ok()?);
+        components = rest;
+    }
+    Some(name)
+}
+
+/// A realm or other string, wrapped in the explicit tag of its field.
+fn string(field: &[u8]) -> Option<String> {
+    let (value, _) = expect(0x1b, field)?;
+    String::from_utf8(value.to_vec()).ok()
+}
+
+fn integer(field: &[u8]) -> Option<i64> {
+    let (value, _) = expect(0x02, field)?;
+    if value.is_empty() || value.len() > 8 {
+        return None;
+    }
+    let sign = if value[0] & 0x80 != 0 { -1 } else { 0 };
+    Some(
+        value
+            .iter()
+            .fold(sign, |integer, &byte| integer << 8 | byte as i64),
+    )
+}
+
+/// A time like `20221014123456Z`, which Kerberos always has in UTC without fractions.
+fn time(field: &[u8]) -> Option<SystemTime> {
+    let (value, _) = expect(0x18, field)?;
+    let value = std::str::from_utf8(value).ok()?.strip_suffix('Z')?;
+    if value.len() != 14 || !value.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| value[range].parse::<i64>().unwrap();
+    let (year, month, day) = (number(0..4), number(4..6), number(6..8));
+    let (hour, minute, second) = (number(8..10), number(10..12), number(12..14));
+    // Converts a civil date to days since the epoch, from Howard Hinnant's date algorithms.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds.try_into().ok()?))
+}
+
+/// Entries of a keytab in the format of MIT Kerberos, which is what `ktpass` and `ktutil` make.
+fn parse_keytab(keytab: &[u8]) -> Option<Vec<ServiceKey>> {
+    // Only the second version is around anymore, which has the numbers in big endian.
+    let mut keytab = keytab.strip_prefix(&[0x05, 0x02])?;
+    let mut keys = Vec::new();
+    while !keytab.is_empty() {
+        let length = i32::from_be_bytes(take(&mut keytab, 4)?.try_into().unwrap());
+        // Deleted entries leave holes of negative length behind.
+        if length < 0 {
+            take(&mut keytab, length.unsigned_abs() as usize)?;
+            continue;
+        }
+        let mut entry = take(&mut keytab, length as usize)?;
+        let components = u16::from_be_bytes(take(&mut entry, 2)?.try_into().unwrap());
+        let realm = String::from_utf8(counted(&mut entry)?.to_vec()).ok()?;
+        let name = (0..components)
+            .map(|_| counted(&mut entry).and_then(|c| String::from_utf8(c.to_vec()).ok()))
+            .collect::<Option<_>>()?;
+        // The name type and the time the key was written.
+        take(&mut entry, 8)?;
+        let short_version = take(&mut entry, 1)?[0];
+        let enctype = u16::from_be_bytes(take(&mut entry, 2)?.try_into().unwrap());
+        let key = counted(&mut entry)?.to_vec();
+        // The version past the key is the full one, as the first only has the lowest 8 bits.
+        let version = match take(&mut entry, 4) {
+            Some(version) if version != [0; 4] => u32::from_be_bytes(version.try_into().unwrap()),
+            _ => short_version as u32,
+        };
+        keys.push(ServiceKey {
+            principal: Principal { name, realm },
+            version,
+            enctype: enctype as i32,
+            key,
+        });
+    }
+    Some(keys)
+}
+
+fn counted<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let length = u16::from_be_bytes(take(input, 2)?.try_into().unwrap());
+    take(input, length as usize)
+}
+
+fn key_length(enctype: i32) -> Option<usize> {
+    match enctype {
+        ENCTYPE_AES128 => Some(16),
+        ENCTYPE_AES256 => Some(32),
+        _ => None,
+    }
+}
+
+/// Decrypts as in RFC 3962, checking the HMAC-SHA1-96 of the confounder and the plaintext that
+/// comes after the ciphertext.
+fn decrypt(enctype: i32, key: &[u8], usage: u32, ciphertext: &[u8]) -> Option<Vec<u8>> {
+    if key_length(enctype) != Some(key.len()) || ciphertext.len() < 16 + 12 {
+        return None;
+    }
+    let (ciphertext, mac) = ciphertext.split_at(ciphertext.len() - 12);
+    let plaintext = cts_decrypt(&derive_key(key, usage, 0xaa), ciphertext);
+    let expected = hmac_sha1(&derive_key(key, usage, 0x55), &plaintext);
+    if !constant_time_eq(&expected[..12], mac) {
+        return None;
+    }
+    Some(plaintext[16..].to_vec())
+}
+
+/// Encrypts like the KDC and clients do, for tests to make tickets with.
+#[cfg(test)]
+pub fn encrypt(key: &[u8], usage: u32, plaintext: &[u8]) -> Vec<u8> {
+    use argon2::password_hash::rand_core::{OsRng, RngCore};
+    let mut confounded = vec![0; 16];
+    OsRng.fill_bytes(&mut confounded);
+    confounded.extend_from_slice(plaintext);
+    let mut ciphertext = cts_encrypt(&derive_key(key, usage, 0xaa), &confounded);
+    ciphertext.extend_from_slice(&hmac_sha1(&derive_key(key, usage, 0x55), &confounded)[..12]);
+    ciphertext
+}
+
+/// The key for one usage and purpose, which is either encryption or integrity, derived from the
+/// base key as DK in RFC 3961.
+fn derive_key(key: &[u8], usage: u32, purpose: u8) -> Vec<u8> {
+    let aes = Aes::new(key);
+    let mut constant = usage.to_be_bytes().to_vec();
+    constant.push(purpose);
+    let mut block = n_fold(&constant, 16);
+    let mut derived = Vec::with_capacity(key.len());
+    while derived.len() < key.len() {
+        aes.encrypt(&mut block);
+        derived.extend_from_slice(&block);
+    }
+    derived.truncate(key.len());
+    derived
+}
+
+/// Stretches or folds the input to the size in bytes, as in RFC 3961. Copies of the input, each
+/// rotated 13 bits to the right more than the last, are added up in ones' complement.
+pub fn n_fold(input: &[u8], size: usize) -> Vec<u8> {
+    let (input_bits, length) = (input.len() * 8, input.len());
+    let lcm = size * length / gcd(size, length);
+    let mut output = vec![0u8; size];
+    let mut carry = 0u32;
+    for i in (0..lcm).rev() {
+        let msbit = (input_bits - 1 + (input_bits + 13) * (i / length) + (length - i % length) * 8)
+            % input_bits;
+        let high = input[(length - 1 - (msbit >> 3)) % length] as u32;
+        let low = input[(length - (msbit >> 3)) % length] as u32;
+        carry += ((high << 8 | low) >> ((msbit & 7) + 1)) & 0xff;
+        carry += output[i % size] as u32;
+        output[i % size] = carry as u8;
+        carry >>= 8;
+    }
+    // The carry out of the top goes back in at the bottom.
+    if carry != 0 {
+        for byte in output.iter_mut().rev() {
+            carry += *byte as u32;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+    }
+    output
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// AES in CBC mode with ciphertext stealing, and an IV of zeros, as in RFC 3962. The last two
+/// blocks are always swapped, even when the last one is full.
+fn cts_decrypt(key: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let aes = Aes::new(key);
+    let mut plaintext = ciphertext.to_vec();
+    if ciphertext.len() == 16 {
+        aes.decrypt(&mut plaintext);
+        return plaintext;
+    }
+    let full = (ciphertext.len() - 1) / 16 * 16 - 16;
+    let mut previous = [0; 16];
+    for (block, encrypted) in plaintext[..full]
+        .chunks_mut(16)
+        .zip(ciphertext[..full].chunks(16))
+    {
+        aes.decrypt(block);
+        block.iter_mut().zip(&previous).for_each(|(b, p)| *b ^= p);
+        previous.copy_from_slice(encrypted);
+    }
+    let last = &ciphertext[full + 16..];
+    let mut decrypted = ciphertext[full..full + 16].to_vec();
+    aes.decrypt(&mut decrypted);
+    // The last block was encrypted with the end of the one before it as padding, which is what
+    // the stolen bytes are put back together with.
+    let mut stolen = last.to_vec();
+    stolen.extend_from_slice(&decrypted[last.len()..]);
+    aes.decrypt(&mut stolen);
+    stolen.iter_mut().zip(&previous).for_each(|(b, p)| *b ^= p);
+    plaintext[full..full + 16].copy_from_slice(&stolen);
+    for (i, byte) in last.iter().enumerate() {
+        plaintext[full + 16 + i] = decrypted[i] ^ byte;
+    }
+    plaintext
+}
+
+#[cfg(test)]
+pub fn cts_encrypt(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let aes = Aes::new(key);
+    let mut padded = plaintext.to_vec();
+    padded.resize((plaintext.len() + 15) / 16 * 16, 0);
+    let mut previous = [0; 16];
+    for block in padded.chunks_mut(16) {
+        block.iter_mut().zip(&previous).for_each(|(b, p)| *b ^= p);
+        aes.encrypt(block);
+        previous.copy_from_slice(block);
+    }
+    if plaintext.len() == 16 {
+        return padded;
+    }
+    let split = padded.len() - 32;
+    let (second_last, last) = padded[split..].split_at(16);
+    let mut ciphertext = padded[..split].to_vec();
+    ciphertext.extend_from_slice(last);
+    ciphertext.extend_from_slice(&second_last[..plaintext.len() - split - 16]);
+    ciphertext
+}
+
+/// HMAC with SHA-1, which Kerberos still checks integrity with.
+fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
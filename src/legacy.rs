@@ -0,0 +1,167 @@
+use crate::error::Error;
+use crate::util::constant_time_eq;
+use hmac::Hmac;
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use std::backtrace::Backtrace;
+
+/// Prefix of the PBKDF2 hashes Django makes, `pbkdf2_sha256$<iterations>$<salt>$<hash>` with the
+/// hash in base64. Passwords of users imported from it are checked against them until they log in
+/// and get them rehashed, see [`crate::user::upgrade_password_hash`].
+const DJANGO_PBKDF2_SHA256: &str = "pbkdf2_sha256$";
+
+/// Prefixes of bcrypt hashes, `$2y$` being what PHP's `password_hash` makes and the others what
+/// OpenBSD and most libraries do, which differ in bugs of old implementations rather than in the
+/// hashes a correct one makes.
+const BCRYPT: &[&str] = &["$2a$", "$2b$", "$2x$", "$2y$"];
+
+/// Prefixes of phpass hashes, `$P$` of WordPress and `$H$` of phpBB. The character after it tells
+/// the log2 of the number of MD5 rounds, then come 8 characters of salt and the hash.
+const PHPASS: &[&str] = &["$P$", "$H$"];
+
+/// What phpass encodes the count, salt and hash with, in this order rather than base64's.
+const PHPASS_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+const PHPASS_SETTING_LENGTH: usize = 12;
+
+/// Length of the 16 bytes of an MD5 hash, encoded.
+const PHPASS_HASH_LENGTH: usize = 22;
+
+/// Bounds phpass itself puts on the count, the lower one keeping the hash from being too quick to
+/// brute-force and the upper one from taking forever to check.
+const PHPASS_MIN_COUNT_LOG2: usize = 7;
+const PHPASS_MAX_COUNT_LOG2: usize = 30;
+
+#[derive(Clone, Copy)]
+enum Scheme {
+    DjangoPbkdf2Sha256,
+    Bcrypt,
+    Phpass,
+}
+
+/// Whether the hash is one made by another system that passwords can still be checked against.
+pub fn is_legacy(password_phc: &str) -> bool {
+    scheme(password_phc).is_some()
+}
+
+/// Checks that the hash is a legacy one that can be verified against, for hashes being imported.
+pub fn check(password_phc: &str) -> Result<(), Error> {
+    match scheme(password_phc) {
+        Some(Scheme::DjangoPbkdf2Sha256) => parse_django(password_phc).map(|_| ()),
+        Some(Scheme::Bcrypt) => password_phc
+            .parse::<bcrypt::HashParts>()
+            .map(|_| ())
+            .map_err(|_| invalid()),
+        Some(Scheme::Phpass) => parse_phpass(password_phc).map(|_| ()),
+        None => Err(invalid()),
+    }
+}
+
+/// Takes as long as the hash has iterations, which can be a lot, so it's better not run on the
+/// async workers.
+pub fn verify(password: &str, password_phc: &str) -> Result<(), Error> {
+    let matches = match scheme(password_phc) {
+        Some(Scheme::DjangoPbkdf2Sha256) => {
+            let (iterations, salt, expected) = parse_django(password_phc)?;
+            let mut hash = vec![0; expected.len()];
+            pbkdf2::pbkdf2::<Hmac<Sha256>>(
+                password.as_bytes(),
+                salt.as_bytes(),
+                iterations,
+                &mut hash,
+            );
+            constant_time_eq(&hash, &expected)
+        }
+        Some(Scheme::Bcrypt) => bcrypt::verify(password, password_phc).map_err(|_| invalid())?,
+        Some(Scheme::Phpass) => {
+            let (setting, count_log2, expected) = parse_phpass(password_phc)?;
+            // The salt follows the prefix and the count.
+            let salt = &setting[4..];
+            let mut hash = Md5::new().chain(salt).chain(password).finalize();
+            for _ in 0..1u32 << count_log2 {
+                hash = Md5::new().chain(hash).chain(password).finalize();
+            }
+            constant_time_eq(phpass_encode(&hash).as_bytes(), expected.as_bytes())
+        }
+        None => return Err(invalid()),
+    };
+    if !matches {
+        return Err(Error::WrongPassword(Backtrace::capture()));
+    }
+    Ok(())
+}
+
+fn scheme(password_phc: &str) -> Option<Scheme> {
+    let has_prefix = |prefixes: &[&str]| {
+        prefixes
+            .iter()
+            .any(|prefix| password_phc.starts_with(prefix))
+    };
+    if password_phc.starts_with(DJANGO_PBKDF2_SHA256) {
+        Some(Scheme::DjangoPbkdf2Sha256)
+    } else if has_prefix(BCRYPT) {
+        Some(Scheme::Bcrypt)
+    } else if has_prefix(PHPASS) {
+        Some(Scheme::Phpass)
+    } else {
+        None
+    }
+}
+
+fn parse_django(password_phc: &str) -> Result<(u32, &str, Vec<u8>), Error> {
+    let rest = password_phc
+        .strip_prefix(DJANGO_PBKDF2_SHA256)
+        .ok_or_else(invalid)?;
+    let mut parts = rest.split('$');
+    let (Some(iterations), Some(salt), Some(hash), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let iterations = iterations.parse().map_err(|_| invalid())?;
+    let hash = base64::decode(hash).map_err(|_| invalid())?;
+    if iterations == 0 || salt.is_empty() || hash.is_empty() {
+        return Err(invalid());
+    }
+    Ok((iterations, salt, hash))
+}
+
+/// Splits the hash into the setting, which is everything up to and including the salt, the log2
+/// of the number of rounds, and the hash itself.
+fn parse_phpass(password_phc: &str) -> Result<(&str, usize, &str), Error> {
+    let valid = password_phc.len() == PHPASS_SETTING_LENGTH + PHPASS_HASH_LENGTH
+        && password_phc
+            .bytes()
+            .all(|c| c == b'$' || PHPASS_ALPHABET.contains(&c));
+    if !valid {
+        return Err(invalid());
+    }
+    let (setting, hash) = password_phc.split_at(PHPASS_SETTING_LENGTH);
+    let count_log2 = PHPASS_ALPHABET
+        .iter()
+        .position(|&c| c == setting.as_bytes()[3])
+        .ok_or_else(invalid)?;
+    if !(PHPASS_MIN_COUNT_LOG2..=PHPASS_MAX_COUNT_LOG2).contains(&count_log2) {
+        return Err(invalid());
+    }
+    Ok((setting, count_log2, hash))
+}
+
+/// Encodes 6 bits at a time starting from the least significant ones, taking the bytes 3 at a time
+/// in little-endian order.
+fn phpass_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let value = chunk
+            .iter()
+            .rev()
+            .fold(0u32, |value, &byte| value << 8 | u32::from(byte));
+        for i in 0..=chunk.len() {
+            encoded.push(PHPASS_ALPHABET[(value >> (6 * i)) as usize & 0x3f] as char);
+        }
+    }
+    encoded
+}
+
+fn invalid() -> Error {
+    Error::InvalidPasswordHash(Backtrace::capture())
+}
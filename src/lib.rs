@@ -0,0 +1,1914 @@
+#![feature(backtrace, let_else, once_cell)]
+
+mod anomaly;
+mod api;
+pub mod audit;
+mod backchannel;
+mod bot;
+pub mod claims;
+mod cleanup;
+mod client;
+mod client_cert;
+pub mod crypto;
+mod database;
+mod der;
+mod disposable;
+pub mod error;
+pub mod events;
+pub mod export;
+pub mod features;
+mod flash;
+mod graphql;
+mod grpc;
+mod handlers;
+mod i18n;
+pub mod jobs;
+mod kerberos;
+mod legacy;
+mod mail;
+mod memory;
+pub mod middleware;
+mod migrations;
+mod mode;
+pub mod notifications;
+pub mod oauth;
+pub mod otp;
+pub mod plugins;
+mod postgres;
+pub mod quota;
+mod risk;
+pub mod routes;
+mod sentry;
+pub mod server;
+pub mod session;
+mod sms;
+mod sqlite;
+mod sso;
+pub mod store;
+mod systemd;
+pub mod templates;
+pub mod tenant;
+mod terms;
+#[cfg(test)]
+mod tests;
+mod tls;
+mod totp;
+mod transfer;
+pub mod user;
+mod util;
+
+pub use crate::crypto::Crypto;
+pub use crate::error::Error;
+pub use crate::server::{App, Server};
+pub use crate::session::Session;
+pub use crate::store::Store;
+
+use crate::audit::AuditEvent;
+use crate::bot::BotPolicy;
+use crate::claims::{Claims, ClaimsPolicy};
+use crate::client::{ClientInfo, NewDevice, RemoteAddr};
+use crate::client_cert::{ClientCertPolicy, PeerCertificate};
+use crate::disposable::DisposablePolicy;
+use crate::events::EventSink;
+use crate::features::{FeaturePolicy, Features};
+use crate::flash::Flash;
+use crate::handlers::{Api, ApiHandler, Endpoint, EndpointHandler, Page, PageHandler};
+use crate::kerberos::KerberosPolicy;
+use crate::mail::Mailer;
+use crate::middleware::{Middleware, SecurityHeaders};
+use crate::mode::{Mode, ModeSwitch};
+use crate::notifications::NotificationPolicy;
+use crate::plugins::Plugin;
+use crate::quota::QuotaPolicy;
+use crate::risk::RiskPolicy;
+use crate::routes::{Access, MatchedPattern};
+use crate::sentry::Sentry;
+use crate::server::{Route, ServerFuture};
+use crate::session::CookiePolicy;
+use crate::sms::SmsSender;
+use crate::sso::SsoPolicy;
+use crate::templates::Templates;
+use crate::tenant::{Tenant, TenantPrefix, Tenants};
+use crate::terms::TermsPolicy;
+use crate::tls::TlsStream;
+use crate::user::{AccountStatus, User};
+use crate::util::{
+    env_duration_ms, env_duration_ms_opt, env_flag, env_var, env_var_opt, is_local_path,
+};
+use cookie::Cookie;
+use hyper::header::{ALLOW, CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE};
+use hyper::http::uri::Scheme;
+use hyper::server::conn::AddrStream;
+use hyper::server::conn::Http;
+use hyper::service::{make_service_fn, service_fn, Service};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use slog::{error, info, o, Logger};
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::lazy::SyncLazy;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    next: Option<String>,
+}
+
+/// Everything about how the service behaves that isn't stored, mostly read from the environment
+/// and otherwise changed through the [`Server`] builder.
+pub struct Config {
+    timeouts: Timeouts,
+    /// How long a password is good for before it has to be changed, if it ever has to be.
+    password_max_age: Option<Duration>,
+    bot: BotPolicy,
+    risk: RiskPolicy,
+    /// Users allowed to impersonate others, from `ADMIN_USER_IDS`.
+    admins: Vec<User>,
+    terms: TermsPolicy,
+    /// Whether new accounts wait for an admin to approve them before they can log in, from
+    /// `REGISTRATION_APPROVAL`.
+    approval: bool,
+    /// Shared with the thread reloading the list of domains.
+    disposable: Arc<DisposablePolicy>,
+    quotas: QuotaPolicy,
+    features: FeaturePolicy,
+    /// Shared with the thread watching the mode file.
+    mode: Arc<ModeSwitch>,
+    tenants: Tenants,
+    claims: ClaimsPolicy,
+    cookies: CookiePolicy,
+    sso: SsoPolicy,
+    /// How old a session cookie gets before it's replaced with one of a new token ID, from
+    /// `SESSION_ROTATION_INTERVAL_MS`, so that a copy of it stops working a while after it was
+    /// taken rather than when the session expires.
+    session_rotation: Duration,
+    /// Path everything is served under, see [`Server::mount`].
+    mount: Option<String>,
+    /// Routes of the program embedding the service, see [`Server::route`].
+    routes: Vec<Route>,
+    middleware: Vec<Box<dyn Middleware>>,
+    plugins: Vec<Arc<dyn Plugin>>,
+    /// Where internal errors are reported, see [`Sentry`].
+    sentry: Option<Arc<Sentry>>,
+    /// Whether account lifecycle events are published, which is when `EVENTS_PUBLISHER` is set,
+    /// see [`events`].
+    publish_events: bool,
+    /// Connections are served over TLS when it's set, see [`tls::server_config_from_env`].
+    tls: Option<Arc<rustls::ServerConfig>>,
+    client_certs: ClientCertPolicy,
+    /// Single sign-on with the tickets of a Kerberos realm, when `KERBEROS_KEYTAB` is set, which
+    /// only works right on a single instance, see [`KerberosPolicy::from_env`].
+    kerberos: Option<KerberosPolicy>,
+    notifications: NotificationPolicy,
+}
+
+#[derive(Clone, Copy)]
+struct Timeouts {
+    body: Duration,
+    handler: Duration,
+}
+
+#[derive(Serialize)]
+struct Ctx<'a> {
+    user: Option<CtxUser>,
+    /// Admin impersonating the user, which pages show a banner about by including
+    /// `impersonation.html`.
+    impersonator: Option<CtxUser>,
+    flash: Option<Flash>,
+    /// Page to return to once logged in, passed along by the forms.
+    next: Option<String>,
+    lang: &'static str,
+    locales: Vec<i18n::LocaleInfo>,
+    /// Which features are switched on, so that pages can leave out forms for the ones that aren't.
+    features: &'a Features,
+    tenant: CtxTenant<'a>,
+}
+
+#[derive(Serialize)]
+struct CtxUser {
+    id: i32,
+    /// Claims sealed into the session, see [`claims`]. Impersonators are shown without theirs.
+    claims: Claims,
+}
+
+#[derive(Serialize)]
+struct CtxTenant<'a> {
+    id: &'a str,
+    name: &'a str,
+    branding: &'a serde_json::Map<String, serde_json::Value>,
+    /// Path prefix the tenant is under, which links and forms start with, or nothing at its own
+    /// host.
+    base: &'a str,
+}
+
+static METRIC_HTTP_REQUEST_COUNT: SyncLazy<IntCounterVec> = SyncLazy::new(|| {
+    register_int_counter_vec!(
+        "authtown_http_request_count",
+        "Number of HTTP requests received",
+        &["method", "endpoint", "status"]
+    )
+    .unwrap()
+});
+static METRIC_HTTP_REQUEST_LATENCY: SyncLazy<HistogramVec> = SyncLazy::new(|| {
+    register_histogram_vec!(
+        "authtown_http_request_latency",
+        "Latency of HTTP requests in seconds",
+        &["method", "endpoint", "status"]
+    )
+    .unwrap()
+});
+
+/// Endpoints for other services, which are answered before looking at the session.
+const SERVICE_ROUTES: routes::Table<EndpointHandler> = &[
+    (Method::GET, "/auth/check", Access::Read, |req, endpoint| {
+        Box::pin(handlers::auth::forward_auth(req, endpoint))
+    }),
+    (
+        Method::GET,
+        "/auth/whoami",
+        Access::Read,
+        |req, endpoint| Box::pin(handlers::auth::whoami(req, endpoint)),
+    ),
+    (
+        Method::GET,
+        "/oauth/logout",
+        Access::Read,
+        |req, endpoint| Box::pin(handlers::oauth::oauth_logout(req, endpoint)),
+    ),
+    (
+        Method::POST,
+        "/oauth/logout",
+        Access::Read,
+        |req, endpoint| Box::pin(handlers::oauth::oauth_logout(req, endpoint)),
+    ),
+];
+
+/// Pages, which [`router`] handles once the endpoints for other services are out of the way.
+const PAGE_ROUTES: routes::Table<PageHandler> = &[
+    (Method::GET, "/", Access::Read, |req, page| {
+        Box::pin(handlers::auth::index(req, page))
+    }),
+    (
+        Method::POST,
+        "/auth/register",
+        Access::Write("/"),
+        |req, page| Box::pin(handlers::auth::register_form(req, page)),
+    ),
+    (Method::POST, "/auth/login", Access::Read, |req, page| {
+        Box::pin(handlers::auth::login_form(req, page))
+    }),
+    (Method::GET, "/auth/negotiate", Access::Read, |req, page| {
+        Box::pin(handlers::auth::negotiate(req, page))
+    }),
+    (Method::GET, "/auth/email", Access::Read, |req, page| {
+        Box::pin(handlers::auth::email_page(req, page))
+    }),
+    (Method::POST, "/auth/email", Access::Read, |req, page| {
+        Box::pin(handlers::auth::email_form(req, page))
+    }),
+    (
+        Method::POST,
+        "/auth/email/code",
+        Access::Read,
+        |req, page| Box::pin(handlers::auth::email_code_form(req, page)),
+    ),
+    (Method::GET, "/auth/sms", Access::Read, |req, page| {
+        Box::pin(handlers::auth::challenge_page(req, page))
+    }),
+    (Method::POST, "/auth/sms", Access::Read, |req, page| {
+        Box::pin(handlers::auth::challenge_form(req, page))
+    }),
+    (
+        Method::POST,
+        "/auth/sms/resend",
+        Access::Read,
+        |req, page| Box::pin(handlers::auth::resend_form(req, page)),
+    ),
+    (Method::GET, "/auth/terms", Access::Read, |req, page| {
+        Box::pin(handlers::auth::terms_page(req, page))
+    }),
+    (Method::POST, "/auth/terms", Access::Read, |req, page| {
+        Box::pin(handlers::auth::terms_form(req, page))
+    }),
+    (Method::GET, "/settings/sms", Access::Read, |req, page| {
+        Box::pin(handlers::settings::phone_page(req, page))
+    }),
+    (
+        Method::POST,
+        "/settings/sms",
+        Access::Credentials("/settings/sms"),
+        |req, page| Box::pin(handlers::settings::phone_form(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/sms/verify",
+        Access::Credentials("/settings/sms"),
+        |req, page| Box::pin(handlers::settings::phone_verify_form(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/sms/remove",
+        Access::Credentials("/settings/sms"),
+        |req, page| Box::pin(handlers::settings::phone_remove_form(req, page)),
+    ),
+    (Method::GET, "/settings/2fa", Access::Read, |req, page| {
+        Box::pin(handlers::settings::totp_page(req, page))
+    }),
+    (
+        Method::GET,
+        "/settings/2fa/qr",
+        Access::Read,
+        |req, page| Box::pin(handlers::settings::totp_qr(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/2fa",
+        Access::Credentials("/settings/2fa"),
+        |req, page| Box::pin(handlers::settings::totp_form(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/2fa/verify",
+        Access::Credentials("/settings/2fa"),
+        |req, page| Box::pin(handlers::settings::totp_verify_form(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/2fa/remove",
+        Access::Credentials("/settings/2fa"),
+        |req, page| Box::pin(handlers::settings::totp_remove_form(req, page)),
+    ),
+    (
+        Method::GET,
+        "/settings/methods",
+        Access::Read,
+        |req, page| Box::pin(handlers::settings::methods_page(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/methods/email",
+        Access::Credentials("/settings/methods"),
+        |req, page| Box::pin(handlers::settings::email_method_form(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/methods/email/remove",
+        Access::Credentials("/settings/methods"),
+        |req, page| Box::pin(handlers::settings::email_method_remove_form(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/methods/password/remove",
+        Access::Credentials("/settings/methods"),
+        |req, page| Box::pin(handlers::settings::password_method_remove_form(req, page)),
+    ),
+    (
+        Method::GET,
+        "/settings/password",
+        Access::Read,
+        |req, page| Box::pin(handlers::settings::password_page(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/password",
+        Access::Credentials("/settings/password"),
+        |req, page| Box::pin(handlers::settings::password_form(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/methods/identities/unlink",
+        Access::Credentials("/settings/methods"),
+        |req, page| Box::pin(handlers::settings::unlink_identity_form(req, page)),
+    ),
+    (
+        Method::GET,
+        "/oauth/authorize",
+        Access::Read,
+        |req, page| Box::pin(handlers::oauth::authorize_page(req, page)),
+    ),
+    (
+        Method::POST,
+        "/oauth/authorize",
+        Access::Write("/"),
+        |req, page| Box::pin(handlers::oauth::consent_form(req, page)),
+    ),
+    (
+        Method::GET,
+        "/settings/applications",
+        Access::Read,
+        |req, page| Box::pin(handlers::settings::applications_page(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/applications/revoke",
+        Access::Write("/settings/applications"),
+        |req, page| Box::pin(handlers::settings::revoke_application_form(req, page)),
+    ),
+    (
+        Method::GET,
+        "/settings/activity",
+        Access::Read,
+        |req, page| Box::pin(handlers::settings::activity_page(req, page)),
+    ),
+    (
+        Method::GET,
+        "/settings/notifications",
+        Access::Read,
+        |req, page| Box::pin(handlers::settings::notifications_page(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/notifications",
+        Access::Write("/settings/notifications"),
+        |req, page| Box::pin(handlers::settings::notifications_form(req, page)),
+    ),
+    (Method::GET, "/unsubscribe", Access::Read, |req, page| {
+        Box::pin(handlers::settings::unsubscribe_page(req, page))
+    }),
+    (
+        Method::POST,
+        "/unsubscribe",
+        Access::Write("/"),
+        |req, page| Box::pin(handlers::settings::unsubscribe_form(req, page)),
+    ),
+    (
+        Method::GET,
+        "/settings/export",
+        Access::Read,
+        |req, page| Box::pin(handlers::settings::export_page(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/export",
+        Access::Credentials("/settings/export"),
+        |req, page| Box::pin(handlers::settings::export_form(req, page)),
+    ),
+    (
+        Method::GET,
+        "/settings/export/download",
+        Access::Read,
+        |req, page| Box::pin(handlers::settings::export_download(req, page)),
+    ),
+    (
+        Method::POST,
+        "/settings/language",
+        Access::Read,
+        |req, page| Box::pin(handlers::settings::language_form(req, page)),
+    ),
+    (
+        Method::GET,
+        "/admin/impersonate",
+        Access::Read,
+        |req, page| Box::pin(handlers::admin::impersonate_page(req, page)),
+    ),
+    (
+        Method::POST,
+        "/admin/impersonate",
+        Access::Read,
+        |req, page| Box::pin(handlers::admin::impersonate_form(req, page)),
+    ),
+    (Method::GET, "/admin/status", Access::Read, |req, page| {
+        Box::pin(handlers::admin::status_page(req, page))
+    }),
+    (
+        Method::POST,
+        "/admin/status",
+        Access::Write("/admin/status"),
+        |req, page| Box::pin(handlers::admin::status_form(req, page)),
+    ),
+    (
+        Method::GET,
+        "/admin/registrations",
+        Access::Read,
+        |req, page| Box::pin(handlers::admin::registrations_page(req, page)),
+    ),
+    (
+        Method::POST,
+        "/admin/registrations/approve",
+        Access::Write("/admin/registrations"),
+        |req, page| Box::pin(handlers::admin::review_form(req, page)),
+    ),
+    (
+        Method::POST,
+        "/admin/registrations/reject",
+        Access::Write("/admin/registrations"),
+        |req, page| Box::pin(handlers::admin::review_form(req, page)),
+    ),
+    (
+        Method::GET,
+        "/admin/service-accounts",
+        Access::Read,
+        |req, page| Box::pin(handlers::admin::service_accounts_page(req, page)),
+    ),
+    (
+        Method::POST,
+        "/admin/service-accounts",
+        Access::Write("/admin/service-accounts"),
+        |req, page| Box::pin(handlers::admin::service_account_form(req, page)),
+    ),
+    (Method::GET, "/auth/suspended", Access::Read, |req, page| {
+        Box::pin(handlers::auth::suspended_page(req, page))
+    }),
+    (
+        Method::POST,
+        "/admin/impersonate/stop",
+        Access::Read,
+        |req, page| Box::pin(handlers::admin::stop_impersonating_form(req, page)),
+    ),
+    (Method::POST, "/auth/logout", Access::Read, |req, page| {
+        Box::pin(handlers::auth::logout_form(req, page))
+    }),
+    (Method::GET, "/readyz", Access::Read, |req, page| {
+        Box::pin(handlers::admin::readyz(req, page))
+    }),
+    (Method::GET, "/metrics", Access::Read, |req, page| {
+        Box::pin(handlers::admin::metrics(req, page))
+    }),
+];
+
+/// The API for the pages' own scripts, under [`api::PREFIX`].
+const API_ROUTES: routes::Table<ApiHandler> = &[
+    (
+        Method::POST,
+        "/auth/register",
+        Access::Write("/"),
+        |req, api| Box::pin(handlers::api::api_register(req, api)),
+    ),
+    (Method::POST, "/auth/login", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_log_in(req, api))
+    }),
+    (Method::POST, "/auth/email", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_send_login_code(req, api))
+    }),
+    (
+        Method::POST,
+        "/auth/email/code",
+        Access::Read,
+        |req, api| Box::pin(handlers::api::api_log_in_with_code(req, api)),
+    ),
+    (Method::POST, "/auth/sms", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_complete_challenge(req, api))
+    }),
+    (Method::POST, "/auth/terms", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_accept_terms(req, api))
+    }),
+    (
+        Method::POST,
+        "/auth/password",
+        Access::Credentials("/settings/password"),
+        |req, api| Box::pin(handlers::api::api_change_password(req, api)),
+    ),
+    (Method::POST, "/auth/logout", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_log_out(req, api))
+    }),
+    (Method::POST, "/graphql", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_graphql(req, api))
+    }),
+    (Method::GET, "/auth/session", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_session(req, api))
+    }),
+    (
+        Method::GET,
+        "/settings/activity",
+        Access::Read,
+        |req, api| Box::pin(handlers::api::api_activity(req, api)),
+    ),
+    (Method::GET, "/admin/features", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_features(req, api))
+    }),
+    (Method::POST, "/admin/features", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_set_feature(req, api))
+    }),
+    (Method::GET, "/admin/mode", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_mode(req, api))
+    }),
+    (Method::POST, "/admin/mode", Access::Read, |req, api| {
+        Box::pin(handlers::api::api_set_mode(req, api))
+    }),
+];
+
+/// The versioned API, under [`api::v1::PREFIX`].
+const API_V1_ROUTES: routes::Table<ApiHandler> = &[
+    (Method::POST, "/users", Access::Write("/"), |req, api| {
+        Box::pin(handlers::api::v1_register(req, api))
+    }),
+    (Method::POST, "/session", Access::Read, |req, api| {
+        Box::pin(handlers::api::v1_log_in(req, api))
+    }),
+    (Method::POST, "/session/sms", Access::Read, |req, api| {
+        Box::pin(handlers::api::v1_complete_challenge(req, api))
+    }),
+    (Method::POST, "/session/terms", Access::Read, |req, api| {
+        Box::pin(handlers::api::v1_accept_terms(req, api))
+    }),
+    (Method::GET, "/session", Access::Read, |req, api| {
+        Box::pin(handlers::api::v1_session(req, api))
+    }),
+    (Method::DELETE, "/session", Access::Read, |req, api| {
+        Box::pin(handlers::api::v1_log_out(req, api))
+    }),
+    (Method::GET, "/profile", Access::Read, |req, api| {
+        Box::pin(handlers::api::v1_profile(req, api))
+    }),
+];
+
+/// OAuth endpoints for clients, besides the authorization page.
+const OAUTH_ROUTES: routes::Table<EndpointHandler> = &[
+    (Method::GET, "/oauth/jwks", Access::Read, |req, endpoint| {
+        Box::pin(handlers::oauth::jwks(req, endpoint))
+    }),
+    (
+        Method::POST,
+        "/oauth/introspect",
+        Access::Read,
+        |req, endpoint| Box::pin(handlers::oauth::introspect(req, endpoint)),
+    ),
+    (
+        Method::POST,
+        "/oauth/token",
+        Access::Read,
+        |req, endpoint| Box::pin(handlers::oauth::token(req, endpoint)),
+    ),
+    (
+        Method::POST,
+        "/oauth/revoke",
+        Access::Read,
+        |req, endpoint| Box::pin(handlers::oauth::revoke_token(req, endpoint)),
+    ),
+];
+
+/// Routes a session with an expired password can still use, enough to change it or give up.
+const PASSWORD_EXPIRED_ROUTES: &[&str] = &[
+    "/settings/password",
+    "/settings/language",
+    "/auth/logout",
+    "/api/auth/password",
+    "/api/auth/logout",
+];
+
+/// Routes still answered in maintenance mode, enough for load balancers to check on the service
+/// and for admins to end the maintenance.
+const MAINTENANCE_ROUTES: &[&str] = &["/readyz", "/metrics", "/api/admin/mode"];
+
+impl Config {
+    pub fn from_env() -> Result<Config, Error> {
+        let sso = SsoPolicy::from_env()?;
+        Ok(Config {
+            timeouts: Timeouts::from_env()?,
+            password_max_age: env_duration_ms_opt("PASSWORD_MAX_AGE_MS")?,
+            bot: BotPolicy::from_env()?,
+            risk: RiskPolicy::from_env()?,
+            admins: match env_var_opt("ADMIN_USER_IDS")? {
+                Some(ids) => ids
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|id| !id.is_empty())
+                    .map(|id| Ok(User { id: id.parse()? }))
+                    .collect::<Result<_, Error>>()?,
+                None => Vec::new(),
+            },
+            terms: TermsPolicy::from_env()?,
+            approval: env_flag("REGISTRATION_APPROVAL")?,
+            disposable: Arc::new(DisposablePolicy::from_env()?),
+            quotas: QuotaPolicy::from_env()?,
+            features: FeaturePolicy::from_env()?,
+            mode: Arc::new(ModeSwitch::from_env()?),
+            tenants: Tenants::from_env()?,
+            claims: ClaimsPolicy::from_env()?,
+            cookies: sso.apply(CookiePolicy::from_env()?)?,
+            sso,
+            session_rotation: env_duration_ms(
+                "SESSION_ROTATION_INTERVAL_MS",
+                Duration::from_secs(60 * 60),
+            )?,
+            mount: None,
+            routes: Vec::new(),
+            middleware: match env_flag("SECURITY_HEADERS")? {
+                true => vec![Box::new(SecurityHeaders)],
+                false => Vec::new(),
+            },
+            plugins: Vec::new(),
+            sentry: Sentry::from_env()?.map(Arc::new),
+            publish_events: EventSink::is_enabled()?,
+            tls: tls::server_config_from_env()?,
+            client_certs: ClientCertPolicy::from_env()?,
+            kerberos: KerberosPolicy::from_env()?,
+            notifications: NotificationPolicy::from_env()?,
+        })
+    }
+}
+
+impl Timeouts {
+    fn from_env() -> Result<Timeouts, Error> {
+        Ok(Timeouts {
+            body: env_duration_ms("BODY_TIMEOUT_MS", Duration::from_secs(10))?,
+            handler: env_duration_ms("HANDLER_TIMEOUT_MS", Duration::from_secs(30))?,
+        })
+    }
+}
+
+/// Runs the command given on the command line, or the server when there's none, which is all the
+/// binary does.
+pub fn run_cli(log: Logger) -> Result<(), Error> {
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(worker_threads) = env_var_opt("WORKER_THREADS")? {
+        runtime.worker_threads(worker_threads.parse()?);
+    }
+    let runtime = runtime.enable_io().enable_time().build()?;
+    runtime.block_on(async {
+        match std::env::args().nth(1).as_deref() {
+            None => run(log).await,
+            Some("migrate") => migrate(log).await,
+            Some("create-client") => create_client(log).await,
+            Some("create-public-client") => create_public_client(log).await,
+            Some("create-api-key") => create_api_key(log).await,
+            Some("create-service-account") => create_service_account_command(log).await,
+            Some("add-redirect-uri") => add_redirect_uri(log).await,
+            Some("send-test-mail") => send_test_mail(log).await,
+            Some("add-logout-redirect-uri") => add_logout_redirect_uri(log).await,
+            Some("set-backchannel-logout-uri") => set_backchannel_logout_uri(log).await,
+            Some("retry-dead-jobs") => retry_dead_jobs(log).await,
+            Some("link-identity") => link_identity(log).await,
+            Some("set-claim") => set_claim(log).await,
+            Some("keygen") => keygen(),
+            Some("users") => users(log).await,
+            Some("hash-password") => hash_password().await,
+            Some("verify-password") => verify_password().await,
+            Some(command) => Err(Error::UnknownCommand(
+                command.to_owned(),
+                Backtrace::capture(),
+            )),
+        }
+    })
+}
+
+async fn migrate(log: Logger) -> Result<(), Error> {
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await
+}
+
+/// Registers an OAuth client with the given scopes, and prints its newly generated secret.
+async fn create_client(log: Logger) -> Result<(), Error> {
+    let mut args = std::env::args().skip(2);
+    let Some(id) = args.next() else {
+        return Err(Error::Usage("create-client <id> [scope...]", Backtrace::capture()));
+    };
+    let scopes: Vec<String> = args.collect();
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    let secret = oauth::generate_secret();
+    store.clients.insert(&id, &secret, &scopes, None).await?;
+    info!(log, "OAuth client created"; "client_id" => &id, "scopes" => scopes.join(" "));
+    println!("{}", secret);
+    Ok(())
+}
+
+/// Registers an OAuth client without a secret, for apps that can't keep one, like ones running on
+/// the user's device. Those have to use PKCE.
+async fn create_public_client(log: Logger) -> Result<(), Error> {
+    let mut args = std::env::args().skip(2);
+    let Some(id) = args.next() else {
+        return Err(Error::Usage("create-public-client <id> [scope...]", Backtrace::capture()));
+    };
+    let scopes: Vec<String> = args.collect();
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    store.clients.insert_public(&id, &scopes).await?;
+    info!(log, "Public OAuth client created"; "client_id" => &id, "scopes" => scopes.join(" "));
+    Ok(())
+}
+
+/// Issues a long-lived token for an OAuth client to use as an API key, and prints it. The scopes
+/// default to everything the client is allowed. Keys of a service account's client act on behalf of
+/// the account.
+async fn create_api_key(log: Logger) -> Result<(), Error> {
+    let mut args = std::env::args().skip(2);
+    let Some(client_id) = args.next() else {
+        return Err(Error::Usage("create-api-key <client-id> [scope...]", Backtrace::capture()));
+    };
+    let scopes: Vec<String> = args.collect();
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    let Some(client) = store.clients.get(&client_id).await? else {
+        return Err(Error::ClientNotFound(client_id, Backtrace::capture()));
+    };
+    let requested: Vec<&str> = scopes.iter().map(String::as_str).collect();
+    let scope = oauth::grant_scope(&client, &requested)?;
+    let token = oauth::issue_token(
+        &*store.tokens,
+        &client,
+        client.user,
+        scope.clone(),
+        oauth::API_KEY_EXPIRATION_TIME,
+    )
+    .await?;
+    info!(log, "API key created"; "client_id" => &client.id, "scope" => scope);
+    println!("{}", token);
+    Ok(())
+}
+
+/// Creates a service account of the tenant along with an OAuth client for it, allowed the given
+/// scopes, and prints the client's ID and newly generated secret. API keys for the account come
+/// from `create-api-key` with the client's ID.
+async fn create_service_account_command(log: Logger) -> Result<(), Error> {
+    let mut args = std::env::args().skip(2);
+    let (Some(tenant), Some(username)) = (args.next(), args.next()) else {
+        return Err(Error::Usage(
+            "create-service-account <tenant> <username> [scope...]",
+            Backtrace::capture(),
+        ));
+    };
+    let scopes: Vec<String> = args.collect();
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    let (user, client_id, secret) =
+        create_service_account(&tenant, &username, &scopes, &store, &log).await?;
+    let details = serde_json::json!({ "admin": null, "client_id": &client_id });
+    let event = AuditEvent {
+        user: Some(user),
+        tenant: tenant.clone(),
+        kind: audit::SERVICE_ACCOUNT_CREATED.to_owned(),
+        ip: None,
+        device: None,
+        country: None,
+        details,
+        created_at: SystemTime::now(),
+    };
+    store.audit.insert(&event).await?;
+    info!(log, "Service account created"; user, "tenant" => &tenant, "client_id" => &client_id, "scopes" => scopes.join(" "));
+    println!("{}", client_id);
+    println!("{}", secret);
+    Ok(())
+}
+
+/// Registers a URI the client may receive authorization codes at.
+async fn add_redirect_uri(log: Logger) -> Result<(), Error> {
+    let mut args = std::env::args().skip(2);
+    let (Some(client_id), Some(uri)) = (args.next(), args.next()) else {
+        return Err(Error::Usage("add-redirect-uri <client-id> <uri>", Backtrace::capture()));
+    };
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    store.clients.add_redirect_uri(&client_id, &uri).await?;
+    info!(log, "Redirect URI added"; "client_id" => &client_id, "uri" => &uri);
+    Ok(())
+}
+
+/// Registers a URI the client may send users back to after logging them out through
+/// `/oauth/logout`.
+async fn add_logout_redirect_uri(log: Logger) -> Result<(), Error> {
+    let mut args = std::env::args().skip(2);
+    let (Some(client_id), Some(uri)) = (args.next(), args.next()) else {
+        return Err(Error::Usage("add-logout-redirect-uri <client-id> <uri>", Backtrace::capture()));
+    };
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    store
+        .clients
+        .add_logout_redirect_uri(&client_id, &uri)
+        .await?;
+    info!(log, "Post-logout redirect URI added"; "client_id" => &client_id, "uri" => &uri);
+    Ok(())
+}
+
+/// Sets the URI logout tokens are POSTed to when users of the client log out, see
+/// [`backchannel::notify`]. Leaving the URI out stops them.
+async fn set_backchannel_logout_uri(log: Logger) -> Result<(), Error> {
+    let mut args = std::env::args().skip(2);
+    let (Some(client_id), uri) = (args.next(), args.next()) else {
+        return Err(Error::Usage("set-backchannel-logout-uri <client-id> [uri]", Backtrace::capture()));
+    };
+    // The tokens are sent with the HTTPS-only client, and they'd log users out if intercepted.
+    if let Some(uri) = &uri {
+        let scheme = uri
+            .parse::<Uri>()
+            .ok()
+            .and_then(|uri| uri.scheme().cloned());
+        if scheme != Some(Scheme::HTTPS) {
+            return Err(Error::InvalidBackchannelLogoutUri(
+                uri.clone(),
+                Backtrace::capture(),
+            ));
+        }
+    }
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    store
+        .clients
+        .set_backchannel_logout_uri(&client_id, uri.as_deref())
+        .await?;
+    info!(log, "Back-channel logout URI set"; "client_id" => &client_id, "uri" => uri.as_deref());
+    Ok(())
+}
+
+/// Sends an email to the address, to check that the mail provider is set up right. With `--queue`
+/// the mail goes through the job queue instead, to be sent by a running server's workers.
+async fn send_test_mail(log: Logger) -> Result<(), Error> {
+    let mut args = std::env::args().skip(2);
+    let (Some(to), queue) = (args.next(), args.next()) else {
+        return Err(Error::Usage("send-test-mail <address> [--queue]", Backtrace::capture()));
+    };
+    let queue = match queue.as_deref() {
+        None => false,
+        Some("--queue") => true,
+        Some(_) => {
+            return Err(Error::Usage(
+                "send-test-mail <address> [--queue]",
+                Backtrace::capture(),
+            ))
+        }
+    };
+    let templates = Templates::load()?;
+    let context = tera::Context::new();
+    let mail = mail::render(&templates, "test", &to, i18n::DEFAULT_LOCALE, &context)?;
+    if queue {
+        let store = Store::from_env()?;
+        store.supervise(&log);
+        store.migrate(&log).await?;
+        jobs::enqueue(&*store.jobs, &jobs::Task::SendMail(mail)).await?;
+        info!(log, "Test mail queued"; "to" => &to);
+        return Ok(());
+    }
+    let Some(mailer) = Mailer::from_env(&log)? else {
+        return Err(Error::MailNotConfigured(Backtrace::capture()));
+    };
+    mailer.send(&mail).await?;
+    info!(log, "Test mail sent"; "to" => &to);
+    Ok(())
+}
+
+/// Links an account at another identity provider to the user, for when it's vouched for by whoever
+/// runs the server rather than by the provider itself. Linking it again is a no-op.
+async fn link_identity(log: Logger) -> Result<(), Error> {
+    let mut args = std::env::args().skip(2);
+    let (Some(user_id), Some(provider), Some(subject)) = (args.next(), args.next(), args.next()) else {
+        return Err(Error::Usage(
+            "link-identity <user-id> <provider> <subject>",
+            Backtrace::capture(),
+        ));
+    };
+    let user = User {
+        id: user_id.parse()?,
+    };
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    store.users.profile(user).await?;
+    match store.users.find_by_identity(&provider, &subject).await? {
+        Some(owner) if owner == user => {
+            info!(log, "Identity already linked"; user, "provider" => &provider, "subject" => &subject);
+            return Ok(());
+        }
+        Some(_) => return Err(Error::IdentityTaken(Backtrace::capture())),
+        None => (),
+    }
+    store.users.link_identity(user, &provider, &subject).await?;
+    info!(log, "Identity linked"; user, "provider" => &provider, "subject" => &subject);
+    Ok(())
+}
+
+/// Sets a claim of the user to the given JSON value, or removes it when the value is `null`. It
+/// reaches the sessions created from then on.
+async fn set_claim(log: Logger) -> Result<(), Error> {
+    let mut args = std::env::args().skip(2);
+    let (Some(user_id), Some(name), Some(value)) = (args.next(), args.next(), args.next()) else {
+        return Err(Error::Usage(
+            "set-claim <user-id> <name> <json-value>",
+            Backtrace::capture(),
+        ));
+    };
+    let user = User {
+        id: user_id.parse()?,
+    };
+    claims::validate_name(&name)?;
+    let value: serde_json::Value = serde_json::from_str(&value)?;
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    store.users.profile(user).await?;
+    if value.is_null() {
+        let removed = store.claims.remove(user, &name).await?;
+        info!(log, "Claim removed"; user, "name" => &name, "existed" => removed);
+    } else {
+        store.claims.set(user, &name, &value).await?;
+        info!(log, "Claim set"; user, "name" => &name, "value" => value.to_string());
+    }
+    Ok(())
+}
+
+const USERS_USAGE: &str = "users export [--format json|csv] [--encrypt] | \
+     users import <file> [--format json|csv] [--on-conflict fail|skip|overwrite] [--decrypt] | \
+     users normalize";
+
+/// Exports every user to standard output, or imports users from a file, for moving them between
+/// instances or in from another system, see [`transfer`]. Exports have password hashes in them,
+/// so they can be encrypted with the secret, which the instance importing them needs to have
+/// among its secrets too.
+///
+/// Also normalizes stored usernames again, for after an operator resolved the ones
+/// [`user::normalize_stored_usernames`] had to leave alone.
+async fn users(log: Logger) -> Result<(), Error> {
+    let usage = || Error::Usage(USERS_USAGE, Backtrace::capture());
+    let mut args = std::env::args().skip(2);
+    let command = args.next();
+    let mut path = None;
+    let mut format = transfer::Format::Json;
+    let mut on_conflict = transfer::OnConflict::Fail;
+    let mut encrypted = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = args
+                    .next()
+                    .and_then(|format| transfer::Format::parse(&format))
+                    .ok_or_else(usage)?;
+            }
+            "--on-conflict" => {
+                on_conflict = args
+                    .next()
+                    .and_then(|on_conflict| transfer::OnConflict::parse(&on_conflict))
+                    .ok_or_else(usage)?;
+            }
+            "--encrypt" | "--decrypt" => encrypted = true,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return Err(usage()),
+        }
+    }
+    let crypto = match encrypted {
+        true => Some(Crypto::from_env()?),
+        false => None,
+    };
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    match (command.as_deref(), path) {
+        (Some("export"), None) => {
+            let data = transfer::export(&store, format).await?;
+            match crypto {
+                Some(crypto) => println!("{}", crypto.seal(data.as_bytes())),
+                None => print!("{}", data),
+            }
+            Ok(())
+        }
+        (Some("import"), Some(path)) => {
+            let mut data = std::fs::read_to_string(path)?;
+            if let Some(crypto) = crypto {
+                data = String::from_utf8_lossy(&crypto.unseal(data.trim())?).into_owned();
+            }
+            transfer::import(&store, &data, format, on_conflict, &log).await?;
+            Ok(())
+        }
+        (Some("normalize"), None) => user::normalize_stored_usernames(&*store.users, &log).await,
+        _ => Err(usage()),
+    }
+}
+
+/// Prints a fresh secret. With `--rotate`, prints the variables that make it the secret while the
+/// current ones stay accepted, for cookies sealed with them to keep working.
+fn keygen() -> Result<(), Error> {
+    let secret = crypto::generate_secret();
+    match std::env::args().nth(2).as_deref() {
+        None => println!("{}", secret),
+        Some("--rotate") => {
+            let mut previous = vec![env_var("SECRET")?];
+            if let Some(older) = env_var_opt("PREVIOUS_SECRETS")? {
+                previous.extend(older.split(',').map(str::trim).map(str::to_owned));
+            }
+            previous.retain(|secret| !secret.is_empty());
+            println!("SECRET={}", secret);
+            println!("PREVIOUS_SECRETS={}", previous.join(","));
+        }
+        Some(_) => return Err(Error::Usage("keygen [--rotate]", Backtrace::capture())),
+    }
+    Ok(())
+}
+
+/// Prints the hash of the password read from standard input, the same as it'd be stored.
+async fn hash_password() -> Result<(), Error> {
+    let password = read_password()?;
+    println!("{}", user::hash_password(&password).await);
+    Ok(())
+}
+
+/// Checks the password read from standard input against a stored hash, failing when it's wrong.
+async fn verify_password() -> Result<(), Error> {
+    let Some(password_phc) = std::env::args().nth(2) else {
+        return Err(Error::Usage("verify-password <hash>", Backtrace::capture()));
+    };
+    let password = read_password()?;
+    user::verify_password(&password, &password_phc).await?;
+    println!("Password matches");
+    Ok(())
+}
+
+/// Reads a password from standard input rather than the arguments, which would leave it in the
+/// shell history and the process list.
+fn read_password() -> Result<String, Error> {
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+/// Gives every job that ran out of attempts another round, once whatever made them fail is fixed.
+async fn retry_dead_jobs(log: Logger) -> Result<(), Error> {
+    let store = Store::from_env()?;
+    store.supervise(&log);
+    store.migrate(&log).await?;
+    let requeued = store.jobs.requeue_dead().await?;
+    info!(log, "Dead jobs requeued"; "count" => requeued);
+    Ok(())
+}
+
+async fn run(log: Logger) -> Result<(), Error> {
+    let server = Server::from_env(log.clone()).await?;
+    let store = server.store().clone();
+    let crypto = server.crypto().clone();
+    let templates = server.templates().clone();
+    let address = SocketAddr::from(([127, 0, 0, 1], 8000));
+    let listener = match systemd::listener(&log)? {
+        Some(listener) => listener,
+        None => bind(address)?,
+    };
+    let app = server.build();
+    let scheme = if app.serves_tls() { "https" } else { "http" };
+    let (address, server) = app.listen(listener)?;
+    if let Some(grpc_address) = env_var_opt("GRPC_ADDRESS")? {
+        let grpc = grpc::serve(
+            grpc_address.parse()?,
+            store.clone(),
+            crypto.clone(),
+            log.clone(),
+        );
+        let log = log.clone();
+        tokio::spawn(async move {
+            if let Err(e) = grpc.await {
+                error!(log, "gRPC server failed"; e.log_message(), e.log_backtrace());
+            }
+        });
+    }
+    let mailer = Mailer::from_env(&log)?.map(Arc::new);
+    let sms = SmsSender::from_env(&log)?.map(Arc::new);
+    let events = EventSink::from_env(&log)?.map(Arc::new);
+    jobs::spawn_workers(store.clone(), crypto, mailer, sms, events, &log)?;
+    cleanup::spawn(store.clone(), &log)?;
+    anomaly::spawn(store.clone(), templates, &log)?;
+    info!(log, "Listening on {}://{}", scheme, address);
+    systemd::notify_ready(&log)?;
+    Ok(server.await?)
+}
+
+fn serve(
+    address: SocketAddr,
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Arc<Config>,
+    log: Logger,
+) -> Result<(SocketAddr, ServerFuture), Error> {
+    serve_listener(bind(address)?, store, templates, crypto, config, log)
+}
+
+fn bind(address: SocketAddr) -> Result<TcpListener, Error> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Serves on a socket that's already listening, which has to be in non-blocking mode.
+fn serve_listener(
+    listener: TcpListener,
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Arc<Config>,
+    log: Logger,
+) -> Result<(SocketAddr, ServerFuture), Error> {
+    if let Some(tls) = config.tls.clone() {
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let address = listener.local_addr()?;
+        let server = serve_tls(listener, tls, store, templates, crypto, config, log);
+        return Ok((address, Box::pin(server)));
+    }
+    let service_factory = make_service_fn(move |conn: &AddrStream| {
+        let service = connection_service(
+            conn.remote_addr().ip(),
+            None,
+            store.clone(),
+            templates.clone(),
+            crypto.clone(),
+            config.clone(),
+            log.clone(),
+        );
+        async move { Ok::<_, Infallible>(service) }
+    });
+    let server = hyper::Server::from_tcp(listener)?.serve(service_factory);
+    Ok((server.local_addr(), Box::pin(server)))
+}
+
+/// Accepts connections until the end of time, with the TLS handshake done by each one's own
+/// task, so that slow clients can't hold up the others.
+async fn serve_tls(
+    listener: tokio::net::TcpListener,
+    tls: Arc<rustls::ServerConfig>,
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Arc<Config>,
+    log: Logger,
+) -> Result<(), hyper::Error> {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                let e = Error::from(e);
+                error!(log, "Accepting connection failed"; e.log_message());
+                // Like running out of file descriptors, which won't get better right away.
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let tls = tls.clone();
+        let store = store.clone();
+        let templates = templates.clone();
+        let crypto = crypto.clone();
+        let config = config.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            let handshake = TlsStream::accept(stream, tls);
+            let stream = match tokio::time::timeout(config.timeouts.body, handshake).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    info!(log, "TLS handshake failed"; e.log_message(), "ip" => address.ip().to_string());
+                    return;
+                }
+                Err(_) => {
+                    info!(log, "TLS handshake timed out"; "ip" => address.ip().to_string());
+                    return;
+                }
+            };
+            let peer = stream.peer_certificate().cloned().map(PeerCertificate);
+            let service = connection_service(
+                address.ip(),
+                peer,
+                store,
+                templates,
+                crypto,
+                config,
+                log.clone(),
+            );
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                info!(log, "Connection failed"; "error" => e.to_string());
+            }
+        });
+    }
+}
+
+/// Answers the requests of a connection from the address, which came with the certificate if the
+/// client presented one.
+fn connection_service(
+    conn_ip: IpAddr,
+    peer: Option<PeerCertificate>,
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Arc<Config>,
+    log: Logger,
+) -> impl Service<
+    Request<Body>,
+    Response = Response<Body>,
+    Error = Infallible,
+    Future = impl Future<Output = Result<Response<Body>, Infallible>> + Send,
+> + Send {
+    service_fn(move |mut req: Request<Body>| {
+        let req_id = Uuid::new_v4();
+        let req_log = log.new(o!("request" => req_id.to_string()));
+        info!(req_log, "HTTP request received"; "method" => req.method().as_str(), "endpoint" => req.uri().path(), "ip" => conn_ip.to_string());
+        let store = store.clone();
+        let templates = templates.clone();
+        let crypto = crypto.clone();
+        let config = config.clone();
+        req.extensions_mut().insert(RemoteAddr(conn_ip));
+        if let Some(peer) = &peer {
+            req.extensions_mut().insert(peer.clone());
+        }
+        async move {
+            let response = catcher(req, req_id, store, templates, crypto, config, req_log).await;
+            Ok::<_, Infallible>(response)
+        }
+    })
+}
+
+/// Answers the request, timing it by the route it was matched to. The route's pattern is what the
+/// requests are logged and counted by, as the raw paths have IDs and such in them, which would
+/// make for a metric per user.
+async fn catcher(
+    mut req: Request<Body>,
+    req_id: Uuid,
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Arc<Config>,
+    log: Logger,
+) -> Response<Body> {
+    let start = Instant::now();
+    let method = req.method().clone();
+    let matched = MatchedPattern::default();
+    req.extensions_mut().insert(matched.clone());
+    let response = respond(req, req_id, store, templates, crypto, config, log.clone()).await;
+    let duration = start.elapsed();
+    let route = matched.get();
+    let route = route.as_deref().unwrap_or("unmatched");
+    let status = response.status();
+    info!(log, "HTTP request finished"; "route" => route, "status" => status.as_u16(), "duration_ms" => duration.as_millis() as u64);
+    let labels = [method.as_str(), route, status.as_str()];
+    METRIC_HTTP_REQUEST_COUNT.with_label_values(&labels).inc();
+    METRIC_HTTP_REQUEST_LATENCY
+        .with_label_values(&labels)
+        .observe(duration.as_secs_f64());
+    response
+}
+
+async fn respond(
+    mut req: Request<Body>,
+    req_id: Uuid,
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Arc<Config>,
+    log: Logger,
+) -> Response<Body> {
+    if let Some(mount) = &config.mount {
+        if !server::strip_mount(&mut req, mount) {
+            return server::not_mounted();
+        }
+    }
+    let tenant = config.tenants.resolve(&mut req);
+    // The mount path goes in front of the tenant's, as that's the order they're in the URL.
+    if let Some(mount) = &config.mount {
+        let tenant_prefix = req.extensions().get::<TenantPrefix>();
+        let tenant_prefix = tenant_prefix.map_or("", |prefix| prefix.0.as_str());
+        let prefix = TenantPrefix(format!("{}{}", mount, tenant_prefix));
+        req.extensions_mut().insert(prefix);
+    }
+    let prefix = req.extensions().get::<TenantPrefix>().cloned();
+    let tenant_id = tenant.id.clone();
+    req.extensions_mut().insert(tenant);
+    let matched = req.extensions().get::<MatchedPattern>().cloned();
+    let json = api::wants_json(&req);
+    let cookies = get_cookies(&req).unwrap_or_default();
+    let locale = i18n::negotiate(&cookies, &req);
+    // It's only for telling apart the users having errors reported, so whether the session is
+    // still active doesn't matter.
+    let user = match &config.sentry {
+        Some(_) => Session::from_cookies(&cookies, &*crypto, &tenant_id, &config.cookies)
+            .ok()
+            .flatten()
+            .map(|session| *session.user()),
+        None => None,
+    };
+    let new_device = (!cookies.contains_key("device")).then(NewDevice::generate);
+    if let Some(new_device) = &new_device {
+        req.extensions_mut().insert(new_device.clone());
+    }
+    // HEAD is handled as GET, with the body dropped once the response is ready.
+    let method = req.method().clone();
+    if method == Method::HEAD {
+        *req.method_mut() = Method::GET;
+    }
+    // Whatever is being worked on during maintenance may well be the store, so it's left alone.
+    let maintenance =
+        config.mode.get() == Mode::Maintenance && !MAINTENANCE_ROUTES.contains(&req.uri().path());
+    let rotated = match maintenance {
+        true => None,
+        false => rotate_session(&mut req, &store, &crypto, &config, &log).await,
+    };
+    let response = match maintenance {
+        true => Err(Error::Maintenance(Backtrace::capture())),
+        false => tokio::time::timeout(
+            config.timeouts.handler,
+            router(
+                req,
+                store,
+                templates.clone(),
+                crypto.clone(),
+                config.clone(),
+                &log,
+            ),
+        )
+        .await
+        .unwrap_or_else(|_| Err(Error::HandlerTimeout(Backtrace::capture()))),
+    };
+    // No route takes OPTIONS, so it's answered with the methods the route not taking it lists.
+    let response = match response {
+        Err(Error::MethodNotAllowed(allowed, _)) if method == Method::OPTIONS => {
+            Ok(routes::options(&allowed))
+        }
+        response => response,
+    };
+    let mut response = match response {
+        Ok(mut resp) => {
+            info!(log, "HTTP request successful"; "status" => resp.status().as_u16());
+            // The device is only remembered once someone logs in on it, which is when it's of use.
+            if let Some(new_device) =
+                new_device.filter(|_| sets_login_cookie(&resp, &config.cookies))
+            {
+                resp.headers_mut()
+                    .append(SET_COOKIE, new_device.cookie().to_string().parse().unwrap());
+            }
+            resp
+        }
+        Err(e) => {
+            let status = e.status_code();
+            // Requests turned away by the mode are what the operators asked for, not failures.
+            let intended = matches!(e, Error::Maintenance(_) | Error::ReadOnly(_));
+            if e.kind().is_client_error() || intended {
+                info!(log, "HTTP request rejected"; "status" => status.as_u16(), e.log_message());
+            } else {
+                error!(log, "HTTP request failed"; "status" => status.as_u16(), e.log_message(), e.log_backtrace());
+                if let Some(sentry) = &config.sentry {
+                    let route = matched.as_ref().and_then(MatchedPattern::get);
+                    let report = sentry::Report {
+                        request_id: req_id,
+                        route: route.as_deref(),
+                        user,
+                    };
+                    sentry.report(&e, report, &crypto, &log);
+                }
+            }
+            let mut resp = if json {
+                api::error_response(&e, req_id, locale)
+            } else {
+                Response::builder()
+                    .status(status)
+                    .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                    .body(templates.render_error(&e, req_id, locale).into())
+                    .unwrap()
+            };
+            if let Error::MethodNotAllowed(allowed, _) = &e {
+                let allow = routes::allow(allowed).parse().unwrap();
+                resp.headers_mut().insert(ALLOW, allow);
+            }
+            resp
+        }
+    };
+    // Whatever the response, the browser needs the new cookie, as the old one stops working soon.
+    // Responses logging in or out set the cookie themselves.
+    let session_name = format!("{}=", config.cookies.session_name());
+    let sets_session = response
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .any(|cookie| cookie.starts_with(&session_name));
+    if let Some(rotated) = rotated.filter(|_| !sets_session) {
+        let cookie = rotated.cookie_login(&config.cookies);
+        response
+            .headers_mut()
+            .append(SET_COOKIE, cookie.to_string().parse().unwrap());
+    }
+    for middleware in &config.middleware {
+        middleware.before_response(&mut response).await;
+    }
+    if let Some(prefix) = &prefix {
+        tenant::scope_response(prefix, &mut response);
+    }
+    if method == Method::HEAD {
+        *response.body_mut() = Body::empty();
+    }
+    response
+}
+
+/// Replaces the session cookie once it's older than the rotation interval, swapping the new one
+/// into the request so that it's routed as if the browser had sent it. Impersonation sessions
+/// don't last long enough to need it. Failing to rotate is no reason to fail the request, as the
+/// old cookie keeps working until it's rotated some other time.
+async fn rotate_session(
+    req: &mut Request<Body>,
+    store: &Store,
+    crypto: &Crypto,
+    config: &Config,
+    log: &Logger,
+) -> Option<Session> {
+    let tenant = req.extensions().get::<Arc<Tenant>>()?.clone();
+    let name = config.cookies.session_name();
+    let cookies = get_cookies(req).ok()?;
+    let session = Session::from_cookies(&cookies, crypto, &tenant.id, &config.cookies).ok()??;
+    let due = session.issued_at() + config.session_rotation <= SystemTime::now();
+    if !due || session.impersonator().is_some() {
+        return None;
+    }
+    let rotated = session.rotate(crypto);
+    match store.sessions.rotate(&session, &rotated).await {
+        Ok(true) => info!(log, "Session rotated"; &session),
+        Ok(false) => return None,
+        Err(e) => {
+            error!(log, "Rotating session failed"; e.log_message(), e.log_backtrace());
+            return None;
+        }
+    }
+    let value = rotated.cookie_login(&config.cookies).value().to_owned();
+    let header = cookies
+        .iter()
+        .map(|(cookie_name, cookie)| match *cookie_name == name {
+            true => format!("{}={}", name, value),
+            false => cookie.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    req.headers_mut().insert(COOKIE, header.parse().unwrap());
+    Some(rotated)
+}
+
+async fn router(
+    mut req: Request<Body>,
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Arc<Config>,
+    log: &Logger,
+) -> Result<Response<Body>, Error> {
+    for middleware in &config.middleware {
+        if let Some(response) = middleware.before_routing(&mut req).await? {
+            return Ok(response);
+        }
+    }
+    let cookies = get_cookies(&req)?;
+    let locale = i18n::negotiate(&cookies, &req);
+    let client = ClientInfo::from_request(&req, &cookies, &config.risk, locale);
+    let endpoint = Endpoint {
+        client: &client,
+        locale,
+        store: &store,
+        templates: &templates,
+        crypto: &crypto,
+        config: &config,
+        log,
+    };
+    match routes::resolve(SERVICE_ROUTES, req.method(), req.uri().path()) {
+        Ok(route) => {
+            routes::record(&req, route.pattern);
+            return (route.handler)(req, endpoint).await;
+        }
+        Err(Error::NotFound(_)) => (),
+        Err(e) => return Err(e),
+    }
+    // The authorization endpoint is a page for the user rather than an API for clients, so it's
+    // routed along with the other pages.
+    if req.uri().path().starts_with("/oauth/") && req.uri().path() != "/oauth/authorize" {
+        return oauth_router(req, endpoint).await;
+    }
+    let session =
+        match Session::from_cookies(&cookies, &*crypto, &client.tenant.id, &config.cookies)? {
+            Some(session) if store.sessions.is_active(&session).await? => Some(session),
+            Some(session) if store.sessions.is_restricted(&session).await? => {
+                if !PASSWORD_EXPIRED_ROUTES.contains(&req.uri().path()) {
+                    info!(log, "Password has expired"; &session, session.user());
+                    if api::wants_json(&req) {
+                        return Err(Error::PasswordExpired(Backtrace::capture()));
+                    }
+                    return Ok(see_other("/settings/password"));
+                }
+                Some(session)
+            }
+            _ => None,
+        };
+    let session = match session {
+        Some(session) => Some(session),
+        None => client_cert_session(&req, &client, &store, &crypto, &config, log).await?,
+    };
+    if let Some(session) = &session {
+        info!(log, "User is logged in"; session, session.user());
+        // Whatever an admin changes while impersonating is on record, whether or not it's
+        // something that gets audited otherwise.
+        if session.impersonator().is_some() && req.method() != Method::GET {
+            let details = serde_json::json!({
+                "impersonator": session.impersonator().map(|impersonator| impersonator.id),
+                "method": req.method().as_str(),
+                "path": req.uri().path(),
+            });
+            let kind = audit::IMPERSONATED_REQUEST;
+            let event = AuditEvent::new(kind, Some(*session.user()), &client, details);
+            store.audit.insert(&event).await?;
+        }
+    } else {
+        info!(log, "User is not logged in");
+    }
+    for middleware in &config.middleware {
+        if let Some(response) = middleware.after_auth(&req, session.as_ref()).await? {
+            return Ok(response);
+        }
+    }
+    let route = config
+        .routes
+        .iter()
+        .filter(|route| route.method == req.method())
+        .find_map(|route| Some((route, routes::matches(&route.path, req.uri().path())?)));
+    if let Some((route, params)) = route {
+        routes::record(&req, &route.path);
+        req.extensions_mut().insert(params);
+        return (route.handler)(req, session, store).await;
+    }
+    if api::wants_json(&req) {
+        return api_router(req, session, store, templates, crypto, config, log).await;
+    }
+    let flash = Flash::from_cookies(&cookies, &*crypto);
+    let had_flash = flash.is_some();
+    let query: PageQuery = serde_urlencoded::from_str(req.uri().query().unwrap_or_default())?;
+    let features = features::load(&*store.features, &config.features).await?;
+    let context = tera::Context::from_serialize(Ctx {
+        user: session.as_ref().map(|session| CtxUser {
+            id: session.user().id,
+            claims: session.claims().clone(),
+        }),
+        impersonator: session
+            .as_ref()
+            .and_then(Session::impersonator)
+            .map(|impersonator| CtxUser {
+                id: impersonator.id,
+                claims: Claims::new(),
+            }),
+        flash,
+        next: query.next.filter(|next| is_local_path(next)),
+        lang: locale,
+        locales: i18n::locales(),
+        features: &features,
+        tenant: CtxTenant {
+            id: &client.tenant.id,
+            name: &client.tenant.name,
+            branding: &client.tenant.branding,
+            base: req
+                .extensions()
+                .get::<TenantPrefix>()
+                .map_or("", |prefix| prefix.0.as_str()),
+        },
+    })?;
+    let page = Page {
+        session,
+        client: &client,
+        locale,
+        context,
+        had_flash,
+        features: &features,
+        store: &store,
+        templates: &templates,
+        crypto: &crypto,
+        config: &config,
+        log,
+    };
+    let route = routes::resolve(PAGE_ROUTES, req.method(), req.uri().path())?;
+    routes::record(&req, route.pattern);
+    let session = page.session.as_ref();
+    let response = refuse_change(&req, route.access, session, locale, &crypto, &config, log)?;
+    if let Some(response) = response {
+        return Ok(response);
+    }
+    (route.handler)(req, page).await
+}
+
+/// Turns the request away when its route makes a change that isn't allowed right now, sending
+/// browsers back to the page of the form they came from with a notice. Read-only mode allows no
+/// changes, and admins impersonating a user can't change how the user logs in or take their data.
+#[allow(clippy::too_many_arguments)]
+fn refuse_change(
+    req: &Request<Body>,
+    access: Access,
+    session: Option<&Session>,
+    locale: &'static str,
+    crypto: &Crypto,
+    config: &Config,
+    log: &Logger,
+) -> Result<Option<Response<Body>>, Error> {
+    let (page, credentials) = match access {
+        Access::Read => return Ok(None),
+        Access::Write(page) => (page, false),
+        Access::Credentials(page) => (page, true),
+    };
+    let impersonated = session.and_then(Session::impersonator).is_some();
+    let e = if config.mode.get() == Mode::ReadOnly {
+        info!(log, "Change rejected in read-only mode");
+        Error::ReadOnly(Backtrace::capture())
+    } else if credentials && impersonated {
+        info!(log, "Change rejected while impersonating");
+        Error::Impersonating(Backtrace::capture())
+    } else {
+        return Ok(None);
+    };
+    if api::wants_json(req) {
+        return Err(e);
+    }
+    let flash = Flash::notice(&e.localized_message(locale));
+    Ok(Some(
+        Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION, page)
+            .header(SET_COOKIE, flash.cookie(crypto)?.to_string())
+            .body(Body::empty())
+            .unwrap(),
+    ))
+}
+
+/// Fails for users an admin suspended or banned, see [`set_account_status`].
+async fn check_status(user: User, store: &Store) -> Result<(), Error> {
+    match store.users.status(user).await? {
+        AccountStatus::Active => Ok(()),
+        status => Err(Error::AccountSuspended(status, Backtrace::capture())),
+    }
+}
+
+/// Creates a service account along with an OAuth client allowed the scopes, returning the client's
+/// ID and newly generated secret. Client IDs are shared by every tenant, so the client is named
+/// after both the account and its tenant.
+async fn create_service_account(
+    tenant: &str,
+    username: &str,
+    scopes: &[String],
+    store: &Store,
+    log: &Logger,
+) -> Result<(User, String, String), Error> {
+    let username = user::normalize_username(username.trim());
+    if username.is_empty() {
+        return Err(Error::EmptyField("username", Backtrace::capture()));
+    }
+    let work = async {
+        let user = store
+            .users
+            .insert_service_account(tenant, &username)
+            .await?;
+        let client_id = format!("{}@{}", username, tenant);
+        let secret = oauth::generate_secret();
+        store
+            .clients
+            .insert(&client_id, &secret, scopes, Some(user))
+            .await?;
+        Ok((user, client_id, secret))
+    };
+    store.transaction(log, work).await
+}
+
+/// Logs in the user the client certificate of the connection is mapped to, for this one request.
+/// The session isn't stored, so there's nothing to log out of, and the account is checked anew
+/// on every request.
+async fn client_cert_session(
+    req: &Request<Body>,
+    client: &ClientInfo,
+    store: &Store,
+    crypto: &Crypto,
+    config: &Config,
+    log: &Logger,
+) -> Result<Option<Session>, Error> {
+    let Some(PeerCertificate(certificate)) = req.extensions().get() else { return Ok(None); };
+    let Some(user) = config.client_certs.user(certificate) else {
+        info!(log, "Client certificate is not mapped to a user");
+        return Ok(None);
+    };
+    let profile = store.users.profile(user).await?;
+    // Service accounts only act through their OAuth clients, a certificate mapped to one included.
+    if profile.service {
+        return Err(Error::ServiceAccountLogin(Backtrace::capture()));
+    }
+    if profile.tenant != client.tenant.id {
+        info!(log, "Client certificate is mapped to a user of another tenant"; user);
+        return Ok(None);
+    }
+    check_status(user, store).await?;
+    let claims = claims::collect(user, &client.tenant.id, store, &config.claims).await?;
+    info!(log, "Logged in with client certificate"; user);
+    Ok(Some(Session::create(
+        user,
+        &client.tenant.id,
+        claims,
+        crypto,
+    )))
+}
+
+/// Counterpart of the router for clients that want JSON, which answers the same requests with
+/// structured responses in place of pages and redirects.
+async fn api_router(
+    req: Request<Body>,
+    session: Option<Session>,
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Arc<Config>,
+    log: &Logger,
+) -> Result<Response<Body>, Error> {
+    let path = req.uri().path().to_owned();
+    if path.starts_with(api::v1::PREFIX) {
+        return api_v1_router(req, session, store, templates, crypto, config, log).await;
+    }
+    let cookies = get_cookies(&req)?;
+    let locale = i18n::negotiate(&cookies, &req);
+    let client = ClientInfo::from_request(&req, &cookies, &config.risk, locale);
+    let prefix = match path.starts_with(api::PREFIX) {
+        true => api::PREFIX,
+        false => "",
+    };
+    let api = Api {
+        session,
+        client: &client,
+        locale,
+        store: &store,
+        templates: &templates,
+        crypto: &crypto,
+        config: &config,
+        log,
+    };
+    let route = routes::resolve(API_ROUTES, req.method(), &path[prefix.len()..])?;
+    routes::record(&req, &format!("{}{}", prefix, route.pattern));
+    let session = api.session.as_ref();
+    let response = refuse_change(&req, route.access, session, locale, &crypto, &config, log)?;
+    if let Some(response) = response {
+        return Ok(response);
+    }
+    (route.handler)(req, api).await
+}
+
+/// The versioned API, kept apart from the other routes so that changes to them can't leak into its
+/// contract.
+async fn api_v1_router(
+    req: Request<Body>,
+    session: Option<Session>,
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Arc<Config>,
+    log: &Logger,
+) -> Result<Response<Body>, Error> {
+    let cookies = get_cookies(&req)?;
+    let locale = i18n::negotiate(&cookies, &req);
+    let client = ClientInfo::from_request(&req, &cookies, &config.risk, locale);
+    let path = req.uri().path().to_owned();
+    let route = &path[api::v1::PREFIX.len()..];
+    let api = Api {
+        session,
+        client: &client,
+        locale,
+        store: &store,
+        templates: &templates,
+        crypto: &crypto,
+        config: &config,
+        log,
+    };
+    let route = routes::resolve(API_V1_ROUTES, req.method(), route)?;
+    routes::record(&req, &format!("{}{}", api::v1::PREFIX, route.pattern));
+    let session = api.session.as_ref();
+    let response = refuse_change(&req, route.access, session, locale, &crypto, &config, log)?;
+    if let Some(response) = response {
+        return Ok(response);
+    }
+    (route.handler)(req, api).await
+}
+
+/// OAuth endpoints, which answer in the formats their RFCs require regardless of what the client
+/// asks for, and don't look at the session cookie.
+async fn oauth_router(req: Request<Body>, endpoint: Endpoint<'_>) -> Result<Response<Body>, Error> {
+    let route = routes::resolve(OAUTH_ROUTES, req.method(), req.uri().path())?;
+    routes::record(&req, route.pattern);
+    (route.handler)(req, endpoint).await
+}
+
+/// Whether the response logs someone in, or gets them to a later step of a login.
+fn sets_login_cookie(response: &Response<Body>, cookies: &CookiePolicy) -> bool {
+    let session = format!("{}=", cookies.session_name());
+    response
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .any(|cookie| {
+            cookie.starts_with(&session)
+                || cookie.starts_with("challenge=")
+                || cookie.starts_with("terms=")
+        })
+}
+
+fn see_other(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn get_cookies(request: &Request<Body>) -> Result<HashMap<&str, Cookie>, Error> {
+    let Some(header) = request.headers().get(COOKIE) else { return Ok(HashMap::new()); };
+    Ok(header
+        .to_str()?
+        .split("; ")
+        .map(|cookie| Cookie::parse(cookie).map(|cookie| (cookie.name_raw().unwrap(), cookie)))
+        .collect::<Result<HashMap<_, _>, _>>()?)
+}
@@ -0,0 +1,139 @@
+use crate::error::Error;
+use crate::templates::Templates;
+use crate::util::{env_duration_ms, env_var, env_var_opt};
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::{Body, Request};
+use hyper_rustls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+use std::backtrace::Backtrace;
+use std::sync::Mutex;
+use std::time::Duration;
+
+mod sendgrid;
+mod ses;
+mod smtp;
+
+pub use sendgrid::SendGridProvider;
+pub use ses::SesProvider;
+pub use smtp::SmtpProvider;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rendered email. It has both a text and an HTML part, so that it reads fine in clients that don't
+/// show HTML.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Something that delivers mail, like an SMTP server or the API of a mail service.
+#[async_trait]
+pub trait MailProvider: Send + Sync {
+    async fn send(&self, from: &str, mail: &Mail) -> Result<(), Error>;
+}
+
+pub struct Mailer {
+    provider: Box<dyn MailProvider>,
+    from: String,
+    timeout: Duration,
+}
+
+/// Logs mail instead of sending it, and keeps it for tests to look at.
+pub struct DryRunProvider {
+    log: Logger,
+    sent: Mutex<Vec<Mail>>,
+}
+
+impl Mailer {
+    /// Picks the provider from `MAIL_PROVIDER`, which is one of `smtp`, `sendgrid`, `ses` and
+    /// `dry-run`. Without it no mail can be sent, which is fine as long as nothing needs to.
+    pub fn from_env(log: &Logger) -> Result<Option<Mailer>, Error> {
+        let Some(name) = env_var_opt("MAIL_PROVIDER")? else {
+            return Ok(None);
+        };
+        let provider: Box<dyn MailProvider> = match name.as_str() {
+            "smtp" => Box::new(SmtpProvider::from_env()?),
+            "sendgrid" => Box::new(SendGridProvider::from_env()?),
+            "ses" => Box::new(SesProvider::from_env()?),
+            "dry-run" => Box::new(DryRunProvider::new(log.clone())),
+            _ => return Err(Error::UnknownMailProvider(name, Backtrace::capture())),
+        };
+        Ok(Some(Mailer {
+            provider,
+            from: env_var("MAIL_FROM")?,
+            timeout: env_duration_ms("MAIL_TIMEOUT_MS", DEFAULT_TIMEOUT)?,
+        }))
+    }
+
+    pub async fn send(&self, mail: &Mail) -> Result<(), Error> {
+        tokio::time::timeout(self.timeout, self.provider.send(&self.from, mail))
+            .await
+            .unwrap_or_else(|_| Err(Error::MailTimeout(Backtrace::capture())))
+    }
+}
+
+impl DryRunProvider {
+    pub fn new(log: Logger) -> DryRunProvider {
+        DryRunProvider {
+            log,
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl MailProvider for DryRunProvider {
+    async fn send(&self, from: &str, mail: &Mail) -> Result<(), Error> {
+        info!(self.log, "Mail not sent in dry-run mode"; "from" => from, "to" => &mail.to, "subject" => &mail.subject);
+        self.sent.lock().unwrap().push(mail.clone());
+        Ok(())
+    }
+}
+
+/// Renders the `mail/{name}.txt` and `mail/{name}.html` templates, with the subject taken from the
+/// `mail-{name}-subject` message so that it's translated along with the rest.
+pub fn render(
+    templates: &Templates,
+    name: &str,
+    to: &str,
+    locale: &str,
+    context: &tera::Context,
+) -> Result<Mail, Error> {
+    let mut context = context.clone();
+    context.insert("lang", locale);
+    Ok(Mail {
+        to: to.to_owned(),
+        subject: crate::i18n::translate(locale, &format!("mail-{}-subject", name), &[]),
+        text: templates.render(&format!("mail/{}.txt", name), &context)?,
+        html: templates.render(&format!("mail/{}.html", name), &context)?,
+    })
+}
+
+/// Sends a request to the API of a mail service, treating anything but a success as the mail being
+/// rejected. The response body is kept for the logs, as that's where the services explain why.
+async fn send_api_request(
+    client: &hyper::Client<HttpsConnector<HttpConnector>>,
+    request: Request<Body>,
+) -> Result<(), Error> {
+    let response = client.request(request).await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Err(Error::MailRejected {
+        status,
+        body: String::from_utf8_lossy(&body).into_owned(),
+        backtrace: Backtrace::capture(),
+    })
+}
@@ -0,0 +1,48 @@
+use crate::error::Error;
+use crate::mail::{send_api_request, Mail, MailProvider};
+use crate::util::{env_var, http_client};
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::json;
+
+const ENDPOINT: &str = "https://api.sendgrid.com/v3/mail/send";
+
+pub struct SendGridProvider {
+    client: Client<HttpsConnector<HttpConnector>>,
+    api_key: String,
+}
+
+impl SendGridProvider {
+    pub fn from_env() -> Result<SendGridProvider, Error> {
+        Ok(SendGridProvider {
+            client: http_client(),
+            api_key: env_var("SENDGRID_API_KEY")?,
+        })
+    }
+}
+
+#[async_trait]
+impl MailProvider for SendGridProvider {
+    async fn send(&self, from: &str, mail: &Mail) -> Result<(), Error> {
+        let body = json!({
+            "personalizations": [{ "to": [{ "email": mail.to }] }],
+            "from": { "email": from },
+            "subject": mail.subject,
+            "content": [
+                { "type": "text/plain", "value": mail.text },
+                { "type": "text/html", "value": mail.html },
+            ],
+        });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(ENDPOINT)
+            .header(AUTHORIZATION, format!("Bearer {}", self.api_key))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        send_api_request(&self.client, request).await
+    }
+}
@@ -0,0 +1,138 @@
+use crate::error::Error;
+use crate::mail::{send_api_request, Mail, MailProvider};
+use crate::util::{env_var, env_var_opt, http_client};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac, NewMac};
+use hyper::client::HttpConnector;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, HOST};
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PATH: &str = "/v2/email/outbound-emails";
+const SERVICE: &str = "ses";
+
+/// Amazon SES, through the SendEmail call of its v2 API. Requests are signed with AWS Signature
+/// Version 4 by hand, which is small enough not to need the whole AWS SDK.
+pub struct SesProvider {
+    client: Client<HttpsConnector<HttpConnector>>,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl SesProvider {
+    /// Reads the region and credentials from the variables the AWS tools use.
+    pub fn from_env() -> Result<SesProvider, Error> {
+        Ok(SesProvider {
+            client: http_client(),
+            region: env_var("AWS_REGION")?,
+            access_key_id: env_var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: env_var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: env_var_opt("AWS_SESSION_TOKEN")?,
+        })
+    }
+}
+
+#[async_trait]
+impl MailProvider for SesProvider {
+    async fn send(&self, from: &str, mail: &Mail) -> Result<(), Error> {
+        let body = json!({
+            "FromEmailAddress": from,
+            "Destination": { "ToAddresses": [mail.to] },
+            "Content": {
+                "Simple": {
+                    "Subject": { "Data": mail.subject, "Charset": "UTF-8" },
+                    "Body": {
+                        "Text": { "Data": mail.text, "Charset": "UTF-8" },
+                        "Html": { "Data": mail.html, "Charset": "UTF-8" },
+                    },
+                },
+            },
+        })
+        .to_string();
+        let host = format!("email.{}.amazonaws.com", self.region);
+        let (date, time) = timestamp(SystemTime::now());
+        let mut headers = vec![("host", host.clone()), ("x-amz-date", time.clone())];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers: Vec<&str> = headers.iter().map(|(name, _)| *name).collect();
+        let signed_headers = signed_headers.join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let canonical_request = format!(
+            "POST\n{}\n\n{}\n{}\n{}",
+            PATH,
+            canonical_headers,
+            signed_headers,
+            hex::encode(Sha256::digest(body.as_bytes()))
+        );
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, SERVICE);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            time,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = [self.region.as_str(), SERVICE, "aws4_request"].iter().fold(
+            hmac(
+                format!("AWS4{}", self.secret_access_key).as_bytes(),
+                date.as_bytes(),
+            ),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        );
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("https://{}{}", host, PATH))
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, authorization);
+        for (name, value) in headers {
+            if name != HOST.as_str() {
+                request = request.header(name, value);
+            }
+        }
+        send_api_request(&self.client, request.body(Body::from(body)).unwrap()).await
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Date and time in the formats Signature Version 4 wants, `20150830` and `20150830T123600Z`.
+fn timestamp(time: SystemTime) -> (String, String) {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+    // Converts days since the epoch to a civil date, from Howard Hinnant's date algorithms.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    (date, time)
+}
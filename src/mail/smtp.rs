@@ -0,0 +1,53 @@
+use crate::error::Error;
+use crate::mail::{Mail, MailProvider};
+use crate::util::{env_var, env_var_opt};
+use async_trait::async_trait;
+use lettre::message::MultiPart;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::backtrace::Backtrace;
+
+pub struct SmtpProvider {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpProvider {
+    /// Connects to `SMTP_HOST`, with TLS from the start by default. `SMTP_TLS` can be set to
+    /// `starttls` for servers that upgrade plain connections instead, or `none` for local relays.
+    pub fn from_env() -> Result<SmtpProvider, Error> {
+        let host = env_var("SMTP_HOST")?;
+        let mut builder = match env_var_opt("SMTP_TLS")?.as_deref() {
+            None | Some("tls") => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+            Some("starttls") => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+            Some("none") => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+            Some(mode) => {
+                return Err(Error::UnknownSmtpTls(mode.to_owned(), Backtrace::capture()));
+            }
+        };
+        if let Some(port) = env_var_opt("SMTP_PORT")? {
+            builder = builder.port(port.parse()?);
+        }
+        if let Some(username) = env_var_opt("SMTP_USERNAME")? {
+            builder = builder.credentials(Credentials::new(username, env_var("SMTP_PASSWORD")?));
+        }
+        Ok(SmtpProvider {
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl MailProvider for SmtpProvider {
+    async fn send(&self, from: &str, mail: &Mail) -> Result<(), Error> {
+        let message = Message::builder()
+            .from(from.parse()?)
+            .to(mail.to.parse()?)
+            .subject(&mail.subject)
+            .multipart(MultiPart::alternative_plain_html(
+                mail.text.clone(),
+                mail.html.clone(),
+            ))?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
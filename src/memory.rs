@@ -0,0 +1,1309 @@
+use crate::audit::{self, AuditEvent, AuditStore};
+use crate::claims::{ClaimStore, Claims};
+use crate::error::Error;
+use crate::export::{Export, ExportStore};
+use crate::features::FeatureStore;
+use crate::jobs::{Job, JobStore};
+use crate::notifications::{Category, NotificationStore};
+use crate::oauth::{
+    hash_token, AccessToken, AuthorizationCode, Client, ClientStore, Consent, ConsentStore,
+    TokenInfo, TokenStore,
+};
+use crate::otp::{hash_code, OtpStore, Phone, Purpose, Totp, MAX_CHECK_ATTEMPTS};
+use crate::quota::QuotaStore;
+use crate::session::{Session, SessionInfo, SessionStore, ROTATION_GRACE_PERIOD};
+use crate::user::{
+    hash_password, upgrade_password_hash, verify_missing_password, verify_password, AccountStatus,
+    Identity, LoginName, Profile, User, UserRecord, UserStore, UsernamePolicy, NO_PASSWORD,
+};
+use async_trait::async_trait;
+use std::backtrace::Backtrace;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// Keeps everything in process memory, for tests and throwaway local instances.
+#[derive(Default)]
+pub struct MemoryUserStore {
+    /// Keyed by the tenant and the username, as that's what has to be unique.
+    users: Mutex<HashMap<(String, String), (User, String)>>,
+    /// Keyed by the tenant and the address.
+    emails: Mutex<HashMap<(String, String), User>>,
+    password_changed_at: Mutex<HashMap<User, SystemTime>>,
+    identities: Mutex<Vec<(User, Identity)>>,
+    next_identity_id: Mutex<i32>,
+    /// Versions of the terms accepted, oldest first.
+    terms: Mutex<Vec<(User, String)>>,
+    /// Only set for users whose status was changed, the rest are active.
+    statuses: Mutex<HashMap<User, AccountStatus>>,
+    service_accounts: Mutex<HashSet<User>>,
+    username_policy: UsernamePolicy,
+}
+
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<Uuid, MemorySession>>,
+}
+
+#[derive(Default)]
+pub struct MemoryClientStore {
+    /// Keyed by the ID, along with the secret hash.
+    clients: Mutex<HashMap<String, (Client, String)>>,
+    logout_redirect_uris: Mutex<HashSet<(String, String)>>,
+    backchannel_logout_uris: Mutex<HashMap<String, String>>,
+    redirect_uris: Mutex<HashSet<(String, String)>>,
+}
+
+#[derive(Default)]
+pub struct MemoryTokenStore {
+    tokens: Mutex<HashMap<String, AccessToken>>,
+    codes: Mutex<HashMap<String, AuthorizationCode>>,
+}
+
+#[derive(Default)]
+pub struct MemoryConsentStore {
+    consents: Mutex<HashMap<(User, String), String>>,
+}
+
+#[derive(Default)]
+pub struct MemoryJobStore {
+    jobs: Mutex<Vec<MemoryJob>>,
+    next_id: Mutex<i64>,
+}
+
+#[derive(Default)]
+pub struct MemoryOtpStore {
+    phones: Mutex<HashMap<User, (String, bool)>>,
+    codes: Mutex<Vec<MemoryCode>>,
+    totp: Mutex<HashMap<User, MemoryTotp>>,
+}
+
+#[derive(Default)]
+pub struct MemoryAuditStore {
+    events: Mutex<Vec<AuditEvent>>,
+}
+
+#[derive(Default)]
+pub struct MemoryExportStore {
+    exports: Mutex<Vec<MemoryExport>>,
+}
+
+#[derive(Default)]
+pub struct MemoryFeatureStore {
+    flags: Mutex<HashMap<String, bool>>,
+}
+
+#[derive(Default)]
+pub struct MemoryClaimStore {
+    claims: Mutex<HashMap<User, Claims>>,
+}
+
+/// Counts keyed by the key and the start of the window, along with when they expire.
+#[derive(Default)]
+pub struct MemoryQuotaStore {
+    counters: Mutex<HashMap<(String, SystemTime), (u64, SystemTime)>>,
+}
+
+#[derive(Default)]
+pub struct MemoryNotificationStore {
+    preferences: Mutex<HashMap<User, HashMap<Category, bool>>>,
+}
+
+struct MemorySession {
+    user: User,
+    created_at: SystemTime,
+    expires_at: SystemTime,
+    restricted: bool,
+    token_id: Option<Uuid>,
+    /// Token ID replaced by the last rotation, along with when it happened.
+    previous: Option<(Option<Uuid>, SystemTime)>,
+}
+
+struct MemoryCode {
+    user: User,
+    purpose: &'static str,
+    code_hash: String,
+    attempts: i32,
+    created_at: SystemTime,
+    expires_at: SystemTime,
+}
+
+#[derive(Default)]
+struct MemoryTotp {
+    secret: Option<String>,
+    pending: Option<String>,
+    last_step: Option<u64>,
+}
+
+struct MemoryExport {
+    id: Uuid,
+    user: User,
+    data: Option<String>,
+    expires_at: SystemTime,
+}
+
+struct MemoryJob {
+    job: Job,
+    run_at: SystemTime,
+    locked_until: Option<SystemTime>,
+    dead: bool,
+}
+
+impl MemorySession {
+    /// Same as the other stores, an unexpired session with the cookie being the current one or
+    /// the one it replaced for a moment after a rotation.
+    fn matches(&self, session: &Session) -> bool {
+        let rotated_after = SystemTime::now() - ROTATION_GRACE_PERIOD;
+        let previous = matches!(self.previous,
+            Some((token_id, rotated_at)) if token_id == session.token_id() && rotated_at > rotated_after);
+        self.expires_at > SystemTime::now() && (self.token_id == session.token_id() || previous)
+    }
+}
+
+impl MemoryUserStore {
+    pub fn new(username_policy: UsernamePolicy) -> MemoryUserStore {
+        MemoryUserStore {
+            username_policy,
+            ..MemoryUserStore::default()
+        }
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryUserStore {
+    async fn get_and_verify(
+        &self,
+        tenant: &str,
+        login: &str,
+        password: &str,
+    ) -> Result<User, Error> {
+        let entry = {
+            let users = self.users.lock().unwrap();
+            match LoginName::parse(login) {
+                LoginName::Username(username) => users.get(&(tenant.to_owned(), username)).cloned(),
+                LoginName::Email(email) => {
+                    let emails = self.emails.lock().unwrap();
+                    let owner = emails.get(&(tenant.to_owned(), email)).copied();
+                    owner.and_then(|owner| users.values().find(|(user, _)| *user == owner).cloned())
+                }
+            }
+        };
+        let Some((user, password_phc)) = entry else {
+            verify_missing_password(password).await;
+            return Err(Error::UserNotFound(Backtrace::capture()));
+        };
+        verify_password(password, &password_phc).await?;
+        upgrade_password_hash(self, user, password, &password_phc).await?;
+        Ok(user)
+    }
+
+    async fn insert(
+        &self,
+        tenant: &str,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> Result<User, Error> {
+        let username = self.username_policy.normalize(username)?;
+        let password_phc = hash_password(password).await;
+        let mut users = self.users.lock().unwrap();
+        let mut emails = self.emails.lock().unwrap();
+        let key = (tenant.to_owned(), username);
+        if users.contains_key(&key) {
+            return Err(Error::UsernameTaken(Backtrace::capture()));
+        }
+        if matches!(email, Some(email) if emails.contains_key(&(tenant.to_owned(), email.to_owned())))
+        {
+            return Err(Error::EmailTaken(Backtrace::capture()));
+        }
+        let user = User {
+            id: users.len() as i32 + 1,
+        };
+        users.insert(key, (user, password_phc));
+        self.password_changed_at
+            .lock()
+            .unwrap()
+            .insert(user, SystemTime::now());
+        if let Some(email) = email {
+            emails.insert((tenant.to_owned(), email.to_owned()), user);
+        }
+        Ok(user)
+    }
+
+    async fn insert_service_account(&self, tenant: &str, username: &str) -> Result<User, Error> {
+        let username = self.username_policy.normalize(username)?;
+        let mut users = self.users.lock().unwrap();
+        let key = (tenant.to_owned(), username);
+        if users.contains_key(&key) {
+            return Err(Error::UsernameTaken(Backtrace::capture()));
+        }
+        let user = User {
+            id: users.len() as i32 + 1,
+        };
+        users.insert(key, (user, NO_PASSWORD.to_owned()));
+        self.password_changed_at
+            .lock()
+            .unwrap()
+            .insert(user, SystemTime::now());
+        self.service_accounts.lock().unwrap().insert(user);
+        Ok(user)
+    }
+
+    async fn service_accounts(&self, tenant: &str) -> Result<Vec<(User, String)>, Error> {
+        let service_accounts = self.service_accounts.lock().unwrap();
+        let mut users: Vec<_> = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((user_tenant, _), (user, _))| {
+                user_tenant == tenant && service_accounts.contains(user)
+            })
+            .map(|((_, username), (user, _))| (*user, username.clone()))
+            .collect();
+        users.sort_by_key(|(user, _)| user.id);
+        Ok(users)
+    }
+
+    async fn find_by_email(&self, tenant: &str, email: &str) -> Result<Option<User>, Error> {
+        let emails = self.emails.lock().unwrap();
+        Ok(emails.get(&(tenant.to_owned(), email.to_owned())).copied())
+    }
+
+    async fn find_by_username(&self, tenant: &str, username: &str) -> Result<Option<User>, Error> {
+        let users = self.users.lock().unwrap();
+        let key = (tenant.to_owned(), username.to_owned());
+        Ok(users.get(&key).map(|(user, _)| *user))
+    }
+
+    async fn set_email(&self, user: User, email: Option<&str>) -> Result<(), Error> {
+        let (tenant, _) = find_username(&self.users.lock().unwrap(), user)?.clone();
+        let mut emails = self.emails.lock().unwrap();
+        let owner = email.and_then(|email| emails.get(&(tenant.clone(), email.to_owned())));
+        if matches!(owner, Some(owner) if *owner != user) {
+            return Err(Error::EmailTaken(Backtrace::capture()));
+        }
+        emails.retain(|_, owner| *owner != user);
+        if let Some(email) = email {
+            emails.insert((tenant, email.to_owned()), user);
+        }
+        Ok(())
+    }
+
+    async fn set_password(&self, user: User, password: &str) -> Result<(), Error> {
+        let new_password_phc = hash_password(password).await;
+        let mut users = self.users.lock().unwrap();
+        if let Some((_, password_phc)) =
+            users.values_mut().find(|(candidate, _)| *candidate == user)
+        {
+            *password_phc = new_password_phc;
+            self.password_changed_at
+                .lock()
+                .unwrap()
+                .insert(user, SystemTime::now());
+        }
+        Ok(())
+    }
+
+    async fn set_password_phc(&self, user: User, new_password_phc: &str) -> Result<(), Error> {
+        let mut users = self.users.lock().unwrap();
+        if let Some((_, password_phc)) =
+            users.values_mut().find(|(candidate, _)| *candidate == user)
+        {
+            *password_phc = new_password_phc.to_owned();
+        }
+        Ok(())
+    }
+
+    async fn remove_password(&self, user: User) -> Result<(), Error> {
+        let mut users = self.users.lock().unwrap();
+        if let Some((_, password_phc)) =
+            users.values_mut().find(|(candidate, _)| *candidate == user)
+        {
+            *password_phc = NO_PASSWORD.to_owned();
+        }
+        Ok(())
+    }
+
+    async fn link_identity(&self, user: User, provider: &str, subject: &str) -> Result<(), Error> {
+        let mut identities = self.identities.lock().unwrap();
+        if identities
+            .iter()
+            .any(|(_, identity)| identity.provider == provider && identity.subject == subject)
+        {
+            return Err(Error::IdentityTaken(Backtrace::capture()));
+        }
+        let mut next_id = self.next_identity_id.lock().unwrap();
+        *next_id += 1;
+        identities.push((
+            user,
+            Identity {
+                id: *next_id,
+                provider: provider.to_owned(),
+                subject: subject.to_owned(),
+            },
+        ));
+        Ok(())
+    }
+
+    async fn find_by_identity(&self, provider: &str, subject: &str) -> Result<Option<User>, Error> {
+        let identities = self.identities.lock().unwrap();
+        Ok(identities
+            .iter()
+            .find(|(_, identity)| identity.provider == provider && identity.subject == subject)
+            .map(|(user, _)| *user))
+    }
+
+    async fn identities(&self, user: User) -> Result<Vec<Identity>, Error> {
+        let identities = self.identities.lock().unwrap();
+        Ok(identities
+            .iter()
+            .filter(|(owner, _)| *owner == user)
+            .map(|(_, identity)| identity.clone())
+            .collect())
+    }
+
+    async fn unlink_identity(&self, user: User, id: i32) -> Result<bool, Error> {
+        let mut identities = self.identities.lock().unwrap();
+        let count = identities.len();
+        identities.retain(|(owner, identity)| !(*owner == user && identity.id == id));
+        Ok(identities.len() < count)
+    }
+
+    async fn profile(&self, user: User) -> Result<Profile, Error> {
+        let users = self.users.lock().unwrap();
+        let key = find_username(&users, user)?;
+        let email = self
+            .emails
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, owner)| **owner == user)
+            .map(|((_, email), _)| email.clone());
+        Ok(Profile {
+            id: user.id,
+            tenant: key.0.clone(),
+            username: key.1.clone(),
+            email,
+            has_password: users[key].1 != NO_PASSWORD,
+            password_changed_at: self.password_changed_at.lock().unwrap()[&user],
+            service: self.service_accounts.lock().unwrap().contains(&user),
+        })
+    }
+
+    async fn rename(&self, user: User, username: &str) -> Result<(), Error> {
+        let username = self.username_policy.normalize(username)?;
+        let mut users = self.users.lock().unwrap();
+        let old_key = find_username(&users, user)?.clone();
+        let key = (old_key.0.clone(), username);
+        if old_key == key {
+            return Ok(());
+        }
+        if users.contains_key(&key) {
+            return Err(Error::UsernameTaken(Backtrace::capture()));
+        }
+        let entry = users.remove(&old_key).unwrap();
+        users.insert(key, entry);
+        Ok(())
+    }
+
+    async fn usernames(&self) -> Result<Vec<(User, String)>, Error> {
+        let mut usernames: Vec<_> = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((_, username), (user, _))| (*user, username.clone()))
+            .collect();
+        usernames.sort_by_key(|(user, _)| user.id);
+        Ok(usernames)
+    }
+
+    async fn accept_terms(&self, user: User, version: &str) -> Result<(), Error> {
+        self.terms.lock().unwrap().push((user, version.to_owned()));
+        Ok(())
+    }
+
+    async fn accepted_terms(&self, user: User) -> Result<Option<String>, Error> {
+        let terms = self.terms.lock().unwrap();
+        Ok(terms
+            .iter()
+            .rev()
+            .find(|(candidate, _)| *candidate == user)
+            .map(|(_, version)| version.clone()))
+    }
+
+    async fn status(&self, user: User) -> Result<AccountStatus, Error> {
+        Ok(*self
+            .statuses
+            .lock()
+            .unwrap()
+            .get(&user)
+            .unwrap_or(&AccountStatus::Active))
+    }
+
+    async fn set_status(&self, user: User, status: AccountStatus) -> Result<(), Error> {
+        self.statuses.lock().unwrap().insert(user, status);
+        Ok(())
+    }
+
+    async fn with_status(
+        &self,
+        tenant: &str,
+        status: AccountStatus,
+    ) -> Result<Vec<(User, String)>, Error> {
+        let statuses = self.statuses.lock().unwrap();
+        let mut users: Vec<_> = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((user_tenant, _), (user, _))| {
+                user_tenant == tenant
+                    && *statuses.get(user).unwrap_or(&AccountStatus::Active) == status
+            })
+            .map(|((_, username), (user, _))| (*user, username.clone()))
+            .collect();
+        users.sort_by_key(|(user, _)| user.id);
+        Ok(users)
+    }
+
+    async fn records(&self) -> Result<Vec<UserRecord>, Error> {
+        let users = self.users.lock().unwrap();
+        let emails = self.emails.lock().unwrap();
+        let password_changed_at = self.password_changed_at.lock().unwrap();
+        let statuses = self.statuses.lock().unwrap();
+        let mut records: Vec<_> = users
+            .iter()
+            .map(|((tenant, username), (user, password_phc))| {
+                let email = emails
+                    .iter()
+                    .find(|(_, owner)| *owner == user)
+                    .map(|((_, email), _)| email.clone());
+                let record = UserRecord {
+                    tenant: tenant.clone(),
+                    username: username.clone(),
+                    email,
+                    password_phc: password_phc.clone(),
+                    password_changed_at: password_changed_at[user],
+                    status: *statuses.get(user).unwrap_or(&AccountStatus::Active),
+                };
+                (user.id, record)
+            })
+            .collect();
+        records.sort_by_key(|(id, _)| *id);
+        Ok(records.into_iter().map(|(_, record)| record).collect())
+    }
+
+    async fn import(&self, record: &UserRecord) -> Result<User, Error> {
+        let username = self.username_policy.normalize(&record.username)?;
+        let mut users = self.users.lock().unwrap();
+        let mut emails = self.emails.lock().unwrap();
+        let key = (record.tenant.clone(), username);
+        if users.contains_key(&key) {
+            return Err(Error::UsernameTaken(Backtrace::capture()));
+        }
+        let email_key = record
+            .email
+            .as_ref()
+            .map(|email| (record.tenant.clone(), email.clone()));
+        if matches!(&email_key, Some(email_key) if emails.contains_key(email_key)) {
+            return Err(Error::EmailTaken(Backtrace::capture()));
+        }
+        let user = User {
+            id: users.len() as i32 + 1,
+        };
+        users.insert(key, (user, record.password_phc.clone()));
+        self.password_changed_at
+            .lock()
+            .unwrap()
+            .insert(user, record.password_changed_at);
+        self.statuses.lock().unwrap().insert(user, record.status);
+        if let Some(email_key) = email_key {
+            emails.insert(email_key, user);
+        }
+        Ok(user)
+    }
+
+    async fn overwrite(&self, user: User, record: &UserRecord) -> Result<(), Error> {
+        self.set_email(user, record.email.as_deref()).await?;
+        let mut users = self.users.lock().unwrap();
+        let key = find_username(&users, user)?.clone();
+        users.get_mut(&key).unwrap().1 = record.password_phc.clone();
+        self.password_changed_at
+            .lock()
+            .unwrap()
+            .insert(user, record.password_changed_at);
+        self.statuses.lock().unwrap().insert(user, record.status);
+        Ok(())
+    }
+}
+
+/// Tenant and username of the user, which they're keyed by.
+fn find_username(
+    users: &HashMap<(String, String), (User, String)>,
+    user: User,
+) -> Result<&(String, String), Error> {
+    users
+        .iter()
+        .find(|(_, (candidate, _))| candidate.id == user.id)
+        .map(|(key, _)| key)
+        .ok_or_else(|| Error::UserNotFound(Backtrace::capture()))
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn insert(&self, session: &Session, restricted: bool) -> Result<(), Error> {
+        let created_at = SystemTime::now();
+        self.sessions.lock().unwrap().insert(
+            session.id(),
+            MemorySession {
+                user: *session.user(),
+                created_at,
+                expires_at: created_at + session.lifetime(),
+                restricted,
+                token_id: session.token_id(),
+                previous: None,
+            },
+        );
+        Ok(())
+    }
+
+    async fn is_active(&self, session: &Session) -> Result<bool, Error> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(matches!(sessions.get(&session.id()),
+            Some(stored) if stored.matches(session) && !stored.restricted))
+    }
+
+    async fn is_restricted(&self, session: &Session) -> Result<bool, Error> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(matches!(sessions.get(&session.id()),
+            Some(stored) if stored.matches(session) && stored.restricted))
+    }
+
+    async fn unrestrict(&self, session: &Session) -> Result<(), Error> {
+        if let Some(stored) = self.sessions.lock().unwrap().get_mut(&session.id()) {
+            stored.restricted = false;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, session: &Session) -> Result<(), Error> {
+        self.sessions.lock().unwrap().remove(&session.id());
+        Ok(())
+    }
+
+    async fn list(&self, user: User) -> Result<Vec<SessionInfo>, Error> {
+        let now = SystemTime::now();
+        let mut sessions: Vec<_> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, stored)| stored.user.id == user.id && stored.expires_at > now)
+            .map(|(id, stored)| SessionInfo {
+                id: *id,
+                created_at: stored.created_at,
+                expires_at: stored.expires_at,
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.created_at);
+        Ok(sessions)
+    }
+
+    async fn rotate(&self, session: &Session, rotated: &Session) -> Result<bool, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = SystemTime::now();
+        match sessions.get_mut(&session.id()) {
+            Some(stored) if stored.token_id == session.token_id() && stored.expires_at > now => {
+                stored.previous = Some((stored.token_id, now));
+                stored.token_id = rotated.token_id();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn revoke(&self, user: User, id: Uuid) -> Result<bool, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if !matches!(sessions.get(&id), Some(stored) if stored.user.id == user.id) {
+            return Ok(false);
+        }
+        sessions.remove(&id);
+        Ok(true)
+    }
+
+    async fn revoke_all(&self, user: User) -> Result<u64, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let count = sessions.len();
+        sessions.retain(|_, stored| stored.user != user);
+        Ok((count - sessions.len()) as u64)
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let count = sessions.len();
+        sessions.retain(|_, stored| stored.expires_at >= before);
+        Ok((count - sessions.len()) as u64)
+    }
+}
+
+#[async_trait]
+impl ClientStore for MemoryClientStore {
+    async fn insert(
+        &self,
+        id: &str,
+        secret: &str,
+        scopes: &[String],
+        user: Option<User>,
+    ) -> Result<(), Error> {
+        let secret_phc = hash_password(secret).await;
+        self.insert_client(id, secret_phc, scopes, user)
+    }
+
+    async fn insert_public(&self, id: &str, scopes: &[String]) -> Result<(), Error> {
+        self.insert_client(id, String::new(), scopes, None)
+    }
+
+    async fn authenticate(&self, id: &str, secret: &str) -> Result<Client, Error> {
+        let (client, secret_phc) = self
+            .clients
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::InvalidClient(Backtrace::capture()))?;
+        verify_password(secret, &secret_phc)
+            .await
+            .map_err(|_| Error::InvalidClient(Backtrace::capture()))?;
+        Ok(client)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Client>, Error> {
+        let clients = self.clients.lock().unwrap();
+        Ok(clients.get(id).map(|(client, _)| client.clone()))
+    }
+
+    async fn of_user(&self, user: User) -> Result<Vec<Client>, Error> {
+        let clients = self.clients.lock().unwrap();
+        let mut clients: Vec<_> = clients
+            .values()
+            .filter(|(client, _)| client.user == Some(user))
+            .map(|(client, _)| client.clone())
+            .collect();
+        clients.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(clients)
+    }
+
+    async fn add_logout_redirect_uri(&self, id: &str, uri: &str) -> Result<(), Error> {
+        self.add_uri(&self.logout_redirect_uris, id, uri)
+    }
+
+    async fn has_logout_redirect_uri(&self, id: &str, uri: &str) -> Result<bool, Error> {
+        let uris = self.logout_redirect_uris.lock().unwrap();
+        Ok(uris.contains(&(id.to_owned(), uri.to_owned())))
+    }
+
+    async fn set_backchannel_logout_uri(&self, id: &str, uri: Option<&str>) -> Result<(), Error> {
+        if !self.clients.lock().unwrap().contains_key(id) {
+            return Err(Error::ClientNotFound(id.to_owned(), Backtrace::capture()));
+        }
+        let mut uris = self.backchannel_logout_uris.lock().unwrap();
+        match uri {
+            Some(uri) => uris.insert(id.to_owned(), uri.to_owned()),
+            None => uris.remove(id),
+        };
+        Ok(())
+    }
+
+    async fn backchannel_logout_uri(&self, id: &str) -> Result<Option<String>, Error> {
+        let uris = self.backchannel_logout_uris.lock().unwrap();
+        Ok(uris.get(id).cloned())
+    }
+
+    async fn add_redirect_uri(&self, id: &str, uri: &str) -> Result<(), Error> {
+        self.add_uri(&self.redirect_uris, id, uri)
+    }
+
+    async fn has_redirect_uri(&self, id: &str, uri: &str) -> Result<bool, Error> {
+        let uris = self.redirect_uris.lock().unwrap();
+        Ok(uris.contains(&(id.to_owned(), uri.to_owned())))
+    }
+}
+
+impl MemoryClientStore {
+    fn insert_client(
+        &self,
+        id: &str,
+        secret_phc: String,
+        scopes: &[String],
+        user: Option<User>,
+    ) -> Result<(), Error> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.contains_key(id) {
+            return Err(Error::ClientIdTaken(Backtrace::capture()));
+        }
+        let client = Client {
+            id: id.to_owned(),
+            scopes: scopes.to_vec(),
+            user,
+            public: secret_phc.is_empty(),
+        };
+        clients.insert(id.to_owned(), (client, secret_phc));
+        Ok(())
+    }
+
+    fn add_uri(
+        &self,
+        uris: &Mutex<HashSet<(String, String)>>,
+        id: &str,
+        uri: &str,
+    ) -> Result<(), Error> {
+        if !self.clients.lock().unwrap().contains_key(id) {
+            return Err(Error::ClientNotFound(id.to_owned(), Backtrace::capture()));
+        }
+        uris.lock().unwrap().insert((id.to_owned(), uri.to_owned()));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn insert(&self, token: &str, details: &AccessToken) -> Result<(), Error> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .insert(hash_token(token), details.clone());
+        Ok(())
+    }
+
+    async fn get(&self, token: &str) -> Result<Option<AccessToken>, Error> {
+        let tokens = self.tokens.lock().unwrap();
+        Ok(tokens
+            .get(&hash_token(token))
+            .filter(|stored| stored.expires_at > SystemTime::now())
+            .cloned())
+    }
+
+    async fn revoke(&self, token: &str, client_id: &str) -> Result<bool, Error> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let token_hash = hash_token(token);
+        if !matches!(tokens.get(&token_hash), Some(stored) if stored.client_id == client_id) {
+            return Ok(false);
+        }
+        tokens.remove(&token_hash);
+        Ok(true)
+    }
+
+    async fn revoke_all(&self, client_id: &str, user: User) -> Result<(), Error> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .retain(|_, token| !(token.client_id == client_id && token.user == Some(user)));
+        Ok(())
+    }
+
+    async fn list(&self, user: User) -> Result<Vec<TokenInfo>, Error> {
+        let tokens = self.tokens.lock().unwrap();
+        let now = SystemTime::now();
+        let mut list: Vec<TokenInfo> = tokens
+            .iter()
+            .filter(|(_, token)| token.user == Some(user) && token.expires_at > now)
+            .map(|(token_hash, token)| TokenInfo {
+                id: token_hash.clone(),
+                client_id: token.client_id.clone(),
+                scope: token.scope.clone(),
+                expires_at: token.expires_at,
+            })
+            .collect();
+        list.sort_by_key(|token| token.expires_at);
+        Ok(list)
+    }
+
+    async fn revoke_by_id(&self, user: User, id: &str) -> Result<bool, Error> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if !matches!(tokens.get(id), Some(stored) if stored.user == Some(user)) {
+            return Ok(false);
+        }
+        tokens.remove(id);
+        Ok(true)
+    }
+
+    async fn insert_code(&self, code: &str, details: &AuthorizationCode) -> Result<(), Error> {
+        self.codes
+            .lock()
+            .unwrap()
+            .insert(hash_token(code), details.clone());
+        Ok(())
+    }
+
+    async fn take_code(&self, code: &str) -> Result<Option<AuthorizationCode>, Error> {
+        let mut codes = self.codes.lock().unwrap();
+        Ok(codes
+            .remove(&hash_token(code))
+            .filter(|stored| stored.expires_at > SystemTime::now()))
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let mut codes = self.codes.lock().unwrap();
+        let count = tokens.len() + codes.len();
+        tokens.retain(|_, stored| stored.expires_at >= before);
+        codes.retain(|_, stored| stored.expires_at >= before);
+        Ok((count - tokens.len() - codes.len()) as u64)
+    }
+}
+
+#[async_trait]
+impl ConsentStore for MemoryConsentStore {
+    async fn grant(&self, user: User, client_id: &str, scope: &str) -> Result<(), Error> {
+        self.consents
+            .lock()
+            .unwrap()
+            .insert((user, client_id.to_owned()), scope.to_owned());
+        Ok(())
+    }
+
+    async fn get(&self, user: User, client_id: &str) -> Result<Option<String>, Error> {
+        let consents = self.consents.lock().unwrap();
+        Ok(consents.get(&(user, client_id.to_owned())).cloned())
+    }
+
+    async fn list(&self, user: User) -> Result<Vec<Consent>, Error> {
+        let consents = self.consents.lock().unwrap();
+        let mut list: Vec<Consent> = consents
+            .iter()
+            .filter(|((owner, _), _)| *owner == user)
+            .map(|((_, client_id), scope)| Consent {
+                client_id: client_id.clone(),
+                scope: scope.clone(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        Ok(list)
+    }
+
+    async fn revoke(&self, user: User, client_id: &str) -> Result<bool, Error> {
+        let mut consents = self.consents.lock().unwrap();
+        Ok(consents.remove(&(user, client_id.to_owned())).is_some())
+    }
+}
+
+#[async_trait]
+impl JobStore for MemoryJobStore {
+    async fn enqueue(&self, kind: &str, payload: &str) -> Result<(), Error> {
+        let mut next_id = self.next_id.lock().unwrap();
+        *next_id += 1;
+        self.jobs.lock().unwrap().push(MemoryJob {
+            job: Job {
+                id: *next_id,
+                kind: kind.to_owned(),
+                payload: payload.to_owned(),
+                attempts: 0,
+            },
+            run_at: SystemTime::now(),
+            locked_until: None,
+            dead: false,
+        });
+        Ok(())
+    }
+
+    async fn claim(&self, lease: Duration) -> Result<Option<Job>, Error> {
+        let now = SystemTime::now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(stored) = jobs
+            .iter_mut()
+            .filter(|stored| {
+                !stored.dead
+                    && stored.run_at <= now
+                    && !matches!(stored.locked_until, Some(until) if until >= now)
+            })
+            .min_by_key(|stored| stored.run_at)
+        else {
+            return Ok(None);
+        };
+        stored.job.attempts += 1;
+        stored.locked_until = Some(now + lease);
+        Ok(Some(Job {
+            id: stored.job.id,
+            kind: stored.job.kind.clone(),
+            payload: stored.job.payload.clone(),
+            attempts: stored.job.attempts,
+        }))
+    }
+
+    async fn complete(&self, id: i64) -> Result<(), Error> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .retain(|stored| stored.job.id != id);
+        Ok(())
+    }
+
+    async fn retry(&self, id: i64, _error: &str, run_at: SystemTime) -> Result<(), Error> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(stored) = jobs.iter_mut().find(|stored| stored.job.id == id) {
+            stored.run_at = run_at;
+            stored.locked_until = None;
+        }
+        Ok(())
+    }
+
+    async fn bury(&self, id: i64, _error: &str) -> Result<(), Error> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(stored) = jobs.iter_mut().find(|stored| stored.job.id == id) {
+            stored.dead = true;
+            stored.locked_until = None;
+        }
+        Ok(())
+    }
+
+    async fn requeue_dead(&self) -> Result<u64, Error> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut requeued = 0;
+        for stored in jobs.iter_mut().filter(|stored| stored.dead) {
+            stored.dead = false;
+            stored.job.attempts = 0;
+            stored.run_at = SystemTime::now();
+            requeued += 1;
+        }
+        Ok(requeued)
+    }
+}
+
+#[async_trait]
+impl OtpStore for MemoryOtpStore {
+    async fn set_phone(&self, user: User, number: &str) -> Result<(), Error> {
+        let mut phones = self.phones.lock().unwrap();
+        phones.insert(user, (number.to_owned(), false));
+        Ok(())
+    }
+
+    async fn phone(&self, user: User) -> Result<Option<Phone>, Error> {
+        let phones = self.phones.lock().unwrap();
+        Ok(phones.get(&user).map(|(number, verified)| Phone {
+            number: number.clone(),
+            verified: *verified,
+        }))
+    }
+
+    async fn verify_phone(&self, user: User, number: &str) -> Result<bool, Error> {
+        let mut phones = self.phones.lock().unwrap();
+        match phones.get_mut(&user) {
+            Some((stored, verified)) if stored == number => {
+                *verified = true;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn remove_phone(&self, user: User) -> Result<(), Error> {
+        self.phones.lock().unwrap().remove(&user);
+        Ok(())
+    }
+
+    async fn set_pending_totp(&self, user: User, secret: &str) -> Result<(), Error> {
+        let mut totp = self.totp.lock().unwrap();
+        totp.entry(user).or_default().pending = Some(secret.to_owned());
+        Ok(())
+    }
+
+    async fn totp(&self, user: User) -> Result<Option<Totp>, Error> {
+        let totp = self.totp.lock().unwrap();
+        Ok(totp.get(&user).map(|stored| Totp {
+            secret: stored.secret.clone(),
+            pending: stored.pending.clone(),
+        }))
+    }
+
+    async fn confirm_totp(&self, user: User, pending: &str, step: u64) -> Result<bool, Error> {
+        let mut totp = self.totp.lock().unwrap();
+        match totp.get_mut(&user) {
+            Some(stored) if stored.pending.as_deref() == Some(pending) => {
+                stored.secret = stored.pending.take();
+                stored.last_step = Some(step);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn accept_totp(&self, user: User, secret: &str, step: u64) -> Result<bool, Error> {
+        let mut totp = self.totp.lock().unwrap();
+        match totp.get_mut(&user) {
+            Some(stored)
+                if stored.secret.as_deref() == Some(secret)
+                    && !matches!(stored.last_step, Some(last_step) if last_step >= step) =>
+            {
+                stored.last_step = Some(step);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn remove_totp(&self, user: User) -> Result<(), Error> {
+        self.totp.lock().unwrap().remove(&user);
+        Ok(())
+    }
+
+    async fn insert_code(
+        &self,
+        user: User,
+        purpose: Purpose,
+        code: &str,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        self.codes.lock().unwrap().push(MemoryCode {
+            user,
+            purpose: purpose.as_str(),
+            code_hash: hash_code(user, code),
+            attempts: 0,
+            created_at: SystemTime::now(),
+            expires_at,
+        });
+        Ok(())
+    }
+
+    async fn count_codes(&self, user: User, since: SystemTime) -> Result<u64, Error> {
+        let codes = self.codes.lock().unwrap();
+        Ok(codes
+            .iter()
+            .filter(|stored| stored.user == user && stored.created_at > since)
+            .count() as u64)
+    }
+
+    async fn check_code(&self, user: User, purpose: Purpose, code: &str) -> Result<bool, Error> {
+        let mut codes = self.codes.lock().unwrap();
+        let now = SystemTime::now();
+        let Some(stored) = codes
+            .iter_mut()
+            .filter(|stored| {
+                stored.user == user && stored.purpose == purpose.as_str() && stored.expires_at > now
+            })
+            .last()
+        else {
+            return Ok(false);
+        };
+        stored.attempts += 1;
+        if stored.attempts > MAX_CHECK_ATTEMPTS || stored.code_hash != hash_code(user, code) {
+            return Ok(false);
+        }
+        codes.retain(|stored| !(stored.user == user && stored.purpose == purpose.as_str()));
+        Ok(true)
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let mut codes = self.codes.lock().unwrap();
+        let count = codes.len();
+        codes.retain(|stored| stored.expires_at >= before);
+        Ok((count - codes.len()) as u64)
+    }
+}
+
+#[async_trait]
+impl AuditStore for MemoryAuditStore {
+    async fn insert(&self, event: &AuditEvent) -> Result<(), Error> {
+        self.events.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+
+    async fn list(
+        &self,
+        user: User,
+        since: SystemTime,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, Error> {
+        let events = self.events.lock().unwrap();
+        Ok(events
+            .iter()
+            .rev()
+            .filter(|event| event.user == Some(user) && event.created_at > since)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn logins(
+        &self,
+        user: User,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, Error> {
+        let events = self.events.lock().unwrap();
+        Ok(events
+            .iter()
+            .rev()
+            .filter(|event| event.user == Some(user))
+            .filter(|event| {
+                event.kind == audit::LOGIN_SUCCEEDED || event.kind == audit::LOGIN_FAILED
+            })
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn position(&self) -> Result<i64, Error> {
+        Ok(self.events.lock().unwrap().len() as i64)
+    }
+
+    async fn after(&self, position: i64, limit: usize) -> Result<Vec<(i64, AuditEvent)>, Error> {
+        let events = self.events.lock().unwrap();
+        Ok(events
+            .iter()
+            .enumerate()
+            .skip(position as usize)
+            .take(limit)
+            .map(|(i, event)| (i as i64 + 1, event.clone()))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ExportStore for MemoryExportStore {
+    async fn insert(&self, user: User, id: Uuid, expires_at: SystemTime) -> Result<(), Error> {
+        self.exports.lock().unwrap().push(MemoryExport {
+            id,
+            user,
+            data: None,
+            expires_at,
+        });
+        Ok(())
+    }
+
+    async fn complete(&self, id: Uuid, data: &str) -> Result<(), Error> {
+        let mut exports = self.exports.lock().unwrap();
+        if let Some(export) = exports.iter_mut().find(|export| export.id == id) {
+            export.data = Some(data.to_owned());
+        }
+        Ok(())
+    }
+
+    async fn latest(&self, user: User) -> Result<Option<Export>, Error> {
+        let now = SystemTime::now();
+        let exports = self.exports.lock().unwrap();
+        Ok(exports
+            .iter()
+            .rev()
+            .find(|export| export.user == user && export.expires_at > now)
+            .map(|export| Export {
+                id: export.id,
+                ready: export.data.is_some(),
+            }))
+    }
+
+    async fn take(&self, id: Uuid) -> Result<Option<(User, String)>, Error> {
+        let now = SystemTime::now();
+        let mut exports = self.exports.lock().unwrap();
+        let Some(index) = exports.iter().position(|export| {
+            export.id == id && export.data.is_some() && export.expires_at > now
+        }) else {
+            return Ok(None);
+        };
+        let export = exports.remove(index);
+        Ok(Some((export.user, export.data.unwrap())))
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let mut exports = self.exports.lock().unwrap();
+        let count = exports.len();
+        exports.retain(|export| export.expires_at >= before);
+        Ok((count - exports.len()) as u64)
+    }
+}
+
+#[async_trait]
+impl FeatureStore for MemoryFeatureStore {
+    async fn list(&self) -> Result<Vec<(String, bool)>, Error> {
+        let mut flags: Vec<_> = self
+            .flags
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, enabled)| (name.clone(), *enabled))
+            .collect();
+        flags.sort();
+        Ok(flags)
+    }
+
+    async fn set(&self, name: &str, enabled: bool) -> Result<(), Error> {
+        self.flags.lock().unwrap().insert(name.to_owned(), enabled);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClaimStore for MemoryClaimStore {
+    async fn list(&self, user: User) -> Result<Claims, Error> {
+        let claims = self.claims.lock().unwrap();
+        Ok(claims.get(&user).cloned().unwrap_or_default())
+    }
+
+    async fn set(&self, user: User, name: &str, value: &serde_json::Value) -> Result<(), Error> {
+        let mut claims = self.claims.lock().unwrap();
+        claims
+            .entry(user)
+            .or_default()
+            .insert(name.to_owned(), value.clone());
+        Ok(())
+    }
+
+    async fn remove(&self, user: User, name: &str) -> Result<bool, Error> {
+        let mut claims = self.claims.lock().unwrap();
+        Ok(claims
+            .get_mut(&user)
+            .and_then(|claims| claims.remove(name))
+            .is_some())
+    }
+}
+
+#[async_trait]
+impl QuotaStore for MemoryQuotaStore {
+    async fn count(&self, key: &str, window: SystemTime) -> Result<u64, Error> {
+        let counters = self.counters.lock().unwrap();
+        Ok(counters
+            .get(&(key.to_owned(), window))
+            .map_or(0, |(count, _)| *count))
+    }
+
+    async fn increment(
+        &self,
+        key: &str,
+        window: SystemTime,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters
+            .entry((key.to_owned(), window))
+            .or_insert((0, expires_at));
+        counter.0 += 1;
+        Ok(())
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let mut counters = self.counters.lock().unwrap();
+        let count = counters.len();
+        counters.retain(|_, (_, expires_at)| *expires_at >= before);
+        Ok((count - counters.len()) as u64)
+    }
+}
+
+#[async_trait]
+impl NotificationStore for MemoryNotificationStore {
+    async fn list(&self, user: User) -> Result<HashMap<Category, bool>, Error> {
+        let preferences = self.preferences.lock().unwrap();
+        Ok(preferences.get(&user).cloned().unwrap_or_default())
+    }
+
+    async fn set(&self, user: User, category: Category, enabled: bool) -> Result<(), Error> {
+        let mut preferences = self.preferences.lock().unwrap();
+        preferences
+            .entry(user)
+            .or_default()
+            .insert(category, enabled);
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+use crate::error::Error;
+use crate::session::Session;
+use async_trait::async_trait;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response};
+
+/// Adds to what happens on every request without changing the router, like rate limiting, extra
+/// headers or logging of its own. Registered in order through [`crate::Server::middleware`], each
+/// stage running the middleware in that order, and the ones doing nothing at a stage can leave it
+/// out.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Runs before anything is routed, once the tenant and the mount path are stripped. Answering
+    /// the request here means nothing else handles it, and an error is shown like a handler's.
+    async fn before_routing(
+        &self,
+        _req: &mut Request<Body>,
+    ) -> Result<Option<Response<Body>>, Error> {
+        Ok(None)
+    }
+
+    /// Runs once the session is known, for the pages and the API. Endpoints for other services,
+    /// like `/auth/check` and `/oauth/token`, answer before that and skip this stage.
+    async fn after_auth(
+        &self,
+        _req: &Request<Body>,
+        _session: Option<&Session>,
+    ) -> Result<Option<Response<Body>>, Error> {
+        Ok(None)
+    }
+
+    /// Runs on every response right before it's sent, error pages included.
+    async fn before_response(&self, _response: &mut Response<Body>) {}
+}
+
+/// Headers telling browsers to be strict with the pages, added when `SECURITY_HEADERS` is set and
+/// unless the handler set them differently.
+pub struct SecurityHeaders;
+
+const SECURITY_HEADERS: &[(&str, &str)] = &[
+    ("x-content-type-options", "nosniff"),
+    ("x-frame-options", "DENY"),
+    ("referrer-policy", "same-origin"),
+];
+
+#[async_trait]
+impl Middleware for SecurityHeaders {
+    async fn before_response(&self, response: &mut Response<Body>) {
+        let headers = response.headers_mut();
+        for (name, value) in SECURITY_HEADERS {
+            headers
+                .entry(HeaderName::from_static(name))
+                .or_insert(HeaderValue::from_static(value));
+        }
+    }
+}
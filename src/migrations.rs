@@ -0,0 +1,355 @@
+use crate::error::Error;
+use slog::{info, Logger};
+use std::backtrace::Backtrace;
+use tokio_postgres::Client;
+
+struct Migration {
+    version: i32,
+    name: &'static str,
+    postgres: &'static str,
+    sqlite: &'static str,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "users",
+        postgres: include_str!("../migrations/postgres/0001_users.sql"),
+        sqlite: include_str!("../migrations/sqlite/0001_users.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "sessions",
+        postgres: include_str!("../migrations/postgres/0002_sessions.sql"),
+        sqlite: include_str!("../migrations/sqlite/0002_sessions.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "oauth",
+        postgres: include_str!("../migrations/postgres/0003_oauth.sql"),
+        sqlite: include_str!("../migrations/sqlite/0003_oauth.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "oauth_logout",
+        postgres: include_str!("../migrations/postgres/0004_oauth_logout.sql"),
+        sqlite: include_str!("../migrations/sqlite/0004_oauth_logout.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "oauth_consent",
+        postgres: include_str!("../migrations/postgres/0005_oauth_consent.sql"),
+        sqlite: include_str!("../migrations/sqlite/0005_oauth_consent.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "jobs",
+        postgres: include_str!("../migrations/postgres/0006_jobs.sql"),
+        sqlite: include_str!("../migrations/sqlite/0006_jobs.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "sms_otp",
+        postgres: include_str!("../migrations/postgres/0007_sms_otp.sql"),
+        sqlite: include_str!("../migrations/sqlite/0007_sms_otp.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "user_email",
+        postgres: include_str!("../migrations/postgres/0008_user_email.sql"),
+        sqlite: include_str!("../migrations/sqlite/0008_user_email.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "identities",
+        postgres: include_str!("../migrations/postgres/0009_identities.sql"),
+        sqlite: include_str!("../migrations/sqlite/0009_identities.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "password_expiry",
+        postgres: include_str!("../migrations/postgres/0010_password_expiry.sql"),
+        sqlite: include_str!("../migrations/sqlite/0010_password_expiry.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "audit_events",
+        postgres: include_str!("../migrations/postgres/0011_audit_events.sql"),
+        sqlite: include_str!("../migrations/sqlite/0011_audit_events.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "terms",
+        postgres: include_str!("../migrations/postgres/0012_terms.sql"),
+        sqlite: include_str!("../migrations/sqlite/0012_terms.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "data_exports",
+        postgres: include_str!("../migrations/postgres/0013_data_exports.sql"),
+        sqlite: include_str!("../migrations/sqlite/0013_data_exports.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "account_status",
+        postgres: include_str!("../migrations/postgres/0014_account_status.sql"),
+        sqlite: include_str!("../migrations/sqlite/0014_account_status.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "feature_flags",
+        postgres: include_str!("../migrations/postgres/0015_feature_flags.sql"),
+        sqlite: include_str!("../migrations/sqlite/0015_feature_flags.sql"),
+    },
+    Migration {
+        version: 16,
+        name: "tenants",
+        postgres: include_str!("../migrations/postgres/0016_tenants.sql"),
+        sqlite: include_str!("../migrations/sqlite/0016_tenants.sql"),
+    },
+    Migration {
+        version: 17,
+        name: "claims",
+        postgres: include_str!("../migrations/postgres/0017_claims.sql"),
+        sqlite: include_str!("../migrations/sqlite/0017_claims.sql"),
+    },
+    Migration {
+        version: 18,
+        name: "session_tokens",
+        postgres: include_str!("../migrations/postgres/0018_session_tokens.sql"),
+        sqlite: include_str!("../migrations/sqlite/0018_session_tokens.sql"),
+    },
+    Migration {
+        version: 19,
+        name: "quota_counters",
+        postgres: include_str!("../migrations/postgres/0019_quota_counters.sql"),
+        sqlite: include_str!("../migrations/sqlite/0019_quota_counters.sql"),
+    },
+    Migration {
+        version: 20,
+        name: "service_accounts",
+        postgres: include_str!("../migrations/postgres/0020_service_accounts.sql"),
+        sqlite: include_str!("../migrations/sqlite/0020_service_accounts.sql"),
+    },
+    Migration {
+        version: 21,
+        name: "totp",
+        postgres: include_str!("../migrations/postgres/0021_totp.sql"),
+        sqlite: include_str!("../migrations/sqlite/0021_totp.sql"),
+    },
+    Migration {
+        version: 22,
+        name: "notification_preferences",
+        postgres: include_str!("../migrations/postgres/0022_notification_preferences.sql"),
+        sqlite: include_str!("../migrations/sqlite/0022_notification_preferences.sql"),
+    },
+    Migration {
+        version: 23,
+        name: "backchannel_logout",
+        postgres: include_str!("../migrations/postgres/0023_backchannel_logout.sql"),
+        sqlite: include_str!("../migrations/sqlite/0023_backchannel_logout.sql"),
+    },
+    Migration {
+        version: 24,
+        name: "pkce",
+        postgres: include_str!("../migrations/postgres/0024_pkce.sql"),
+        sqlite: include_str!("../migrations/sqlite/0024_pkce.sql"),
+    },
+    Migration {
+        version: 25,
+        name: "normalize_usernames",
+        postgres: include_str!("../migrations/postgres/0025_normalize_usernames.sql"),
+        sqlite: include_str!("../migrations/sqlite/0025_normalize_usernames.sql"),
+    },
+];
+
+/// Migration after which the usernames stored before are normalized, which SQL can't do. Being
+/// recorded along with the others makes it run once rather than scan every user on each start.
+pub const NORMALIZE_USERNAMES: i32 = 25;
+
+/// Schema of the Postgres databases created before there were migrations, which was just the users
+/// table. Databases that have it but no migrations recorded start out with it marked as applied,
+/// rather than failing to create the table again. SQLite only came along with migrations, so its
+/// databases never lack them.
+const BASELINE: &Migration = &MIGRATIONS[0];
+
+// Arbitrary key for the advisory lock, so that several instances starting at the same time don't
+// try to apply the same migration twice.
+const LOCK_KEY: i64 = 0x6175_7468_746f_776e;
+
+/// Applies the pending migrations, returning the versions of those it did.
+pub async fn run_postgres(client: &mut Client, log: &Logger) -> Result<Vec<i32>, Error> {
+    let transaction = client.transaction().await?;
+    transaction
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY])
+        .await?;
+    transaction
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .await?;
+    let mut current: i32 = transaction
+        .query_one(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+            &[],
+        )
+        .await?
+        .get(0);
+    let has_users: bool = transaction
+        .query_one("SELECT to_regclass('users') IS NOT NULL", &[])
+        .await?
+        .get(0);
+    if current == 0 && has_users {
+        info!(log, "Adopting database created before migrations"; "version" => BASELINE.version, "name" => BASELINE.name);
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                &[&BASELINE.version, &BASELINE.name],
+            )
+            .await?;
+        current = BASELINE.version;
+    }
+    let mut applied = Vec::new();
+    for migration in pending(current)? {
+        info!(log, "Applying database migration"; "version" => migration.version, "name" => migration.name);
+        transaction.batch_execute(migration.postgres).await?;
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                &[&migration.version, &migration.name],
+            )
+            .await?;
+        applied.push(migration.version);
+    }
+    transaction.commit().await?;
+    Ok(applied)
+}
+
+/// Applies the pending migrations, returning the versions of those it did.
+pub fn run_sqlite(connection: &mut rusqlite::Connection, log: &Logger) -> Result<Vec<i32>, Error> {
+    // Some changes can only be made by recreating a table, and dropping the old one would cascade
+    // to everything referencing it. The setting can't be changed inside a transaction, and the
+    // references are checked before it's switched back on.
+    connection.execute_batch("PRAGMA foreign_keys = OFF")?;
+    let result = run_sqlite_transaction(connection, log);
+    connection.execute_batch("PRAGMA foreign_keys = ON")?;
+    result
+}
+
+fn run_sqlite_transaction(
+    connection: &mut rusqlite::Connection,
+    log: &Logger,
+) -> Result<Vec<i32>, Error> {
+    // An immediate transaction takes the write lock upfront, which serves the same purpose as the
+    // advisory lock does for Postgres.
+    let transaction =
+        connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    transaction.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        )",
+    )?;
+    let current: i32 = transaction.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        [],
+        |row| row.get(0),
+    )?;
+    let mut applied = Vec::new();
+    for migration in pending(current)? {
+        info!(log, "Applying database migration"; "version" => migration.version, "name" => migration.name);
+        transaction.execute_batch(migration.sqlite)?;
+        transaction.execute(
+            "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+            rusqlite::params![migration.version, migration.name],
+        )?;
+        applied.push(migration.version);
+    }
+    let mut check = transaction.prepare("PRAGMA foreign_key_check")?;
+    if check.exists([])? {
+        return Err(Error::ForeignKeyViolation(Backtrace::capture()));
+    }
+    drop(check);
+    transaction.commit()?;
+    Ok(applied)
+}
+
+fn pending(current: i32) -> Result<impl Iterator<Item = &'static Migration>, Error> {
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if current > latest {
+        return Err(Error::SchemaTooNew {
+            current,
+            latest,
+            backtrace: Backtrace::capture(),
+        });
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .filter(move |migration| migration.version > current))
+}
+
+/// Versions of the migrations [`pending`] for a database at the given one, for tests to check.
+#[cfg(test)]
+pub fn pending_versions(current: i32) -> Result<Vec<i32>, Error> {
+    Ok(pending(current)?
+        .map(|migration| migration.version)
+        .collect())
+}
+
+pub async fn check_postgres(client: &Client) -> Result<(), Error> {
+    let applied = client
+        .query(
+            "SELECT version, name FROM schema_migrations ORDER BY version",
+            &[],
+        )
+        .await?
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+    check_applied(applied)
+}
+
+pub fn check_sqlite(connection: &rusqlite::Connection) -> Result<(), Error> {
+    let mut statement =
+        connection.prepare("SELECT version, name FROM schema_migrations ORDER BY version")?;
+    let applied = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    check_applied(applied)
+}
+
+/// Checks that the migrations applied are exactly the ones of this build, which they aren't when
+/// another build with different migrations under the same versions migrated the database first.
+fn check_applied(applied: Vec<(i32, String)>) -> Result<(), Error> {
+    let mut applied = applied.into_iter();
+    let mut expected = MIGRATIONS.iter();
+    loop {
+        let (version, found, expected) = match (applied.next(), expected.next()) {
+            (None, None) => return Ok(()),
+            (Some((version, name)), Some(migration))
+                if version == migration.version && name == migration.name =>
+            {
+                continue
+            }
+            (Some((version, name)), Some(migration)) if version == migration.version => {
+                (version, Some(name), Some(migration.name))
+            }
+            (Some((version, _)), Some(migration)) if version > migration.version => {
+                (migration.version, None, Some(migration.name))
+            }
+            (Some((version, name)), _) => (version, Some(name), None),
+            (None, Some(migration)) => (migration.version, None, Some(migration.name)),
+        };
+        return Err(Error::SchemaMismatch {
+            version,
+            found,
+            expected,
+            backtrace: Backtrace::capture(),
+        });
+    }
+}
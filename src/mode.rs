@@ -0,0 +1,136 @@
+use crate::error::Error;
+use crate::util::env_var_opt;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use slog::{error, info, Logger};
+use std::backtrace::Backtrace;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How much of the service is up, for operators to take it down while they work on it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    Normal,
+    /// Logins keep working, but registrations and changes to accounts are turned away, like while
+    /// the database is being moved and whatever is written could be lost.
+    ReadOnly,
+    /// Everything but the health checks answers with a page saying the service is down.
+    Maintenance,
+}
+
+/// The mode the service is in, which can be switched while it's running, either by admins through
+/// the API or by writing the mode to `MODE_FILE`. Without the file, the service starts in the mode
+/// from `MODE`.
+pub struct ModeSwitch {
+    mode: RwLock<Mode>,
+    path: Option<PathBuf>,
+}
+
+impl Mode {
+    pub fn parse(mode: &str) -> Option<Mode> {
+        match mode {
+            "normal" => Some(Mode::Normal),
+            "read-only" => Some(Mode::ReadOnly),
+            "maintenance" => Some(Mode::Maintenance),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::Normal => "normal",
+            Mode::ReadOnly => "read-only",
+            Mode::Maintenance => "maintenance",
+        }
+    }
+}
+
+impl ModeSwitch {
+    pub fn from_env() -> Result<ModeSwitch, Error> {
+        let path = env_var_opt("MODE_FILE")?.map(PathBuf::from);
+        let mode = match &path {
+            Some(path) => read(path)?,
+            None => match env_var_opt("MODE")? {
+                Some(mode) => parse(&mode)?,
+                None => Mode::Normal,
+            },
+        };
+        Ok(ModeSwitch {
+            mode: RwLock::new(mode),
+            path,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn new(mode: Mode) -> ModeSwitch {
+        ModeSwitch {
+            mode: RwLock::new(mode),
+            path: None,
+        }
+    }
+
+    pub fn get(&self) -> Mode {
+        *self.mode.read().unwrap()
+    }
+
+    /// Lasts until the mode is switched again, or until the server restarts or the mode file
+    /// changes, as the mode isn't stored anywhere.
+    pub fn set(&self, mode: Mode) {
+        *self.mode.write().unwrap() = mode;
+    }
+
+    /// Switches to the mode in the file whenever it changes. Removing the file switches back to
+    /// normal, so that maintenance can be started with `echo maintenance > $MODE_FILE` and ended
+    /// with `rm $MODE_FILE`. If the file can't be read, the mode stays as it was.
+    pub fn watch(self: Arc<Self>, log: Logger) -> Result<(), Error> {
+        let Some(path) = self.path.clone() else { return Ok(()); };
+        // Editors tend to replace the file rather than write to it, which a watch on the file
+        // itself wouldn't survive.
+        let directory = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher = notify::watcher(sender, Duration::from_millis(100))?;
+        watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+        std::thread::spawn(move || {
+            let _watcher = watcher;
+            for event in receiver {
+                let touched = match &event {
+                    DebouncedEvent::Create(changed)
+                    | DebouncedEvent::Write(changed)
+                    | DebouncedEvent::Remove(changed) => changed.file_name() == path.file_name(),
+                    DebouncedEvent::Rename(from, to) => {
+                        from.file_name() == path.file_name() || to.file_name() == path.file_name()
+                    }
+                    DebouncedEvent::Rescan => true,
+                    _ => false,
+                };
+                if !touched {
+                    continue;
+                }
+                match read(&path) {
+                    Ok(mode) => {
+                        self.set(mode);
+                        info!(log, "Mode switched"; "mode" => mode.as_str());
+                    }
+                    Err(e) => error!(log, "Mode switch failed"; e.log_message()),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> Result<Mode, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(mode) => parse(mode.trim()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Mode::Normal),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse(mode: &str) -> Result<Mode, Error> {
+    Mode::parse(mode).ok_or_else(|| Error::UnknownMode(mode.to_owned(), Backtrace::capture()))
+}
@@ -0,0 +1,162 @@
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::jobs::{self, Task};
+use crate::mail;
+use crate::store::Store;
+use crate::templates::Templates;
+use crate::user::User;
+use crate::util::env_var_opt;
+use async_trait::async_trait;
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+
+/// Kinds of mail users choose whether to get. Mail that's part of logging in or answers something
+/// the user asked for, like codes and registration reviews, isn't of any and always goes out.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Category {
+    /// After logins from a browser the user hasn't logged in from before.
+    NewDevice,
+    /// After changes to how the account is logged in to, like a new password.
+    Security,
+    /// News about the service, sent by the deployment on its own.
+    Product,
+}
+
+pub const CATEGORIES: [Category; 3] = [Category::NewDevice, Category::Security, Category::Product];
+
+/// The choices users made about the categories. Categories without one keep their default, so new
+/// categories don't need anything stored for them.
+#[async_trait]
+pub trait NotificationStore: Send + Sync {
+    async fn list(&self, user: User) -> Result<HashMap<Category, bool>, Error>;
+
+    async fn set(&self, user: User, category: Category, enabled: bool) -> Result<(), Error>;
+}
+
+pub struct NotificationPolicy {
+    /// From `PUBLIC_URL`, like `https://auth.example.com`, which links in mail point to. Without
+    /// it mail goes out with no unsubscribe link, leaving the settings page for opting out. It's
+    /// also the issuer of logout tokens, which aren't sent without it, see
+    /// [`crate::backchannel::notify`].
+    pub public_url: Option<String>,
+}
+
+impl Category {
+    pub fn parse(category: &str) -> Option<Category> {
+        match category {
+            "new_device" => Some(Category::NewDevice),
+            "security" => Some(Category::Security),
+            "product" => Some(Category::Product),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::NewDevice => "new_device",
+            Category::Security => "security",
+            Category::Product => "product",
+        }
+    }
+
+    /// Product mail is opt-in, and the rest is there to tell users about someone else in their
+    /// account, so it's opt-out.
+    pub fn default_enabled(self) -> bool {
+        !matches!(self, Category::Product)
+    }
+
+    /// Whether the mail comes with a link to stop it without logging in. Security alerts don't,
+    /// so that turning them off takes more than having a hold of the mailbox.
+    pub fn unsubscribable(self) -> bool {
+        !matches!(self, Category::Security)
+    }
+}
+
+impl NotificationPolicy {
+    pub fn from_env() -> Result<NotificationPolicy, Error> {
+        Ok(NotificationPolicy {
+            public_url: env_var_opt("PUBLIC_URL")?.map(|url| url.trim_end_matches('/').to_owned()),
+        })
+    }
+}
+
+/// Whether the user gets each category, in the order of [`CATEGORIES`].
+pub async fn preferences(
+    store: &dyn NotificationStore,
+    user: User,
+) -> Result<Vec<(Category, bool)>, Error> {
+    let chosen = store.list(user).await?;
+    Ok(CATEGORIES
+        .iter()
+        .map(|&category| {
+            let enabled = chosen.get(&category).copied();
+            (
+                category,
+                enabled.unwrap_or_else(|| category.default_enabled()),
+            )
+        })
+        .collect())
+}
+
+pub async fn is_enabled(
+    store: &dyn NotificationStore,
+    user: User,
+    category: Category,
+) -> Result<bool, Error> {
+    let enabled = store.list(user).await?.get(&category).copied();
+    Ok(enabled.unwrap_or_else(|| category.default_enabled()))
+}
+
+/// Queues a mail of the category for the user, unless they opted out of it or have no address,
+/// returning whether it was. The mail also gets an `unsubscribe_url` for the template to link to,
+/// when the category can be unsubscribed from.
+#[allow(clippy::too_many_arguments)]
+pub async fn notify(
+    user: User,
+    category: Category,
+    name: &str,
+    locale: &str,
+    context: &tera::Context,
+    store: &Store,
+    templates: &Templates,
+    crypto: &Crypto,
+    policy: &NotificationPolicy,
+) -> Result<bool, Error> {
+    if !is_enabled(&*store.notifications, user, category).await? {
+        return Ok(false);
+    }
+    let Some(email) = store.users.profile(user).await?.email else {
+        return Ok(false);
+    };
+    let mut context = context.clone();
+    if let (true, Some(public_url)) = (category.unsubscribable(), &policy.public_url) {
+        let token = unsubscribe_token(user, category, crypto);
+        let url = format!("{}/unsubscribe?token={}", public_url, token);
+        context.insert("unsubscribe_url", &url);
+    }
+    let mail = mail::render(templates, name, &email, locale, &context)?;
+    jobs::enqueue(&*store.jobs, &Task::SendMail(mail)).await?;
+    Ok(true)
+}
+
+/// Sealed for the user and category, so that links in mail can't be made up for other users. They
+/// don't expire, as unsubscribing from an old mail should work as well as from a new one.
+pub fn unsubscribe_token(user: User, category: Category, crypto: &Crypto) -> String {
+    let token = format!("unsubscribe:{}:{}", user.id, category.as_str());
+    crypto.seal(token.as_bytes())
+}
+
+pub fn open_unsubscribe_token(token: &str, crypto: &Crypto) -> Result<(User, Category), Error> {
+    let invalid = || Error::InvalidUnsubscribeToken(Backtrace::capture());
+    let token =
+        String::from_utf8(crypto.unseal(token).map_err(|_| invalid())?).map_err(|_| invalid())?;
+    let token = token.strip_prefix("unsubscribe:").ok_or_else(invalid)?;
+    let (id, category) = token.split_once(':').ok_or_else(invalid)?;
+    let user = User {
+        id: id.parse().map_err(|_| invalid())?,
+    };
+    let category = Category::parse(category)
+        .filter(|category| category.unsubscribable())
+        .ok_or_else(invalid)?;
+    Ok((user, category))
+}
@@ -0,0 +1,505 @@
+use crate::claims::Claims;
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::session::Session;
+use crate::user::User;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use async_trait::async_trait;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::backtrace::Backtrace;
+use std::time::{Duration, SystemTime};
+
+/// Application allowed to talk to the OAuth endpoints, like a resource server introspecting tokens.
+#[derive(Clone)]
+pub struct Client {
+    pub id: String,
+    /// Scopes the client may be granted, which limits what it can ask for.
+    pub scopes: Vec<String>,
+    /// Service account the client belongs to, which tokens from the client credentials grant and
+    /// API keys then act on behalf of.
+    pub user: Option<User>,
+    /// Whether the client has no secret, like apps running on the user's device, which can't keep
+    /// one. Those can only use the authorization code grant, with PKCE.
+    pub public: bool,
+}
+
+/// Opaque access token, as known to the store. The token itself is only ever stored hashed.
+#[derive(Clone)]
+pub struct AccessToken {
+    pub client_id: String,
+    /// User the token acts on behalf of, or none for tokens of the client itself.
+    pub user: Option<User>,
+    /// Space-separated granted scopes, in the form OAuth uses on the wire.
+    pub scope: String,
+    pub expires_at: SystemTime,
+}
+
+/// Short-lived code from the authorization endpoint, exchanged by the client for an access token.
+#[derive(Clone)]
+pub struct AuthorizationCode {
+    pub client_id: String,
+    pub user: User,
+    pub scope: String,
+    /// Redirect URI the code was sent to, which the token request has to repeat.
+    pub redirect_uri: String,
+    /// PKCE challenge from the authorization request, which the verifier in the token request has
+    /// to hash to, see [`verify_code_challenge`].
+    pub code_challenge: Option<String>,
+    pub expires_at: SystemTime,
+}
+
+/// Access token a client got on behalf of a user, as the user sees it. The token itself isn't
+/// stored, so it goes by the hash of it instead.
+pub struct TokenInfo {
+    pub id: String,
+    pub client_id: String,
+    pub scope: String,
+    pub expires_at: SystemTime,
+}
+
+/// Scopes a user has allowed a client to have, so that they aren't asked again every time.
+pub struct Consent {
+    pub client_id: String,
+    pub scope: String,
+}
+
+#[async_trait]
+pub trait ClientStore: Send + Sync {
+    async fn insert(
+        &self,
+        id: &str,
+        secret: &str,
+        scopes: &[String],
+        user: Option<User>,
+    ) -> Result<(), Error>;
+
+    /// Registers a client without a secret, see [`Client::public`]. It's stored with an empty
+    /// secret hash, which no secret verifies against.
+    async fn insert_public(&self, id: &str, scopes: &[String]) -> Result<(), Error>;
+
+    async fn authenticate(&self, id: &str, secret: &str) -> Result<Client, Error>;
+
+    async fn get(&self, id: &str) -> Result<Option<Client>, Error>;
+
+    /// Clients belonging to the service account.
+    async fn of_user(&self, user: User) -> Result<Vec<Client>, Error>;
+
+    /// Allows the client to send users to the URI after logging them out.
+    async fn add_logout_redirect_uri(&self, id: &str, uri: &str) -> Result<(), Error>;
+
+    async fn has_logout_redirect_uri(&self, id: &str, uri: &str) -> Result<bool, Error>;
+
+    /// Has logout tokens POSTed to the URI whenever a user who allowed the client logs out, per
+    /// OpenID Connect Back-Channel Logout. `None` stops them.
+    async fn set_backchannel_logout_uri(&self, id: &str, uri: Option<&str>) -> Result<(), Error>;
+
+    async fn backchannel_logout_uri(&self, id: &str) -> Result<Option<String>, Error>;
+
+    /// Allows the client to receive authorization codes at the URI.
+    async fn add_redirect_uri(&self, id: &str, uri: &str) -> Result<(), Error>;
+
+    async fn has_redirect_uri(&self, id: &str, uri: &str) -> Result<bool, Error>;
+}
+
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    async fn insert(&self, token: &str, details: &AccessToken) -> Result<(), Error>;
+
+    /// Details of the token, if it exists and hasn't expired yet.
+    async fn get(&self, token: &str) -> Result<Option<AccessToken>, Error>;
+
+    /// Deletes the token if it was issued to the given client, returning whether it was.
+    async fn revoke(&self, token: &str, client_id: &str) -> Result<bool, Error>;
+
+    /// Deletes every token the client got on behalf of the user.
+    async fn revoke_all(&self, client_id: &str, user: User) -> Result<(), Error>;
+
+    /// Tokens clients got on behalf of the user that haven't expired yet, soonest to expire first.
+    async fn list(&self, user: User) -> Result<Vec<TokenInfo>, Error>;
+
+    /// Deletes the user's token with the ID from [`TokenStore::list`], returning whether there was
+    /// one.
+    async fn revoke_by_id(&self, user: User, id: &str) -> Result<bool, Error>;
+
+    async fn insert_code(&self, code: &str, details: &AuthorizationCode) -> Result<(), Error>;
+
+    /// Details of the code if it exists and hasn't expired yet, deleting it so that it can only be
+    /// used once.
+    async fn take_code(&self, code: &str) -> Result<Option<AuthorizationCode>, Error>;
+
+    /// Deletes tokens and codes that expired before the given time, returning how many there were.
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error>;
+}
+
+#[async_trait]
+pub trait ConsentStore: Send + Sync {
+    /// Records the scopes the user allowed, replacing whatever was allowed before.
+    async fn grant(&self, user: User, client_id: &str, scope: &str) -> Result<(), Error>;
+
+    /// Scopes the user allowed the client, if any.
+    async fn get(&self, user: User, client_id: &str) -> Result<Option<String>, Error>;
+
+    async fn list(&self, user: User) -> Result<Vec<Consent>, Error>;
+
+    /// Forgets the consent, returning whether there was one.
+    async fn revoke(&self, user: User, client_id: &str) -> Result<bool, Error>;
+}
+
+/// How long tokens from the token endpoint stay valid. Clients can always get a new one with their
+/// credentials, so this is kept short.
+pub const ACCESS_TOKEN_EXPIRATION_TIME: Duration = Duration::from_secs(60 * 60);
+
+/// How long authorization codes stay valid, the maximum RFC 6749 section 4.1.2 recommends.
+pub const CODE_EXPIRATION_TIME: Duration = Duration::from_secs(10 * 60);
+
+/// How long API keys made with the `create-api-key` command stay valid.
+pub const API_KEY_EXPIRATION_TIME: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+/// The only PKCE challenge method accepted, as with `plain` whoever sees the authorization request
+/// can redeem the code too.
+pub const CODE_CHALLENGE_METHOD: &str = "S256";
+
+const CONSENT_SIGNATURE_DOMAIN: &str = "consent.";
+
+#[derive(Debug, Deserialize)]
+pub struct IntrospectRequest {
+    pub token: String,
+    #[serde(flatten)]
+    pub credentials: ClientCredentials,
+}
+
+/// Token request from RFC 6749, for either the `authorization_code` or the `client_credentials`
+/// grant.
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: Option<String>,
+    pub scope: Option<String>,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    /// From PKCE, see [`verify_code_challenge`].
+    pub code_verifier: Option<String>,
+    #[serde(flatten)]
+    pub credentials: ClientCredentials,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: u64,
+    pub scope: String,
+}
+
+/// Revocation request from RFC 7009. The `token_type_hint` parameter is accepted but unused, as
+/// there is only one kind of token to look through.
+#[derive(Debug, Deserialize)]
+pub struct RevokeRequest {
+    pub token: String,
+    #[serde(flatten)]
+    pub credentials: ClientCredentials,
+}
+
+/// Authorization request from RFC 6749 section 4.1.1. It's serialized again to carry it through the
+/// login page and the consent form.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuthorizeRequest {
+    pub response_type: Option<String>,
+    pub client_id: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    /// From PKCE, see [`AuthorizationCode::code_challenge`].
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+/// Answer to the consent form, which is the authorization request plus the user's decision.
+#[derive(Debug, Deserialize)]
+pub struct ConsentRequest {
+    #[serde(flatten)]
+    pub request: AuthorizeRequest,
+    pub decision: String,
+    /// See [`consent_token`].
+    pub consent_token: Option<String>,
+}
+
+/// RP-initiated logout request from OpenID Connect, sent by the browser as a query string. It's
+/// serialized again to carry it through the confirmation form.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogoutRequest {
+    pub client_id: Option<String>,
+    pub post_logout_redirect_uri: Option<String>,
+    pub state: Option<String>,
+    /// Sent by the confirmation form, see [`crate::sso::logout_token`].
+    #[serde(skip_serializing)]
+    pub logout_token: Option<String>,
+}
+
+/// Client authentication sent in the body, which RFC 6749 allows as an alternative to HTTP Basic.
+#[derive(Debug, Default, Deserialize)]
+pub struct ClientCredentials {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+/// Introspection response as specified by RFC 7662. Inactive tokens are described by `active`
+/// alone, so that nothing about them leaks.
+#[derive(Serialize)]
+pub struct IntrospectResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<&'static str>,
+    /// Claims of the user the token was issued to, see [`crate::claims`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claims: Option<Claims>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    error: &'a str,
+    error_description: &'a str,
+}
+
+/// Scope to grant when the client asks for the given scopes, which is everything it's allowed when
+/// it asks for nothing in particular.
+pub fn grant_scope(client: &Client, requested: &[&str]) -> Result<String, Error> {
+    if requested.is_empty() {
+        return Ok(client.scopes.join(" "));
+    }
+    if let Some(scope) = requested
+        .iter()
+        .find(|scope| !client.scopes.iter().any(|allowed| allowed == *scope))
+    {
+        return Err(Error::ScopeNotAllowed(
+            (*scope).to_owned(),
+            Backtrace::capture(),
+        ));
+    }
+    Ok(requested.join(" "))
+}
+
+/// Generates a token for the client and saves it, returning the token itself.
+pub async fn issue_token(
+    tokens: &dyn TokenStore,
+    client: &Client,
+    user: Option<User>,
+    scope: String,
+    lifetime: Duration,
+) -> Result<String, Error> {
+    let token = generate_secret();
+    let details = AccessToken {
+        client_id: client.id.clone(),
+        user,
+        scope,
+        expires_at: SystemTime::now() + lifetime,
+    };
+    tokens.insert(&token, &details).await?;
+    Ok(token)
+}
+
+/// Generates an authorization code for the user and saves it, returning the code itself.
+pub async fn issue_code(
+    tokens: &dyn TokenStore,
+    client: &Client,
+    user: User,
+    scope: String,
+    redirect_uri: &str,
+    code_challenge: Option<String>,
+) -> Result<String, Error> {
+    let code = generate_secret();
+    let details = AuthorizationCode {
+        client_id: client.id.clone(),
+        user,
+        scope,
+        redirect_uri: redirect_uri.to_owned(),
+        code_challenge,
+        expires_at: SystemTime::now() + CODE_EXPIRATION_TIME,
+    };
+    tokens.insert_code(&code, &details).await?;
+    Ok(code)
+}
+
+/// Whether every scope in `scope` is also in `granted`, both being space-separated.
+pub fn scope_covers(granted: &str, scope: &str) -> bool {
+    let granted: Vec<&str> = granted.split_whitespace().collect();
+    scope
+        .split_whitespace()
+        .all(|scope| granted.contains(&scope))
+}
+
+/// Both space-separated scopes combined, for remembering consent for new scopes without
+/// forgetting the old ones.
+pub fn merge_scopes(a: &str, b: &str) -> String {
+    let mut scopes: Vec<&str> = a.split_whitespace().collect();
+    for scope in b.split_whitespace() {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    scopes.join(" ")
+}
+
+/// Redirect URI of the client with parameters and the request's state added to its query string.
+pub fn redirect_location(
+    uri: &str,
+    params: &[(&str, &str)],
+    state: Option<&str>,
+) -> Result<String, Error> {
+    let mut params = params.to_vec();
+    if let Some(state) = state {
+        params.push(("state", state));
+    }
+    if params.is_empty() {
+        return Ok(uri.to_owned());
+    }
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    let query = serde_urlencoded::to_string(params)?;
+    Ok(format!("{}{}{}", uri, separator, query))
+}
+
+pub fn generate_secret() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Tokens are random enough that a fast hash is sufficient, unlike for passwords. It also lets them
+/// be looked up by their hash directly.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Whether the verifier from the token request hashes to the challenge from the authorization
+/// request, per RFC 7636 section 4.6.
+pub fn verify_code_challenge(verifier: &str, challenge: &str) -> bool {
+    let well_formed = (43..=128).contains(&verifier.len())
+        && verifier
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-._~".contains(&byte));
+    let hash = Sha256::digest(verifier.as_bytes());
+    well_formed && base64::encode_config(hash, base64::URL_SAFE_NO_PAD) == challenge
+}
+
+/// Token the consent form carries, so that pages elsewhere can't post an allow decision in the
+/// user's name. It's tied to the session and the client.
+pub fn consent_token(session: &Session, client_id: &str, crypto: &Crypto) -> String {
+    hex::encode(crypto.sign(&consent_data(session, client_id)).hash)
+}
+
+pub fn verify_consent_token(
+    token: &str,
+    session: &Session,
+    client_id: &str,
+    crypto: &Crypto,
+) -> bool {
+    match hex::decode(token) {
+        Ok(signature) => crypto
+            .verify(&consent_data(session, client_id), &signature)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn consent_data(session: &Session, client_id: &str) -> Vec<u8> {
+    format!("{}{}.{}", CONSENT_SIGNATURE_DOMAIN, session.id(), client_id).into_bytes()
+}
+
+/// ID of the client making a token request without a secret, which only public clients do.
+pub fn public_client_id(request: &Request<Body>, body: &ClientCredentials) -> Option<String> {
+    if request.headers().contains_key(AUTHORIZATION) || body.client_secret.is_some() {
+        return None;
+    }
+    body.client_id.clone()
+}
+
+/// Client ID and secret from the HTTP Basic authorization header, or from the body if there's none.
+pub fn client_credentials(
+    request: &Request<Body>,
+    body: ClientCredentials,
+) -> Option<(String, String)> {
+    if let Some(header) = request.headers().get(AUTHORIZATION) {
+        let encoded = header.to_str().ok()?.strip_prefix("Basic ")?;
+        let decoded = String::from_utf8(base64::decode(encoded).ok()?).ok()?;
+        let (id, secret) = decoded.split_once(':')?;
+        // Both parts are form-encoded before being put in the header, per RFC 6749 section 2.3.1.
+        let decode = |s: &str| -> Option<String> {
+            let pairs: Vec<(String, String)> =
+                serde_urlencoded::from_str(&format!("v={}", s)).ok()?;
+            pairs.into_iter().next().map(|(_, value)| value)
+        };
+        return Some((decode(id)?, decode(secret)?));
+    }
+    Some((body.client_id?, body.client_secret?))
+}
+
+impl IntrospectResponse {
+    pub fn inactive() -> IntrospectResponse {
+        IntrospectResponse {
+            active: false,
+            scope: None,
+            client_id: None,
+            sub: None,
+            exp: None,
+            token_type: None,
+            claims: None,
+        }
+    }
+
+    pub fn active(token: AccessToken, claims: Option<Claims>) -> IntrospectResponse {
+        IntrospectResponse {
+            active: true,
+            scope: Some(token.scope),
+            sub: token.user.map(|user| user.id.to_string()),
+            client_id: Some(token.client_id),
+            exp: Some(unix_time(token.expires_at)),
+            token_type: Some("Bearer"),
+            claims,
+        }
+    }
+}
+
+pub fn response(status: StatusCode, value: &impl Serialize) -> Response<Body> {
+    // Token responses must not be cached, per RFC 6749 section 5.1.
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header("Cache-Control", "no-store")
+        .body(serde_json::to_vec(value).unwrap().into())
+        .unwrap()
+}
+
+/// Error response in the format of RFC 6749 section 5.2, which OAuth clients expect instead of the
+/// one used by the rest of the API.
+pub fn error_response(status: StatusCode, error: &str, description: &str) -> Response<Body> {
+    let mut response = response(
+        status,
+        &ErrorResponse {
+            error,
+            error_description: description,
+        },
+    );
+    if status == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(
+            WWW_AUTHENTICATE,
+            "Basic realm=\"authtown\"".parse().unwrap(),
+        );
+    }
+    response
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,294 @@
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::i18n;
+use crate::jobs::{self, Task};
+use crate::mail;
+use crate::store::Store;
+use crate::templates::Templates;
+use crate::user::User;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use async_trait::async_trait;
+use cookie::{Cookie, SameSite};
+use sha2::{Digest, Sha256};
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Phone number a user gets second factor codes at. It only counts once a code sent to it was
+/// entered back, so that a typo can't lock anyone out.
+pub struct Phone {
+    pub number: String,
+    pub verified: bool,
+}
+
+/// Secrets shared with an authenticator app, sealed with [`Crypto::seal`]. A new secret stays
+/// pending until a code from the app was entered back, so that a botched setup can't lock anyone
+/// out, and the one in use before keeps working meanwhile, see [`crate::totp`].
+pub struct Totp {
+    pub secret: Option<String>,
+    pub pending: Option<String>,
+}
+
+/// What a one-time code is for, so that a code sent for one thing can't be used for another.
+#[derive(Clone, Copy)]
+pub enum Purpose {
+    Login,
+    EnrollPhone,
+    /// Logging in with a code mailed in place of the password.
+    LoginEmail,
+}
+
+#[async_trait]
+pub trait OtpStore: Send + Sync {
+    /// Sets the user's phone number, unverified, replacing any number they had.
+    async fn set_phone(&self, user: User, number: &str) -> Result<(), Error>;
+
+    async fn phone(&self, user: User) -> Result<Option<Phone>, Error>;
+
+    /// Marks the phone number as verified, unless it was changed to a different one meanwhile.
+    async fn verify_phone(&self, user: User, number: &str) -> Result<bool, Error>;
+
+    async fn remove_phone(&self, user: User) -> Result<(), Error>;
+
+    /// Sets a new authenticator secret for the user to set up, replacing any other pending one
+    /// but not the one in use.
+    async fn set_pending_totp(&self, user: User, secret: &str) -> Result<(), Error>;
+
+    async fn totp(&self, user: User) -> Result<Option<Totp>, Error>;
+
+    /// Puts the pending secret in use in place of the one before, with the time step of the code
+    /// that confirmed it used, unless it was replaced by another pending secret meanwhile.
+    async fn confirm_totp(&self, user: User, pending: &str, step: u64) -> Result<bool, Error>;
+
+    /// Marks the time step as used with the secret in use, unless the secret was changed meanwhile
+    /// or a code of the same or a later step was already accepted, so that each code only works
+    /// once.
+    async fn accept_totp(&self, user: User, secret: &str, step: u64) -> Result<bool, Error>;
+
+    async fn remove_totp(&self, user: User) -> Result<(), Error>;
+
+    async fn insert_code(
+        &self,
+        user: User,
+        purpose: Purpose,
+        code: &str,
+        expires_at: SystemTime,
+    ) -> Result<(), Error>;
+
+    /// Number of codes sent to the user since the given time, for any purpose and by any means.
+    async fn count_codes(&self, user: User, since: SystemTime) -> Result<u64, Error>;
+
+    /// Checks the code against the newest unexpired one sent for the purpose, counting the
+    /// attempt. Codes that were guessed at too many times never match, and a matching code is
+    /// deleted along with the older ones so that it only works once.
+    async fn check_code(&self, user: User, purpose: Purpose, code: &str) -> Result<bool, Error>;
+
+    /// Deletes codes that expired before the given time, returning how many there were.
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error>;
+}
+
+pub const CODE_EXPIRATION_TIME: Duration = Duration::from_secs(10 * 60);
+
+/// Six digits are short enough to type, and with the attempts limited a code can only be guessed
+/// one time in 200 000.
+const CODE_DIGITS: u32 = 6;
+
+pub const MAX_CHECK_ATTEMPTS: i32 = 5;
+
+// Every message costs money and annoys whoever it's sent to, so sends are limited per user.
+const SEND_LIMIT: u64 = 5;
+const SEND_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// How long the password step of a login stays good for while waiting for the second factor.
+const CHALLENGE_EXPIRATION_TIME: Duration = Duration::from_secs(10 * 60);
+
+const CHALLENGE_SIGNATURE_DOMAIN: &str = "challenge.";
+
+/// Login that got past the password and still needs the second factor, carried in a signed cookie
+/// so that the user doesn't have to type the password again on the next page.
+pub struct Challenge {
+    pub user: User,
+    pub channel: Channel,
+    expires_at: u64,
+}
+
+/// Where the code a challenge waits for was sent.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Channel {
+    Sms,
+    /// Mailed to confirm a login that looked risky, for users without a phone number enrolled.
+    Email,
+    /// Read off an authenticator app, so nothing is sent at all.
+    Totp,
+}
+
+impl Purpose {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Purpose::Login => "login",
+            Purpose::EnrollPhone => "enroll_phone",
+            Purpose::LoginEmail => "login_email",
+        }
+    }
+}
+
+impl Channel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Channel::Sms => "sms",
+            Channel::Email => "email",
+            Channel::Totp => "totp",
+        }
+    }
+
+    fn parse(channel: &str) -> Option<Channel> {
+        match channel {
+            "sms" => Some(Channel::Sms),
+            "email" => Some(Channel::Email),
+            "totp" => Some(Channel::Totp),
+            _ => None,
+        }
+    }
+}
+
+impl Challenge {
+    pub fn new(user: User, channel: Channel) -> Challenge {
+        Challenge {
+            user,
+            channel,
+            expires_at: unix_time(SystemTime::now() + CHALLENGE_EXPIRATION_TIME),
+        }
+    }
+
+    pub fn from_cookies(
+        cookies: &HashMap<&str, Cookie>,
+        crypto: &Crypto,
+    ) -> Result<Challenge, Error> {
+        let no_challenge = || Error::NoLoginChallenge(Backtrace::capture());
+        let cookie = cookies.get("challenge").ok_or_else(no_challenge)?;
+        let (payload, signature) = cookie.value().rsplit_once('.').ok_or_else(no_challenge)?;
+        crypto.verify(&signed_data(payload), &hex::decode(signature)?)?;
+        let mut fields = payload.split(':');
+        let (Some(user_id), Some(channel), Some(expires_at), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(no_challenge());
+        };
+        let challenge = Challenge {
+            user: User {
+                id: user_id.parse()?,
+            },
+            channel: Channel::parse(channel).ok_or_else(no_challenge)?,
+            expires_at: expires_at.parse()?,
+        };
+        if challenge.expires_at < unix_time(SystemTime::now()) {
+            return Err(no_challenge());
+        }
+        Ok(challenge)
+    }
+
+    pub fn cookie(&self, crypto: &Crypto) -> Cookie<'static> {
+        let payload = format!(
+            "{}:{}:{}",
+            self.user.id,
+            self.channel.as_str(),
+            self.expires_at
+        );
+        let signature = crypto.sign(&signed_data(&payload));
+        cookie_raw(
+            format!("{}.{}", payload, hex::encode(&signature.hash)),
+            CHALLENGE_EXPIRATION_TIME,
+        )
+    }
+
+    pub fn cookie_clear() -> Cookie<'static> {
+        cookie_raw(String::new(), Duration::ZERO)
+    }
+}
+
+/// Texts a fresh code to the number, through the job queue so that a slow gateway doesn't hold up
+/// the page. Fails once the user has been sent too many codes lately.
+pub async fn send_code(
+    store: &Store,
+    crypto: &Crypto,
+    user: User,
+    purpose: Purpose,
+    number: &str,
+    locale: &str,
+) -> Result<(), Error> {
+    let code = issue_code(store, user, purpose).await?;
+    let text = i18n::translate(locale, "sms-code", &[("code", &code)]);
+    let task = Task::SendSms {
+        to: number.to_owned(),
+        text: crypto.seal(text.as_bytes()),
+        expires_at: unix_time(SystemTime::now() + CODE_EXPIRATION_TIME),
+    };
+    jobs::enqueue(&*store.jobs, &task).await
+}
+
+/// Mails a fresh code to the address, the same way [`send_code`] texts one.
+pub async fn mail_code(
+    store: &Store,
+    templates: &Templates,
+    user: User,
+    purpose: Purpose,
+    to: &str,
+    locale: &str,
+) -> Result<(), Error> {
+    let code = issue_code(store, user, purpose).await?;
+    let mut context = tera::Context::new();
+    context.insert("code", &code);
+    let mail = mail::render(templates, "login-code", to, locale, &context)?;
+    jobs::enqueue(&*store.jobs, &Task::SendMail(mail)).await
+}
+
+async fn issue_code(store: &Store, user: User, purpose: Purpose) -> Result<String, Error> {
+    let now = SystemTime::now();
+    if store.otp.count_codes(user, now - SEND_WINDOW).await? >= SEND_LIMIT {
+        return Err(Error::TooManyCodes(Backtrace::capture()));
+    }
+    let code = generate_code();
+    store
+        .otp
+        .insert_code(user, purpose, &code, now + CODE_EXPIRATION_TIME)
+        .await?;
+    Ok(code)
+}
+
+/// Codes are hashed along with the user, as there are few enough of them that equal codes of
+/// different users would otherwise be easy to spot.
+pub fn hash_code(user: User, code: &str) -> String {
+    hex::encode(Sha256::digest(format!("{}:{}", user.id, code).as_bytes()))
+}
+
+fn generate_code() -> String {
+    let modulus = 10u32.pow(CODE_DIGITS);
+    // Rejecting the top of the range keeps every code equally likely.
+    let limit = u32::MAX - u32::MAX % modulus;
+    let value = loop {
+        let value = OsRng.next_u32();
+        if value < limit {
+            break value % modulus;
+        }
+    };
+    format!("{:0width$}", value, width = CODE_DIGITS as usize)
+}
+
+fn signed_data(payload: &str) -> Vec<u8> {
+    format!("{}{}", CHALLENGE_SIGNATURE_DOMAIN, payload).into_bytes()
+}
+
+fn cookie_raw(value: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build("challenge", value)
+        .max_age(max_age.try_into().unwrap())
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
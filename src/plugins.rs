@@ -0,0 +1,69 @@
+use crate::claims::{Claims, ClaimsHook};
+use crate::error::Error;
+use crate::session::Session;
+use crate::store::Store;
+use crate::user::User;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Business rules of a deployment run along with the built-in ones, like syncing new accounts to
+/// a CRM or turning away passwords found in breaches. Registered through [`crate::Server::plugin`],
+/// and run in the order they were. An error from any of the hooks fails the request, so plugins
+/// whose failures shouldn't keep users out have to deal with them on their own.
+#[async_trait]
+pub trait Plugin: Send + Sync {
+    /// Checks a password about to be set, at registration or when it's changed, before anything
+    /// is stored. Rejecting it with [`Error::PasswordRejected`] shows the reason to the user.
+    async fn validate_password(&self, _user: Option<User>, _password: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Runs once the account is stored, whether it's logged in right away or waits for approval.
+    async fn on_register(&self, _user: User, _tenant: &str, _store: &Store) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Runs once a login gets past every factor and its session is stored. Registering doesn't
+    /// count as logging in.
+    async fn on_login(&self, _session: &Session, _store: &Store) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Adds to the claims of a new session, the same as a [`ClaimsHook`].
+    async fn enrich_session(
+        &self,
+        _user: User,
+        _tenant: &str,
+        _store: &Store,
+        _claims: &mut Claims,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Runs [`Plugin::enrich_session`] along with the other claims hooks.
+pub struct PluginClaims(pub Arc<dyn Plugin>);
+
+#[async_trait]
+impl ClaimsHook for PluginClaims {
+    async fn claims(
+        &self,
+        user: User,
+        tenant: &str,
+        store: &Store,
+        claims: &mut Claims,
+    ) -> Result<(), Error> {
+        self.0.enrich_session(user, tenant, store, claims).await
+    }
+}
+
+pub async fn validate_password(
+    plugins: &[Arc<dyn Plugin>],
+    user: Option<User>,
+    password: &str,
+) -> Result<(), Error> {
+    for plugin in plugins {
+        plugin.validate_password(user, password).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,1659 @@
+use crate::audit::{self, AuditEvent, AuditStore};
+use crate::claims::{ClaimStore, Claims};
+use crate::database::Database;
+use crate::error::Error;
+use crate::export::{Export, ExportStore};
+use crate::features::FeatureStore;
+use crate::jobs::{Job, JobStore};
+use crate::notifications::{Category, NotificationStore};
+use crate::oauth::{
+    hash_token, AccessToken, AuthorizationCode, Client, ClientStore, Consent, ConsentStore,
+    TokenInfo, TokenStore,
+};
+use crate::otp::{hash_code, OtpStore, Phone, Purpose, Totp, MAX_CHECK_ATTEMPTS};
+use crate::quota::QuotaStore;
+use crate::session::{Session, SessionInfo, SessionStore, ROTATION_GRACE_PERIOD};
+use crate::user::{
+    hash_password, upgrade_password_hash, verify_missing_password, verify_password, AccountStatus,
+    Identity, LoginName, Profile, User, UserRecord, UserStore, UsernamePolicy, NO_PASSWORD,
+};
+use async_trait::async_trait;
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+pub struct PostgresUserStore {
+    database: Arc<Database>,
+    username_policy: UsernamePolicy,
+}
+
+pub struct PostgresSessionStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresClientStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresTokenStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresConsentStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresJobStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresOtpStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresAuditStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresExportStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresFeatureStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresClaimStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresQuotaStore {
+    database: Arc<Database>,
+}
+
+pub struct PostgresNotificationStore {
+    database: Arc<Database>,
+}
+
+impl PostgresUserStore {
+    pub fn new(database: Arc<Database>, username_policy: UsernamePolicy) -> PostgresUserStore {
+        PostgresUserStore {
+            database,
+            username_policy,
+        }
+    }
+}
+
+impl PostgresSessionStore {
+    pub fn new(database: Arc<Database>) -> PostgresSessionStore {
+        PostgresSessionStore { database }
+    }
+
+    /// Cookies match the current token ID, or the one it replaced for a moment after a rotation.
+    async fn has_session(&self, session: &Session, restricted: bool) -> Result<bool, Error> {
+        let rotated_after = SystemTime::now() - ROTATION_GRACE_PERIOD;
+        let client = self.database.client()?;
+        let statement = self
+            .database
+            .prepare(
+                &client,
+                "SELECT EXISTS (SELECT 1 FROM sessions \
+                 WHERE id = $1 AND expires_at > now() AND restricted = $2 \
+                 AND (token_id IS NOT DISTINCT FROM $3 \
+                 OR (previous_token_id IS NOT DISTINCT FROM $3 AND rotated_at > $4)))",
+            )
+            .await?;
+        let row = self
+            .database
+            .timeout(client.query_one(
+                &statement,
+                &[
+                    &session.id(),
+                    &restricted,
+                    &session.token_id(),
+                    &rotated_after,
+                ],
+            ))
+            .await?;
+        Ok(row.get(0))
+    }
+}
+
+impl PostgresClientStore {
+    pub fn new(database: Arc<Database>) -> PostgresClientStore {
+        PostgresClientStore { database }
+    }
+}
+
+impl PostgresTokenStore {
+    pub fn new(database: Arc<Database>) -> PostgresTokenStore {
+        PostgresTokenStore { database }
+    }
+}
+
+impl PostgresConsentStore {
+    pub fn new(database: Arc<Database>) -> PostgresConsentStore {
+        PostgresConsentStore { database }
+    }
+}
+
+impl PostgresJobStore {
+    pub fn new(database: Arc<Database>) -> PostgresJobStore {
+        PostgresJobStore { database }
+    }
+}
+
+impl PostgresOtpStore {
+    pub fn new(database: Arc<Database>) -> PostgresOtpStore {
+        PostgresOtpStore { database }
+    }
+}
+
+impl PostgresAuditStore {
+    pub fn new(database: Arc<Database>) -> PostgresAuditStore {
+        PostgresAuditStore { database }
+    }
+}
+
+impl PostgresExportStore {
+    pub fn new(database: Arc<Database>) -> PostgresExportStore {
+        PostgresExportStore { database }
+    }
+}
+
+impl PostgresFeatureStore {
+    pub fn new(database: Arc<Database>) -> PostgresFeatureStore {
+        PostgresFeatureStore { database }
+    }
+}
+
+impl PostgresClaimStore {
+    pub fn new(database: Arc<Database>) -> PostgresClaimStore {
+        PostgresClaimStore { database }
+    }
+}
+
+impl PostgresQuotaStore {
+    pub fn new(database: Arc<Database>) -> PostgresQuotaStore {
+        PostgresQuotaStore { database }
+    }
+}
+
+impl PostgresNotificationStore {
+    pub fn new(database: Arc<Database>) -> PostgresNotificationStore {
+        PostgresNotificationStore { database }
+    }
+}
+
+#[async_trait]
+impl UserStore for PostgresUserStore {
+    async fn get_and_verify(
+        &self,
+        tenant: &str,
+        login: &str,
+        password: &str,
+    ) -> Result<User, Error> {
+        let (query, value) = match LoginName::parse(login) {
+            LoginName::Username(username) => (
+                "SELECT id, password_phc FROM users WHERE tenant_id = $1 AND username = $2",
+                username,
+            ),
+            LoginName::Email(email) => (
+                "SELECT id, password_phc FROM users WHERE tenant_id = $1 AND email = $2",
+                email,
+            ),
+        };
+        let client = self.database.client()?;
+        let statement = self.database.prepare(&client, query).await?;
+        let row = self
+            .database
+            .timeout(client.query_opt(&statement, &[&tenant, &value]))
+            .await?;
+        let Some(row) = row else {
+            verify_missing_password(password).await;
+            return Err(Error::UserNotFound(Backtrace::capture()));
+        };
+        let user = User { id: row.get(0) };
+        let password_phc: &str = row.get(1);
+        verify_password(password, password_phc).await?;
+        upgrade_password_hash(self, user, password, password_phc).await?;
+        Ok(user)
+    }
+
+    async fn insert(
+        &self,
+        tenant: &str,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> Result<User, Error> {
+        let username = self.username_policy.normalize(username)?;
+        let password_phc = hash_password(password).await;
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_one(
+                "INSERT INTO users (tenant_id, username, password_phc, email) \
+                 VALUES ($1, $2, $3, $4) RETURNING id;",
+                &[&tenant, &username, &password_phc, &email],
+            ))
+            .await
+            .map_err(user_conflict)?;
+        let id = row.get(0);
+        Ok(User { id })
+    }
+
+    async fn insert_service_account(&self, tenant: &str, username: &str) -> Result<User, Error> {
+        let username = self.username_policy.normalize(username)?;
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_one(
+                "INSERT INTO users (tenant_id, username, password_phc, service) \
+                 VALUES ($1, $2, $3, TRUE) RETURNING id",
+                &[&tenant, &username, &NO_PASSWORD],
+            ))
+            .await
+            .map_err(user_conflict)?;
+        Ok(User { id: row.get(0) })
+    }
+
+    async fn service_accounts(&self, tenant: &str) -> Result<Vec<(User, String)>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT id, username FROM users WHERE tenant_id = $1 AND service ORDER BY id",
+                &[&tenant],
+            ))
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| (User { id: row.get(0) }, row.get(1)))
+            .collect())
+    }
+
+    async fn find_by_email(&self, tenant: &str, email: &str) -> Result<Option<User>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT id FROM users WHERE tenant_id = $1 AND email = $2",
+                &[&tenant, &email],
+            ))
+            .await?;
+        Ok(row.map(|row| User { id: row.get(0) }))
+    }
+
+    async fn find_by_username(&self, tenant: &str, username: &str) -> Result<Option<User>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT id FROM users WHERE tenant_id = $1 AND username = $2",
+                &[&tenant, &username],
+            ))
+            .await?;
+        Ok(row.map(|row| User { id: row.get(0) }))
+    }
+
+    async fn set_email(&self, user: User, email: Option<&str>) -> Result<(), Error> {
+        let updated = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "UPDATE users SET email = $1 WHERE id = $2",
+                &[&email, &user.id],
+            ))
+            .await
+            .map_err(user_conflict)?;
+        if updated == 0 {
+            return Err(Error::UserNotFound(Backtrace::capture()));
+        }
+        Ok(())
+    }
+
+    async fn set_password(&self, user: User, password: &str) -> Result<(), Error> {
+        let password_phc = hash_password(password).await;
+        self.database
+            .timeout(self.database.client()?.execute(
+                "UPDATE users SET password_phc = $1, password_changed_at = now() WHERE id = $2",
+                &[&password_phc, &user.id],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn set_password_phc(&self, user: User, password_phc: &str) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "UPDATE users SET password_phc = $1 WHERE id = $2",
+                &[&password_phc, &user.id],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_password(&self, user: User) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "UPDATE users SET password_phc = $1 WHERE id = $2",
+                &[&NO_PASSWORD, &user.id],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn link_identity(&self, user: User, provider: &str, subject: &str) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO identities (user_id, provider, subject) VALUES ($1, $2, $3)",
+                &[&user.id, &provider, &subject],
+            ))
+            .await
+            .map_err(identity_taken)?;
+        Ok(())
+    }
+
+    async fn find_by_identity(&self, provider: &str, subject: &str) -> Result<Option<User>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT user_id FROM identities WHERE provider = $1 AND subject = $2",
+                &[&provider, &subject],
+            ))
+            .await?;
+        Ok(row.map(|row| User { id: row.get(0) }))
+    }
+
+    async fn identities(&self, user: User) -> Result<Vec<Identity>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT id, provider, subject FROM identities WHERE user_id = $1 ORDER BY id",
+                &[&user.id],
+            ))
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Identity {
+                id: row.get(0),
+                provider: row.get(1),
+                subject: row.get(2),
+            })
+            .collect())
+    }
+
+    async fn unlink_identity(&self, user: User, id: i32) -> Result<bool, Error> {
+        let deleted = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "DELETE FROM identities WHERE id = $1 AND user_id = $2",
+                &[&id, &user.id],
+            ))
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn profile(&self, user: User) -> Result<Profile, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT tenant_id, username, email, password_phc, password_changed_at, service \
+                 FROM users WHERE id = $1",
+                &[&user.id],
+            ))
+            .await?
+            .ok_or_else(|| Error::UserNotFound(Backtrace::capture()))?;
+        let password_phc: &str = row.get(3);
+        Ok(Profile {
+            id: user.id,
+            tenant: row.get(0),
+            username: row.get(1),
+            email: row.get(2),
+            has_password: password_phc != NO_PASSWORD,
+            password_changed_at: row.get(4),
+            service: row.get(5),
+        })
+    }
+
+    async fn rename(&self, user: User, username: &str) -> Result<(), Error> {
+        let username = self.username_policy.normalize(username)?;
+        let updated = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "UPDATE users SET username = $1 WHERE id = $2",
+                &[&username, &user.id],
+            ))
+            .await
+            .map_err(user_conflict)?;
+        if updated == 0 {
+            return Err(Error::UserNotFound(Backtrace::capture()));
+        }
+        Ok(())
+    }
+
+    async fn usernames(&self) -> Result<Vec<(User, String)>, Error> {
+        let rows = self
+            .database
+            .timeout(
+                self.database
+                    .client()?
+                    .query("SELECT id, username FROM users ORDER BY id", &[]),
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| (User { id: row.get(0) }, row.get(1)))
+            .collect())
+    }
+
+    async fn accept_terms(&self, user: User, version: &str) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO terms_acceptances (user_id, version) VALUES ($1, $2)",
+                &[&user.id, &version],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn accepted_terms(&self, user: User) -> Result<Option<String>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT version FROM terms_acceptances WHERE user_id = $1 \
+                 ORDER BY accepted_at DESC, id DESC LIMIT 1",
+                &[&user.id],
+            ))
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    async fn status(&self, user: User) -> Result<AccountStatus, Error> {
+        let client = self.database.client()?;
+        let statement = self
+            .database
+            .prepare(&client, "SELECT status FROM users WHERE id = $1")
+            .await?;
+        let row = self
+            .database
+            .timeout(client.query_opt(&statement, &[&user.id]))
+            .await?;
+        let Some(row) = row else { return Err(Error::UserNotFound(Backtrace::capture())); };
+        parse_status(row.get(0))
+    }
+
+    async fn set_status(&self, user: User, status: AccountStatus) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "UPDATE users SET status = $1 WHERE id = $2",
+                &[&status.as_str(), &user.id],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn with_status(
+        &self,
+        tenant: &str,
+        status: AccountStatus,
+    ) -> Result<Vec<(User, String)>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT id, username FROM users WHERE tenant_id = $1 AND status = $2 ORDER BY id",
+                &[&tenant, &status.as_str()],
+            ))
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| (User { id: row.get(0) }, row.get(1)))
+            .collect())
+    }
+
+    async fn records(&self) -> Result<Vec<UserRecord>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT tenant_id, username, email, password_phc, password_changed_at, status \
+                 FROM users ORDER BY id",
+                &[],
+            ))
+            .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(UserRecord {
+                    tenant: row.get(0),
+                    username: row.get(1),
+                    email: row.get(2),
+                    password_phc: row.get(3),
+                    password_changed_at: row.get(4),
+                    status: parse_status(row.get(5))?,
+                })
+            })
+            .collect()
+    }
+
+    async fn import(&self, record: &UserRecord) -> Result<User, Error> {
+        let username = self.username_policy.normalize(&record.username)?;
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_one(
+                "INSERT INTO users \
+                 (tenant_id, username, password_phc, email, password_changed_at, status) \
+                 VALUES ($1, $2, $3, $4, $5, $6) RETURNING id;",
+                &[
+                    &record.tenant,
+                    &username,
+                    &record.password_phc,
+                    &record.email,
+                    &record.password_changed_at,
+                    &record.status.as_str(),
+                ],
+            ))
+            .await
+            .map_err(user_conflict)?;
+        Ok(User { id: row.get(0) })
+    }
+
+    async fn overwrite(&self, user: User, record: &UserRecord) -> Result<(), Error> {
+        let updated = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "UPDATE users SET email = $1, password_phc = $2, password_changed_at = $3, \
+                 status = $4 WHERE id = $5",
+                &[
+                    &record.email,
+                    &record.password_phc,
+                    &record.password_changed_at,
+                    &record.status.as_str(),
+                    &user.id,
+                ],
+            ))
+            .await
+            .map_err(user_conflict)?;
+        if updated == 0 {
+            return Err(Error::UserNotFound(Backtrace::capture()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for PostgresSessionStore {
+    async fn insert(&self, session: &Session, restricted: bool) -> Result<(), Error> {
+        let expires_at = SystemTime::now() + session.lifetime();
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO sessions (id, user_id, expires_at, restricted, token_id) \
+                 VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    &session.id(),
+                    &session.user().id,
+                    &expires_at,
+                    &restricted,
+                    &session.token_id(),
+                ],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn is_active(&self, session: &Session) -> Result<bool, Error> {
+        self.has_session(session, false).await
+    }
+
+    async fn is_restricted(&self, session: &Session) -> Result<bool, Error> {
+        self.has_session(session, true).await
+    }
+
+    async fn unrestrict(&self, session: &Session) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "UPDATE sessions SET restricted = FALSE WHERE id = $1",
+                &[&session.id()],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session: &Session) -> Result<(), Error> {
+        self.database
+            .timeout(
+                self.database
+                    .client()?
+                    .execute("DELETE FROM sessions WHERE id = $1", &[&session.id()]),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self, user: User) -> Result<Vec<SessionInfo>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT id, created_at, expires_at FROM sessions \
+                 WHERE user_id = $1 AND expires_at > now() ORDER BY created_at",
+                &[&user.id],
+            ))
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| SessionInfo {
+                id: row.get(0),
+                created_at: row.get(1),
+                expires_at: row.get(2),
+            })
+            .collect())
+    }
+
+    async fn rotate(&self, session: &Session, rotated: &Session) -> Result<bool, Error> {
+        let updated = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "UPDATE sessions \
+                 SET token_id = $3, previous_token_id = token_id, rotated_at = now() \
+                 WHERE id = $1 AND token_id IS NOT DISTINCT FROM $2 AND expires_at > now()",
+                &[&session.id(), &session.token_id(), &rotated.token_id()],
+            ))
+            .await?;
+        Ok(updated > 0)
+    }
+
+    async fn revoke(&self, user: User, id: Uuid) -> Result<bool, Error> {
+        let deleted = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "DELETE FROM sessions WHERE id = $1 AND user_id = $2",
+                &[&id, &user.id],
+            ))
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn revoke_all(&self, user: User) -> Result<u64, Error> {
+        self.database
+            .timeout(
+                self.database
+                    .client()?
+                    .execute("DELETE FROM sessions WHERE user_id = $1", &[&user.id]),
+            )
+            .await
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        self.database
+            .timeout(
+                self.database
+                    .client()?
+                    .execute("DELETE FROM sessions WHERE expires_at < $1", &[&before]),
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl ClientStore for PostgresClientStore {
+    async fn insert(
+        &self,
+        id: &str,
+        secret: &str,
+        scopes: &[String],
+        user: Option<User>,
+    ) -> Result<(), Error> {
+        let secret_phc = hash_password(secret).await;
+        self.insert_client(id, &secret_phc, scopes, user).await
+    }
+
+    async fn insert_public(&self, id: &str, scopes: &[String]) -> Result<(), Error> {
+        self.insert_client(id, "", scopes, None).await
+    }
+
+    async fn authenticate(&self, id: &str, secret: &str) -> Result<Client, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT id, scopes, user_id, secret_phc = '', secret_phc \
+                 FROM oauth_clients WHERE id = $1",
+                &[&id],
+            ))
+            .await?
+            .ok_or_else(|| Error::InvalidClient(Backtrace::capture()))?;
+        let secret_phc: &str = row.get(4);
+        verify_password(secret, secret_phc)
+            .await
+            .map_err(|_| Error::InvalidClient(Backtrace::capture()))?;
+        Ok(client(&row))
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Client>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT id, scopes, user_id, secret_phc = '' FROM oauth_clients WHERE id = $1",
+                &[&id],
+            ))
+            .await?;
+        Ok(row.map(|row| client(&row)))
+    }
+
+    async fn of_user(&self, user: User) -> Result<Vec<Client>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT id, scopes, user_id, secret_phc = '' FROM oauth_clients \
+                 WHERE user_id = $1 ORDER BY id",
+                &[&user.id],
+            ))
+            .await?;
+        Ok(rows.iter().map(client).collect())
+    }
+
+    async fn add_logout_redirect_uri(&self, id: &str, uri: &str) -> Result<(), Error> {
+        self.add_uri("oauth_client_logout_uris", id, uri).await
+    }
+
+    async fn has_logout_redirect_uri(&self, id: &str, uri: &str) -> Result<bool, Error> {
+        self.has_uri("oauth_client_logout_uris", id, uri).await
+    }
+
+    async fn set_backchannel_logout_uri(&self, id: &str, uri: Option<&str>) -> Result<(), Error> {
+        let client = self.database.client()?;
+        let Some(uri) = uri else {
+            self.database
+                .timeout(client.execute(
+                    "DELETE FROM oauth_client_backchannel_uris WHERE client_id = $1",
+                    &[&id],
+                ))
+                .await?;
+            return Ok(());
+        };
+        self.database
+            .timeout(client.execute(
+                "INSERT INTO oauth_client_backchannel_uris (client_id, uri) VALUES ($1, $2) \
+                 ON CONFLICT (client_id) DO UPDATE SET uri = excluded.uri",
+                &[&id, &uri],
+            ))
+            .await
+            .map_err(|e| match e {
+                Error::Database(e, backtrace)
+                    if e.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) =>
+                {
+                    Error::ClientNotFound(id.to_owned(), backtrace)
+                }
+                e => e,
+            })?;
+        Ok(())
+    }
+
+    async fn backchannel_logout_uri(&self, id: &str) -> Result<Option<String>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT uri FROM oauth_client_backchannel_uris WHERE client_id = $1",
+                &[&id],
+            ))
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    async fn add_redirect_uri(&self, id: &str, uri: &str) -> Result<(), Error> {
+        self.add_uri("oauth_client_redirect_uris", id, uri).await
+    }
+
+    async fn has_redirect_uri(&self, id: &str, uri: &str) -> Result<bool, Error> {
+        self.has_uri("oauth_client_redirect_uris", id, uri).await
+    }
+}
+
+fn client(row: &Row) -> Client {
+    let scopes: &str = row.get(1);
+    Client {
+        id: row.get(0),
+        scopes: scopes.split_whitespace().map(str::to_owned).collect(),
+        user: row.get::<_, Option<i32>>(2).map(|id| User { id }),
+        public: row.get(3),
+    }
+}
+
+impl PostgresClientStore {
+    async fn insert_client(
+        &self,
+        id: &str,
+        secret_phc: &str,
+        scopes: &[String],
+        user: Option<User>,
+    ) -> Result<(), Error> {
+        let user_id = user.map(|user| user.id);
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO oauth_clients (id, secret_phc, scopes, user_id) VALUES ($1, $2, $3, $4)",
+                &[&id, &secret_phc, &scopes.join(" "), &user_id],
+            ))
+            .await
+            .map_err(|e| match e {
+                Error::Database(e, backtrace) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+                    Error::ClientIdTaken(backtrace)
+                }
+                e => e,
+            })?;
+        Ok(())
+    }
+
+    async fn add_uri(&self, table: &str, id: &str, uri: &str) -> Result<(), Error> {
+        let query = format!(
+            "INSERT INTO {} (client_id, uri) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            table
+        );
+        self.database
+            .timeout(self.database.client()?.execute(&query, &[&id, &uri]))
+            .await
+            .map_err(|e| match e {
+                Error::Database(e, backtrace)
+                    if e.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) =>
+                {
+                    Error::ClientNotFound(id.to_owned(), backtrace)
+                }
+                e => e,
+            })?;
+        Ok(())
+    }
+
+    async fn has_uri(&self, table: &str, id: &str, uri: &str) -> Result<bool, Error> {
+        let query = format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE client_id = $1 AND uri = $2)",
+            table
+        );
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_one(&query, &[&id, &uri]))
+            .await?;
+        Ok(row.get(0))
+    }
+}
+
+#[async_trait]
+impl TokenStore for PostgresTokenStore {
+    async fn insert(&self, token: &str, details: &AccessToken) -> Result<(), Error> {
+        let user_id = details.user.map(|user| user.id);
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO oauth_tokens (token_hash, client_id, user_id, scope, expires_at) \
+                 VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    &hash_token(token),
+                    &details.client_id,
+                    &user_id,
+                    &details.scope,
+                    &details.expires_at,
+                ],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, token: &str) -> Result<Option<AccessToken>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT client_id, user_id, scope, expires_at FROM oauth_tokens \
+                 WHERE token_hash = $1 AND expires_at > now()",
+                &[&hash_token(token)],
+            ))
+            .await?;
+        Ok(row.map(|row| AccessToken {
+            client_id: row.get(0),
+            user: row.get::<_, Option<i32>>(1).map(|id| User { id }),
+            scope: row.get(2),
+            expires_at: row.get(3),
+        }))
+    }
+
+    async fn revoke(&self, token: &str, client_id: &str) -> Result<bool, Error> {
+        let deleted = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "DELETE FROM oauth_tokens WHERE token_hash = $1 AND client_id = $2",
+                &[&hash_token(token), &client_id],
+            ))
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn revoke_all(&self, client_id: &str, user: User) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "DELETE FROM oauth_tokens WHERE client_id = $1 AND user_id = $2",
+                &[&client_id, &user.id],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self, user: User) -> Result<Vec<TokenInfo>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT token_hash, client_id, scope, expires_at FROM oauth_tokens \
+                 WHERE user_id = $1 AND expires_at > now() ORDER BY expires_at",
+                &[&user.id],
+            ))
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| TokenInfo {
+                id: row.get(0),
+                client_id: row.get(1),
+                scope: row.get(2),
+                expires_at: row.get(3),
+            })
+            .collect())
+    }
+
+    async fn revoke_by_id(&self, user: User, id: &str) -> Result<bool, Error> {
+        let deleted = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "DELETE FROM oauth_tokens WHERE token_hash = $1 AND user_id = $2",
+                &[&id, &user.id],
+            ))
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn insert_code(&self, code: &str, details: &AuthorizationCode) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO oauth_codes \
+                 (code_hash, client_id, user_id, scope, redirect_uri, code_challenge, expires_at) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    &hash_token(code),
+                    &details.client_id,
+                    &details.user.id,
+                    &details.scope,
+                    &details.redirect_uri,
+                    &details.code_challenge,
+                    &details.expires_at,
+                ],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn take_code(&self, code: &str) -> Result<Option<AuthorizationCode>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "DELETE FROM oauth_codes WHERE code_hash = $1 \
+                 RETURNING client_id, user_id, scope, redirect_uri, code_challenge, expires_at",
+                &[&hash_token(code)],
+            ))
+            .await?;
+        Ok(row
+            .map(|row| AuthorizationCode {
+                client_id: row.get(0),
+                user: User { id: row.get(1) },
+                scope: row.get(2),
+                redirect_uri: row.get(3),
+                code_challenge: row.get(4),
+                expires_at: row.get(5),
+            })
+            .filter(|code| code.expires_at > SystemTime::now()))
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let client = self.database.client()?;
+        let tokens = self
+            .database
+            .timeout(client.execute("DELETE FROM oauth_tokens WHERE expires_at < $1", &[&before]))
+            .await?;
+        let codes = self
+            .database
+            .timeout(client.execute("DELETE FROM oauth_codes WHERE expires_at < $1", &[&before]))
+            .await?;
+        Ok(tokens + codes)
+    }
+}
+
+#[async_trait]
+impl ConsentStore for PostgresConsentStore {
+    async fn grant(&self, user: User, client_id: &str, scope: &str) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO oauth_consents (user_id, client_id, scope) VALUES ($1, $2, $3) \
+                 ON CONFLICT (user_id, client_id) DO UPDATE SET scope = excluded.scope",
+                &[&user.id, &client_id, &scope],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, user: User, client_id: &str) -> Result<Option<String>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT scope FROM oauth_consents WHERE user_id = $1 AND client_id = $2",
+                &[&user.id, &client_id],
+            ))
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    async fn list(&self, user: User) -> Result<Vec<Consent>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT client_id, scope FROM oauth_consents WHERE user_id = $1 \
+                 ORDER BY client_id",
+                &[&user.id],
+            ))
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Consent {
+                client_id: row.get(0),
+                scope: row.get(1),
+            })
+            .collect())
+    }
+
+    async fn revoke(&self, user: User, client_id: &str) -> Result<bool, Error> {
+        let deleted = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "DELETE FROM oauth_consents WHERE user_id = $1 AND client_id = $2",
+                &[&user.id, &client_id],
+            ))
+            .await?;
+        Ok(deleted > 0)
+    }
+}
+
+#[async_trait]
+impl JobStore for PostgresJobStore {
+    async fn enqueue(&self, kind: &str, payload: &str) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO jobs (kind, payload) VALUES ($1, $2)",
+                &[&kind, &payload],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn claim(&self, lease: Duration) -> Result<Option<Job>, Error> {
+        // SKIP LOCKED lets workers running at the same time claim different jobs instead of
+        // waiting for each other.
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "UPDATE jobs SET attempts = attempts + 1, locked_until = $1 WHERE id = (\
+                 SELECT id FROM jobs WHERE NOT dead AND run_at <= now() \
+                 AND (locked_until IS NULL OR locked_until < now()) \
+                 ORDER BY run_at LIMIT 1 FOR UPDATE SKIP LOCKED) \
+                 RETURNING id, kind, payload, attempts",
+                &[&(SystemTime::now() + lease)],
+            ))
+            .await?;
+        Ok(row.map(|row| Job {
+            id: row.get(0),
+            kind: row.get(1),
+            payload: row.get(2),
+            attempts: row.get(3),
+        }))
+    }
+
+    async fn complete(&self, id: i64) -> Result<(), Error> {
+        self.database
+            .timeout(
+                self.database
+                    .client()?
+                    .execute("DELETE FROM jobs WHERE id = $1", &[&id]),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn retry(&self, id: i64, error: &str, run_at: SystemTime) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "UPDATE jobs SET run_at = $2, locked_until = NULL, last_error = $3 WHERE id = $1",
+                &[&id, &run_at, &error],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn bury(&self, id: i64, error: &str) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "UPDATE jobs SET dead = TRUE, locked_until = NULL, last_error = $2 WHERE id = $1",
+                &[&id, &error],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn requeue_dead(&self) -> Result<u64, Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "UPDATE jobs SET dead = FALSE, attempts = 0, run_at = now() WHERE dead",
+                &[],
+            ))
+            .await
+    }
+}
+
+#[async_trait]
+impl OtpStore for PostgresOtpStore {
+    async fn set_phone(&self, user: User, number: &str) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO phone_numbers (user_id, number) VALUES ($1, $2) \
+                 ON CONFLICT (user_id) DO UPDATE SET number = excluded.number, verified = FALSE",
+                &[&user.id, &number],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn phone(&self, user: User) -> Result<Option<Phone>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT number, verified FROM phone_numbers WHERE user_id = $1",
+                &[&user.id],
+            ))
+            .await?;
+        Ok(row.map(|row| Phone {
+            number: row.get(0),
+            verified: row.get(1),
+        }))
+    }
+
+    async fn verify_phone(&self, user: User, number: &str) -> Result<bool, Error> {
+        let updated = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "UPDATE phone_numbers SET verified = TRUE WHERE user_id = $1 AND number = $2",
+                &[&user.id, &number],
+            ))
+            .await?;
+        Ok(updated > 0)
+    }
+
+    async fn remove_phone(&self, user: User) -> Result<(), Error> {
+        self.database
+            .timeout(
+                self.database
+                    .client()?
+                    .execute("DELETE FROM phone_numbers WHERE user_id = $1", &[&user.id]),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn set_pending_totp(&self, user: User, secret: &str) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO totp_secrets (user_id, pending_secret) VALUES ($1, $2) \
+                 ON CONFLICT (user_id) DO UPDATE SET pending_secret = excluded.pending_secret",
+                &[&user.id, &secret],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn totp(&self, user: User) -> Result<Option<Totp>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT secret, pending_secret FROM totp_secrets WHERE user_id = $1",
+                &[&user.id],
+            ))
+            .await?;
+        Ok(row.map(|row| Totp {
+            secret: row.get(0),
+            pending: row.get(1),
+        }))
+    }
+
+    async fn confirm_totp(&self, user: User, pending: &str, step: u64) -> Result<bool, Error> {
+        let updated = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "UPDATE totp_secrets \
+                 SET secret = pending_secret, pending_secret = NULL, last_step = $3 \
+                 WHERE user_id = $1 AND pending_secret = $2",
+                &[&user.id, &pending, &(step as i64)],
+            ))
+            .await?;
+        Ok(updated > 0)
+    }
+
+    async fn accept_totp(&self, user: User, secret: &str, step: u64) -> Result<bool, Error> {
+        let updated = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "UPDATE totp_secrets SET last_step = $3 \
+                 WHERE user_id = $1 AND secret = $2 AND (last_step IS NULL OR last_step < $3)",
+                &[&user.id, &secret, &(step as i64)],
+            ))
+            .await?;
+        Ok(updated > 0)
+    }
+
+    async fn remove_totp(&self, user: User) -> Result<(), Error> {
+        self.database
+            .timeout(
+                self.database
+                    .client()?
+                    .execute("DELETE FROM totp_secrets WHERE user_id = $1", &[&user.id]),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_code(
+        &self,
+        user: User,
+        purpose: Purpose,
+        code: &str,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO otp_codes (user_id, purpose, code_hash, expires_at) \
+                 VALUES ($1, $2, $3, $4)",
+                &[
+                    &user.id,
+                    &purpose.as_str(),
+                    &hash_code(user, code),
+                    &expires_at,
+                ],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn count_codes(&self, user: User, since: SystemTime) -> Result<u64, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_one(
+                "SELECT count(*) FROM otp_codes WHERE user_id = $1 AND created_at > $2",
+                &[&user.id, &since],
+            ))
+            .await?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    async fn check_code(&self, user: User, purpose: Purpose, code: &str) -> Result<bool, Error> {
+        let client = self.database.client()?;
+        let row = self
+            .database
+            .timeout(client.query_opt(
+                "UPDATE otp_codes SET attempts = attempts + 1 WHERE id = (\
+                 SELECT id FROM otp_codes WHERE user_id = $1 AND purpose = $2 AND expires_at > now() \
+                 ORDER BY created_at DESC, id DESC LIMIT 1) \
+                 RETURNING code_hash, attempts",
+                &[&user.id, &purpose.as_str()],
+            ))
+            .await?;
+        let Some(row) = row else { return Ok(false) };
+        let code_hash: String = row.get(0);
+        let attempts: i32 = row.get(1);
+        if attempts > MAX_CHECK_ATTEMPTS || code_hash != hash_code(user, code) {
+            return Ok(false);
+        }
+        self.database
+            .timeout(client.execute(
+                "DELETE FROM otp_codes WHERE user_id = $1 AND purpose = $2",
+                &[&user.id, &purpose.as_str()],
+            ))
+            .await?;
+        Ok(true)
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        self.database
+            .timeout(
+                self.database
+                    .client()?
+                    .execute("DELETE FROM otp_codes WHERE expires_at < $1", &[&before]),
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl AuditStore for PostgresAuditStore {
+    async fn insert(&self, event: &AuditEvent) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO audit_events \
+                 (user_id, tenant_id, kind, ip, device, country, details, created_at) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                &[
+                    &event.user.map(|user| user.id),
+                    &event.tenant,
+                    &event.kind,
+                    &event.ip,
+                    &event.device,
+                    &event.country,
+                    &event.details.to_string(),
+                    &event.created_at,
+                ],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn list(
+        &self,
+        user: User,
+        since: SystemTime,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT tenant_id, kind, ip, device, country, details, created_at FROM audit_events \
+                 WHERE user_id = $1 AND created_at > $2 ORDER BY created_at DESC, id DESC LIMIT $3",
+                &[&user.id, &since, &(limit as i64)],
+            ))
+            .await?;
+        rows.into_iter()
+            .map(|row| audit_event(Some(user), row))
+            .collect()
+    }
+
+    async fn logins(
+        &self,
+        user: User,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT tenant_id, kind, ip, device, country, details, created_at FROM audit_events \
+                 WHERE user_id = $1 AND kind IN ($2, $3) ORDER BY created_at DESC, id DESC \
+                 LIMIT $4 OFFSET $5",
+                &[
+                    &user.id,
+                    &audit::LOGIN_SUCCEEDED,
+                    &audit::LOGIN_FAILED,
+                    &(limit as i64),
+                    &(offset as i64),
+                ],
+            ))
+            .await?;
+        rows.into_iter()
+            .map(|row| audit_event(Some(user), row))
+            .collect()
+    }
+
+    async fn position(&self) -> Result<i64, Error> {
+        let row = self
+            .database
+            .timeout(
+                self.database
+                    .client()?
+                    .query_one("SELECT COALESCE(MAX(id), 0) FROM audit_events", &[]),
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    async fn after(&self, position: i64, limit: usize) -> Result<Vec<(i64, AuditEvent)>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT tenant_id, kind, ip, device, country, details, created_at, id, user_id \
+                 FROM audit_events WHERE id > $1 ORDER BY id LIMIT $2",
+                &[&position, &(limit as i64)],
+            ))
+            .await?;
+        rows.into_iter()
+            .map(|row| {
+                let id = row.get(7);
+                let user_id: Option<i32> = row.get(8);
+                Ok((id, audit_event(user_id.map(|id| User { id }), row)?))
+            })
+            .collect()
+    }
+}
+
+fn audit_event(user: Option<User>, row: Row) -> Result<AuditEvent, Error> {
+    Ok(AuditEvent {
+        user,
+        tenant: row.get(0),
+        kind: row.get(1),
+        ip: row.get(2),
+        device: row.get(3),
+        country: row.get(4),
+        details: serde_json::from_str(row.get(5))?,
+        created_at: row.get(6),
+    })
+}
+
+#[async_trait]
+impl ExportStore for PostgresExportStore {
+    async fn insert(&self, user: User, id: Uuid, expires_at: SystemTime) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO data_exports (id, user_id, expires_at) VALUES ($1, $2, $3)",
+                &[&id, &user.id, &expires_at],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn complete(&self, id: Uuid, data: &str) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "UPDATE data_exports SET data = $1 WHERE id = $2",
+                &[&data, &id],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn latest(&self, user: User) -> Result<Option<Export>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT id, data IS NOT NULL FROM data_exports \
+                 WHERE user_id = $1 AND expires_at > now() ORDER BY created_at DESC LIMIT 1",
+                &[&user.id],
+            ))
+            .await?;
+        Ok(row.map(|row| Export {
+            id: row.get(0),
+            ready: row.get(1),
+        }))
+    }
+
+    async fn take(&self, id: Uuid) -> Result<Option<(User, String)>, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "DELETE FROM data_exports \
+                 WHERE id = $1 AND data IS NOT NULL AND expires_at > now() \
+                 RETURNING user_id, data",
+                &[&id],
+            ))
+            .await?;
+        Ok(row.map(|row| (User { id: row.get(0) }, row.get(1))))
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        self.database
+            .timeout(
+                self.database
+                    .client()?
+                    .execute("DELETE FROM data_exports WHERE expires_at < $1", &[&before]),
+            )
+            .await
+    }
+}
+
+fn parse_status(status: &str) -> Result<AccountStatus, Error> {
+    AccountStatus::parse(status)
+        .ok_or_else(|| Error::UnknownAccountStatus(status.to_owned(), Backtrace::capture()))
+}
+
+#[async_trait]
+impl FeatureStore for PostgresFeatureStore {
+    async fn list(&self) -> Result<Vec<(String, bool)>, Error> {
+        let rows = self
+            .database
+            .timeout(
+                self.database
+                    .client()?
+                    .query("SELECT name, enabled FROM feature_flags ORDER BY name", &[]),
+            )
+            .await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    async fn set(&self, name: &str, enabled: bool) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO feature_flags (name, enabled) VALUES ($1, $2) \
+                 ON CONFLICT (name) DO UPDATE SET enabled = excluded.enabled, updated_at = now()",
+                &[&name, &enabled],
+            ))
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClaimStore for PostgresClaimStore {
+    async fn list(&self, user: User) -> Result<Claims, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT name, value FROM user_claims WHERE user_id = $1",
+                &[&user.id],
+            ))
+            .await?;
+        rows.iter()
+            .map(|row| Ok((row.get(0), serde_json::from_str(row.get(1))?)))
+            .collect()
+    }
+
+    async fn set(&self, user: User, name: &str, value: &serde_json::Value) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO user_claims (user_id, name, value) VALUES ($1, $2, $3) \
+                 ON CONFLICT (user_id, name) DO UPDATE SET value = excluded.value",
+                &[&user.id, &name, &value.to_string()],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, user: User, name: &str) -> Result<bool, Error> {
+        let deleted = self
+            .database
+            .timeout(self.database.client()?.execute(
+                "DELETE FROM user_claims WHERE user_id = $1 AND name = $2",
+                &[&user.id, &name],
+            ))
+            .await?;
+        Ok(deleted > 0)
+    }
+}
+
+#[async_trait]
+impl QuotaStore for PostgresQuotaStore {
+    async fn count(&self, key: &str, window: SystemTime) -> Result<u64, Error> {
+        let row = self
+            .database
+            .timeout(self.database.client()?.query_opt(
+                "SELECT count FROM quota_counters WHERE key = $1 AND window_start = $2",
+                &[&key, &window],
+            ))
+            .await?;
+        Ok(row.map_or(0, |row| row.get::<_, i64>(0) as u64))
+    }
+
+    async fn increment(
+        &self,
+        key: &str,
+        window: SystemTime,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO quota_counters (key, window_start, count, expires_at) \
+                 VALUES ($1, $2, 1, $3) \
+                 ON CONFLICT (key, window_start) DO UPDATE SET count = quota_counters.count + 1",
+                &[&key, &window, &expires_at],
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "DELETE FROM quota_counters WHERE expires_at < $1",
+                &[&before],
+            ))
+            .await
+    }
+}
+
+fn identity_taken(e: Error) -> Error {
+    match e {
+        Error::Database(e, backtrace) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            Error::IdentityTaken(backtrace)
+        }
+        e => e,
+    }
+}
+
+fn user_conflict(e: Error) -> Error {
+    match e {
+        Error::Database(e, backtrace) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            match e.as_db_error().and_then(|e| e.constraint()) {
+                Some("users_email") => Error::EmailTaken(backtrace),
+                _ => Error::UsernameTaken(backtrace),
+            }
+        }
+        e => e,
+    }
+}
+
+#[async_trait]
+impl NotificationStore for PostgresNotificationStore {
+    async fn list(&self, user: User) -> Result<HashMap<Category, bool>, Error> {
+        let rows = self
+            .database
+            .timeout(self.database.client()?.query(
+                "SELECT category, enabled FROM notification_preferences WHERE user_id = $1",
+                &[&user.id],
+            ))
+            .await?;
+        // Categories dropped since are left alone rather than failing the query.
+        Ok(rows
+            .iter()
+            .filter_map(|row| Some((Category::parse(row.get(0))?, row.get(1))))
+            .collect())
+    }
+
+    async fn set(&self, user: User, category: Category, enabled: bool) -> Result<(), Error> {
+        self.database
+            .timeout(self.database.client()?.execute(
+                "INSERT INTO notification_preferences (user_id, category, enabled) \
+                 VALUES ($1, $2, $3) \
+                 ON CONFLICT (user_id, category) DO UPDATE SET enabled = excluded.enabled",
+                &[&user.id, &category.as_str(), &enabled],
+            ))
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use crate::error::Error;
+use crate::util::{env_duration_ms, env_usize_opt, env_var_opt};
+use async_trait::async_trait;
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Counts of registrations, and of wrong authenticator app codes, in fixed windows of time, like
+/// days since the Unix epoch.
+#[async_trait]
+pub trait QuotaStore: Send + Sync {
+    /// Registrations counted for the key in the window starting at the given time.
+    async fn count(&self, key: &str, window: SystemTime) -> Result<u64, Error>;
+
+    /// Counts a registration for the key, with the count kept until the window expires.
+    async fn increment(
+        &self,
+        key: &str,
+        window: SystemTime,
+        expires_at: SystemTime,
+    ) -> Result<(), Error>;
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error>;
+}
+
+/// Caps on how many accounts can be registered from one IP address and with addresses at one
+/// email domain in each window, to slow down scripts creating accounts in bulk. Registrations
+/// without an email address only count against the IP address.
+pub struct QuotaPolicy {
+    /// From `REGISTRATION_QUOTA_PER_IP`. IPv6 addresses are counted by their /64 network, as
+    /// that's usually what a single host gets.
+    pub per_ip: Option<usize>,
+    /// From `REGISTRATION_QUOTA_PER_DOMAIN`, for the domains not listed in `domains`.
+    pub per_domain: Option<usize>,
+    /// From `REGISTRATION_QUOTA_DOMAINS`, like `example.com=500,gmail.com=10000`, for domains
+    /// that need a cap of their own.
+    pub domains: HashMap<String, usize>,
+    /// From `REGISTRATION_QUOTA_WINDOW_MS`, a day by default.
+    pub window: Duration,
+}
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60 * 60 * 24);
+
+impl QuotaPolicy {
+    pub fn from_env() -> Result<QuotaPolicy, Error> {
+        let domains = match env_var_opt("REGISTRATION_QUOTA_DOMAINS")? {
+            Some(domains) => domains
+                .split(',')
+                .map(str::trim)
+                .filter(|domain| !domain.is_empty())
+                .map(|domain| {
+                    let invalid =
+                        || Error::InvalidRegistrationQuota(domain.to_owned(), Backtrace::capture());
+                    let (name, limit) = domain.split_once('=').ok_or_else(invalid)?;
+                    let limit = limit.trim().parse().map_err(|_| invalid())?;
+                    Ok((name.trim().to_lowercase(), limit))
+                })
+                .collect::<Result<_, Error>>()?,
+            None => HashMap::new(),
+        };
+        Ok(QuotaPolicy {
+            per_ip: env_usize_opt("REGISTRATION_QUOTA_PER_IP")?,
+            per_domain: env_usize_opt("REGISTRATION_QUOTA_PER_DOMAIN")?,
+            domains,
+            window: env_duration_ms("REGISTRATION_QUOTA_WINDOW_MS", DEFAULT_WINDOW)?,
+        })
+    }
+
+    /// Fails when another registration from the address or with the normalized email address
+    /// would go over a cap.
+    pub async fn check(
+        &self,
+        store: &dyn QuotaStore,
+        ip: Option<IpAddr>,
+        email: Option<&str>,
+    ) -> Result<(), Error> {
+        let window = self.window_start(SystemTime::now());
+        for (key, limit) in self.limits(ip, email) {
+            if store.count(&key, window).await? >= limit as u64 {
+                return Err(Error::RegistrationQuotaExceeded(Backtrace::capture()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a registration that went through against the caps it's under.
+    pub async fn record(
+        &self,
+        store: &dyn QuotaStore,
+        ip: Option<IpAddr>,
+        email: Option<&str>,
+    ) -> Result<(), Error> {
+        let window = self.window_start(SystemTime::now());
+        for (key, _) in self.limits(ip, email) {
+            store.increment(&key, window, window + self.window).await?;
+        }
+        Ok(())
+    }
+
+    fn limits(&self, ip: Option<IpAddr>, email: Option<&str>) -> Vec<(String, usize)> {
+        let mut limits = Vec::new();
+        if let (Some(ip), Some(limit)) = (ip, self.per_ip) {
+            limits.push((format!("ip:{}", network(ip)), limit));
+        }
+        if let Some((_, domain)) = email.and_then(|email| email.rsplit_once('@')) {
+            if let Some(limit) = self.domains.get(domain).copied().or(self.per_domain) {
+                limits.push((format!("domain:{}", domain), limit));
+            }
+        }
+        limits
+    }
+
+    fn window_start(&self, now: SystemTime) -> SystemTime {
+        let window = self.window.as_secs().max(1);
+        let since = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        UNIX_EPOCH + Duration::from_secs(since / window * window)
+    }
+}
+
+fn network(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!(
+                "{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+    }
+}
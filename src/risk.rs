@@ -0,0 +1,125 @@
+use crate::audit::{self, AuditStore};
+use crate::client::ClientInfo;
+use crate::error::Error;
+use crate::user::User;
+use crate::util::{env_duration_ms, env_usize, env_var_opt};
+use serde::Serialize;
+use std::time::{Duration, SystemTime};
+
+/// When a login counts as risky enough that it has to be confirmed some other way.
+pub struct RiskPolicy {
+    /// Logins scoring this much or more get a code mailed to confirm them, unless the user has
+    /// a phone number enrolled, which gets asked for regardless.
+    pub step_up_score: usize,
+    /// Logins from another country sooner than this after the last one count as impossible travel.
+    pub travel_window: Duration,
+    /// Header the proxy in front puts the country of the client in, like `CF-IPCountry`. Without
+    /// it nothing is known about countries, and both country factors stay out of the score.
+    pub country_header: Option<String>,
+}
+
+/// Score of a login along with the factors it's made up of, which go to the audit log.
+#[derive(Serialize)]
+pub struct Assessment {
+    pub score: usize,
+    pub factors: Vec<&'static str>,
+}
+
+const DEFAULT_STEP_UP_SCORE: usize = 60;
+const DEFAULT_TRAVEL_WINDOW: Duration = Duration::from_secs(60 * 60 * 4);
+
+/// How far back the audit log is looked at for devices and countries the user is known to log in
+/// from.
+const HISTORY_WINDOW: Duration = Duration::from_secs(60 * 60 * 24 * 90);
+const HISTORY_LIMIT: usize = 1000;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+const NEW_DEVICE_SCORE: usize = 30;
+const NEW_COUNTRY_SCORE: usize = 30;
+const IMPOSSIBLE_TRAVEL_SCORE: usize = 60;
+const FAILURE_SCORE: usize = 10;
+const MAX_FAILURES_SCORE: usize = 30;
+
+impl RiskPolicy {
+    pub fn from_env() -> Result<RiskPolicy, Error> {
+        Ok(RiskPolicy {
+            step_up_score: env_usize("RISK_STEP_UP_SCORE", DEFAULT_STEP_UP_SCORE)?,
+            travel_window: env_duration_ms("RISK_TRAVEL_WINDOW_MS", DEFAULT_TRAVEL_WINDOW)?,
+            country_header: env_var_opt("RISK_COUNTRY_HEADER")?,
+        })
+    }
+}
+
+impl Assessment {
+    pub fn requires_step_up(&self, policy: &RiskPolicy) -> bool {
+        self.score >= policy.step_up_score
+    }
+
+    fn add(&mut self, factor: &'static str, score: usize) {
+        self.score += score;
+        self.factors.push(factor);
+    }
+}
+
+/// Scores the login of a user who already got past the password, against where they logged in from
+/// before. Users without any history yet are only scored on recent failures, as everything about
+/// their first login is new.
+pub async fn assess(
+    audit: &dyn AuditStore,
+    user: User,
+    client: &ClientInfo,
+    policy: &RiskPolicy,
+) -> Result<Assessment, Error> {
+    let now = SystemTime::now();
+    let history = audit
+        .list(user, now - HISTORY_WINDOW, HISTORY_LIMIT)
+        .await?;
+    let known: Vec<_> = history
+        .iter()
+        .filter(|event| event.kind == audit::LOGIN_SUCCEEDED || event.kind == audit::REGISTERED)
+        .collect();
+    let mut assessment = Assessment {
+        score: 0,
+        factors: Vec::new(),
+    };
+    if !known.is_empty() {
+        let device_known =
+            client.device.is_some() && known.iter().any(|event| event.device == client.device);
+        if !device_known {
+            assessment.add("new_device", NEW_DEVICE_SCORE);
+        }
+        if let Some(country) = &client.country {
+            let countries: Vec<_> = known
+                .iter()
+                .filter_map(|event| event.country.as_ref())
+                .collect();
+            if !countries.is_empty() && !countries.contains(&country) {
+                assessment.add("new_country", NEW_COUNTRY_SCORE);
+            }
+            // Without geolocation there's no telling how far apart the countries are, so any
+            // change within the window is taken as travel no one could have made.
+            let last = known.iter().find(|event| event.country.is_some());
+            if let Some(last) = last {
+                let recent = now
+                    .duration_since(last.created_at)
+                    .map_or(true, |elapsed| elapsed < policy.travel_window);
+                if recent && last.country.as_ref() != Some(country) {
+                    assessment.add("impossible_travel", IMPOSSIBLE_TRAVEL_SCORE);
+                }
+            }
+        }
+    }
+    let failures = history
+        .iter()
+        .filter(|event| {
+            event.kind == audit::LOGIN_FAILED && event.created_at > now - FAILURE_WINDOW
+        })
+        .count();
+    if failures > 0 {
+        assessment.add(
+            "recent_failures",
+            (failures * FAILURE_SCORE).min(MAX_FAILURES_SCORE),
+        );
+    }
+    Ok(assessment)
+}
@@ -0,0 +1,207 @@
+use crate::api;
+use crate::error::Error;
+use crate::session::Session;
+use hyper::body::Bytes;
+use hyper::header::ALLOW;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use std::backtrace::Backtrace;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Every route of a router, by the path pattern, with what it does and the handler answering it.
+/// Having them all in one place tells a path that doesn't exist from one that doesn't take the
+/// method. See [`matches`] for what the patterns look like.
+pub type Table<H> = &'static [(Method, &'static str, Access, H)];
+
+/// Whether a route makes changes to accounts, which read-only mode turns away, and whether those are
+/// ones that impersonating admins can't make. Saying so in the
+/// tables, rather than in a list of its own, means a new route can't be left out of it. Logging in
+/// and out doesn't count, as sessions keep working in read-only mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    /// Makes changes, from a form on the page, which browsers are sent back to when the change is
+    /// turned away.
+    Write(&'static str),
+    /// Makes changes to how the user logs in, or takes their data, which admins impersonating them
+    /// can't do either.
+    Credentials(&'static str),
+}
+
+/// What handlers of the tables return, borrowing from what the router worked out about the request.
+pub type HandlerFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send + 'a>>;
+
+/// Route a request was matched to.
+pub struct Matched<H> {
+    pub pattern: &'static str,
+    pub params: Params,
+    pub access: Access,
+    pub handler: H,
+}
+
+/// Where the routers leave the pattern of the route the request went to, for the request to be
+/// logged and measured by. Put in the request extensions before it's routed.
+#[derive(Clone, Default)]
+pub struct MatchedPattern(Arc<Mutex<Option<String>>>);
+
+impl MatchedPattern {
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Segments of the path that the pattern had parameters for. Routes added through
+/// [`crate::Server::route`] find them in the request extensions.
+#[derive(Clone, Debug, Default)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Parses the parameter, for handlers taking IDs and such. Routes only match with every
+    /// parameter of their pattern present, so that a missing one is a typo in the handler.
+    pub fn parse<T>(&self, name: &str) -> Result<T, Error>
+    where
+        T: FromStr,
+        Error: From<T::Err>,
+    {
+        let Some(value) = self.get(name) else {
+            return Err(Error::NoSuchRouteParam(
+                name.to_owned(),
+                Backtrace::capture(),
+            ));
+        };
+        Ok(value.parse()?)
+    }
+}
+
+/// Matches the path against a pattern like `/users/{id}`, where each `{name}` stands for one whole
+/// segment, which ends up in the parameters under that name.
+pub fn matches(pattern: &str, path: &str) -> Option<Params> {
+    let mut params = Vec::new();
+    let mut segments = path.split('/');
+    for expected in pattern.split('/') {
+        let segment = segments.next()?;
+        match expected
+            .strip_prefix('{')
+            .and_then(|name| name.strip_suffix('}'))
+        {
+            Some(_) if segment.is_empty() => return None,
+            Some(name) => params.push((name.to_owned(), segment.to_owned())),
+            None if expected == segment => (),
+            None => return None,
+        }
+    }
+    match segments.next() {
+        Some(_) => None,
+        None => Some(Params(params)),
+    }
+}
+
+/// Finds the route of the path, failing with [`Error::NotFound`] when no route has that path and
+/// [`Error::MethodNotAllowed`] when none of the ones that do take the method.
+pub fn resolve<H: Copy>(table: Table<H>, method: &Method, path: &str) -> Result<Matched<H>, Error> {
+    let mut allowed = Vec::new();
+    for (route_method, pattern, access, handler) in table {
+        let Some(params) = matches(pattern, path) else { continue; };
+        if route_method == method {
+            return Ok(Matched {
+                pattern,
+                params,
+                access: *access,
+                handler: *handler,
+            });
+        }
+        allowed.push(route_method.clone());
+    }
+    match allowed.is_empty() {
+        true => Err(Error::NotFound(Backtrace::capture())),
+        false => Err(Error::MethodNotAllowed(allowed, Backtrace::capture())),
+    }
+}
+
+/// Leaves the pattern in the request's [`MatchedPattern`]. Routers that take a prefix off the path
+/// before looking it up put it back in front of the pattern.
+pub fn record(req: &Request<Body>, pattern: &str) {
+    if let Some(matched) = req.extensions().get::<MatchedPattern>() {
+        *matched.0.lock().unwrap() = Some(pattern.to_owned());
+    }
+}
+
+/// Value of the `Allow` header for a path taking the methods. Every GET route answers HEAD as well,
+/// and every route answers OPTIONS, neither of which the tables list.
+pub fn allow(methods: &[Method]) -> String {
+    let mut allow = Vec::new();
+    for method in methods {
+        if !allow.contains(&method.as_str()) {
+            allow.push(method.as_str());
+        }
+        if method == Method::GET && !allow.contains(&"HEAD") {
+            allow.push("HEAD");
+        }
+    }
+    allow.push("OPTIONS");
+    allow.join(", ")
+}
+
+/// Answer to an OPTIONS request for a path taking the methods.
+pub fn options(methods: &[Method]) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ALLOW, allow(methods))
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// The session of a handler that needs the user to be logged in.
+pub fn session(session: &Option<Session>) -> Result<&Session, Error> {
+    session
+        .as_ref()
+        .ok_or_else(|| Error::NotLoggedIn(Backtrace::capture()))
+}
+
+/// Reads the body of a form post.
+pub async fn form<T: DeserializeOwned>(
+    req: &mut Request<Body>,
+    timeout: Duration,
+) -> Result<T, Error> {
+    Ok(serde_urlencoded::from_bytes(
+        &read_body(req, timeout).await?,
+    )?)
+}
+
+/// Reads the body of an API request, in whichever format the client sent it in, see
+/// [`api::parse_body`].
+pub async fn body<T: DeserializeOwned>(
+    req: &mut Request<Body>,
+    timeout: Duration,
+) -> Result<T, Error> {
+    let body_bytes = read_body(req, timeout).await?;
+    api::parse_body(req, &body_bytes)
+}
+
+/// Reads the body of a request that's only taken as JSON.
+pub async fn json<T: DeserializeOwned>(
+    req: &mut Request<Body>,
+    timeout: Duration,
+) -> Result<T, Error> {
+    Ok(serde_json::from_slice(&read_body(req, timeout).await?)?)
+}
+
+/// Reads the whole request body, failing if the client takes longer than the timeout to send it.
+pub async fn read_body(req: &mut Request<Body>, timeout: Duration) -> Result<Bytes, Error> {
+    match tokio::time::timeout(timeout, hyper::body::to_bytes(req.body_mut())).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Error::BodyTimeout(Backtrace::capture())),
+    }
+}
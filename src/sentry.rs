@@ -0,0 +1,122 @@
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::user::User;
+use crate::util::{env_var_opt, http_client};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use slog::{error, Logger};
+use std::backtrace::Backtrace;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Reports internal errors to Sentry, or anything else taking its events, when `SENTRY_DSN` is
+/// set. `SENTRY_ENVIRONMENT` tells apart the events of different deployments.
+pub struct Sentry {
+    client: Client<HttpsConnector<HttpConnector>>,
+    store_url: String,
+    auth: String,
+    environment: Option<String>,
+}
+
+/// What's known about the request that failed.
+pub struct Report<'a> {
+    pub request_id: Uuid,
+    pub route: Option<&'a str>,
+    pub user: Option<User>,
+}
+
+const USER_ID_DOMAIN: &str = "sentry-user.";
+
+impl Sentry {
+    pub fn from_env() -> Result<Option<Sentry>, Error> {
+        let Some(dsn) = env_var_opt("SENTRY_DSN")? else { return Ok(None); };
+        let mut sentry = Sentry::new(&dsn)?;
+        sentry.environment = env_var_opt("SENTRY_ENVIRONMENT")?;
+        Ok(Some(sentry))
+    }
+
+    /// Takes a DSN like `https://<key>@<host>/<project>`, where the host can be followed by a
+    /// path for self-hosted installations.
+    pub fn new(dsn: &str) -> Result<Sentry, Error> {
+        let invalid = || Error::InvalidSentryDsn(Backtrace::capture());
+        let rest = dsn.strip_prefix("https://").ok_or_else(invalid)?;
+        let (key, rest) = rest.split_once('@').ok_or_else(invalid)?;
+        // Old DSNs have the secret after the key, which isn't needed anymore.
+        let key = key.split(':').next().unwrap_or_default();
+        let (host, project) = rest
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .ok_or_else(invalid)?;
+        if key.is_empty() || host.is_empty() || project.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Sentry {
+            client: http_client(),
+            store_url: format!("https://{}/api/{}/store/", host, project),
+            auth: format!(
+                "Sentry sentry_version=7, sentry_client=authtown/{}, sentry_key={}",
+                env!("CARGO_PKG_VERSION"),
+                key
+            ),
+            environment: None,
+        })
+    }
+
+    /// Sends the error in the background, so that the response doesn't wait for it. Users are
+    /// only identified by a keyed hash of their ID, which is the same across their errors while
+    /// telling Sentry nothing about who they are.
+    pub fn report(self: &Arc<Self>, error: &Error, report: Report, crypto: &Crypto, log: &Logger) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let user = report.user.map(|user| {
+            let hash = crypto.sign(format!("{}{}", USER_ID_DOMAIN, user.id).as_bytes());
+            serde_json::json!({ "id": hex::encode(&hash.hash[..16]) })
+        });
+        let event = serde_json::json!({
+            "event_id": Uuid::new_v4().to_simple().to_string(),
+            "timestamp": timestamp,
+            "platform": "other",
+            "level": "error",
+            "logger": "authtown",
+            "release": format!("authtown@{}", env!("CARGO_PKG_VERSION")),
+            "environment": self.environment,
+            "transaction": report.route,
+            "exception": {
+                "values": [{ "type": error.code(), "value": error.full_message() }],
+            },
+            "tags": {
+                "request_id": report.request_id.to_string(),
+                "route": report.route,
+            },
+            "user": user,
+            "extra": { "backtrace": error.backtrace_string() },
+        });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.store_url)
+            .header("x-sentry-auth", &self.auth)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(event.to_string()))
+            .unwrap();
+        let sentry = self.clone();
+        let log = log.clone();
+        tokio::spawn(async move {
+            let result = sentry.client.request(request).await;
+            match result {
+                Ok(response) if response.status().is_success() => (),
+                Ok(response) => {
+                    error!(log, "Sentry rejected the error report"; "status" => response.status().as_u16());
+                }
+                Err(e) => {
+                    let e = Error::from(e);
+                    error!(log, "Sending the error report to Sentry failed"; e.log_message());
+                }
+            }
+        });
+    }
+}
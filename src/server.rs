@@ -0,0 +1,239 @@
+use crate::claims::ClaimsHook;
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::middleware::Middleware;
+use crate::plugins::{Plugin, PluginClaims};
+use crate::session::Session;
+use crate::store::Store;
+use crate::templates::Templates;
+use crate::util::env_flag;
+use crate::{catcher, serve, serve_listener, Config};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use slog::Logger;
+use std::future::Future;
+use std::net::{SocketAddr, TcpListener};
+use std::pin::Pin;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Builds the HTTP service for running it in another program, whether on an address of its own or
+/// by handing it requests, as the binary and the tests do.
+pub struct Server {
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Config,
+    log: Logger,
+}
+
+/// The service once built, which answers requests in-process or binds to an address.
+#[derive(Clone)]
+pub struct App {
+    store: Arc<Store>,
+    templates: Arc<Templates>,
+    crypto: Arc<Crypto>,
+    config: Arc<Config>,
+    log: Logger,
+}
+
+/// Handles a route added by whoever embeds the service, see [`Server::route`]. It's given the
+/// session the request came with, if there's a valid one.
+pub type Handler = Arc<
+    dyn Fn(
+            Request<Body>,
+            Option<Session>,
+            Arc<Store>,
+        ) -> Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// Serves connections until the server fails, which over TLS it never does, as the errors of
+/// single connections are only logged.
+pub type ServerFuture = Pin<Box<dyn Future<Output = Result<(), hyper::Error>> + Send>>;
+
+pub struct Route {
+    pub method: Method,
+    pub path: String,
+    pub handler: Handler,
+}
+
+impl Server {
+    pub fn new(
+        store: Arc<Store>,
+        templates: Arc<Templates>,
+        crypto: Arc<Crypto>,
+        config: Config,
+        log: Logger,
+    ) -> Server {
+        Server {
+            store,
+            templates,
+            crypto,
+            config,
+            log,
+        }
+    }
+
+    /// Sets everything up from the environment like the binary does, migrating the database and
+    /// watching the files it's told to. Checks that the schema, the templates and the secrets all
+    /// work before anything is served, so that a broken deployment fails to start rather than
+    /// failing requests.
+    pub async fn from_env(log: Logger) -> Result<Server, Error> {
+        let store = Arc::new(Store::from_env()?);
+        store.supervise(&log);
+        store.migrate(&log).await?;
+        store.check_schema(&log).await?;
+        let templates = Arc::new(Templates::load()?);
+        templates.check()?;
+        if env_flag("DEV_MODE")? {
+            templates.clone().watch(log.clone())?;
+        }
+        let crypto = Arc::new(Crypto::from_env()?);
+        crypto.self_check()?;
+        let config = Config::from_env()?;
+        config.disposable.clone().watch(log.clone())?;
+        config.mode.clone().watch(log.clone())?;
+        Ok(Server::new(store, templates, crypto, config, log))
+    }
+
+    /// Serves everything under the path instead of at the root, like `/auth`. Redirects, links
+    /// and cookies all get the path in front, the same as for tenants under `/t/<id>`.
+    pub fn mount(mut self, path: &str) -> Server {
+        let path = path.trim_end_matches('/');
+        self.config.mount = Some(path.to_owned()).filter(|path| !path.is_empty());
+        self
+    }
+
+    /// Adds a route of the embedding program, taking precedence over the built-in one at the same
+    /// method and path, if there is one.
+    pub fn route<F, R>(mut self, method: Method, path: &str, handler: F) -> Server
+    where
+        F: Fn(Request<Body>, Option<Session>, Arc<Store>) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Response<Body>, Error>> + Send + 'static,
+    {
+        self.config.routes.push(Route {
+            method,
+            path: path.to_owned(),
+            handler: Arc::new(move |req, session, store| Box::pin(handler(req, session, store))),
+        });
+        self
+    }
+
+    /// Adds middleware after the ones already there, see [`Middleware`].
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Server {
+        self.config.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Adds a plugin after the ones already there, see [`Plugin`].
+    pub fn plugin(mut self, plugin: impl Plugin + 'static) -> Server {
+        let plugin: Arc<dyn Plugin> = Arc::new(plugin);
+        self.config.plugins.push(plugin.clone());
+        self.config
+            .claims
+            .hooks
+            .push(Box::new(PluginClaims(plugin)));
+        self
+    }
+
+    /// Adds to the claims of sessions, see [`crate::claims`].
+    pub fn claims_hook(mut self, hook: impl ClaimsHook + 'static) -> Server {
+        self.config.claims.hooks.push(Box::new(hook));
+        self
+    }
+
+    pub fn store(&self) -> &Arc<Store> {
+        &self.store
+    }
+
+    pub fn crypto(&self) -> &Arc<Crypto> {
+        &self.crypto
+    }
+
+    pub fn templates(&self) -> &Arc<Templates> {
+        &self.templates
+    }
+
+    pub fn build(self) -> App {
+        App {
+            store: self.store,
+            templates: self.templates,
+            crypto: self.crypto,
+            config: Arc::new(self.config),
+            log: self.log,
+        }
+    }
+}
+
+impl App {
+    /// Answers the request without going through a socket. Errors are turned into responses the
+    /// same way as for requests from the network.
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let req_id = Uuid::new_v4();
+        catcher(
+            req,
+            req_id,
+            self.store.clone(),
+            self.templates.clone(),
+            self.crypto.clone(),
+            self.config.clone(),
+            self.log.clone(),
+        )
+        .await
+    }
+
+    /// Whether connections are served over TLS, which is when `TLS_CERT` is set.
+    pub fn serves_tls(&self) -> bool {
+        self.config.tls.is_some()
+    }
+
+    /// Starts listening, returning the address it got, which is of use when the port was 0, and
+    /// the future serving the connections.
+    pub fn bind(self, address: SocketAddr) -> Result<(SocketAddr, ServerFuture), Error> {
+        serve(
+            address,
+            self.store,
+            self.templates,
+            self.crypto,
+            self.config,
+            self.log,
+        )
+    }
+
+    /// Serves on a socket that's already listening, like one passed by systemd. It has to be in
+    /// non-blocking mode.
+    pub fn listen(self, listener: TcpListener) -> Result<(SocketAddr, ServerFuture), Error> {
+        serve_listener(
+            listener,
+            self.store,
+            self.templates,
+            self.crypto,
+            self.config,
+            self.log,
+        )
+    }
+}
+
+/// Takes the mount path off the front of the request path, returning whether it was there at all.
+pub fn strip_mount(req: &mut Request<Body>, mount: &str) -> bool {
+    let Some(rest) = req.uri().path().strip_prefix(mount) else { return false; };
+    let rest = match rest {
+        "" => "/",
+        rest if rest.starts_with('/') => rest,
+        _ => return false,
+    };
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", rest, query),
+        None => rest.to_owned(),
+    };
+    *req.uri_mut() = path_and_query.parse().unwrap();
+    true
+}
+
+pub fn not_mounted() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .unwrap()
+}
@@ -1,66 +1,502 @@
-use crate::crypto::{Crypto, Signature};
+use crate::claims::Claims;
+use crate::crypto::Crypto;
 use crate::error::Error;
+use crate::tenant::DEFAULT_TENANT;
 use crate::user::User;
+use crate::util::{env_flag, env_var_opt, take};
+use async_trait::async_trait;
 use cookie::{Cookie, SameSite};
+use prost::encoding::{decode_varint, encode_varint};
+use std::backtrace::Backtrace;
 use std::collections::HashMap;
 use std::convert::TryInto;
-use std::fmt;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 pub struct Session {
     session: UnsignedSession,
-    signature: Signature,
+    /// The session as it goes into the cookie, see [`Crypto::seal`].
+    sealed: String,
 }
 
 struct UnsignedSession {
     id: Uuid,
     user: User,
+    /// Admin acting as the user, see [`Session::impersonate`].
+    impersonator: Option<User>,
+    /// Tenant the user belongs to, which is the only one the session works for.
+    tenant: String,
+    claims: Claims,
+    /// Changes whenever the session is rotated, while the session ID stays, so that the store can
+    /// tell the current cookie from ones it replaced. Cookies from before there were token IDs have
+    /// none.
+    token_id: Option<Uuid>,
+    /// When the cookie was issued, in seconds since the Unix epoch, which is when it's due to be
+    /// rotated from.
+    issued_at: u64,
 }
 
-const EXPIRATION_TIME: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+/// Attributes of the session cookie, which deployments serving apps from other subdomains or from
+/// other sites embedding them may need to change. The impersonator cookie gets the same ones, with
+/// `impersonator_` in front of the name.
+pub struct CookiePolicy {
+    /// From `SESSION_COOKIE_NAME`, `session` by default.
+    pub name: String,
+    /// Whether the name gets the `__Host-` prefix, from `SESSION_COOKIE_HOST_PREFIX`. Browsers
+    /// then only take the cookie when it's secure, host-only and for every path, so that it can't
+    /// be set from another subdomain.
+    pub host_prefix: bool,
+    /// From `SESSION_COOKIE_SAME_SITE`, one of `strict`, `lax` and `none`, the last of which
+    /// browsers only take on secure cookies.
+    pub same_site: SameSite,
+    /// Set unless `SESSION_COOKIE_SECURE` is `false`, which only makes sense when trying the
+    /// server out over plain HTTP.
+    pub secure: bool,
+    /// From `SESSION_COOKIE_DOMAIN`, to share the session with subdomains. Left out by default, so
+    /// that the cookie stays with the host that set it.
+    pub domain: Option<String>,
+    /// From `SESSION_COOKIE_PATH`, `/` by default.
+    pub path: String,
+}
+
+/// What the store knows about a session, for listing them without their signed cookies.
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub created_at: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Stores a new session. Restricted ones only get to change the password, see
+    /// [`SessionStore::is_restricted`].
+    async fn insert(&self, session: &Session, restricted: bool) -> Result<(), Error>;
+
+    /// Whether the session is unexpired and unrestricted, and the cookie is its current one.
+    async fn is_active(&self, session: &Session) -> Result<bool, Error>;
+
+    /// Whether the session is unexpired but was created with an expired password, which has to be
+    /// changed before the session can be used for anything else.
+    async fn is_restricted(&self, session: &Session) -> Result<bool, Error>;
+
+    /// Lifts the restriction once the password has been changed. Other sessions of the user stay
+    /// restricted, as they might have been created by whoever else knew the old password.
+    async fn unrestrict(&self, session: &Session) -> Result<(), Error>;
+
+    async fn delete(&self, session: &Session) -> Result<(), Error>;
+
+    /// Active sessions of the user, oldest first.
+    async fn list(&self, user: User) -> Result<Vec<SessionInfo>, Error>;
+
+    /// Replaces the token ID of the session with the one of the rotated session, returning whether
+    /// it was still current. The replaced one keeps working for [`ROTATION_GRACE_PERIOD`], and
+    /// after that only the rotated cookie does.
+    async fn rotate(&self, session: &Session, rotated: &Session) -> Result<bool, Error>;
+
+    /// Ends a session of the user by its ID alone, returning whether it existed. Sessions of other
+    /// users are left alone, so that IDs leaking can't be used to log people out.
+    async fn revoke(&self, user: User, id: Uuid) -> Result<bool, Error>;
+
+    /// Ends every session of the user, returning how many there were.
+    async fn revoke_all(&self, user: User) -> Result<u64, Error>;
+
+    /// Deletes sessions that expired before the given time, returning how many there were.
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error>;
+}
+
+pub const EXPIRATION_TIME: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+/// Impersonation is meant for looking into a problem, so it doesn't last long enough to be left
+/// behind by accident.
+pub const IMPERSONATION_EXPIRATION_TIME: Duration = Duration::from_secs(60 * 60);
+
+/// Makes browsers only take the cookie when it's secure, host-only and for every path.
+pub const HOST_COOKIE_PREFIX: &str = "__Host-";
+
+const SECURE_COOKIE_PREFIX: &str = "__Secure-";
+
+/// First byte of sessions in the binary format, which the text format of older cookies can't start
+/// with, as it starts with the hex digits of the session ID.
+const BINARY_FORMAT_VERSION: u8 = 1;
+
+const HAS_IMPERSONATOR: u8 = 1 << 0;
+const HAS_TENANT: u8 = 1 << 1;
+const HAS_TOKEN_ID: u8 = 1 << 2;
+const HAS_CLAIMS: u8 = 1 << 3;
+
+/// How long a cookie keeps working after its session was rotated, for the requests the browser
+/// sent with it before getting the new one.
+pub const ROTATION_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+impl CookiePolicy {
+    pub fn from_env() -> Result<CookiePolicy, Error> {
+        let defaults = CookiePolicy::default();
+        CookiePolicy {
+            name: env_var_opt("SESSION_COOKIE_NAME")?.unwrap_or(defaults.name),
+            host_prefix: env_flag("SESSION_COOKIE_HOST_PREFIX")?,
+            same_site: match env_var_opt("SESSION_COOKIE_SAME_SITE")? {
+                Some(same_site) => match same_site.to_lowercase().as_str() {
+                    "strict" => SameSite::Strict,
+                    "lax" => SameSite::Lax,
+                    "none" => SameSite::None,
+                    _ => {
+                        return Err(Error::InvalidCookiePolicy(
+                            format!("unknown SameSite value {}", same_site),
+                            Backtrace::capture(),
+                        ))
+                    }
+                },
+                None => defaults.same_site,
+            },
+            secure: !matches!(
+                env_var_opt("SESSION_COOKIE_SECURE")?.as_deref(),
+                Some("0" | "false")
+            ),
+            domain: env_var_opt("SESSION_COOKIE_DOMAIN")?,
+            path: env_var_opt("SESSION_COOKIE_PATH")?.unwrap_or(defaults.path),
+        }
+        .validate()
+    }
+
+    /// Rejects combinations browsers would refuse to store the cookie with, which would otherwise
+    /// only show up as nobody being able to log in.
+    pub fn validate(self) -> Result<CookiePolicy, Error> {
+        let invalid = |reason: &str| {
+            Err(Error::InvalidCookiePolicy(
+                reason.to_owned(),
+                Backtrace::capture(),
+            ))
+        };
+        let valid_name = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_name {
+            return invalid("the name must be made of ASCII letters, digits, _ and -");
+        }
+        if self.name.starts_with(HOST_COOKIE_PREFIX) || self.name.starts_with(SECURE_COOKIE_PREFIX)
+        {
+            return invalid("the name must not have a prefix, which is set on its own");
+        }
+        if !self.path.starts_with('/') || self.path.contains(';') {
+            return invalid("the path must start with /");
+        }
+        if self.same_site == SameSite::None && !self.secure {
+            return invalid("SameSite=None requires the cookie to be secure");
+        }
+        if self.host_prefix {
+            if !self.secure {
+                return invalid("the __Host- prefix requires the cookie to be secure");
+            }
+            if self.domain.is_some() {
+                return invalid("the __Host- prefix doesn't allow a domain");
+            }
+            if self.path != "/" {
+                return invalid("the __Host- prefix requires the path to be /");
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn session_name(&self) -> String {
+        self.prefixed(&self.name)
+    }
+
+    pub fn impersonator_name(&self) -> String {
+        self.prefixed(&format!("impersonator_{}", self.name))
+    }
+
+    fn prefixed(&self, name: &str) -> String {
+        match self.host_prefix {
+            true => format!("{}{}", HOST_COOKIE_PREFIX, name),
+            false => name.to_owned(),
+        }
+    }
+}
+
+/// What the session cookie was before it could be configured.
+impl Default for CookiePolicy {
+    fn default() -> CookiePolicy {
+        // Default SameSite is specified to be Lax, but some browsers (Firefox) haven't made the
+        // switch from None to Lax yet.
+        CookiePolicy {
+            name: "session".to_owned(),
+            host_prefix: false,
+            same_site: SameSite::Lax,
+            secure: true,
+            domain: None,
+            path: "/".to_owned(),
+        }
+    }
+}
 
 impl Session {
+    /// Sessions of other tenants are left out as if there were none, which only happens when
+    /// tenants share a host and the browser sent a cookie of one to another anyway.
     pub fn from_cookies(
         cookies: &HashMap<&str, Cookie>,
         crypto: &Crypto,
+        tenant: &str,
+        policy: &CookiePolicy,
     ) -> Result<Option<Session>, Error> {
-        let Some(cookie) = cookies.get("session") else { return Ok(None); };
-        let (session_str, signature) = cookie.value().rsplit_once('.').unwrap();
-        let session = session_str.parse()?;
-        Ok(Some(Session {
+        let Some(cookie) = cookies.get(policy.session_name().as_str()) else { return Ok(None); };
+        let session = Session::from_cookie_value(cookie.value(), crypto)?;
+        Ok(Some(session).filter(|session| session.tenant() == tenant))
+    }
+
+    /// Opens and parses the value of a session cookie, for when it arrives some other way than in
+    /// a cookie header. Cookies from before sessions were sealed are only signed, with the
+    /// signature after the last dot, which sealed ones never have. Sealed ones from before the
+    /// binary format have the text one inside, see [`UnsignedSession::from_str`].
+    pub fn from_cookie_value(value: &str, crypto: &Crypto) -> Result<Session, Error> {
+        let session = match value.rsplit_once('.') {
+            Some((session_str, signature)) => {
+                crypto.verify(session_str.as_bytes(), &hex::decode(signature)?)?;
+                session_str.parse()?
+            }
+            None => {
+                let payload = crypto.unseal(value)?;
+                match payload.split_first() {
+                    Some((&BINARY_FORMAT_VERSION, rest)) => UnsignedSession::decode(rest)?,
+                    _ => String::from_utf8(payload)
+                        .map_err(|_| Error::MalformedSession(Backtrace::capture()))?
+                        .parse()?,
+                }
+            }
+        };
+        Ok(Session {
             session,
-            signature: crypto.verify(session_str.as_bytes(), &hex::decode(signature)?)?,
-        }))
+            sealed: value.to_owned(),
+        })
+    }
+
+    pub fn create(user: User, tenant: &str, claims: Claims, crypto: &Crypto) -> Session {
+        Session::seal(UnsignedSession::create(user, None, tenant, claims), crypto)
+    }
+
+    /// Creates a session of the user for an admin to see what they see. It's told apart in the
+    /// sealed payload, so that it can't be passed off as the user's own.
+    pub fn impersonate(
+        user: User,
+        impersonator: User,
+        tenant: &str,
+        claims: Claims,
+        crypto: &Crypto,
+    ) -> Session {
+        Session::seal(
+            UnsignedSession::create(user, Some(impersonator), tenant, claims),
+            crypto,
+        )
     }
 
-    pub fn create(user: User, crypto: &Crypto) -> Session {
-        let session = UnsignedSession::create(user);
-        let signature = crypto.sign(session.to_string().as_bytes());
-        Session { session, signature }
+    /// The same session with a new token ID, for replacing the cookie once it's been around for a
+    /// while. A stolen copy of the old cookie stops working as soon as the store knows of the new
+    /// one, see [`SessionStore::rotate`].
+    pub fn rotate(&self, crypto: &Crypto) -> Session {
+        let session = UnsignedSession {
+            token_id: Some(Uuid::new_v4()),
+            issued_at: now_unix(),
+            claims: self.session.claims.clone(),
+            tenant: self.session.tenant.clone(),
+            ..self.session
+        };
+        Session::seal(session, crypto)
+    }
+
+    fn seal(session: UnsignedSession, crypto: &Crypto) -> Session {
+        let sealed = crypto.seal(&session.encode());
+        Session { session, sealed }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.session.id
     }
 
     pub fn user(&self) -> &User {
         &self.session.user
     }
 
-    pub fn cookie_login(&self) -> Cookie {
-        cookie_raw(Some(self), EXPIRATION_TIME)
+    pub fn impersonator(&self) -> Option<User> {
+        self.session.impersonator
+    }
+
+    pub fn tenant(&self) -> &str {
+        &self.session.tenant
+    }
+
+    /// Claims the user had when the session was created, see [`crate::claims`].
+    pub fn claims(&self) -> &Claims {
+        &self.session.claims
+    }
+
+    pub fn token_id(&self) -> Option<Uuid> {
+        self.session.token_id
+    }
+
+    pub fn issued_at(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(self.session.issued_at)
+    }
+
+    /// How long the session lasts, which the store and the cookie both go by.
+    pub fn lifetime(&self) -> Duration {
+        match self.session.impersonator {
+            Some(_) => IMPERSONATION_EXPIRATION_TIME,
+            None => EXPIRATION_TIME,
+        }
+    }
+
+    pub fn cookie_login(&self, policy: &CookiePolicy) -> Cookie<'static> {
+        cookie_raw(policy.session_name(), Some(self), self.lifetime(), policy)
+    }
+
+    pub fn cookie_logout(policy: &CookiePolicy) -> Cookie<'static> {
+        cookie_raw(policy.session_name(), None, Duration::ZERO, policy)
+    }
+
+    /// The admin's own session, kept aside while they impersonate someone so that stopping brings
+    /// them back to it.
+    pub fn from_impersonator_cookies(
+        cookies: &HashMap<&str, Cookie>,
+        crypto: &Crypto,
+        tenant: &str,
+        policy: &CookiePolicy,
+    ) -> Result<Option<Session>, Error> {
+        let name = policy.impersonator_name();
+        let Some(cookie) = cookies.get(name.as_str()) else { return Ok(None); };
+        let session = Session::from_cookie_value(cookie.value(), crypto)?;
+        Ok(Some(session).filter(|session| session.tenant() == tenant))
+    }
+
+    pub fn cookie_impersonator(&self, policy: &CookiePolicy) -> Cookie<'static> {
+        cookie_raw(
+            policy.impersonator_name(),
+            Some(self),
+            IMPERSONATION_EXPIRATION_TIME,
+            policy,
+        )
     }
 
-    pub fn cookie_logout() -> Cookie<'static> {
-        cookie_raw(None, Duration::ZERO)
+    pub fn cookie_impersonator_clear(policy: &CookiePolicy) -> Cookie<'static> {
+        cookie_raw(policy.impersonator_name(), None, Duration::ZERO, policy)
     }
 }
 
 impl UnsignedSession {
-    pub fn create(user: User) -> UnsignedSession {
+    fn create(
+        user: User,
+        impersonator: Option<User>,
+        tenant: &str,
+        claims: Claims,
+    ) -> UnsignedSession {
         UnsignedSession {
             id: Uuid::new_v4(),
             user,
+            impersonator,
+            tenant: tenant.to_owned(),
+            claims,
+            token_id: Some(Uuid::new_v4()),
+            issued_at: now_unix(),
         }
     }
+
+    /// The binary format, which is what new cookies are sealed with. It's a version byte and the
+    /// session ID, followed by the user ID, a byte of flags saying which of the remaining fields
+    /// are there and those fields, with varints for numbers and lengths. The claims are last, as
+    /// JSON running to the end, since they're what takes most of the room in big sessions.
+    fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.impersonator.is_some() {
+            flags |= HAS_IMPERSONATOR;
+        }
+        if self.tenant != DEFAULT_TENANT {
+            flags |= HAS_TENANT;
+        }
+        if self.token_id.is_some() {
+            flags |= HAS_TOKEN_ID;
+        }
+        if !self.claims.is_empty() {
+            flags |= HAS_CLAIMS;
+        }
+        let mut bytes = vec![BINARY_FORMAT_VERSION];
+        bytes.extend_from_slice(self.id.as_bytes());
+        encode_varint(self.user.id as u32 as u64, &mut bytes);
+        bytes.push(flags);
+        if let Some(impersonator) = self.impersonator {
+            encode_varint(impersonator.id as u32 as u64, &mut bytes);
+        }
+        if self.tenant != DEFAULT_TENANT {
+            encode_varint(self.tenant.len() as u64, &mut bytes);
+            bytes.extend_from_slice(self.tenant.as_bytes());
+        }
+        if let Some(token_id) = self.token_id {
+            bytes.extend_from_slice(token_id.as_bytes());
+        }
+        encode_varint(self.issued_at, &mut bytes);
+        if !self.claims.is_empty() {
+            bytes.extend(serde_json::to_vec(&self.claims).unwrap());
+        }
+        bytes
+    }
+
+    /// Parses the binary format after the version byte, see [`UnsignedSession::encode`].
+    fn decode(mut bytes: &[u8]) -> Result<UnsignedSession, Error> {
+        let malformed = || Error::MalformedSession(Backtrace::capture());
+        let id = Uuid::from_slice(take(&mut bytes, 16).ok_or_else(malformed)?)?;
+        let user = User {
+            id: decode_id(&mut bytes).ok_or_else(malformed)?,
+        };
+        let (&flags, rest) = bytes.split_first().ok_or_else(malformed)?;
+        bytes = rest;
+        let impersonator = match flags & HAS_IMPERSONATOR != 0 {
+            true => Some(User {
+                id: decode_id(&mut bytes).ok_or_else(malformed)?,
+            }),
+            false => None,
+        };
+        let tenant = match flags & HAS_TENANT != 0 {
+            true => {
+                let len = decode_varint(&mut bytes).map_err(|_| malformed())?;
+                let tenant = take(&mut bytes, len as usize).ok_or_else(malformed)?;
+                String::from_utf8(tenant.to_vec()).map_err(|_| malformed())?
+            }
+            false => DEFAULT_TENANT.to_owned(),
+        };
+        let token_id = match flags & HAS_TOKEN_ID != 0 {
+            true => Some(Uuid::from_slice(
+                take(&mut bytes, 16).ok_or_else(malformed)?,
+            )?),
+            false => None,
+        };
+        let issued_at = decode_varint(&mut bytes).map_err(|_| malformed())?;
+        let claims = match flags & HAS_CLAIMS != 0 {
+            true => serde_json::from_slice(bytes)?,
+            false if bytes.is_empty() => Claims::new(),
+            false => return Err(malformed()),
+        };
+        Ok(UnsignedSession {
+            id,
+            user,
+            impersonator,
+            tenant,
+            claims,
+            token_id,
+            issued_at,
+        })
+    }
+}
+
+fn decode_id(bytes: &mut &[u8]) -> Option<i32> {
+    let id: u32 = decode_varint(bytes).ok()?.try_into().ok()?;
+    Some(id as i32)
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
 }
 
 impl slog::KV for Session {
@@ -69,47 +505,82 @@ impl slog::KV for Session {
         _record: &slog::Record,
         serializer: &mut dyn slog::Serializer,
     ) -> slog::Result {
+        if let Some(impersonator) = self.session.impersonator {
+            serializer.emit_i32("impersonator", impersonator.id)?;
+        }
         serializer.emit_str("session", &self.session.id.to_string())
     }
 }
 
+/// The text format cookies were sealed with before the binary one, and signed with before that.
+/// Sessions of the default tenant keep the format from before there were tenants, so that they
+/// stay valid. Others have the tenant as a fourth field, after a possibly empty impersonator, and
+/// sessions with claims have them as a fifth, in base64url-encoded JSON. Sessions with token IDs
+/// have every field, with the possibly empty claims followed by the token ID and the issue time.
 impl FromStr for UnsignedSession {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<UnsignedSession, Error> {
-        let (id, user_id) = s.split_once('.').unwrap();
+        let mut fields = s.split('.');
+        let (Some(id), Some(user_id), impersonator_id, tenant, claims, token) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            return Err(Error::MalformedSession(Backtrace::capture()));
+        };
+        let (token_id, issued_at) = match (token, fields.next(), fields.next()) {
+            (Some(token_id), Some(issued_at), None) => {
+                (Some(token_id.parse()?), issued_at.parse()?)
+            }
+            (None, None, None) => (None, 0),
+            _ => return Err(Error::MalformedSession(Backtrace::capture())),
+        };
         Ok(UnsignedSession {
             id: id.parse()?,
             user: User {
                 id: user_id.parse()?,
             },
+            impersonator: match impersonator_id {
+                Some(id) if !id.is_empty() => Some(User { id: id.parse()? }),
+                _ => None,
+            },
+            tenant: tenant.unwrap_or(DEFAULT_TENANT).to_owned(),
+            claims: match claims {
+                Some(claims) if !claims.is_empty() => {
+                    let json = base64::decode_config(claims, base64::URL_SAFE_NO_PAD)
+                        .map_err(|_| Error::MalformedSession(Backtrace::capture()))?;
+                    serde_json::from_slice(&json)?
+                }
+                _ => Claims::new(),
+            },
+            token_id,
+            issued_at,
         })
     }
 }
 
-impl fmt::Display for UnsignedSession {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}.{}", self.id, self.user.id)
-    }
-}
-
-fn cookie_raw(session: Option<&Session>, max_age: Duration) -> Cookie {
-    // Default SameSite is specified to be Lax, but some browsers (Firefox) haven't made the switch
-    // from None to Lax yet.
-    Cookie::build(
-        "session",
-        session.map_or(String::new(), |session| {
-            format!(
-                "{}.{}",
-                session.session,
-                hex::encode(&session.signature.hash)
-            )
-        }),
+fn cookie_raw(
+    name: String,
+    session: Option<&Session>,
+    max_age: Duration,
+    policy: &CookiePolicy,
+) -> Cookie<'static> {
+    let mut cookie = Cookie::build(
+        name,
+        session.map_or(String::new(), |session| session.sealed.clone()),
     )
     .max_age(max_age.try_into().unwrap())
-    .path("/")
-    .secure(true)
+    .path(policy.path.clone())
+    .secure(policy.secure)
     .http_only(true)
-    .same_site(SameSite::Lax)
-    .finish()
+    .same_site(policy.same_site)
+    .finish();
+    if let Some(domain) = &policy.domain {
+        cookie.set_domain(domain.clone());
+    }
+    cookie
 }
@@ -0,0 +1,124 @@
+use crate::error::Error;
+use crate::util::{env_duration_ms, env_var, env_var_opt};
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::{Body, Request};
+use hyper_rustls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+use std::backtrace::Backtrace;
+use std::sync::Mutex;
+use std::time::Duration;
+
+mod twilio;
+mod vonage;
+
+pub use twilio::TwilioProvider;
+pub use vonage::VonageProvider;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Sms {
+    /// Phone number in the E.164 format, like `+48123456789`.
+    pub to: String,
+    pub text: String,
+}
+
+/// Something that delivers text messages, like the API of an SMS gateway.
+#[async_trait]
+pub trait SmsProvider: Send + Sync {
+    async fn send(&self, from: &str, sms: &Sms) -> Result<(), Error>;
+}
+
+pub struct SmsSender {
+    provider: Box<dyn SmsProvider>,
+    from: String,
+    timeout: Duration,
+}
+
+/// Logs messages instead of sending them, and keeps them for tests to look at.
+pub struct DryRunProvider {
+    log: Logger,
+    sent: Mutex<Vec<Sms>>,
+}
+
+impl SmsSender {
+    /// Picks the provider from `SMS_PROVIDER`, which is one of `twilio`, `vonage` and `dry-run`.
+    /// `SMS_FROM` is the number or alphanumeric sender ID the messages come from.
+    pub fn from_env(log: &Logger) -> Result<Option<SmsSender>, Error> {
+        let Some(name) = env_var_opt("SMS_PROVIDER")? else {
+            return Ok(None);
+        };
+        let provider: Box<dyn SmsProvider> = match name.as_str() {
+            "twilio" => Box::new(TwilioProvider::from_env()?),
+            "vonage" => Box::new(VonageProvider::from_env()?),
+            "dry-run" => Box::new(DryRunProvider::new(log.clone())),
+            _ => return Err(Error::UnknownSmsProvider(name, Backtrace::capture())),
+        };
+        Ok(Some(SmsSender {
+            provider,
+            from: env_var("SMS_FROM")?,
+            timeout: env_duration_ms("SMS_TIMEOUT_MS", DEFAULT_TIMEOUT)?,
+        }))
+    }
+
+    pub async fn send(&self, sms: &Sms) -> Result<(), Error> {
+        tokio::time::timeout(self.timeout, self.provider.send(&self.from, sms))
+            .await
+            .unwrap_or_else(|_| Err(Error::SmsTimeout(Backtrace::capture())))
+    }
+}
+
+impl DryRunProvider {
+    pub fn new(log: Logger) -> DryRunProvider {
+        DryRunProvider {
+            log,
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<Sms> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl SmsProvider for DryRunProvider {
+    async fn send(&self, from: &str, sms: &Sms) -> Result<(), Error> {
+        info!(self.log, "SMS not sent in dry-run mode"; "from" => from, "to" => &sms.to, "text" => &sms.text);
+        self.sent.lock().unwrap().push(sms.clone());
+        Ok(())
+    }
+}
+
+/// Whether the number looks like E.164, which is what the gateways expect. Whether it's actually
+/// reachable only comes out once a code sent to it comes back.
+pub fn is_valid_number(number: &str) -> bool {
+    let Some(digits) = number.strip_prefix('+') else {
+        return false;
+    };
+    (8..=15).contains(&digits.len())
+        && !digits.starts_with('0')
+        && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Sends a request to the API of an SMS gateway, treating anything but a success as the message
+/// being rejected. The response body is returned for gateways that report failures inside it.
+async fn send_api_request(
+    client: &hyper::Client<HttpsConnector<HttpConnector>>,
+    request: Request<Body>,
+) -> Result<Vec<u8>, Error> {
+    let response = client.request(request).await?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    if status.is_success() {
+        return Ok(body.to_vec());
+    }
+    Err(Error::SmsRejected {
+        status,
+        body: String::from_utf8_lossy(&body).into_owned(),
+        backtrace: Backtrace::capture(),
+    })
+}
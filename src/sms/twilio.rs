@@ -0,0 +1,48 @@
+use crate::error::Error;
+use crate::sms::{send_api_request, Sms, SmsProvider};
+use crate::util::{env_var, http_client};
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+
+pub struct TwilioProvider {
+    client: Client<HttpsConnector<HttpConnector>>,
+    account_sid: String,
+    auth_token: String,
+}
+
+impl TwilioProvider {
+    pub fn from_env() -> Result<TwilioProvider, Error> {
+        Ok(TwilioProvider {
+            client: http_client(),
+            account_sid: env_var("TWILIO_ACCOUNT_SID")?,
+            auth_token: env_var("TWILIO_AUTH_TOKEN")?,
+        })
+    }
+}
+
+#[async_trait]
+impl SmsProvider for TwilioProvider {
+    async fn send(&self, from: &str, sms: &Sms) -> Result<(), Error> {
+        let body = serde_urlencoded::to_string([
+            ("To", sms.to.as_str()),
+            ("From", from),
+            ("Body", sms.text.as_str()),
+        ])?;
+        let credentials = base64::encode(format!("{}:{}", self.account_sid, self.auth_token));
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!(
+                "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
+                self.account_sid
+            ))
+            .header(AUTHORIZATION, format!("Basic {}", credentials))
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .unwrap();
+        send_api_request(&self.client, request).await?;
+        Ok(())
+    }
+}
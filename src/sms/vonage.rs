@@ -0,0 +1,74 @@
+use crate::error::Error;
+use crate::sms::{send_api_request, Sms, SmsProvider};
+use crate::util::{env_var, http_client};
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use serde::Deserialize;
+use std::backtrace::Backtrace;
+
+const ENDPOINT: &str = "https://rest.nexmo.com/sms/json";
+
+pub struct VonageProvider {
+    client: Client<HttpsConnector<HttpConnector>>,
+    api_key: String,
+    api_secret: String,
+}
+
+#[derive(Deserialize)]
+struct SendResponse {
+    messages: Vec<MessageStatus>,
+}
+
+#[derive(Deserialize)]
+struct MessageStatus {
+    status: String,
+}
+
+impl VonageProvider {
+    pub fn from_env() -> Result<VonageProvider, Error> {
+        Ok(VonageProvider {
+            client: http_client(),
+            api_key: env_var("VONAGE_API_KEY")?,
+            api_secret: env_var("VONAGE_API_SECRET")?,
+        })
+    }
+}
+
+#[async_trait]
+impl SmsProvider for VonageProvider {
+    async fn send(&self, from: &str, sms: &Sms) -> Result<(), Error> {
+        // Vonage wants the number without the plus sign.
+        let to = sms.to.trim_start_matches('+');
+        let body = serde_urlencoded::to_string([
+            ("api_key", self.api_key.as_str()),
+            ("api_secret", self.api_secret.as_str()),
+            ("from", from),
+            ("to", to),
+            ("text", sms.text.as_str()),
+        ])?;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(ENDPOINT)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .unwrap();
+        let body = send_api_request(&self.client, request).await?;
+        // Failures come with a 200 too, only the status of each message part tells them apart.
+        let response: SendResponse = serde_json::from_slice(&body)?;
+        if response
+            .messages
+            .iter()
+            .all(|message| message.status == "0")
+        {
+            return Ok(());
+        }
+        Err(Error::SmsRejected {
+            status: StatusCode::OK,
+            body: String::from_utf8_lossy(&body).into_owned(),
+            backtrace: Backtrace::capture(),
+        })
+    }
+}
@@ -0,0 +1,2063 @@
+use crate::audit::{self, AuditEvent, AuditStore};
+use crate::claims::{ClaimStore, Claims};
+use crate::error::Error;
+use crate::export::{Export, ExportStore};
+use crate::features::FeatureStore;
+use crate::jobs::{Job, JobStore};
+use crate::notifications::{Category, NotificationStore};
+use crate::oauth::{
+    hash_token, AccessToken, AuthorizationCode, Client, ClientStore, Consent, ConsentStore,
+    TokenInfo, TokenStore,
+};
+use crate::otp::{hash_code, OtpStore, Phone, Purpose, Totp, MAX_CHECK_ATTEMPTS};
+use crate::quota::QuotaStore;
+use crate::session::{Session, SessionInfo, SessionStore, ROTATION_GRACE_PERIOD};
+use crate::user::{
+    hash_password, upgrade_password_hash, verify_missing_password, verify_password, AccountStatus,
+    Identity, LoginName, Profile, User, UserRecord, UserStore, UsernamePolicy, NO_PASSWORD,
+};
+use async_trait::async_trait;
+use rusqlite::{ffi, params, Connection, OptionalExtension};
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// A single SQLite connection shared by the whole server. SQLite only allows one writer at a time
+/// anyway, and all queries run on the blocking thread pool so they don't stall the reactor.
+pub struct Sqlite {
+    connection: Arc<Mutex<Connection>>,
+}
+
+pub struct SqliteUserStore {
+    sqlite: Arc<Sqlite>,
+    username_policy: UsernamePolicy,
+}
+
+pub struct SqliteSessionStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteClientStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteTokenStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteConsentStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteJobStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteOtpStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteAuditStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteExportStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteFeatureStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteClaimStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteQuotaStore {
+    sqlite: Arc<Sqlite>,
+}
+
+pub struct SqliteNotificationStore {
+    sqlite: Arc<Sqlite>,
+}
+
+impl Sqlite {
+    pub fn open(path: &str) -> Result<Sqlite, Error> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        Ok(Sqlite {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    pub async fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || f(&mut connection.lock().unwrap()))
+            .await
+            .unwrap()
+    }
+}
+
+impl SqliteUserStore {
+    pub fn new(sqlite: Arc<Sqlite>, username_policy: UsernamePolicy) -> SqliteUserStore {
+        SqliteUserStore {
+            sqlite,
+            username_policy,
+        }
+    }
+}
+
+impl SqliteSessionStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteSessionStore {
+        SqliteSessionStore { sqlite }
+    }
+
+    /// Cookies match the current token ID, or the one it replaced for a moment after a rotation.
+    async fn has_session(&self, session: &Session, restricted: bool) -> Result<bool, Error> {
+        let id = session.id().to_string();
+        let now = unix_time(SystemTime::now());
+        let token_id = session.token_id().map(|token_id| token_id.to_string());
+        let rotated_after = unix_time(SystemTime::now() - ROTATION_GRACE_PERIOD);
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM sessions \
+                     WHERE id = $1 AND expires_at > $2 AND restricted = $3 \
+                     AND (token_id IS $4 OR (previous_token_id IS $4 AND rotated_at > $5)))",
+                    params![id, now, restricted, token_id, rotated_after],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+    }
+}
+
+impl SqliteClientStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteClientStore {
+        SqliteClientStore { sqlite }
+    }
+}
+
+impl SqliteTokenStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteTokenStore {
+        SqliteTokenStore { sqlite }
+    }
+}
+
+impl SqliteConsentStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteConsentStore {
+        SqliteConsentStore { sqlite }
+    }
+}
+
+impl SqliteJobStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteJobStore {
+        SqliteJobStore { sqlite }
+    }
+}
+
+impl SqliteOtpStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteOtpStore {
+        SqliteOtpStore { sqlite }
+    }
+}
+
+impl SqliteAuditStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteAuditStore {
+        SqliteAuditStore { sqlite }
+    }
+}
+
+impl SqliteExportStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteExportStore {
+        SqliteExportStore { sqlite }
+    }
+}
+
+impl SqliteFeatureStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteFeatureStore {
+        SqliteFeatureStore { sqlite }
+    }
+}
+
+impl SqliteClaimStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteClaimStore {
+        SqliteClaimStore { sqlite }
+    }
+}
+
+impl SqliteQuotaStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteQuotaStore {
+        SqliteQuotaStore { sqlite }
+    }
+}
+
+impl SqliteNotificationStore {
+    pub fn new(sqlite: Arc<Sqlite>) -> SqliteNotificationStore {
+        SqliteNotificationStore { sqlite }
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteUserStore {
+    async fn get_and_verify(
+        &self,
+        tenant: &str,
+        login: &str,
+        password: &str,
+    ) -> Result<User, Error> {
+        let (query, value) = match LoginName::parse(login) {
+            LoginName::Username(username) => (
+                "SELECT id, password_phc FROM users WHERE tenant_id = $1 AND username = $2",
+                username,
+            ),
+            LoginName::Email(email) => (
+                "SELECT id, password_phc FROM users WHERE tenant_id = $1 AND email = $2",
+                email,
+            ),
+        };
+        let tenant = tenant.to_owned();
+        let row: Option<(i32, String)> = self
+            .sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(query, params![tenant, value], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })
+                    .optional()?)
+            })
+            .await?;
+        let Some((id, password_phc)) = row else {
+            verify_missing_password(password).await;
+            return Err(Error::UserNotFound(Backtrace::capture()));
+        };
+        let user = User { id };
+        verify_password(password, &password_phc).await?;
+        upgrade_password_hash(self, user, password, &password_phc).await?;
+        Ok(user)
+    }
+
+    async fn insert(
+        &self,
+        tenant: &str,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> Result<User, Error> {
+        let username = self.username_policy.normalize(username)?;
+        let password_phc = hash_password(password).await;
+        let tenant = tenant.to_owned();
+        let email = email.map(str::to_owned);
+        let now = unix_time(SystemTime::now());
+        let id = self
+            .sqlite
+            .call(move |connection| {
+                connection
+                    .query_row(
+                        "INSERT INTO users \
+                         (tenant_id, username, password_phc, email, password_changed_at) \
+                         VALUES ($1, $2, $3, $4, $5) RETURNING id",
+                        params![tenant, username, password_phc, email, now],
+                        |row| row.get(0),
+                    )
+                    .map_err(user_conflict)
+            })
+            .await?;
+        Ok(User { id })
+    }
+
+    async fn insert_service_account(&self, tenant: &str, username: &str) -> Result<User, Error> {
+        let username = self.username_policy.normalize(username)?;
+        let tenant = tenant.to_owned();
+        let now = unix_time(SystemTime::now());
+        let id = self
+            .sqlite
+            .call(move |connection| {
+                connection
+                    .query_row(
+                        "INSERT INTO users \
+                         (tenant_id, username, password_phc, password_changed_at, service) \
+                         VALUES ($1, $2, $3, $4, 1) RETURNING id",
+                        params![tenant, username, NO_PASSWORD, now],
+                        |row| row.get(0),
+                    )
+                    .map_err(user_conflict)
+            })
+            .await?;
+        Ok(User { id })
+    }
+
+    async fn service_accounts(&self, tenant: &str) -> Result<Vec<(User, String)>, Error> {
+        let tenant = tenant.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT id, username FROM users WHERE tenant_id = $1 AND service ORDER BY id",
+                )?;
+                let rows = statement.query_map(params![tenant], |row| {
+                    Ok((User { id: row.get(0)? }, row.get(1)?))
+                })?;
+                Ok(rows.collect::<Result<_, _>>()?)
+            })
+            .await
+    }
+
+    async fn find_by_email(&self, tenant: &str, email: &str) -> Result<Option<User>, Error> {
+        let tenant = tenant.to_owned();
+        let email = email.to_owned();
+        let id = self
+            .sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT id FROM users WHERE tenant_id = $1 AND email = $2",
+                        params![tenant, email],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        Ok(id.map(|id| User { id }))
+    }
+
+    async fn find_by_username(&self, tenant: &str, username: &str) -> Result<Option<User>, Error> {
+        let tenant = tenant.to_owned();
+        let username = username.to_owned();
+        let id = self
+            .sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT id FROM users WHERE tenant_id = $1 AND username = $2",
+                        params![tenant, username],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        Ok(id.map(|id| User { id }))
+    }
+
+    async fn set_email(&self, user: User, email: Option<&str>) -> Result<(), Error> {
+        let email = email.map(str::to_owned);
+        let updated = self
+            .sqlite
+            .call(move |connection| {
+                connection
+                    .execute(
+                        "UPDATE users SET email = $1 WHERE id = $2",
+                        params![email, user.id],
+                    )
+                    .map_err(user_conflict)
+            })
+            .await?;
+        if updated == 0 {
+            return Err(Error::UserNotFound(Backtrace::capture()));
+        }
+        Ok(())
+    }
+
+    async fn set_password(&self, user: User, password: &str) -> Result<(), Error> {
+        let password_phc = hash_password(password).await;
+        let now = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE users SET password_phc = $1, password_changed_at = $2 WHERE id = $3",
+                    params![password_phc, now, user.id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn set_password_phc(&self, user: User, password_phc: &str) -> Result<(), Error> {
+        let password_phc = password_phc.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE users SET password_phc = $1 WHERE id = $2",
+                    params![password_phc, user.id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn remove_password(&self, user: User) -> Result<(), Error> {
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE users SET password_phc = $1 WHERE id = $2",
+                    params![NO_PASSWORD, user.id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn link_identity(&self, user: User, provider: &str, subject: &str) -> Result<(), Error> {
+        let provider = provider.to_owned();
+        let subject = subject.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                connection
+                    .execute(
+                        "INSERT INTO identities (user_id, provider, subject) VALUES ($1, $2, $3)",
+                        params![user.id, provider, subject],
+                    )
+                    .map_err(identity_taken)?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn find_by_identity(&self, provider: &str, subject: &str) -> Result<Option<User>, Error> {
+        let provider = provider.to_owned();
+        let subject = subject.to_owned();
+        let id = self
+            .sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT user_id FROM identities WHERE provider = $1 AND subject = $2",
+                        params![provider, subject],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        Ok(id.map(|id| User { id }))
+    }
+
+    async fn identities(&self, user: User) -> Result<Vec<Identity>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT id, provider, subject FROM identities WHERE user_id = $1 ORDER BY id",
+                )?;
+                let rows = statement.query_map(params![user.id], |row| {
+                    Ok(Identity {
+                        id: row.get(0)?,
+                        provider: row.get(1)?,
+                        subject: row.get(2)?,
+                    })
+                })?;
+                Ok(rows.collect::<Result<_, _>>()?)
+            })
+            .await
+    }
+
+    async fn unlink_identity(&self, user: User, id: i32) -> Result<bool, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let deleted = connection.execute(
+                    "DELETE FROM identities WHERE id = $1 AND user_id = $2",
+                    params![id, user.id],
+                )?;
+                Ok(deleted > 0)
+            })
+            .await
+    }
+
+    async fn profile(&self, user: User) -> Result<Profile, Error> {
+        let (tenant, username, email, password_phc, password_changed_at, service): (
+            String,
+            String,
+            Option<String>,
+            String,
+            i64,
+            bool,
+        ) = self
+            .sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT tenant_id, username, email, password_phc, password_changed_at, \
+                         service FROM users WHERE id = $1",
+                        params![user.id],
+                        |row| {
+                            Ok((
+                                row.get(0)?,
+                                row.get(1)?,
+                                row.get(2)?,
+                                row.get(3)?,
+                                row.get(4)?,
+                                row.get(5)?,
+                            ))
+                        },
+                    )
+                    .optional()?)
+            })
+            .await?
+            .ok_or_else(|| Error::UserNotFound(Backtrace::capture()))?;
+        Ok(Profile {
+            id: user.id,
+            tenant,
+            username,
+            email,
+            has_password: password_phc != NO_PASSWORD,
+            password_changed_at: from_unix_time(password_changed_at),
+            service,
+        })
+    }
+
+    async fn rename(&self, user: User, username: &str) -> Result<(), Error> {
+        let username = self.username_policy.normalize(username)?;
+        let updated = self
+            .sqlite
+            .call(move |connection| {
+                connection
+                    .execute(
+                        "UPDATE users SET username = $1 WHERE id = $2",
+                        params![username, user.id],
+                    )
+                    .map_err(user_conflict)
+            })
+            .await?;
+        if updated == 0 {
+            return Err(Error::UserNotFound(Backtrace::capture()));
+        }
+        Ok(())
+    }
+
+    async fn usernames(&self) -> Result<Vec<(User, String)>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let mut statement =
+                    connection.prepare("SELECT id, username FROM users ORDER BY id")?;
+                let rows =
+                    statement.query_map([], |row| Ok((User { id: row.get(0)? }, row.get(1)?)))?;
+                Ok(rows.collect::<Result<_, _>>()?)
+            })
+            .await
+    }
+
+    async fn accept_terms(&self, user: User, version: &str) -> Result<(), Error> {
+        let version = version.to_owned();
+        let accepted_at = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO terms_acceptances (user_id, version, accepted_at) \
+                     VALUES ($1, $2, $3)",
+                    params![user.id, version, accepted_at],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn accepted_terms(&self, user: User) -> Result<Option<String>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT version FROM terms_acceptances WHERE user_id = $1 \
+                         ORDER BY accepted_at DESC, id DESC LIMIT 1",
+                        params![user.id],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn status(&self, user: User) -> Result<AccountStatus, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let status: Option<String> = connection
+                    .query_row(
+                        "SELECT status FROM users WHERE id = $1",
+                        params![user.id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let Some(status) = status else { return Err(Error::UserNotFound(Backtrace::capture())); };
+                AccountStatus::parse(&status)
+                    .ok_or_else(|| Error::UnknownAccountStatus(status, Backtrace::capture()))
+            })
+            .await
+    }
+
+    async fn set_status(&self, user: User, status: AccountStatus) -> Result<(), Error> {
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE users SET status = $1 WHERE id = $2",
+                    params![status.as_str(), user.id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn with_status(
+        &self,
+        tenant: &str,
+        status: AccountStatus,
+    ) -> Result<Vec<(User, String)>, Error> {
+        let tenant = tenant.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT id, username FROM users WHERE tenant_id = $1 AND status = $2 \
+                     ORDER BY id",
+                )?;
+                let rows = statement.query_map(params![tenant, status.as_str()], |row| {
+                    Ok((User { id: row.get(0)? }, row.get(1)?))
+                })?;
+                Ok(rows.collect::<Result<_, _>>()?)
+            })
+            .await
+    }
+
+    async fn records(&self) -> Result<Vec<UserRecord>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT tenant_id, username, email, password_phc, password_changed_at, status \
+                     FROM users ORDER BY id",
+                )?;
+                let rows = statement.query_map([], |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get::<_, String>(5)?,
+                    ))
+                })?;
+                rows.map(|row| {
+                    let (tenant, username, email, password_phc, password_changed_at, status) = row?;
+                    Ok(UserRecord {
+                        tenant,
+                        username,
+                        email,
+                        password_phc,
+                        password_changed_at: from_unix_time(password_changed_at),
+                        status: AccountStatus::parse(&status).ok_or_else(|| {
+                            Error::UnknownAccountStatus(status, Backtrace::capture())
+                        })?,
+                    })
+                })
+                .collect()
+            })
+            .await
+    }
+
+    async fn import(&self, record: &UserRecord) -> Result<User, Error> {
+        let username = self.username_policy.normalize(&record.username)?;
+        let record = record.clone();
+        let id = self
+            .sqlite
+            .call(move |connection| {
+                connection
+                    .query_row(
+                        "INSERT INTO users \
+                         (tenant_id, username, password_phc, email, password_changed_at, status) \
+                         VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+                        params![
+                            record.tenant,
+                            username,
+                            record.password_phc,
+                            record.email,
+                            unix_time(record.password_changed_at),
+                            record.status.as_str(),
+                        ],
+                        |row| row.get(0),
+                    )
+                    .map_err(user_conflict)
+            })
+            .await?;
+        Ok(User { id })
+    }
+
+    async fn overwrite(&self, user: User, record: &UserRecord) -> Result<(), Error> {
+        let record = record.clone();
+        let updated = self
+            .sqlite
+            .call(move |connection| {
+                connection
+                    .execute(
+                        "UPDATE users SET email = $1, password_phc = $2, password_changed_at = $3, \
+                         status = $4 WHERE id = $5",
+                        params![
+                            record.email,
+                            record.password_phc,
+                            unix_time(record.password_changed_at),
+                            record.status.as_str(),
+                            user.id,
+                        ],
+                    )
+                    .map_err(user_conflict)
+            })
+            .await?;
+        if updated == 0 {
+            return Err(Error::UserNotFound(Backtrace::capture()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn insert(&self, session: &Session, restricted: bool) -> Result<(), Error> {
+        let id = session.id().to_string();
+        let user_id = session.user().id;
+        let expires_at = unix_time(SystemTime::now() + session.lifetime());
+        let token_id = session.token_id().map(|token_id| token_id.to_string());
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO sessions (id, user_id, expires_at, restricted, token_id) \
+                     VALUES ($1, $2, $3, $4, $5)",
+                    params![id, user_id, expires_at, restricted, token_id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn is_active(&self, session: &Session) -> Result<bool, Error> {
+        self.has_session(session, false).await
+    }
+
+    async fn is_restricted(&self, session: &Session) -> Result<bool, Error> {
+        self.has_session(session, true).await
+    }
+
+    async fn unrestrict(&self, session: &Session) -> Result<(), Error> {
+        let id = session.id().to_string();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE sessions SET restricted = 0 WHERE id = $1",
+                    params![id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn delete(&self, session: &Session) -> Result<(), Error> {
+        let id = session.id().to_string();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute("DELETE FROM sessions WHERE id = $1", params![id])?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn list(&self, user: User) -> Result<Vec<SessionInfo>, Error> {
+        let now = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT id, created_at, expires_at FROM sessions \
+                     WHERE user_id = $1 AND expires_at > $2 ORDER BY created_at",
+                )?;
+                let rows = statement.query_map(params![user.id, now], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+                })?;
+                let mut sessions = Vec::new();
+                for row in rows {
+                    let (id, created_at, expires_at) = row?;
+                    sessions.push(SessionInfo {
+                        id: id.parse()?,
+                        created_at: from_unix_time(created_at),
+                        expires_at: from_unix_time(expires_at),
+                    });
+                }
+                Ok(sessions)
+            })
+            .await
+    }
+
+    async fn rotate(&self, session: &Session, rotated: &Session) -> Result<bool, Error> {
+        let id = session.id().to_string();
+        let token_id = session.token_id().map(|token_id| token_id.to_string());
+        let rotated_token_id = rotated.token_id().map(|token_id| token_id.to_string());
+        let now = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                let updated = connection.execute(
+                    "UPDATE sessions \
+                     SET token_id = $1, previous_token_id = token_id, rotated_at = $2 \
+                     WHERE id = $3 AND token_id IS $4 AND expires_at > $2",
+                    params![rotated_token_id, now, id, token_id],
+                )?;
+                Ok(updated > 0)
+            })
+            .await
+    }
+
+    async fn revoke(&self, user: User, id: Uuid) -> Result<bool, Error> {
+        let id = id.to_string();
+        self.sqlite
+            .call(move |connection| {
+                let deleted = connection.execute(
+                    "DELETE FROM sessions WHERE id = $1 AND user_id = $2",
+                    params![id, user.id],
+                )?;
+                Ok(deleted > 0)
+            })
+            .await
+    }
+
+    async fn revoke_all(&self, user: User) -> Result<u64, Error> {
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .execute("DELETE FROM sessions WHERE user_id = $1", params![user.id])?
+                    as u64)
+            })
+            .await
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let before = unix_time(before);
+        self.sqlite
+            .call(move |connection| {
+                let deleted = connection.execute(
+                    "DELETE FROM sessions WHERE expires_at < $1",
+                    params![before],
+                )?;
+                Ok(deleted as u64)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl ClientStore for SqliteClientStore {
+    async fn insert(
+        &self,
+        id: &str,
+        secret: &str,
+        scopes: &[String],
+        user: Option<User>,
+    ) -> Result<(), Error> {
+        let secret_phc = hash_password(secret).await;
+        self.insert_client(id, secret_phc, scopes, user).await
+    }
+
+    async fn insert_public(&self, id: &str, scopes: &[String]) -> Result<(), Error> {
+        self.insert_client(id, String::new(), scopes, None).await
+    }
+
+    async fn authenticate(&self, id: &str, secret: &str) -> Result<Client, Error> {
+        let id = id.to_owned();
+        let (client, secret_phc): (Client, String) = self
+            .sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT id, scopes, user_id, secret_phc = '', secret_phc \
+                         FROM oauth_clients WHERE id = $1",
+                        params![id],
+                        |row| Ok((client(row)?, row.get(4)?)),
+                    )
+                    .optional()?)
+            })
+            .await?
+            .ok_or_else(|| Error::InvalidClient(Backtrace::capture()))?;
+        verify_password(secret, &secret_phc)
+            .await
+            .map_err(|_| Error::InvalidClient(Backtrace::capture()))?;
+        Ok(client)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Client>, Error> {
+        let id = id.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT id, scopes, user_id, secret_phc = '' FROM oauth_clients \
+                         WHERE id = $1",
+                        params![id],
+                        client,
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn of_user(&self, user: User) -> Result<Vec<Client>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT id, scopes, user_id, secret_phc = '' FROM oauth_clients \
+                     WHERE user_id = $1 ORDER BY id",
+                )?;
+                let rows = statement.query_map(params![user.id], client)?;
+                Ok(rows.collect::<Result<_, _>>()?)
+            })
+            .await
+    }
+
+    async fn add_logout_redirect_uri(&self, id: &str, uri: &str) -> Result<(), Error> {
+        self.add_uri("oauth_client_logout_uris", id, uri).await
+    }
+
+    async fn has_logout_redirect_uri(&self, id: &str, uri: &str) -> Result<bool, Error> {
+        self.has_uri("oauth_client_logout_uris", id, uri).await
+    }
+
+    async fn set_backchannel_logout_uri(&self, id: &str, uri: Option<&str>) -> Result<(), Error> {
+        let client_id = id.to_owned();
+        let uri = uri.map(str::to_owned);
+        self.sqlite
+            .call(move |connection| {
+                let Some(uri) = uri else {
+                    connection.execute(
+                        "DELETE FROM oauth_client_backchannel_uris WHERE client_id = $1",
+                        params![client_id],
+                    )?;
+                    return Ok(());
+                };
+                connection
+                    .execute(
+                        "INSERT INTO oauth_client_backchannel_uris (client_id, uri) VALUES ($1, $2) \
+                         ON CONFLICT (client_id) DO UPDATE SET uri = excluded.uri",
+                        params![client_id, uri],
+                    )
+                    .map_err(|e| match e {
+                        rusqlite::Error::SqliteFailure(failure, _)
+                            if failure.extended_code == ffi::SQLITE_CONSTRAINT_FOREIGNKEY =>
+                        {
+                            Error::ClientNotFound(client_id.clone(), Backtrace::capture())
+                        }
+                        e => Error::from(e),
+                    })?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn backchannel_logout_uri(&self, id: &str) -> Result<Option<String>, Error> {
+        let client_id = id.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT uri FROM oauth_client_backchannel_uris WHERE client_id = $1",
+                        params![client_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn add_redirect_uri(&self, id: &str, uri: &str) -> Result<(), Error> {
+        self.add_uri("oauth_client_redirect_uris", id, uri).await
+    }
+
+    async fn has_redirect_uri(&self, id: &str, uri: &str) -> Result<bool, Error> {
+        self.has_uri("oauth_client_redirect_uris", id, uri).await
+    }
+}
+
+fn client(row: &rusqlite::Row) -> rusqlite::Result<Client> {
+    let scopes: String = row.get(1)?;
+    Ok(Client {
+        id: row.get(0)?,
+        scopes: scopes.split_whitespace().map(str::to_owned).collect(),
+        user: row.get::<_, Option<i32>>(2)?.map(|id| User { id }),
+        public: row.get(3)?,
+    })
+}
+
+impl SqliteClientStore {
+    async fn insert_client(
+        &self,
+        id: &str,
+        secret_phc: String,
+        scopes: &[String],
+        user: Option<User>,
+    ) -> Result<(), Error> {
+        let id = id.to_owned();
+        let scopes = scopes.join(" ");
+        let user_id = user.map(|user| user.id);
+        self.sqlite
+            .call(move |connection| {
+                connection
+                    .execute(
+                        "INSERT INTO oauth_clients (id, secret_phc, scopes, user_id) \
+                         VALUES ($1, $2, $3, $4)",
+                        params![id, secret_phc, scopes, user_id],
+                    )
+                    .map_err(|e| {
+                        if violates_unique(&e, "oauth_clients.id") {
+                            return Error::ClientIdTaken(Backtrace::capture());
+                        }
+                        Error::from(e)
+                    })?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn add_uri(&self, table: &'static str, id: &str, uri: &str) -> Result<(), Error> {
+        let client_id = id.to_owned();
+        let uri = uri.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                connection
+                    .execute(
+                        &format!(
+                            "INSERT OR IGNORE INTO {} (client_id, uri) VALUES ($1, $2)",
+                            table
+                        ),
+                        params![client_id, uri],
+                    )
+                    .map_err(|e| match e {
+                        rusqlite::Error::SqliteFailure(failure, _)
+                            if failure.extended_code == ffi::SQLITE_CONSTRAINT_FOREIGNKEY =>
+                        {
+                            Error::ClientNotFound(client_id.clone(), Backtrace::capture())
+                        }
+                        e => Error::from(e),
+                    })?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn has_uri(&self, table: &'static str, id: &str, uri: &str) -> Result<bool, Error> {
+        let client_id = id.to_owned();
+        let uri = uri.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection.query_row(
+                    &format!(
+                        "SELECT EXISTS (SELECT 1 FROM {} WHERE client_id = $1 AND uri = $2)",
+                        table
+                    ),
+                    params![client_id, uri],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl TokenStore for SqliteTokenStore {
+    async fn insert(&self, token: &str, details: &AccessToken) -> Result<(), Error> {
+        let token_hash = hash_token(token);
+        let client_id = details.client_id.clone();
+        let user_id = details.user.map(|user| user.id);
+        let scope = details.scope.clone();
+        let expires_at = unix_time(details.expires_at);
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO oauth_tokens (token_hash, client_id, user_id, scope, expires_at) \
+                     VALUES ($1, $2, $3, $4, $5)",
+                    params![token_hash, client_id, user_id, scope, expires_at],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn get(&self, token: &str) -> Result<Option<AccessToken>, Error> {
+        let token_hash = hash_token(token);
+        let now = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT client_id, user_id, scope, expires_at FROM oauth_tokens \
+                         WHERE token_hash = $1 AND expires_at > $2",
+                        params![token_hash, now],
+                        |row| {
+                            Ok(AccessToken {
+                                client_id: row.get(0)?,
+                                user: row.get::<_, Option<i32>>(1)?.map(|id| User { id }),
+                                scope: row.get(2)?,
+                                expires_at: from_unix_time(row.get(3)?),
+                            })
+                        },
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn revoke(&self, token: &str, client_id: &str) -> Result<bool, Error> {
+        let token_hash = hash_token(token);
+        let client_id = client_id.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                let deleted = connection.execute(
+                    "DELETE FROM oauth_tokens WHERE token_hash = $1 AND client_id = $2",
+                    params![token_hash, client_id],
+                )?;
+                Ok(deleted > 0)
+            })
+            .await
+    }
+
+    async fn revoke_all(&self, client_id: &str, user: User) -> Result<(), Error> {
+        let client_id = client_id.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "DELETE FROM oauth_tokens WHERE client_id = $1 AND user_id = $2",
+                    params![client_id, user.id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn list(&self, user: User) -> Result<Vec<TokenInfo>, Error> {
+        let now = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT token_hash, client_id, scope, expires_at FROM oauth_tokens \
+                     WHERE user_id = $1 AND expires_at > $2 ORDER BY expires_at",
+                )?;
+                let tokens = statement
+                    .query_map(params![user.id, now], |row| {
+                        Ok(TokenInfo {
+                            id: row.get(0)?,
+                            client_id: row.get(1)?,
+                            scope: row.get(2)?,
+                            expires_at: from_unix_time(row.get(3)?),
+                        })
+                    })?
+                    .collect::<Result<_, _>>()?;
+                Ok(tokens)
+            })
+            .await
+    }
+
+    async fn revoke_by_id(&self, user: User, id: &str) -> Result<bool, Error> {
+        let id = id.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                let deleted = connection.execute(
+                    "DELETE FROM oauth_tokens WHERE token_hash = $1 AND user_id = $2",
+                    params![id, user.id],
+                )?;
+                Ok(deleted > 0)
+            })
+            .await
+    }
+
+    async fn insert_code(&self, code: &str, details: &AuthorizationCode) -> Result<(), Error> {
+        let code_hash = hash_token(code);
+        let client_id = details.client_id.clone();
+        let user_id = details.user.id;
+        let scope = details.scope.clone();
+        let redirect_uri = details.redirect_uri.clone();
+        let code_challenge = details.code_challenge.clone();
+        let expires_at = unix_time(details.expires_at);
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO oauth_codes \
+                     (code_hash, client_id, user_id, scope, redirect_uri, code_challenge, \
+                     expires_at) \
+                     VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                    params![
+                        code_hash,
+                        client_id,
+                        user_id,
+                        scope,
+                        redirect_uri,
+                        code_challenge,
+                        expires_at
+                    ],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn take_code(&self, code: &str) -> Result<Option<AuthorizationCode>, Error> {
+        let code_hash = hash_token(code);
+        self.sqlite
+            .call(move |connection| {
+                let code = connection
+                    .query_row(
+                        "DELETE FROM oauth_codes WHERE code_hash = $1 \
+                         RETURNING client_id, user_id, scope, redirect_uri, code_challenge, \
+                         expires_at",
+                        params![code_hash],
+                        |row| {
+                            Ok(AuthorizationCode {
+                                client_id: row.get(0)?,
+                                user: User { id: row.get(1)? },
+                                scope: row.get(2)?,
+                                redirect_uri: row.get(3)?,
+                                code_challenge: row.get(4)?,
+                                expires_at: from_unix_time(row.get(5)?),
+                            })
+                        },
+                    )
+                    .optional()?;
+                Ok(code.filter(|code| code.expires_at > SystemTime::now()))
+            })
+            .await
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let before = unix_time(before);
+        self.sqlite
+            .call(move |connection| {
+                let tokens = connection.execute(
+                    "DELETE FROM oauth_tokens WHERE expires_at < $1",
+                    params![before],
+                )?;
+                let codes = connection.execute(
+                    "DELETE FROM oauth_codes WHERE expires_at < $1",
+                    params![before],
+                )?;
+                Ok((tokens + codes) as u64)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl ConsentStore for SqliteConsentStore {
+    async fn grant(&self, user: User, client_id: &str, scope: &str) -> Result<(), Error> {
+        let client_id = client_id.to_owned();
+        let scope = scope.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO oauth_consents (user_id, client_id, scope) VALUES ($1, $2, $3) \
+                     ON CONFLICT (user_id, client_id) DO UPDATE SET scope = excluded.scope",
+                    params![user.id, client_id, scope],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn get(&self, user: User, client_id: &str) -> Result<Option<String>, Error> {
+        let client_id = client_id.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT scope FROM oauth_consents WHERE user_id = $1 AND client_id = $2",
+                        params![user.id, client_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn list(&self, user: User) -> Result<Vec<Consent>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT client_id, scope FROM oauth_consents WHERE user_id = $1 \
+                     ORDER BY client_id",
+                )?;
+                let consents = statement
+                    .query_map(params![user.id], |row| {
+                        Ok(Consent {
+                            client_id: row.get(0)?,
+                            scope: row.get(1)?,
+                        })
+                    })?
+                    .collect::<Result<_, _>>()?;
+                Ok(consents)
+            })
+            .await
+    }
+
+    async fn revoke(&self, user: User, client_id: &str) -> Result<bool, Error> {
+        let client_id = client_id.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                let deleted = connection.execute(
+                    "DELETE FROM oauth_consents WHERE user_id = $1 AND client_id = $2",
+                    params![user.id, client_id],
+                )?;
+                Ok(deleted > 0)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl JobStore for SqliteJobStore {
+    async fn enqueue(&self, kind: &str, payload: &str) -> Result<(), Error> {
+        let kind = kind.to_owned();
+        let payload = payload.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO jobs (kind, payload) VALUES ($1, $2)",
+                    params![kind, payload],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn claim(&self, lease: Duration) -> Result<Option<Job>, Error> {
+        let now = unix_time(SystemTime::now());
+        let locked_until = unix_time(SystemTime::now() + lease);
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "UPDATE jobs SET attempts = attempts + 1, locked_until = $1 WHERE id = (\
+                         SELECT id FROM jobs WHERE NOT dead AND run_at <= $2 \
+                         AND (locked_until IS NULL OR locked_until < $2) \
+                         ORDER BY run_at LIMIT 1) \
+                         RETURNING id, kind, payload, attempts",
+                        params![locked_until, now],
+                        |row| {
+                            Ok(Job {
+                                id: row.get(0)?,
+                                kind: row.get(1)?,
+                                payload: row.get(2)?,
+                                attempts: row.get(3)?,
+                            })
+                        },
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn complete(&self, id: i64) -> Result<(), Error> {
+        self.sqlite
+            .call(move |connection| {
+                connection.execute("DELETE FROM jobs WHERE id = $1", params![id])?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn retry(&self, id: i64, error: &str, run_at: SystemTime) -> Result<(), Error> {
+        let error = error.to_owned();
+        let run_at = unix_time(run_at);
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE jobs SET run_at = $1, locked_until = NULL, last_error = $2 \
+                     WHERE id = $3",
+                    params![run_at, error, id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn bury(&self, id: i64, error: &str) -> Result<(), Error> {
+        let error = error.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE jobs SET dead = 1, locked_until = NULL, last_error = $1 WHERE id = $2",
+                    params![error, id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn requeue_dead(&self) -> Result<u64, Error> {
+        let now = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                let requeued = connection.execute(
+                    "UPDATE jobs SET dead = 0, attempts = 0, run_at = $1 WHERE dead",
+                    params![now],
+                )?;
+                Ok(requeued as u64)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl OtpStore for SqliteOtpStore {
+    async fn set_phone(&self, user: User, number: &str) -> Result<(), Error> {
+        let number = number.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO phone_numbers (user_id, number) VALUES ($1, $2) \
+                     ON CONFLICT (user_id) DO UPDATE SET number = excluded.number, verified = 0",
+                    params![user.id, number],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn phone(&self, user: User) -> Result<Option<Phone>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT number, verified FROM phone_numbers WHERE user_id = $1",
+                        params![user.id],
+                        |row| {
+                            Ok(Phone {
+                                number: row.get(0)?,
+                                verified: row.get(1)?,
+                            })
+                        },
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn verify_phone(&self, user: User, number: &str) -> Result<bool, Error> {
+        let number = number.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                let updated = connection.execute(
+                    "UPDATE phone_numbers SET verified = 1 WHERE user_id = $1 AND number = $2",
+                    params![user.id, number],
+                )?;
+                Ok(updated > 0)
+            })
+            .await
+    }
+
+    async fn remove_phone(&self, user: User) -> Result<(), Error> {
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "DELETE FROM phone_numbers WHERE user_id = $1",
+                    params![user.id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn set_pending_totp(&self, user: User, secret: &str) -> Result<(), Error> {
+        let secret = secret.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO totp_secrets (user_id, pending_secret) VALUES ($1, $2) \
+                     ON CONFLICT (user_id) DO UPDATE SET pending_secret = excluded.pending_secret",
+                    params![user.id, secret],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn totp(&self, user: User) -> Result<Option<Totp>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT secret, pending_secret FROM totp_secrets WHERE user_id = $1",
+                        params![user.id],
+                        |row| {
+                            Ok(Totp {
+                                secret: row.get(0)?,
+                                pending: row.get(1)?,
+                            })
+                        },
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn confirm_totp(&self, user: User, pending: &str, step: u64) -> Result<bool, Error> {
+        let pending = pending.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                let updated = connection.execute(
+                    "UPDATE totp_secrets \
+                     SET secret = pending_secret, pending_secret = NULL, last_step = $3 \
+                     WHERE user_id = $1 AND pending_secret = $2",
+                    params![user.id, pending, step as i64],
+                )?;
+                Ok(updated > 0)
+            })
+            .await
+    }
+
+    async fn accept_totp(&self, user: User, secret: &str, step: u64) -> Result<bool, Error> {
+        let secret = secret.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                let updated = connection.execute(
+                    "UPDATE totp_secrets SET last_step = $3 \
+                     WHERE user_id = $1 AND secret = $2 AND (last_step IS NULL OR last_step < $3)",
+                    params![user.id, secret, step as i64],
+                )?;
+                Ok(updated > 0)
+            })
+            .await
+    }
+
+    async fn remove_totp(&self, user: User) -> Result<(), Error> {
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "DELETE FROM totp_secrets WHERE user_id = $1",
+                    params![user.id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn insert_code(
+        &self,
+        user: User,
+        purpose: Purpose,
+        code: &str,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        let code_hash = hash_code(user, code);
+        let created_at = unix_time(SystemTime::now());
+        let expires_at = unix_time(expires_at);
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO otp_codes (user_id, purpose, code_hash, created_at, expires_at) \
+                     VALUES ($1, $2, $3, $4, $5)",
+                    params![user.id, purpose.as_str(), code_hash, created_at, expires_at],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn count_codes(&self, user: User, since: SystemTime) -> Result<u64, Error> {
+        let since = unix_time(since);
+        self.sqlite
+            .call(move |connection| {
+                let count: i64 = connection.query_row(
+                    "SELECT count(*) FROM otp_codes WHERE user_id = $1 AND created_at > $2",
+                    params![user.id, since],
+                    |row| row.get(0),
+                )?;
+                Ok(count as u64)
+            })
+            .await
+    }
+
+    async fn check_code(&self, user: User, purpose: Purpose, code: &str) -> Result<bool, Error> {
+        let code_hash = hash_code(user, code);
+        let now = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                let stored: Option<(String, i32)> = connection
+                    .query_row(
+                        "UPDATE otp_codes SET attempts = attempts + 1 WHERE id = (\
+                         SELECT id FROM otp_codes WHERE user_id = $1 AND purpose = $2 \
+                         AND expires_at > $3 ORDER BY created_at DESC, id DESC LIMIT 1) \
+                         RETURNING code_hash, attempts",
+                        params![user.id, purpose.as_str(), now],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()?;
+                let Some((stored_hash, attempts)) = stored else { return Ok(false) };
+                if attempts > MAX_CHECK_ATTEMPTS || stored_hash != code_hash {
+                    return Ok(false);
+                }
+                connection.execute(
+                    "DELETE FROM otp_codes WHERE user_id = $1 AND purpose = $2",
+                    params![user.id, purpose.as_str()],
+                )?;
+                Ok(true)
+            })
+            .await
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let before = unix_time(before);
+        self.sqlite
+            .call(move |connection| {
+                let deleted = connection.execute(
+                    "DELETE FROM otp_codes WHERE expires_at < $1",
+                    params![before],
+                )?;
+                Ok(deleted as u64)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl AuditStore for SqliteAuditStore {
+    async fn insert(&self, event: &AuditEvent) -> Result<(), Error> {
+        let event = event.clone();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO audit_events \
+                     (user_id, tenant_id, kind, ip, device, country, details, created_at) \
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                    params![
+                        event.user.map(|user| user.id),
+                        event.tenant,
+                        event.kind,
+                        event.ip,
+                        event.device,
+                        event.country,
+                        event.details.to_string(),
+                        unix_time(event.created_at),
+                    ],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn list(
+        &self,
+        user: User,
+        since: SystemTime,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, Error> {
+        let since = unix_time(since);
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT tenant_id, kind, ip, device, country, details, created_at FROM audit_events \
+                     WHERE user_id = $1 AND created_at > $2 ORDER BY created_at DESC, id DESC LIMIT $3",
+                )?;
+                let rows = statement
+                    .query_map(params![user.id, since, limit as i64], audit_row)?
+                    .collect::<Result<Vec<_>, _>>()?;
+                rows.into_iter()
+                    .map(|row| audit_event(Some(user), row))
+                    .collect()
+            })
+            .await
+    }
+
+    async fn logins(
+        &self,
+        user: User,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT tenant_id, kind, ip, device, country, details, created_at FROM audit_events \
+                     WHERE user_id = $1 AND kind IN ($2, $3) ORDER BY created_at DESC, id DESC \
+                     LIMIT $4 OFFSET $5",
+                )?;
+                let params = params![
+                    user.id,
+                    audit::LOGIN_SUCCEEDED,
+                    audit::LOGIN_FAILED,
+                    limit as i64,
+                    offset as i64,
+                ];
+                let rows = statement
+                    .query_map(params, audit_row)?
+                    .collect::<Result<Vec<_>, _>>()?;
+                rows.into_iter()
+                    .map(|row| audit_event(Some(user), row))
+                    .collect()
+            })
+            .await
+    }
+
+    async fn position(&self) -> Result<i64, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let position = connection.query_row(
+                    "SELECT COALESCE(MAX(id), 0) FROM audit_events",
+                    [],
+                    |row| row.get(0),
+                )?;
+                Ok(position)
+            })
+            .await
+    }
+
+    async fn after(&self, position: i64, limit: usize) -> Result<Vec<(i64, AuditEvent)>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT id, user_id, tenant_id, kind, ip, device, country, details, created_at \
+                     FROM audit_events WHERE id > $1 ORDER BY id LIMIT $2",
+                )?;
+                let rows = statement
+                    .query_map(params![position, limit as i64], |row| {
+                        let id: i64 = row.get(0)?;
+                        let user_id: Option<i32> = row.get(1)?;
+                        let event = (
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                            row.get(5)?,
+                            row.get(6)?,
+                            row.get(7)?,
+                            row.get(8)?,
+                        );
+                        Ok((id, user_id, event))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                rows.into_iter()
+                    .map(|(id, user_id, row)| {
+                        Ok((id, audit_event(user_id.map(|id| User { id }), row)?))
+                    })
+                    .collect()
+            })
+            .await
+    }
+}
+
+type AuditRow = (
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    String,
+    i64,
+);
+
+fn audit_row(row: &rusqlite::Row) -> rusqlite::Result<AuditRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+        row.get(6)?,
+    ))
+}
+
+fn audit_event(user: Option<User>, row: AuditRow) -> Result<AuditEvent, Error> {
+    let (tenant, kind, ip, device, country, details, created_at) = row;
+    Ok(AuditEvent {
+        user,
+        tenant,
+        kind,
+        ip,
+        device,
+        country,
+        details: serde_json::from_str(&details)?,
+        created_at: from_unix_time(created_at),
+    })
+}
+
+#[async_trait]
+impl ExportStore for SqliteExportStore {
+    async fn insert(&self, user: User, id: Uuid, expires_at: SystemTime) -> Result<(), Error> {
+        let id = id.to_string();
+        let expires_at = unix_time(expires_at);
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO data_exports (id, user_id, expires_at) VALUES ($1, $2, $3)",
+                    params![id, user.id, expires_at],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn complete(&self, id: Uuid, data: &str) -> Result<(), Error> {
+        let id = id.to_string();
+        let data = data.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "UPDATE data_exports SET data = $1 WHERE id = $2",
+                    params![data, id],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn latest(&self, user: User) -> Result<Option<Export>, Error> {
+        let now = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                let row = connection
+                    .query_row(
+                        "SELECT id, data IS NOT NULL FROM data_exports \
+                         WHERE user_id = $1 AND expires_at > $2 \
+                         ORDER BY created_at DESC, rowid DESC LIMIT 1",
+                        params![user.id, now],
+                        |row| Ok((row.get::<_, String>(0)?, row.get(1)?)),
+                    )
+                    .optional()?;
+                match row {
+                    Some((id, ready)) => Ok(Some(Export {
+                        id: id.parse()?,
+                        ready,
+                    })),
+                    None => Ok(None),
+                }
+            })
+            .await
+    }
+
+    async fn take(&self, id: Uuid) -> Result<Option<(User, String)>, Error> {
+        let id = id.to_string();
+        let now = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "DELETE FROM data_exports \
+                         WHERE id = $1 AND data IS NOT NULL AND expires_at > $2 \
+                         RETURNING user_id, data",
+                        params![id, now],
+                        |row| Ok((User { id: row.get(0)? }, row.get(1)?)),
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let before = unix_time(before);
+        self.sqlite
+            .call(move |connection| {
+                Ok(connection.execute(
+                    "DELETE FROM data_exports WHERE expires_at < $1",
+                    params![before],
+                )? as u64)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl FeatureStore for SqliteFeatureStore {
+    async fn list(&self) -> Result<Vec<(String, bool)>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let mut statement =
+                    connection.prepare("SELECT name, enabled FROM feature_flags ORDER BY name")?;
+                let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                Ok(rows.collect::<Result<_, _>>()?)
+            })
+            .await
+    }
+
+    async fn set(&self, name: &str, enabled: bool) -> Result<(), Error> {
+        let name = name.to_owned();
+        let updated_at = unix_time(SystemTime::now());
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO feature_flags (name, enabled, updated_at) VALUES ($1, $2, $3) \
+                     ON CONFLICT (name) DO UPDATE \
+                     SET enabled = excluded.enabled, updated_at = excluded.updated_at",
+                    params![name, enabled, updated_at],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl ClaimStore for SqliteClaimStore {
+    async fn list(&self, user: User) -> Result<Claims, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let mut statement =
+                    connection.prepare("SELECT name, value FROM user_claims WHERE user_id = $1")?;
+                let rows = statement.query_map(params![user.id], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?;
+                let mut claims = Claims::new();
+                for row in rows {
+                    let (name, value) = row?;
+                    claims.insert(name, serde_json::from_str(&value)?);
+                }
+                Ok(claims)
+            })
+            .await
+    }
+
+    async fn set(&self, user: User, name: &str, value: &serde_json::Value) -> Result<(), Error> {
+        let name = name.to_owned();
+        let value = value.to_string();
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO user_claims (user_id, name, value) VALUES ($1, $2, $3) \
+                     ON CONFLICT (user_id, name) DO UPDATE SET value = excluded.value",
+                    params![user.id, name, value],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn remove(&self, user: User, name: &str) -> Result<bool, Error> {
+        let name = name.to_owned();
+        self.sqlite
+            .call(move |connection| {
+                let deleted = connection.execute(
+                    "DELETE FROM user_claims WHERE user_id = $1 AND name = $2",
+                    params![user.id, name],
+                )?;
+                Ok(deleted > 0)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl QuotaStore for SqliteQuotaStore {
+    async fn count(&self, key: &str, window: SystemTime) -> Result<u64, Error> {
+        let key = key.to_owned();
+        let window = unix_time(window);
+        self.sqlite
+            .call(move |connection| {
+                let count: Option<i64> = connection
+                    .query_row(
+                        "SELECT count FROM quota_counters WHERE key = $1 AND window_start = $2",
+                        params![key, window],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                Ok(count.unwrap_or(0) as u64)
+            })
+            .await
+    }
+
+    async fn increment(
+        &self,
+        key: &str,
+        window: SystemTime,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        let key = key.to_owned();
+        let (window, expires_at) = (unix_time(window), unix_time(expires_at));
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO quota_counters (key, window_start, count, expires_at) \
+                     VALUES ($1, $2, 1, $3) \
+                     ON CONFLICT (key, window_start) DO UPDATE SET count = count + 1",
+                    params![key, window, expires_at],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn purge_expired(&self, before: SystemTime) -> Result<u64, Error> {
+        let before = unix_time(before);
+        self.sqlite
+            .call(move |connection| {
+                let deleted = connection.execute(
+                    "DELETE FROM quota_counters WHERE expires_at < $1",
+                    params![before],
+                )?;
+                Ok(deleted as u64)
+            })
+            .await
+    }
+}
+
+fn identity_taken(e: rusqlite::Error) -> Error {
+    if violates_unique(&e, "identities.provider") {
+        return Error::IdentityTaken(Backtrace::capture());
+    }
+    Error::from(e)
+}
+
+fn user_conflict(e: rusqlite::Error) -> Error {
+    if violates_unique(&e, "users.username") {
+        Error::UsernameTaken(Backtrace::capture())
+    } else if violates_unique(&e, "users.email") {
+        Error::EmailTaken(Backtrace::capture())
+    } else {
+        Error::from(e)
+    }
+}
+
+/// Whether the error is a unique constraint naming the column failing. SQLite only tells the
+/// constraints of a table apart in the message, as in `users.tenant_id, users.username`, and a
+/// failing NOT NULL or foreign key constraint is something else entirely.
+pub fn violates_unique(e: &rusqlite::Error, column: &str) -> bool {
+    matches!(
+        e,
+        rusqlite::Error::SqliteFailure(failure, Some(message))
+            if matches!(
+                failure.extended_code,
+                ffi::SQLITE_CONSTRAINT_UNIQUE | ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+            ) && message.contains(column)
+    )
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+fn from_unix_time(seconds: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds as u64)
+}
+
+#[async_trait]
+impl NotificationStore for SqliteNotificationStore {
+    async fn list(&self, user: User) -> Result<HashMap<Category, bool>, Error> {
+        self.sqlite
+            .call(move |connection| {
+                let mut statement = connection.prepare(
+                    "SELECT category, enabled FROM notification_preferences WHERE user_id = $1",
+                )?;
+                let rows = statement.query_map(params![user.id], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+                })?;
+                let mut preferences = HashMap::new();
+                for row in rows {
+                    let (category, enabled) = row?;
+                    // Categories dropped since are left alone rather than failing the query.
+                    if let Some(category) = Category::parse(&category) {
+                        preferences.insert(category, enabled);
+                    }
+                }
+                Ok(preferences)
+            })
+            .await
+    }
+
+    async fn set(&self, user: User, category: Category, enabled: bool) -> Result<(), Error> {
+        self.sqlite
+            .call(move |connection| {
+                connection.execute(
+                    "INSERT INTO notification_preferences (user_id, category, enabled) \
+                     VALUES ($1, $2, $3) \
+                     ON CONFLICT (user_id, category) DO UPDATE SET enabled = excluded.enabled",
+                    params![user.id, category.as_str(), enabled],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
@@ -0,0 +1,107 @@
+use crate::claims::Claims;
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::session::{CookiePolicy, Session};
+use crate::util::env_var_opt;
+use hyper::header::{HOST, ORIGIN};
+use hyper::{Body, Request, Uri};
+use serde::Serialize;
+use std::backtrace::Backtrace;
+
+/// Single sign-on for apps at sibling subdomains, from `SSO_DOMAIN`. The session cookie is issued
+/// for the whole domain, apps there can ask `/auth/whoami` who's logged in, and they can log the
+/// user out by posting to `/auth/logout` with the token they got along with the answer.
+pub struct SsoPolicy {
+    /// Parent domain of the apps, like `example.com`, without the leading dot.
+    pub domain: Option<String>,
+}
+
+/// Answer of `/auth/whoami`, which apps read with credentials from their own origins.
+#[derive(Serialize)]
+pub struct WhoamiResponse {
+    pub user: WhoamiUser,
+    pub tenant: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<i32>,
+    pub claims: Claims,
+    /// Passed back when logging out from the app, see [`verify_logout_token`].
+    pub logout_token: String,
+}
+
+#[derive(Serialize)]
+pub struct WhoamiUser {
+    pub id: i32,
+    pub username: String,
+}
+
+const LOGOUT_SIGNATURE_DOMAIN: &str = "logout.";
+
+impl SsoPolicy {
+    pub fn from_env() -> Result<SsoPolicy, Error> {
+        Ok(SsoPolicy {
+            domain: env_var_opt("SSO_DOMAIN")?
+                .map(|domain| domain.trim_start_matches('.').to_lowercase()),
+        })
+    }
+
+    /// Issues the session cookie for the domain, which only works when it wasn't given another one
+    /// or the `__Host-` prefix.
+    pub fn apply(&self, mut cookies: CookiePolicy) -> Result<CookiePolicy, Error> {
+        let Some(domain) = &self.domain else { return Ok(cookies); };
+        match &cookies.domain {
+            Some(other) if other.trim_start_matches('.') != domain => {
+                return Err(Error::InvalidCookiePolicy(
+                    "SESSION_COOKIE_DOMAIN differs from SSO_DOMAIN".to_owned(),
+                    Backtrace::capture(),
+                ));
+            }
+            _ => cookies.domain = Some(domain.clone()),
+        }
+        cookies.validate()
+    }
+
+    /// Whether the URL, or the origin, is of an app at the domain. Those get to read
+    /// `/auth/whoami` and to be sent back to once the user logs out. Only HTTPS ones are, as the
+    /// cookie wouldn't be sent along to the others anyway.
+    pub fn allows(&self, url: &str) -> bool {
+        let Some(domain) = &self.domain else { return false; };
+        let Ok(url) = url.parse::<Uri>() else { return false; };
+        let Some(host) = url.host() else { return false; };
+        let host = host.to_lowercase();
+        url.scheme_str() == Some("https")
+            && (host == *domain || host.ends_with(&format!(".{}", domain)))
+    }
+}
+
+/// Token an app has to send to log the user out from its own origin. It's tied to the session, and
+/// as only the apps allowed to read `/auth/whoami` can get it, other sites can't log anyone out.
+pub fn logout_token(session: &Session, crypto: &Crypto) -> String {
+    hex::encode(&crypto.sign(&signed_data(session)).hash)
+}
+
+pub fn verify_logout_token(token: &str, session: &Session, crypto: &Crypto) -> bool {
+    match hex::decode(token) {
+        Ok(signature) => crypto.verify(&signed_data(session), &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Whether the browser says the request came from a page of another origin. Ones that don't say
+/// are taken to be from this one, as only old browsers leave the header out of form posts.
+pub fn is_cross_origin(req: &Request<Body>) -> bool {
+    let Some(origin) = req.headers().get(ORIGIN) else { return false; };
+    let host = req.headers().get(HOST).and_then(|host| host.to_str().ok());
+    let origin = origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.parse::<Uri>().ok());
+    let authority = origin.as_ref().and_then(Uri::authority);
+    match (authority, host) {
+        (Some(authority), Some(host)) => !authority.as_str().eq_ignore_ascii_case(host),
+        _ => true,
+    }
+}
+
+fn signed_data(session: &Session) -> Vec<u8> {
+    format!("{}{}", LOGOUT_SIGNATURE_DOMAIN, session.id()).into_bytes()
+}
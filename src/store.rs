@@ -0,0 +1,219 @@
+use crate::audit::AuditStore;
+use crate::claims::ClaimStore;
+use crate::database::Database;
+use crate::error::Error;
+use crate::export::ExportStore;
+use crate::features::FeatureStore;
+use crate::jobs::JobStore;
+use crate::memory::{
+    MemoryAuditStore, MemoryClaimStore, MemoryClientStore, MemoryConsentStore, MemoryExportStore,
+    MemoryFeatureStore, MemoryJobStore, MemoryNotificationStore, MemoryOtpStore, MemoryQuotaStore,
+    MemorySessionStore, MemoryTokenStore, MemoryUserStore,
+};
+use crate::migrations;
+use crate::notifications::NotificationStore;
+use crate::oauth::{ClientStore, ConsentStore, TokenStore};
+use crate::otp::OtpStore;
+use crate::postgres::{
+    PostgresAuditStore, PostgresClaimStore, PostgresClientStore, PostgresConsentStore,
+    PostgresExportStore, PostgresFeatureStore, PostgresJobStore, PostgresNotificationStore,
+    PostgresOtpStore, PostgresQuotaStore, PostgresSessionStore, PostgresTokenStore,
+    PostgresUserStore,
+};
+use crate::quota::QuotaStore;
+use crate::session::SessionStore;
+use crate::sqlite::{
+    Sqlite, SqliteAuditStore, SqliteClaimStore, SqliteClientStore, SqliteConsentStore,
+    SqliteExportStore, SqliteFeatureStore, SqliteJobStore, SqliteNotificationStore, SqliteOtpStore,
+    SqliteQuotaStore, SqliteSessionStore, SqliteTokenStore, SqliteUserStore,
+};
+use crate::user::{self, UserStore, UsernamePolicy};
+use crate::util::env_var;
+use slog::Logger;
+use std::backtrace::Backtrace;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Store {
+    pub users: Box<dyn UserStore>,
+    pub sessions: Box<dyn SessionStore>,
+    pub clients: Box<dyn ClientStore>,
+    pub tokens: Box<dyn TokenStore>,
+    pub consents: Box<dyn ConsentStore>,
+    pub jobs: Box<dyn JobStore>,
+    pub otp: Box<dyn OtpStore>,
+    pub audit: Box<dyn AuditStore>,
+    pub exports: Box<dyn ExportStore>,
+    pub features: Box<dyn FeatureStore>,
+    pub claims: Box<dyn ClaimStore>,
+    pub quotas: Box<dyn QuotaStore>,
+    pub notifications: Box<dyn NotificationStore>,
+    backend: Backend,
+}
+
+enum Backend {
+    Postgres(Arc<Database>),
+    Sqlite(Arc<Sqlite>),
+    Memory,
+}
+
+impl Store {
+    /// Picks the backend from the scheme of `DATABASE_URL`. `sqlite://` URLs are followed by a
+    /// path to the database file, `memory://` keeps everything in memory until the server exits,
+    /// and anything else is treated as a Postgres connection string.
+    pub fn from_env() -> Result<Store, Error> {
+        let url = env_var("DATABASE_URL")?;
+        let username_policy = UsernamePolicy::from_env()?;
+        if url == "memory://" {
+            return Ok(Store {
+                users: Box::new(MemoryUserStore::new(username_policy)),
+                ..Store::memory()
+            });
+        }
+        Ok(match url.strip_prefix("sqlite://") {
+            Some(path) => Store::sqlite(Arc::new(Sqlite::open(path)?), username_policy),
+            None => Store::postgres(Arc::new(Database::new(&url)?), username_policy),
+        })
+    }
+
+    pub fn postgres(database: Arc<Database>, username_policy: UsernamePolicy) -> Store {
+        Store {
+            users: Box::new(PostgresUserStore::new(database.clone(), username_policy)),
+            sessions: Box::new(PostgresSessionStore::new(database.clone())),
+            clients: Box::new(PostgresClientStore::new(database.clone())),
+            tokens: Box::new(PostgresTokenStore::new(database.clone())),
+            consents: Box::new(PostgresConsentStore::new(database.clone())),
+            jobs: Box::new(PostgresJobStore::new(database.clone())),
+            otp: Box::new(PostgresOtpStore::new(database.clone())),
+            audit: Box::new(PostgresAuditStore::new(database.clone())),
+            exports: Box::new(PostgresExportStore::new(database.clone())),
+            features: Box::new(PostgresFeatureStore::new(database.clone())),
+            claims: Box::new(PostgresClaimStore::new(database.clone())),
+            quotas: Box::new(PostgresQuotaStore::new(database.clone())),
+            notifications: Box::new(PostgresNotificationStore::new(database.clone())),
+            backend: Backend::Postgres(database),
+        }
+    }
+
+    pub fn sqlite(sqlite: Arc<Sqlite>, username_policy: UsernamePolicy) -> Store {
+        Store {
+            users: Box::new(SqliteUserStore::new(sqlite.clone(), username_policy)),
+            sessions: Box::new(SqliteSessionStore::new(sqlite.clone())),
+            clients: Box::new(SqliteClientStore::new(sqlite.clone())),
+            tokens: Box::new(SqliteTokenStore::new(sqlite.clone())),
+            consents: Box::new(SqliteConsentStore::new(sqlite.clone())),
+            jobs: Box::new(SqliteJobStore::new(sqlite.clone())),
+            otp: Box::new(SqliteOtpStore::new(sqlite.clone())),
+            audit: Box::new(SqliteAuditStore::new(sqlite.clone())),
+            exports: Box::new(SqliteExportStore::new(sqlite.clone())),
+            features: Box::new(SqliteFeatureStore::new(sqlite.clone())),
+            claims: Box::new(SqliteClaimStore::new(sqlite.clone())),
+            quotas: Box::new(SqliteQuotaStore::new(sqlite.clone())),
+            notifications: Box::new(SqliteNotificationStore::new(sqlite.clone())),
+            backend: Backend::Sqlite(sqlite),
+        }
+    }
+
+    pub fn memory() -> Store {
+        Store {
+            users: Box::new(MemoryUserStore::default()),
+            sessions: Box::new(MemorySessionStore::default()),
+            clients: Box::new(MemoryClientStore::default()),
+            tokens: Box::new(MemoryTokenStore::default()),
+            consents: Box::new(MemoryConsentStore::default()),
+            jobs: Box::new(MemoryJobStore::default()),
+            otp: Box::new(MemoryOtpStore::default()),
+            audit: Box::new(MemoryAuditStore::default()),
+            exports: Box::new(MemoryExportStore::default()),
+            features: Box::new(MemoryFeatureStore::default()),
+            claims: Box::new(MemoryClaimStore::default()),
+            quotas: Box::new(MemoryQuotaStore::default()),
+            notifications: Box::new(MemoryNotificationStore::default()),
+            backend: Backend::Memory,
+        }
+    }
+
+    /// Applies pending migrations, and normalizes stored usernames the one time the migration
+    /// asking for it is applied, which needs [`Store::supervise`] to have been called first.
+    pub async fn migrate(&self, log: &Logger) -> Result<(), Error> {
+        let applied = match &self.backend {
+            Backend::Postgres(database) => {
+                migrations::run_postgres(&mut database.connect(log).await?, log).await
+            }
+            Backend::Sqlite(sqlite) => {
+                let log = log.clone();
+                sqlite
+                    .call(move |connection| migrations::run_sqlite(connection, &log))
+                    .await
+            }
+            Backend::Memory => Ok(Vec::new()),
+        }?;
+        if !applied.contains(&migrations::NORMALIZE_USERNAMES) {
+            return Ok(());
+        }
+        // Unlike the migrations this goes through the store, so it waits for the supervised
+        // connection.
+        self.ready().await?;
+        user::normalize_stored_usernames(&*self.users, log).await
+    }
+
+    /// Checks that the database schema is the one this build expects, for failing at startup
+    /// rather than on the first query that doesn't fit it.
+    pub async fn check_schema(&self, log: &Logger) -> Result<(), Error> {
+        match &self.backend {
+            Backend::Postgres(database) => {
+                migrations::check_postgres(&database.connect(log).await?).await
+            }
+            Backend::Sqlite(sqlite) => {
+                sqlite
+                    .call(|connection| migrations::check_sqlite(connection))
+                    .await
+            }
+            Backend::Memory => Ok(()),
+        }
+    }
+
+    /// Runs a unit of work which has to happen either completely or not at all, like storing a
+    /// new account along with its first session. On Postgres it's a transaction rolled back when
+    /// the work fails, see [`Database::transaction`]. The other backends run it as is.
+    pub async fn transaction<T>(
+        &self,
+        log: &Logger,
+        work: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        match &self.backend {
+            Backend::Postgres(database) => database.transaction(log, work).await,
+            Backend::Sqlite(_) | Backend::Memory => work.await,
+        }
+    }
+
+    /// Spawns the background tasks the backend needs to stay connected.
+    pub fn supervise(&self, log: &Logger) {
+        if let Backend::Postgres(database) = &self.backend {
+            tokio::spawn(database.clone().supervise(log.clone()));
+        }
+    }
+
+    /// Waits for the backend to connect, for commands that need the store right after starting.
+    pub async fn ready(&self) -> Result<(), Error> {
+        let wait = async {
+            while !self.is_healthy() {
+                tokio::time::sleep(READY_POLL_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(READY_TIMEOUT, wait)
+            .await
+            .map_err(|_| Error::DatabaseUnavailable(Backtrace::capture()))
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        match &self.backend {
+            Backend::Postgres(database) => database.is_healthy(),
+            Backend::Sqlite(_) | Backend::Memory => true,
+        }
+    }
+}
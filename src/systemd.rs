@@ -0,0 +1,66 @@
+use crate::error::Error;
+use crate::util::env_var_opt;
+use slog::{info, warn, Logger};
+use std::net::TcpListener;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixDatagram;
+
+/// First descriptor systemd passes sockets from, right after the standard streams.
+const LISTEN_FDS_START: i32 = 3;
+
+/// Takes the socket systemd listens on for the service when it's socket-activated, which lets it
+/// listen on privileged ports without running as root. The variables telling about the sockets
+/// are removed once taken, so that processes started later don't mistake them for their own.
+pub fn listener(log: &Logger) -> Result<Option<TcpListener>, Error> {
+    // The variables are inherited by children too, so the ones meant for another process are
+    // left alone.
+    let Some(listen_pid) = env_var_opt("LISTEN_PID")? else { return Ok(None); };
+    let listen_fds = env_var_opt("LISTEN_FDS")?;
+    let fds = passed_sockets(&listen_pid, listen_fds.as_deref(), std::process::id())?;
+    let Some(fds) = fds else { return Ok(None); };
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if fds == 0 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!(log, "Only the first socket passed by systemd is listened on"; "sockets" => fds);
+    }
+    // SAFETY: With LISTEN_PID set to this process, systemd guarantees the descriptor is an open
+    // socket nothing else in the process owns, and the variables are gone so it's taken once.
+    let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    info!(log, "Using the socket passed by systemd");
+    Ok(Some(listener))
+}
+
+/// Number of sockets `LISTEN_FDS` says were passed, or nothing when `LISTEN_PID` says they're meant
+/// for a process other than the given one.
+pub fn passed_sockets(
+    listen_pid: &str,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Result<Option<usize>, Error> {
+    if listen_pid.parse::<u32>()? != pid {
+        return Ok(None);
+    }
+    match listen_fds {
+        Some(fds) => Ok(Some(fds.parse()?)),
+        None => Ok(Some(0)),
+    }
+}
+
+/// Tells systemd the service is ready to handle requests, for units with `Type=notify`. Does
+/// nothing when not started by systemd.
+pub fn notify_ready(log: &Logger) -> Result<(), Error> {
+    let Some(path) = env_var_opt("NOTIFY_SOCKET")? else { return Ok(()); };
+    // Sockets in the abstract namespace would need an API the standard library doesn't have yet.
+    if path.starts_with('@') {
+        warn!(log, "Readiness can't be sent to abstract notify sockets"; "socket" => &path);
+        return Ok(());
+    }
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(b"READY=1", &path)?;
+    Ok(())
+}
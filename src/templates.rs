@@ -0,0 +1,216 @@
+use crate::error::Error;
+use crate::i18n;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use sha2::{Digest, Sha256};
+use slog::{error, info, warn, Logger};
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tera::{Context, Tera};
+use uuid::Uuid;
+
+pub struct Templates {
+    tera: RwLock<Tera>,
+    /// Pages rendered by [`Templates::render_cached`], by the hash of the template and context.
+    cache: Mutex<HashMap<String, Arc<Rendered>>>,
+}
+
+/// A page from [`Templates::render_cached`], along with the entity tag to answer conditional
+/// requests with.
+pub struct Rendered {
+    pub html: String,
+    pub etag: String,
+}
+
+/// Templates the handlers render, which have to be there for the pages to work.
+const REQUIRED: &[&str] = &[
+    "403.html",
+    "404.html",
+    "429.html",
+    "500.html",
+    "503.html",
+    "activity.html",
+    "applications.html",
+    "consent.html",
+    "email.html",
+    "error.html",
+    "export.html",
+    "impersonate.html",
+    "index.html",
+    "logout.html",
+    "methods.html",
+    "notifications.html",
+    "password.html",
+    "phone.html",
+    "quota.html",
+    "registrations.html",
+    "service-accounts.html",
+    "sms.html",
+    "status.html",
+    "suspended.html",
+    "terms.html",
+    "totp.html",
+    "unsubscribe.html",
+];
+
+/// Pages the render cache holds before it starts over, as parts of the context like the `next`
+/// query parameter are up to whoever sends the request.
+const CACHE_CAPACITY: usize = 1024;
+
+const DIRECTORY: &str = "templates";
+#[cfg(not(feature = "embed-templates"))]
+const GLOB: &str = "templates/**/*";
+
+#[cfg(feature = "embed-templates")]
+static EMBEDDED: include_dir::Dir = include_dir::include_dir!("$CARGO_MANIFEST_DIR/templates");
+
+impl Templates {
+    /// Loads the templates from the templates directory, or from the copy compiled into the binary
+    /// when built with the `embed-templates` feature.
+    pub fn load() -> Result<Templates, Error> {
+        Ok(Templates {
+            tera: RwLock::new(build()?),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, Error> {
+        Ok(self.tera.read().unwrap().render(name, context)?)
+    }
+
+    /// Checks that none of the templates the handlers render is missing, which otherwise only
+    /// shows once someone opens the page.
+    pub fn check(&self) -> Result<(), Error> {
+        let tera = self.tera.read().unwrap();
+        let names: Vec<_> = tera.get_template_names().collect();
+        match REQUIRED.iter().find(|name| !names.contains(name)) {
+            Some(name) => Err(Error::TemplateMissing(name, Backtrace::capture())),
+            None => Ok(()),
+        }
+    }
+
+    /// Renders the template once for every distinct context, which is only right for pages that
+    /// show nothing of the visitor beyond what's in the context, like the ones for anonymous
+    /// visitors.
+    pub fn render_cached(&self, name: &str, context: &Context) -> Result<Arc<Rendered>, Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(name.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(context.clone().into_json().to_string().as_bytes());
+        let key = hex::encode(hasher.finalize());
+        if let Some(rendered) = self.cache.lock().unwrap().get(&key) {
+            return Ok(rendered.clone());
+        }
+        let rendered = Arc::new(Rendered {
+            html: self.render(name, context)?,
+            etag: format!("\"{}\"", &key[..32]),
+        });
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(key, rendered.clone());
+        Ok(rendered)
+    }
+
+    /// Renders the error page for the given status, falling back to a minimal built-in page if the
+    /// templates themselves are broken.
+    pub fn render_error(&self, error: &Error, request_id: Uuid, locale: &str) -> String {
+        let status = error.status_code();
+        // Some errors need more explaining than the page for their status does.
+        let name = match (error, status.as_u16()) {
+            (Error::RegistrationQuotaExceeded(_), _) => "quota.html".to_owned(),
+            (_, 403 | 404 | 429 | 500 | 503) => format!("{}.html", status.as_u16()),
+            _ => "error.html".to_owned(),
+        };
+        let reason = status.canonical_reason().unwrap_or_default();
+        let mut context = Context::new();
+        context.insert("status", &status.as_u16());
+        context.insert("reason", reason);
+        context.insert("request_id", &request_id.to_string());
+        context.insert("lang", locale);
+        self.render(&name, &context).unwrap_or_else(|_| {
+            format!(
+                "<!DOCTYPE html><title>{0} {1}</title><h1>{0} {1}</h1><p>Request ID: {2}</p>",
+                status.as_u16(),
+                reason,
+                request_id
+            )
+        })
+    }
+
+    /// Rebuilds the templates, dropping the pages rendered with the previous ones. If the new
+    /// templates fail to parse, the previous ones are kept.
+    pub fn reload(&self) -> Result<(), Error> {
+        let tera = build()?;
+        *self.tera.write().unwrap() = tera;
+        self.cache.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Reloads the templates whenever something in the templates directory changes, so they can
+    /// be edited without restarting the server.
+    pub fn watch(self: Arc<Self>, log: Logger) -> Result<(), Error> {
+        if cfg!(feature = "embed-templates") {
+            warn!(
+                log,
+                "Templates are embedded in the binary and won't be reloaded"
+            );
+            return Ok(());
+        }
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher = notify::watcher(sender, Duration::from_millis(100))?;
+        watcher.watch(DIRECTORY, RecursiveMode::Recursive)?;
+        std::thread::spawn(move || {
+            let _watcher = watcher;
+            for event in receiver {
+                if let DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) = event {
+                    continue;
+                }
+                match self.reload() {
+                    Ok(()) => info!(log, "Templates reloaded"),
+                    Err(e) => error!(log, "Templates reload failed"; e.log_message()),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+fn build() -> Result<Tera, Error> {
+    let mut tera = parse()?;
+    tera.register_function("t", i18n::tera_translate);
+    Ok(tera)
+}
+
+#[cfg(not(feature = "embed-templates"))]
+fn parse() -> Result<Tera, Error> {
+    Ok(Tera::new(GLOB)?)
+}
+
+#[cfg(feature = "embed-templates")]
+fn parse() -> Result<Tera, Error> {
+    let mut tera = Tera::default();
+    let mut files = Vec::new();
+    embedded_files(&EMBEDDED, &mut files);
+    tera.add_raw_templates(
+        files
+            .into_iter()
+            .map(|file| (file.path().to_str().unwrap(), file.contents_utf8().unwrap())),
+    )?;
+    Ok(tera)
+}
+
+/// Mail templates are in a subdirectory, which `Dir::files` alone doesn't look into.
+#[cfg(feature = "embed-templates")]
+fn embedded_files(
+    dir: &'static include_dir::Dir<'static>,
+    files: &mut Vec<&'static include_dir::File<'static>>,
+) {
+    files.extend(dir.files());
+    for dir in dir.dirs() {
+        embedded_files(dir, files);
+    }
+}
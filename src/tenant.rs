@@ -0,0 +1,196 @@
+use crate::error::Error;
+use crate::session::HOST_COOKIE_PREFIX;
+use crate::terms::TermsPolicy;
+use crate::util::env_var_opt;
+use cookie::Cookie;
+use hyper::header::{HOST, LOCATION, SET_COOKIE};
+use hyper::{Body, Request, Response, Uri};
+use serde::Deserialize;
+use std::backtrace::Backtrace;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Organization hosted alongside others by the same instance, with users of its own. Usernames and
+/// email addresses only have to be unique within a tenant, and sessions only work for the one they
+/// were created for.
+#[derive(Deserialize)]
+pub struct Tenant {
+    /// Made of lowercase ASCII letters, digits and dashes, as it's used in paths and cookies.
+    pub id: String,
+    pub name: String,
+    /// Host names the tenant is served at, besides under `/t/<id>` at any of them.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Whatever the templates need to look like the tenant's own, such as a logo, passed to them
+    /// as is.
+    #[serde(default)]
+    pub branding: serde_json::Map<String, serde_json::Value>,
+    /// Replaces `REGISTRATION_APPROVAL` for the tenant.
+    #[serde(default)]
+    pub approval: Option<bool>,
+    /// Replaces `TERMS_VERSION` and `TERMS_URL` for the tenant, both at once.
+    #[serde(default)]
+    pub terms: Option<TermsPolicy>,
+}
+
+/// Every tenant, read from the JSON list in `TENANTS_FILE`. Requests not for any of them go to the
+/// default tenant, which everything stored before there were tenants belongs to, and which the
+/// list can give a name and branding by including one with the [`DEFAULT_TENANT`] ID.
+pub struct Tenants {
+    tenants: Vec<Arc<Tenant>>,
+    default: Arc<Tenant>,
+}
+
+/// Path prefix a request came in under, put in the request extensions along with the tenant. It's
+/// stripped before routing, and added back by [`scope_response`] and the templates.
+#[derive(Clone)]
+pub struct TenantPrefix(pub String);
+
+pub const DEFAULT_TENANT: &str = "default";
+
+const PATH_PREFIX: &str = "/t/";
+
+impl Tenant {
+    pub fn terms<'a>(&'a self, default: &'a TermsPolicy) -> &'a TermsPolicy {
+        self.terms.as_ref().unwrap_or(default)
+    }
+
+    pub fn approval(&self, default: bool) -> bool {
+        self.approval.unwrap_or(default)
+    }
+}
+
+impl Default for Tenant {
+    fn default() -> Tenant {
+        Tenant {
+            id: DEFAULT_TENANT.to_owned(),
+            name: "Authtown".to_owned(),
+            hosts: Vec::new(),
+            branding: serde_json::Map::new(),
+            approval: None,
+            terms: None,
+        }
+    }
+}
+
+impl Tenants {
+    pub fn from_env() -> Result<Tenants, Error> {
+        let tenants: Vec<Tenant> = match env_var_opt("TENANTS_FILE")? {
+            Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+            None => Vec::new(),
+        };
+        Tenants::new(tenants)
+    }
+
+    pub fn new(tenants: Vec<Tenant>) -> Result<Tenants, Error> {
+        let mut ids = HashSet::new();
+        let mut hosts = HashSet::new();
+        for tenant in &tenants {
+            let valid_id = !tenant.id.is_empty()
+                && tenant
+                    .id
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+            if !valid_id || !ids.insert(tenant.id.as_str()) {
+                return Err(Error::InvalidTenant(
+                    tenant.id.clone(),
+                    Backtrace::capture(),
+                ));
+            }
+            for host in &tenant.hosts {
+                if !hosts.insert(host.to_lowercase()) {
+                    return Err(Error::InvalidTenant(
+                        tenant.id.clone(),
+                        Backtrace::capture(),
+                    ));
+                }
+            }
+        }
+        let (default, tenants): (Vec<_>, Vec<_>) = tenants
+            .into_iter()
+            .map(Arc::new)
+            .partition(|tenant| tenant.id == DEFAULT_TENANT);
+        Ok(Tenants {
+            tenants,
+            default: default.into_iter().next().unwrap_or_default(),
+        })
+    }
+
+    /// Finds the tenant by the path prefix, then by the host, and otherwise picks the default one.
+    /// A request under a prefix has it stripped from the URI, so that it's routed like one at the
+    /// tenant's own host would be, and the prefix is put in the extensions.
+    pub fn resolve(&self, req: &mut Request<Body>) -> Arc<Tenant> {
+        if let Some((tenant, rest)) = self.by_path(req.uri()) {
+            let prefix = format!("{}{}", PATH_PREFIX, tenant.id);
+            let path_and_query = match req.uri().query() {
+                Some(query) => format!("{}?{}", rest, query),
+                None => rest.to_owned(),
+            };
+            *req.uri_mut() = path_and_query.parse().unwrap();
+            req.extensions_mut().insert(TenantPrefix(prefix));
+            return tenant;
+        }
+        let host = req
+            .uri()
+            .host()
+            .or_else(|| req.headers().get(HOST).and_then(|host| host.to_str().ok()))
+            .map(|host| host.rsplit_once(':').map_or(host, |(host, _)| host));
+        match host.and_then(|host| self.by_host(host)) {
+            Some(tenant) => tenant,
+            None => self.default.clone(),
+        }
+    }
+
+    fn by_path<'a>(&self, uri: &'a Uri) -> Option<(Arc<Tenant>, &'a str)> {
+        let path = uri.path().strip_prefix(PATH_PREFIX)?;
+        let (id, rest) = match path.find('/') {
+            Some(slash) => path.split_at(slash),
+            None => (path, "/"),
+        };
+        let tenant = self.tenants.iter().find(|tenant| tenant.id == id)?;
+        Some((tenant.clone(), rest))
+    }
+
+    fn by_host(&self, host: &str) -> Option<Arc<Tenant>> {
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+            .cloned()
+    }
+}
+
+/// Puts the prefix back in front of redirects within the service, and limits the cookies to the
+/// tenant's paths, so that there can be a session for each tenant sharing a host. Cookies named
+/// with the `__Host-` prefix have to be for every path, so they're shared by the tenants instead,
+/// and logging in to one logs out of the others.
+pub fn scope_response(prefix: &TenantPrefix, response: &mut Response<Body>) {
+    let headers = response.headers_mut();
+    if let Some(location) = headers.get(LOCATION).and_then(|l| l.to_str().ok()) {
+        if location.starts_with('/') && !location.starts_with("//") {
+            let location = format!("{}{}", prefix.0, location);
+            headers.insert(LOCATION, location.parse().unwrap());
+        }
+    }
+    let cookies: Vec<_> = headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .filter_map(|cookie| Cookie::parse(cookie.to_owned()).ok())
+        .collect();
+    if cookies.is_empty() {
+        return;
+    }
+    headers.remove(SET_COOKIE);
+    for mut cookie in cookies {
+        let path = match cookie.path() {
+            Some(_) if cookie.name().starts_with(HOST_COOKIE_PREFIX) => None,
+            Some("/") => Some(prefix.0.clone()),
+            Some(path) if path.starts_with('/') => Some(format!("{}{}", prefix.0, path)),
+            _ => None,
+        };
+        if let Some(path) = path {
+            cookie.set_path(path);
+        }
+        headers.append(SET_COOKIE, cookie.to_string().parse().unwrap());
+    }
+}
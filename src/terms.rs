@@ -0,0 +1,108 @@
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::user::User;
+use crate::util::env_var_opt;
+use cookie::{Cookie, SameSite};
+use serde::Deserialize;
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Terms of service and privacy policy users have to accept, from `TERMS_VERSION` and `TERMS_URL`.
+/// Changing the version has everyone accept the terms again the next time they log in.
+#[derive(Deserialize)]
+pub struct TermsPolicy {
+    pub version: Option<String>,
+    /// Where the terms can be read, linked from the pages asking to accept them.
+    pub url: Option<String>,
+}
+
+/// Login that completed every factor and only waits for the terms to be accepted, carried in a
+/// signed cookie like a [`Challenge`](crate::otp::Challenge) is.
+pub struct PendingTerms {
+    pub user: User,
+    expires_at: u64,
+}
+
+const PENDING_EXPIRATION_TIME: Duration = Duration::from_secs(30 * 60);
+
+const PENDING_SIGNATURE_DOMAIN: &str = "terms.";
+
+impl TermsPolicy {
+    pub fn from_env() -> Result<TermsPolicy, Error> {
+        Ok(TermsPolicy {
+            version: env_var_opt("TERMS_VERSION")?,
+            url: env_var_opt("TERMS_URL")?,
+        })
+    }
+
+    /// Version a registering user accepts by ticking the box, failing when it went unticked.
+    pub fn accept(&self, accepted: bool) -> Result<Option<&str>, Error> {
+        match &self.version {
+            Some(_) if !accepted => Err(Error::TermsNotAccepted(Backtrace::capture())),
+            version => Ok(version.as_deref()),
+        }
+    }
+}
+
+impl PendingTerms {
+    pub fn new(user: User) -> PendingTerms {
+        PendingTerms {
+            user,
+            expires_at: unix_time(SystemTime::now() + PENDING_EXPIRATION_TIME),
+        }
+    }
+
+    pub fn from_cookies(
+        cookies: &HashMap<&str, Cookie>,
+        crypto: &Crypto,
+    ) -> Result<PendingTerms, Error> {
+        let no_pending = || Error::NoLoginChallenge(Backtrace::capture());
+        let cookie = cookies.get("terms").ok_or_else(no_pending)?;
+        let (payload, signature) = cookie.value().rsplit_once('.').ok_or_else(no_pending)?;
+        crypto.verify(&signed_data(payload), &hex::decode(signature)?)?;
+        let (user_id, expires_at) = payload.split_once(':').ok_or_else(no_pending)?;
+        let pending = PendingTerms {
+            user: User {
+                id: user_id.parse()?,
+            },
+            expires_at: expires_at.parse()?,
+        };
+        if pending.expires_at < unix_time(SystemTime::now()) {
+            return Err(no_pending());
+        }
+        Ok(pending)
+    }
+
+    pub fn cookie(&self, crypto: &Crypto) -> Cookie<'static> {
+        let payload = format!("{}:{}", self.user.id, self.expires_at);
+        let signature = crypto.sign(&signed_data(&payload));
+        cookie_raw(
+            format!("{}.{}", payload, hex::encode(&signature.hash)),
+            PENDING_EXPIRATION_TIME,
+        )
+    }
+
+    pub fn cookie_clear() -> Cookie<'static> {
+        cookie_raw(String::new(), Duration::ZERO)
+    }
+}
+
+fn signed_data(payload: &str) -> Vec<u8> {
+    format!("{}{}", PENDING_SIGNATURE_DOMAIN, payload).into_bytes()
+}
+
+fn cookie_raw(value: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build("terms", value)
+        .max_age(max_age.try_into().unwrap())
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
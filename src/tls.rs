@@ -0,0 +1,249 @@
+use crate::error::Error;
+use crate::util::{env_var, env_var_opt};
+use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, ServerConnection};
+use rustls_pemfile::Item;
+use std::backtrace::Backtrace;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+pub fn load_certificates(path: &str) -> Result<Vec<Certificate>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader)?;
+    if certificates.is_empty() {
+        return Err(Error::TlsPem {
+            path: path.to_owned(),
+            backtrace: Backtrace::capture(),
+        });
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+pub fn load_private_key(path: &str) -> Result<PrivateKey, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        if let Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) = item {
+            return Ok(PrivateKey(key));
+        }
+    }
+    Err(Error::TlsPem {
+        path: path.to_owned(),
+        backtrace: Backtrace::capture(),
+    })
+}
+
+/// Reads `TLS_CERT` and `TLS_KEY`, which when set have connections served over TLS rather than
+/// left to a proxy in front. With `TLS_CLIENT_CA_CERT`, clients are asked for a certificate issued
+/// by that CA, which browsers without one can go without, see [`crate::client_cert`].
+pub fn server_config_from_env() -> Result<Option<Arc<ServerConfig>>, Error> {
+    let Some(cert_path) = env_var_opt("TLS_CERT")? else { return Ok(None); };
+    let key_path = env_var("TLS_KEY")?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match env_var_opt("TLS_CLIENT_CA_CERT")? {
+        Some(ca_path) => {
+            let certificates: Vec<_> = load_certificates(&ca_path)?
+                .into_iter()
+                .map(|certificate| certificate.0)
+                .collect();
+            let mut roots = RootCertStore::empty();
+            if roots.add_parsable_certificates(&certificates).1 != 0 {
+                return Err(Error::TlsPem {
+                    path: ca_path,
+                    backtrace: Backtrace::capture(),
+                });
+            }
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config =
+        builder.with_single_cert(load_certificates(&cert_path)?, load_private_key(&key_path)?)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Some(Arc::new(config)))
+}
+
+/// Server side of a TLS connection, as driven by hyper once the handshake is done.
+pub struct TlsStream {
+    io: TcpStream,
+    connection: ServerConnection,
+    closing: bool,
+}
+
+/// The blocking IO rustls wants over a socket's polling, which reports not being ready as
+/// [`io::ErrorKind::WouldBlock`] with the waker of the context registered.
+struct SyncIo<'a, 'b> {
+    io: &'a mut TcpStream,
+    cx: &'a mut Context<'b>,
+}
+
+impl TlsStream {
+    pub async fn accept(mut io: TcpStream, config: Arc<ServerConfig>) -> Result<TlsStream, Error> {
+        let mut connection = ServerConnection::new(config)?;
+        let mut buffer = [0; 4096];
+        while connection.is_handshaking() {
+            if connection.wants_write() {
+                let mut records = Vec::new();
+                connection.write_tls(&mut records)?;
+                io.write_all(&records).await?;
+                continue;
+            }
+            let read = io.read(&mut buffer).await?;
+            if read == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let mut records = &buffer[..read];
+            while !records.is_empty() {
+                connection.read_tls(&mut records)?;
+                if let Err(e) = connection.process_new_packets() {
+                    // The alert saying what went wrong is worth a try, if the client listens.
+                    let mut alert = Vec::new();
+                    connection.write_tls(&mut alert)?;
+                    io.write_all(&alert).await?;
+                    return Err(e.into());
+                }
+            }
+        }
+        // Session tickets and the like are sent once the handshake is over.
+        while connection.wants_write() {
+            let mut records = Vec::new();
+            connection.write_tls(&mut records)?;
+            io.write_all(&records).await?;
+        }
+        Ok(TlsStream {
+            io,
+            connection,
+            closing: false,
+        })
+    }
+
+    /// The certificate the client presented, which has been verified against the client CA.
+    pub fn peer_certificate(&self) -> Option<&Certificate> {
+        self.connection.peer_certificates()?.first()
+    }
+
+    fn poll_write_tls(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.connection.wants_write() {
+            let mut io = SyncIo {
+                io: &mut self.io,
+                cx,
+            };
+            match self.connection.write_tls(&mut io) {
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.connection.reader().read(buf.initialize_unfilled()) {
+                Ok(read) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            let mut io = SyncIo {
+                io: &mut this.io,
+                cx,
+            };
+            match this.connection.read_tls(&mut io) {
+                // Plenty of clients just close the connection without a close_notify, which is
+                // no reason to fail the request they've already been answered.
+                Ok(0) => return Poll::Ready(Ok(())),
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            let processed = this.connection.process_new_packets();
+            // Alerts and key updates go out whether or not there's anything to read.
+            if let Poll::Ready(Err(e)) = this.poll_write_tls(cx) {
+                return Poll::Ready(Err(e));
+            }
+            if let Err(e) = processed {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            let written = this.connection.writer().write(buf)?;
+            match this.poll_write_tls(cx) {
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending if written == 0 => return Poll::Pending,
+                _ => (),
+            }
+            // Nothing gets written only when the buffer was full, which it's not anymore.
+            if written != 0 || buf.is_empty() {
+                return Poll::Ready(Ok(written));
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.connection.writer().flush()?;
+        match this.poll_write_tls(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.io).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closing {
+            this.connection.send_close_notify();
+            this.closing = true;
+        }
+        match this.poll_write_tls(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.io).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+impl Read for SyncIo<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut *self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for SyncIo<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::crypto::Crypto;
+use crate::error::Error;
+use crate::store::Store;
+use crate::user::User;
+use crate::util::constant_time_eq;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac, NewMac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use sha1::Sha1;
+use std::backtrace::Backtrace;
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// RFC 4226 recommends 160 bits, the length of the SHA-1 the codes are made with.
+const SECRET_LENGTH: usize = 20;
+
+/// What authenticator apps assume when the URI doesn't say otherwise, and some of them ignore
+/// the URI saying otherwise, so these are the only ones that work everywhere.
+const DIGITS: u32 = 6;
+const STEP: Duration = Duration::from_secs(30);
+
+/// Steps before and after the current one whose codes are accepted too, as RFC 6238 section 6
+/// suggests for clocks that drift and users that type slowly.
+const SKEW: u64 = 1;
+
+// With a code being good for a minute and a half, wrong ones are limited by time rather than per
+// code like those sent out.
+const MAX_ATTEMPTS: u64 = 5;
+const ATTEMPT_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// Size of the QR code image, big enough for phone cameras to read off a screen.
+const QR_DIMENSIONS: u32 = 240;
+
+/// Generates a new secret for the user to set up, which the QR code of [`qr_code`] carries over
+/// to their authenticator app. Any secret already in use stays so until the new one is confirmed
+/// with [`verify`].
+pub async fn enroll(user: User, store: &Store, crypto: &Crypto) -> Result<(), Error> {
+    let mut secret = [0; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+    let sealed = crypto.seal(&secret);
+    store.otp.set_pending_totp(user, &sealed).await
+}
+
+/// SVG image of the `otpauth://` URI of the pending secret, labeled with the account and the
+/// tenant it's for. The secret in use is never shown, so that it can't be read off later by
+/// anyone who gets hold of the session.
+pub async fn qr_code(
+    user: User,
+    issuer: &str,
+    store: &Store,
+    crypto: &Crypto,
+) -> Result<String, Error> {
+    let pending = store.otp.totp(user).await?.and_then(|totp| totp.pending);
+    let Some(pending) = pending else {
+        return Err(Error::NoTotp(Backtrace::capture()));
+    };
+    let secret = crypto.unseal(&pending)?;
+    let account = store.users.profile(user).await?.username;
+    let uri = key_uri(&secret, issuer, &account);
+    Ok(QrCode::new(uri.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(QR_DIMENSIONS, QR_DIMENSIONS)
+        .build())
+}
+
+/// Checks a code from the app the pending secret is being set up in, putting the secret in use
+/// once one matched.
+pub async fn verify(user: User, code: &str, store: &Store, crypto: &Crypto) -> Result<(), Error> {
+    let pending = store.otp.totp(user).await?.and_then(|totp| totp.pending);
+    let Some(pending) = pending else {
+        return Err(Error::NoTotp(Backtrace::capture()));
+    };
+    check_secret(user, &pending, code, true, store, crypto).await
+}
+
+/// Checks a code made with the secret in use, for logging in or for confirming changes to it.
+pub async fn check(user: User, code: &str, store: &Store, crypto: &Crypto) -> Result<(), Error> {
+    let secret = store.otp.totp(user).await?.and_then(|totp| totp.secret);
+    let Some(secret) = secret else {
+        return Err(Error::NoTotp(Backtrace::capture()));
+    };
+    check_secret(user, &secret, code, false, store, crypto).await
+}
+
+async fn check_secret(
+    user: User,
+    sealed: &str,
+    code: &str,
+    pending: bool,
+    store: &Store,
+    crypto: &Crypto,
+) -> Result<(), Error> {
+    let key = format!("totp:{}", user.id);
+    let window = window_start(SystemTime::now());
+    if store.quotas.count(&key, window).await? >= MAX_ATTEMPTS {
+        return Err(Error::TooManyCodeAttempts(Backtrace::capture()));
+    }
+    let secret = crypto.unseal(sealed)?;
+    let code = code.trim();
+    let current = unix_time(SystemTime::now()) / STEP.as_secs();
+    let step = (current.saturating_sub(SKEW)..=current + SKEW)
+        .find(|&step| constant_time_eq(hotp(&secret, step).as_bytes(), code.as_bytes()));
+    let accepted = match step {
+        Some(step) if pending => store.otp.confirm_totp(user, sealed, step).await?,
+        Some(step) => store.otp.accept_totp(user, sealed, step).await?,
+        None => false,
+    };
+    if accepted {
+        return Ok(());
+    }
+    store
+        .quotas
+        .increment(&key, window, window + ATTEMPT_WINDOW)
+        .await?;
+    Err(Error::WrongCode(Backtrace::capture()))
+}
+
+/// Key URI of the format authenticator apps take, see
+/// <https://github.com/google/google-authenticator/wiki/Key-Uri-Format>.
+fn key_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC).to_string();
+    let account = utf8_percent_encode(account, NON_ALPHANUMERIC);
+    let secret = base32::encode(base32::Alphabet::RFC4648 { padding: false }, secret);
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer,
+        account,
+        secret,
+        issuer,
+        DIGITS,
+        STEP.as_secs()
+    )
+}
+
+/// HOTP code of RFC 4226 section 5.3 for the counter, which for TOTP is the time step.
+fn hotp(secret: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).unwrap();
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    format!(
+        "{:0width$}",
+        value % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+fn window_start(now: SystemTime) -> SystemTime {
+    let window = ATTEMPT_WINDOW.as_secs();
+    UNIX_EPOCH + Duration::from_secs(unix_time(now) / window * window)
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The code the app would show the given number of steps from now, for tests to log in with.
+#[cfg(test)]
+pub fn code_in(secret: &[u8], steps: u64) -> String {
+    let current = unix_time(SystemTime::now()) / STEP.as_secs();
+    hotp(secret, current + steps)
+}
@@ -0,0 +1,339 @@
+use crate::error::Error;
+use crate::store::Store;
+use crate::tenant::DEFAULT_TENANT;
+use crate::user::{self, normalize_email, normalize_username, AccountStatus, UserRecord};
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+use std::backtrace::Backtrace;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How the users are written down, for moving them between instances or in from another system.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// An array of objects with the fields of [`Row`].
+    Json,
+    /// A header naming the fields of [`Row`], in any order, followed by a line per user.
+    Csv,
+}
+
+/// What to do with users whose username is already taken in their tenant.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OnConflict {
+    /// Stops the import, which has to be run again once the conflict is resolved.
+    Fail,
+    /// Leaves the existing user alone.
+    Skip,
+    /// Replaces the existing user's email address, password and status.
+    Overwrite,
+}
+
+/// How many users were imported, and what happened to the ones already there.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Summary {
+    pub imported: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+}
+
+/// A user as written down. Only the username is required, so that exports of other systems don't
+/// need to make up the rest.
+#[derive(Deserialize, Serialize)]
+struct Row {
+    #[serde(default = "default_tenant")]
+    tenant: String,
+    username: String,
+    #[serde(default)]
+    email: Option<String>,
+    /// Empty for users without a password.
+    #[serde(default)]
+    password_phc: String,
+    /// Seconds since the Unix epoch, the time of the import when missing.
+    #[serde(default)]
+    password_changed_at: Option<u64>,
+    #[serde(default)]
+    status: Option<String>,
+}
+
+const CSV_HEADER: &[&str] = &[
+    "tenant",
+    "username",
+    "email",
+    "password_phc",
+    "password_changed_at",
+    "status",
+];
+
+/// Imports are big enough for progress to be worth reporting, but not so big for every user.
+const PROGRESS_INTERVAL: usize = 1000;
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Format> {
+        match format {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl OnConflict {
+    pub fn parse(on_conflict: &str) -> Option<OnConflict> {
+        match on_conflict {
+            "fail" => Some(OnConflict::Fail),
+            "skip" => Some(OnConflict::Skip),
+            "overwrite" => Some(OnConflict::Overwrite),
+            _ => None,
+        }
+    }
+}
+
+/// Writes down every user of every tenant, with their password hashes. Anything else about them,
+/// like sessions and linked identities, stays behind.
+pub async fn export(store: &Store, format: Format) -> Result<String, Error> {
+    let rows: Vec<_> = store
+        .users
+        .records()
+        .await?
+        .into_iter()
+        .map(|record| Row {
+            tenant: record.tenant,
+            username: record.username,
+            email: record.email,
+            password_phc: record.password_phc,
+            password_changed_at: Some(unix_time(record.password_changed_at)),
+            status: Some(record.status.as_str().to_owned()),
+        })
+        .collect();
+    match format {
+        Format::Json => Ok(serde_json::to_string_pretty(&rows)? + "\n"),
+        Format::Csv => Ok(write_csv(&rows)),
+    }
+}
+
+/// Creates the users written down in the data. Every one of them is checked before any is
+/// stored, and on Postgres the import happens in a single transaction, so that a failed import
+/// doesn't leave a part of the users behind.
+pub async fn import(
+    store: &Store,
+    data: &str,
+    format: Format,
+    on_conflict: OnConflict,
+    log: &Logger,
+) -> Result<Summary, Error> {
+    let rows = match format {
+        Format::Json => serde_json::from_str(data)?,
+        Format::Csv => read_csv(data)?,
+    };
+    let records = validate(rows)?;
+    info!(log, "Importing users"; "count" => records.len());
+    let work = async {
+        let mut summary = Summary::default();
+        for (i, record) in records.iter().enumerate() {
+            import_one(store, record, on_conflict, &mut summary)
+                .await
+                .map_err(|e| invalid(i, e))?;
+            if (i + 1) % PROGRESS_INTERVAL == 0 {
+                info!(log, "Importing users"; "done" => i + 1, "count" => records.len());
+            }
+        }
+        Ok(summary)
+    };
+    let summary = store.transaction(log, work).await?;
+    info!(log, "Users imported"; "imported" => summary.imported, "overwritten" => summary.overwritten, "skipped" => summary.skipped);
+    Ok(summary)
+}
+
+async fn import_one(
+    store: &Store,
+    record: &UserRecord,
+    on_conflict: OnConflict,
+    summary: &mut Summary,
+) -> Result<(), Error> {
+    let existing = store
+        .users
+        .find_by_username(&record.tenant, &normalize_username(&record.username))
+        .await?;
+    match (existing, on_conflict) {
+        (None, _) => {
+            store.users.import(record).await?;
+            summary.imported += 1;
+        }
+        (Some(_), OnConflict::Fail) => return Err(Error::UsernameTaken(Backtrace::capture())),
+        (Some(_), OnConflict::Skip) => summary.skipped += 1,
+        (Some(user), OnConflict::Overwrite) => {
+            store.users.overwrite(user, record).await?;
+            summary.overwritten += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Checks and normalizes the rows, failing on the first one that can't be imported. Usernames
+/// are only checked against the policy as they're stored.
+fn validate(rows: Vec<Row>) -> Result<Vec<UserRecord>, Error> {
+    let now = SystemTime::now();
+    let mut seen = HashSet::new();
+    let mut records = Vec::with_capacity(rows.len());
+    for (i, row) in rows.into_iter().enumerate() {
+        let record = validate_row(row, now).map_err(|e| invalid(i, e))?;
+        let key = (record.tenant.clone(), normalize_username(&record.username));
+        if !seen.insert(key) {
+            return Err(invalid(i, Error::UsernameTaken(Backtrace::capture())));
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn validate_row(row: Row, now: SystemTime) -> Result<UserRecord, Error> {
+    let email = match row.email.as_deref() {
+        Some("") | None => None,
+        Some(email) => Some(normalize_email(email)?),
+    };
+    if row.password_phc != user::NO_PASSWORD {
+        user::check_password_phc(&row.password_phc)?;
+    }
+    let status = match row.status.as_deref() {
+        Some("") | None => AccountStatus::Active,
+        Some(status) => AccountStatus::parse(status)
+            .ok_or_else(|| Error::UnknownAccountStatus(status.to_owned(), Backtrace::capture()))?,
+    };
+    Ok(UserRecord {
+        tenant: row.tenant,
+        username: row.username,
+        email,
+        password_phc: row.password_phc,
+        password_changed_at: row
+            .password_changed_at
+            .map_or(now, |seconds| UNIX_EPOCH + Duration::from_secs(seconds)),
+        status,
+    })
+}
+
+/// Records are counted from 1, as people reading the error would.
+fn invalid(i: usize, e: Error) -> Error {
+    Error::InvalidImport {
+        record: i + 1,
+        source: Box::new(e),
+        backtrace: Backtrace::capture(),
+    }
+}
+
+fn write_csv(rows: &[Row]) -> String {
+    let mut csv = CSV_HEADER.join(",") + "\r\n";
+    for row in rows {
+        let fields = [
+            row.tenant.clone(),
+            row.username.clone(),
+            row.email.clone().unwrap_or_default(),
+            row.password_phc.clone(),
+            row.password_changed_at
+                .map(|seconds| seconds.to_string())
+                .unwrap_or_default(),
+            row.status.clone().unwrap_or_default(),
+        ];
+        let fields: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+        csv += &fields.join(",");
+        csv += "\r\n";
+    }
+    csv
+}
+
+/// Quotes the field when it has anything that would otherwise end it, as in RFC 4180.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Reads the rows under the header line, which names the columns. Columns other than the fields
+/// of [`Row`] are left out, so that exports of other systems can be imported as they are.
+fn read_csv(data: &str) -> Result<Vec<Row>, Error> {
+    let mut lines = parse_csv(data)?.into_iter();
+    let header = lines.next().unwrap_or_default();
+    let column = |name| header.iter().position(|column| column == name);
+    let (tenant, username, email, password_phc, password_changed_at, status) = (
+        column("tenant"),
+        column("username"),
+        column("email"),
+        column("password_phc"),
+        column("password_changed_at"),
+        column("status"),
+    );
+    let Some(username) = username else {
+        return Err(Error::InvalidCsv("no username column".to_owned(), Backtrace::capture()));
+    };
+    let mut rows = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|column| line.get(column))
+                .filter(|field| !field.is_empty())
+                .cloned()
+        };
+        let password_changed_at = field(password_changed_at)
+            .map(|seconds| seconds.parse())
+            .transpose()
+            .map_err(|e| invalid(i, Error::from(e)))?;
+        rows.push(Row {
+            tenant: field(tenant).unwrap_or_else(default_tenant),
+            username: field(Some(username)).unwrap_or_default(),
+            email: field(email),
+            password_phc: field(password_phc).unwrap_or_default(),
+            password_changed_at,
+            status: field(status),
+        });
+    }
+    Ok(rows)
+}
+
+/// Splits the data into lines of fields, with quoted fields able to span lines.
+fn parse_csv(data: &str) -> Result<Vec<Vec<String>>, Error> {
+    let mut lines = Vec::new();
+    let mut line = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = data.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            c if quoted => field.push(c),
+            ',' => line.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => (),
+            // Blank lines, like the one a file often ends with, aren't users.
+            '\n' if line.is_empty() && field.is_empty() => (),
+            '\n' => {
+                line.push(std::mem::take(&mut field));
+                lines.push(std::mem::take(&mut line));
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(Error::InvalidCsv(
+            "unterminated quoted field".to_owned(),
+            Backtrace::capture(),
+        ));
+    }
+    if !field.is_empty() || !line.is_empty() {
+        line.push(field);
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+fn default_tenant() -> String {
+    DEFAULT_TENANT.to_owned()
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
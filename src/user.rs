@@ -1,62 +1,452 @@
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
+use crate::legacy;
+use crate::util::{env_usize, env_var_opt};
 use argon2::password_hash::rand_core::OsRng;
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use tokio_postgres::GenericClient;
+use slog::{info, warn, Logger};
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::lazy::SyncLazy;
+use std::time::SystemTime;
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Clone, Copy, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[serde(transparent)]
 pub struct User {
     pub id: i32,
 }
 
-pub struct UserStore<'d, D: GenericClient> {
-    argon2: Argon2<'static>,
-    database: &'d D,
+pub struct Profile {
+    pub id: i32,
+    pub tenant: String,
+    pub username: String,
+    pub email: Option<String>,
+    /// Whether the user can log in with the password, which they may have removed in favor of
+    /// other methods.
+    pub has_password: bool,
+    pub password_changed_at: SystemTime,
+    /// Whether the account is a service account, see [`UserStore::insert_service_account`].
+    pub service: bool,
+}
+
+/// Everything about a user that goes along with them when they're moved to another instance,
+/// see [`crate::transfer`].
+#[derive(Clone, Debug)]
+pub struct UserRecord {
+    pub tenant: String,
+    pub username: String,
+    pub email: Option<String>,
+    /// [`NO_PASSWORD`] for users who removed it.
+    pub password_phc: String,
+    pub password_changed_at: SystemTime,
+    pub status: AccountStatus,
+}
+
+/// Account at some other identity provider linked as a way of logging in. The subject is whatever
+/// the provider identifies the user by, so it's only unique along with the provider.
+#[derive(Clone)]
+pub struct Identity {
+    pub id: i32,
+    pub provider: String,
+    pub subject: String,
+}
+
+/// Whether the user may log in. Suspensions are meant to be lifted and bans aren't, though admins
+/// can reinstate either.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccountStatus {
+    Active,
+    Suspended,
+    Banned,
+    /// Registered while registrations need approval, and not yet looked at by an admin.
+    Pending,
+    /// Registration an admin turned down.
+    Rejected,
+}
+
+/// Which usernames can be taken, checked by the stores whenever one is set after being brought to
+/// the form given by [`normalize_username`]. Reserved names are
+/// ones that could pass for the service itself, so that nobody can pose as its staff.
+#[derive(Clone)]
+pub struct UsernamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Lowercase, as names are reserved regardless of case.
+    pub reserved: Vec<String>,
+}
+
+/// What a user typed in to say who they are when logging in with a password.
+pub enum LoginName {
+    Username(String),
+    /// Normalized like stored addresses, although not checked for being valid, as an invalid one
+    /// just doesn't match anyone.
+    Email(String),
 }
 
-impl<'d, D: GenericClient> UserStore<'d, D> {
-    pub fn new(database: &'d D) -> Self {
-        Self {
-            argon2: Argon2::default(),
-            database,
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    /// Checks the password of the tenant's user with the username or the email address, as told
+    /// apart by [`LoginName::parse`].
+    async fn get_and_verify(
+        &self,
+        tenant: &str,
+        login: &str,
+        password: &str,
+    ) -> Result<User, Error>;
+
+    /// Creates a user of the tenant, with the email address already normalized by
+    /// [`normalize_email`]. Fails when the username isn't allowed by the [`UsernamePolicy`], or is
+    /// taken within the tenant.
+    async fn insert(
+        &self,
+        tenant: &str,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> Result<User, Error>;
+
+    /// Creates a service account of the tenant, which belongs to some other system rather than a
+    /// person. It has no password or email address, and can't log in interactively even with some
+    /// other method linked, so it only acts through the credentials of its OAuth clients. Fails the
+    /// same as [`UserStore::insert`].
+    async fn insert_service_account(&self, tenant: &str, username: &str) -> Result<User, Error>;
+
+    /// Service accounts of the tenant along with their usernames, oldest first.
+    async fn service_accounts(&self, tenant: &str) -> Result<Vec<(User, String)>, Error>;
+
+    async fn find_by_email(&self, tenant: &str, email: &str) -> Result<Option<User>, Error>;
+
+    /// Finds the tenant's user by the username, already normalized by [`normalize_username`].
+    async fn find_by_username(&self, tenant: &str, username: &str) -> Result<Option<User>, Error>;
+
+    /// Sets or removes the email address, normalized by [`normalize_email`], which only has to be
+    /// unique within the user's tenant.
+    async fn set_email(&self, user: User, email: Option<&str>) -> Result<(), Error>;
+
+    /// Replaces the password, or sets one for users who had it removed.
+    async fn set_password(&self, user: User, password: &str) -> Result<(), Error>;
+
+    /// Replaces the hash with another one of the same password, so when it was changed stays.
+    async fn set_password_phc(&self, user: User, password_phc: &str) -> Result<(), Error>;
+
+    /// Makes the password stop working, for users who log in some other way.
+    async fn remove_password(&self, user: User) -> Result<(), Error>;
+
+    async fn link_identity(&self, user: User, provider: &str, subject: &str) -> Result<(), Error>;
+
+    async fn find_by_identity(&self, provider: &str, subject: &str) -> Result<Option<User>, Error>;
+
+    async fn identities(&self, user: User) -> Result<Vec<Identity>, Error>;
+
+    /// Returns whether the user had the identity.
+    async fn unlink_identity(&self, user: User, id: i32) -> Result<bool, Error>;
+
+    async fn profile(&self, user: User) -> Result<Profile, Error>;
+
+    /// Changes the username, which has to be allowed by the [`UsernamePolicy`] like a new one.
+    async fn rename(&self, user: User, username: &str) -> Result<(), Error>;
+
+    /// Every user of every tenant along with their username, as stored.
+    async fn usernames(&self) -> Result<Vec<(User, String)>, Error>;
+
+    /// Records that the user accepted the version of the terms of service, keeping the versions
+    /// accepted before.
+    async fn accept_terms(&self, user: User, version: &str) -> Result<(), Error>;
+
+    /// The version of the terms the user accepted last, if any.
+    async fn accepted_terms(&self, user: User) -> Result<Option<String>, Error>;
+
+    async fn status(&self, user: User) -> Result<AccountStatus, Error>;
+
+    async fn set_status(&self, user: User, status: AccountStatus) -> Result<(), Error>;
+
+    /// Users of the tenant with the status along with their usernames, oldest first.
+    async fn with_status(
+        &self,
+        tenant: &str,
+        status: AccountStatus,
+    ) -> Result<Vec<(User, String)>, Error>;
+
+    /// Every user of every tenant, password hashes included, oldest first.
+    async fn records(&self) -> Result<Vec<UserRecord>, Error>;
+
+    /// Creates a user as they were somewhere else, with the password hash kept as it is. Fails
+    /// the same as [`UserStore::insert`].
+    async fn import(&self, record: &UserRecord) -> Result<User, Error>;
+
+    /// Replaces everything about the user but the username with the record's.
+    async fn overwrite(&self, user: User, record: &UserRecord) -> Result<(), Error>;
+}
+
+const DEFAULT_MIN_USERNAME_LENGTH: usize = 3;
+const DEFAULT_MAX_USERNAME_LENGTH: usize = 32;
+const DEFAULT_RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "api",
+    "auth",
+    "authtown",
+    "help",
+    "oauth",
+    "root",
+    "security",
+    "settings",
+    "support",
+    "system",
+];
+
+impl UsernamePolicy {
+    /// Reads `USERNAME_MIN_LENGTH`, `USERNAME_MAX_LENGTH`, and `USERNAME_RESERVED`, a
+    /// comma-separated list replacing the default reserved names.
+    pub fn from_env() -> Result<UsernamePolicy, Error> {
+        let default = UsernamePolicy::default();
+        let reserved = match env_var_opt("USERNAME_RESERVED")? {
+            Some(reserved) => reserved
+                .split(',')
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            None => default.reserved,
+        };
+        Ok(UsernamePolicy {
+            min_length: env_usize("USERNAME_MIN_LENGTH", default.min_length)?,
+            max_length: env_usize("USERNAME_MAX_LENGTH", default.max_length)?,
+            reserved,
+        })
+    }
+
+    /// Normalizes the username and checks that it's allowed. Usernames are made of ASCII letters,
+    /// digits, dots, dashes and underscores, so that they can be told apart at a glance and fit in
+    /// URLs and logs as they are.
+    pub fn normalize(&self, username: &str) -> Result<String, Error> {
+        let username = normalize_username(username);
+        let length = username.chars().count();
+        if length < self.min_length || length > self.max_length {
+            return Err(Error::InvalidUsernameLength {
+                min: self.min_length,
+                max: self.max_length,
+                backtrace: Backtrace::capture(),
+            });
+        }
+        let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_');
+        if !username.chars().all(allowed) {
+            return Err(Error::InvalidUsernameCharacters(Backtrace::capture()));
         }
+        if self.reserved.contains(&username) {
+            return Err(Error::UsernameReserved(Backtrace::capture()));
+        }
+        Ok(username)
     }
+}
 
-    pub async fn get_and_verify(&self, username: &str, password: &str) -> Result<User, Error> {
-        let row = self
-            .database
-            .query_one(
-                "SELECT id, password_phc FROM users WHERE username = $1",
-                &[&username],
-            )
-            .await?;
-        let id: i32 = row.get(0);
-        let password_phc: &str = row.get(1);
-        let password_phc = PasswordHash::new(password_phc).unwrap();
-        self.argon2
-            .verify_password(password.as_bytes(), &password_phc)
-            .unwrap();
-        Ok(User { id })
+impl Default for UsernamePolicy {
+    fn default() -> UsernamePolicy {
+        UsernamePolicy {
+            min_length: DEFAULT_MIN_USERNAME_LENGTH,
+            max_length: DEFAULT_MAX_USERNAME_LENGTH,
+            reserved: DEFAULT_RESERVED_USERNAMES
+                .iter()
+                .map(|name| (*name).to_owned())
+                .collect(),
+        }
+    }
+}
+
+impl AccountStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Suspended => "suspended",
+            AccountStatus::Banned => "banned",
+            AccountStatus::Pending => "pending",
+            AccountStatus::Rejected => "rejected",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<AccountStatus> {
+        match status {
+            "active" => Some(AccountStatus::Active),
+            "suspended" => Some(AccountStatus::Suspended),
+            "banned" => Some(AccountStatus::Banned),
+            "pending" => Some(AccountStatus::Pending),
+            "rejected" => Some(AccountStatus::Rejected),
+            _ => None,
+        }
+    }
+}
+
+impl LoginName {
+    /// Usernames can't contain `@`, so anything with one is taken for an email address.
+    pub fn parse(login: &str) -> LoginName {
+        if login.contains('@') {
+            LoginName::Email(login.trim().to_lowercase())
+        } else {
+            LoginName::Username(normalize_username(login))
+        }
+    }
+}
+
+/// Finds whoever the login name belongs to, for telling who a failed login was aimed at.
+pub async fn find_by_login(
+    users: &dyn UserStore,
+    tenant: &str,
+    login: &str,
+) -> Result<Option<User>, Error> {
+    match LoginName::parse(login) {
+        LoginName::Username(username) => users.find_by_username(tenant, &username).await,
+        LoginName::Email(email) => users.find_by_email(tenant, &email).await,
+    }
+}
+
+/// Brings the username to the form it's stored and compared in, so that names differing only in
+/// case or in how the same characters are encoded can't belong to different users. Lowercasing
+/// stands in for case folding, and normalizing again afterwards catches what lowercasing decomposes.
+pub fn normalize_username(username: &str) -> String {
+    username
+        .nfkc()
+        .collect::<String>()
+        .to_lowercase()
+        .nfkc()
+        .collect()
+}
+
+/// Normalizes usernames stored before [`normalize_username`] was applied to them. Ones that would
+/// end up the same as another user's, or no longer allowed by the policy, are left alone and
+/// reported, as those users can't log in by username until an operator resolves it and runs
+/// `users normalize` to finish the job.
+pub async fn normalize_stored_usernames(users: &dyn UserStore, log: &Logger) -> Result<(), Error> {
+    let usernames = users.usernames().await?;
+    let mut owners = HashMap::<String, usize>::new();
+    for (_, username) in &usernames {
+        *owners.entry(normalize_username(username)).or_default() += 1;
+    }
+    for (user, username) in usernames {
+        let normalized = normalize_username(&username);
+        if normalized == username {
+            continue;
+        }
+        if owners[&normalized] > 1 {
+            warn!(log, "Username collides with another one once normalized"; user, "username" => &username, "normalized" => &normalized);
+            continue;
+        }
+        match users.rename(user, &username).await {
+            Ok(()) => info!(log, "Username normalized"; user, "normalized" => &normalized),
+            Err(e) if e.kind() == ErrorKind::Unprocessable => {
+                warn!(log, "Username is not allowed once normalized"; user, "username" => &username, e.log_message())
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the email address looks deliverable and brings it to the form it's stored in.
+/// Addresses are compared case-insensitively, as that's what people expect even though the local
+/// part technically isn't.
+pub fn normalize_email(email: &str) -> Result<String, Error> {
+    let email = email.trim().to_lowercase();
+    if email.parse::<lettre::Address>().is_err() {
+        return Err(Error::InvalidEmail(Backtrace::capture()));
     }
+    Ok(email)
+}
 
-    pub async fn insert(&self, username: &str, password: &str) -> Result<User, Error> {
+// Argon2 is deliberately slow, so both of these run on the blocking thread pool instead of stalling
+// other requests being served by the same worker.
+
+pub async fn hash_password(password: &str) -> String {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || {
         let salt = SaltString::generate(OsRng);
-        let hash = self
-            .argon2
+        Argon2::default()
             .hash_password(password.as_bytes(), &salt)
-            .unwrap();
-        let row = self
-            .database
-            .query_one(
-                "INSERT INTO users (username, password_phc) VALUES ($1, $2) RETURNING id;",
-                &[&username, &hash.to_string()],
-            )
-            .await?;
-        let id = row.get(0);
-        Ok(User { id })
+            .unwrap()
+            .to_string()
+    })
+    .await
+    .unwrap()
+}
+
+/// Stands in for the password of users who removed it. The column can't be made nullable, as
+/// SQLite can only do that by recreating the table, which would cascade to everything referencing
+/// it.
+pub const NO_PASSWORD: &str = "";
+
+/// What hashes made by [`hash_password`] start with.
+const CURRENT_ALGORITHM: &str = "$argon2id$";
+
+/// Hash of nothing in particular, for [`verify_missing_password`] to check passwords against.
+static DUMMY_PASSWORD_PHC: SyncLazy<String> = SyncLazy::new(|| {
+    let salt = SaltString::generate(OsRng);
+    Argon2::default()
+        .hash_password(b"", &salt)
+        .unwrap()
+        .to_string()
+});
+
+/// Checks that the hash is one passwords can be verified against, for hashes coming from elsewhere.
+/// Besides PHC strings, that's the hashes of other systems in [`legacy`].
+pub fn check_password_phc(password_phc: &str) -> Result<(), Error> {
+    if legacy::is_legacy(password_phc) {
+        return legacy::check(password_phc);
     }
+    match PasswordHash::new(password_phc) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Error::InvalidPasswordHash(Backtrace::capture())),
+    }
+}
+
+pub async fn verify_password(password: &str, password_phc: &str) -> Result<(), Error> {
+    if password_phc == NO_PASSWORD {
+        verify_missing_password(password).await;
+        return Err(Error::WrongPassword(Backtrace::capture()));
+    }
+    let password = password.to_owned();
+    let password_phc = password_phc.to_owned();
+    tokio::task::spawn_blocking(move || {
+        if legacy::is_legacy(&password_phc) {
+            return legacy::verify(&password, &password_phc);
+        }
+        let password_phc = PasswordHash::new(&password_phc)
+            .map_err(|_| Error::InvalidPasswordHash(Backtrace::capture()))?;
+        Argon2::default()
+            .verify_password(password.as_bytes(), &password_phc)
+            .map_err(|_| Error::WrongPassword(Backtrace::capture()))
+    })
+    .await
+    .unwrap()
+}
+
+/// Rehashes the password with Argon2id after a successful login, if its hash was made some other
+/// way, like by the system the user was imported from. It's the only time the password is known.
+pub async fn upgrade_password_hash(
+    users: &dyn UserStore,
+    user: User,
+    password: &str,
+    password_phc: &str,
+) -> Result<(), Error> {
+    if password_phc == NO_PASSWORD || password_phc.starts_with(CURRENT_ALGORITHM) {
+        return Ok(());
+    }
+    users
+        .set_password_phc(user, &hash_password(password).await)
+        .await
+}
+
+/// Takes as long as checking a password, for when there's no password to check it against. Without
+/// it, logins as users that don't exist would fail noticeably faster than ones with a wrong
+/// password, telling which usernames are taken.
+pub async fn verify_missing_password(password: &str) {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let password_phc = PasswordHash::new(&DUMMY_PASSWORD_PHC).unwrap();
+        let _ = Argon2::default().verify_password(password.as_bytes(), &password_phc);
+    })
+    .await
+    .unwrap()
 }
 
 impl slog::KV for User {
@@ -1,5 +1,10 @@
 use crate::error::Error;
+use hyper::client::HttpConnector;
+use hyper::Client;
+use hyper_rustls::HttpsConnector;
 use std::backtrace::Backtrace;
+use std::env::VarError;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub fn env_var(name: &'static str) -> Result<String, Error> {
     match std::env::var(name) {
@@ -11,3 +16,113 @@ pub fn env_var(name: &'static str) -> Result<String, Error> {
         }),
     }
 }
+
+pub fn env_var_opt(name: &'static str) -> Result<Option<String>, Error> {
+    match env_var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::Environment {
+            source: VarError::NotPresent,
+            ..
+        }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn env_duration_ms(name: &'static str, default: Duration) -> Result<Duration, Error> {
+    match env_var_opt(name)? {
+        Some(value) => Ok(Duration::from_millis(value.parse()?)),
+        None => Ok(default),
+    }
+}
+
+pub fn env_duration_ms_opt(name: &'static str) -> Result<Option<Duration>, Error> {
+    match env_var_opt(name)? {
+        Some(value) => Ok(Some(Duration::from_millis(value.parse()?))),
+        None => Ok(None),
+    }
+}
+
+pub fn env_usize(name: &'static str, default: usize) -> Result<usize, Error> {
+    match env_var_opt(name)? {
+        Some(value) => Ok(value.parse()?),
+        None => Ok(default),
+    }
+}
+
+pub fn env_usize_opt(name: &'static str) -> Result<Option<usize>, Error> {
+    match env_var_opt(name)? {
+        Some(value) => Ok(Some(value.parse()?)),
+        None => Ok(None),
+    }
+}
+
+pub fn env_flag(name: &'static str) -> Result<bool, Error> {
+    Ok(matches!(env_var_opt(name)?.as_deref(), Some("1" | "true")))
+}
+
+/// Whether redirecting to the path keeps the browser on this site. Browsers treat `//host` and
+/// `/\host` as links to other hosts, and ignore tabs and newlines inside URLs.
+pub fn is_local_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.contains('\\')
+        && !path.chars().any(char::is_control)
+}
+
+/// Compared in constant time, so that how long it takes doesn't tell how much matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    a.len() == b.len() && difference == 0
+}
+
+/// Splits off the first bytes of the input, for reading binary formats a field at a time.
+pub fn take<'a>(input: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    if input.len() < length {
+        return None;
+    }
+    let (taken, rest) = input.split_at(length);
+    *input = rest;
+    Some(taken)
+}
+
+/// Client for calling the HTTPS APIs of mail and SMS services.
+pub fn http_client() -> Client<HttpsConnector<HttpConnector>> {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_only()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
+
+/// Time as shown on pages, like `2022-03-04 05:06 UTC`, as the time zone of whoever reads them
+/// isn't known.
+pub fn format_time(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (days, seconds) = ((seconds / 86400) as i64, seconds % 86400);
+    // Days since the epoch to a date in the Gregorian calendar, counting eras of 400 years from
+    // the 1st of March so that leap days come at the end of each year.
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = match month < 10 {
+        true => (year_of_era + era * 400, month + 3),
+        false => (year_of_era + era * 400 + 1, month - 9),
+    };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}